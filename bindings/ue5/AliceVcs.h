@@ -1,5 +1,5 @@
 // ALICE-VCS UE5 C++ Header
-// 20 FFI functions for AST tree, diff, commit, and repository
+// 22 FFI functions for AST tree, diff, commit, and repository
 //
 // Author: Moroya Sakamoto
 
@@ -27,6 +27,9 @@ struct AliceVcsDiffStats {
     uint32_t move_count;
     uint32_t total_ops;
     uint32_t patch_bytes;
+    uint32_t retype_count;
+    uint32_t set_attr_count;
+    uint32_t remove_attr_count;
 };
 
 // --- AstTree ---
@@ -37,6 +40,8 @@ uint32_t alice_vcs_tree_add_node_float(AliceAstTreeHandle handle, uint8_t kind,
 uint32_t alice_vcs_tree_node_count(AliceAstTreeHandle handle);
 uint32_t alice_vcs_tree_root_id(AliceAstTreeHandle handle);
 char*    alice_vcs_tree_get_label(AliceAstTreeHandle handle, uint32_t node_id);
+char*    alice_vcs_tree_get_path(AliceAstTreeHandle handle, uint32_t node_id);
+uint32_t alice_vcs_tree_find_path(AliceAstTreeHandle handle, const char* path);
 uint8_t  alice_vcs_tree_get_kind(AliceAstTreeHandle handle, uint32_t node_id);
 uint64_t alice_vcs_tree_subtree_hash(AliceAstTreeHandle handle, uint32_t node_id);
 void     alice_vcs_tree_remove_subtree(AliceAstTreeHandle handle, uint32_t node_id);
@@ -102,6 +107,12 @@ public:
     /// Get label. Caller must free with FreeString().
     char* GetLabel(uint32_t NodeId) const { return alice_vcs_tree_get_label(Handle, NodeId); }
 
+    /// Get path, e.g. "/root/union/sphere[1]/radius". Caller must free with FreeString().
+    char* GetPath(uint32_t NodeId) const { return alice_vcs_tree_get_path(Handle, NodeId); }
+
+    /// Node at a path, or UINT32_MAX if there is none.
+    uint32_t FindPath(const char* Path) const { return alice_vcs_tree_find_path(Handle, Path); }
+
     static void FreeString(char* S) { if (S) alice_vcs_string_free(S); }
 
     AliceAstTreeHandle GetHandle() const { return Handle; }
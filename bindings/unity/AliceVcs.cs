@@ -1,5 +1,5 @@
 // ALICE-VCS Unity C# Bindings
-// 20 FFI functions for AST tree, diff, commit, and repository
+// 22 FFI functions for AST tree, diff, commit, and repository
 //
 // Author: Moroya Sakamoto
 
@@ -19,6 +19,9 @@ namespace AliceVcs
         public uint moveCount;
         public uint totalOps;
         public uint patchBytes;
+        public uint retypeCount;
+        public uint setAttrCount;
+        public uint removeAttrCount;
     }
 
     internal static class Native
@@ -33,6 +36,8 @@ namespace AliceVcs
         [DllImport(DLL)] public static extern uint alice_vcs_tree_node_count(IntPtr handle);
         [DllImport(DLL)] public static extern uint alice_vcs_tree_root_id(IntPtr handle);
         [DllImport(DLL)] public static extern IntPtr alice_vcs_tree_get_label(IntPtr handle, uint nodeId);
+        [DllImport(DLL)] public static extern IntPtr alice_vcs_tree_get_path(IntPtr handle, uint nodeId);
+        [DllImport(DLL)] public static extern uint alice_vcs_tree_find_path(IntPtr handle, byte[] path);
         [DllImport(DLL)] public static extern byte alice_vcs_tree_get_kind(IntPtr handle, uint nodeId);
         [DllImport(DLL)] public static extern ulong alice_vcs_tree_subtree_hash(IntPtr handle, uint nodeId);
         [DllImport(DLL)] public static extern void alice_vcs_tree_remove_subtree(IntPtr handle, uint nodeId);
@@ -109,6 +114,18 @@ namespace AliceVcs
             return str;
         }
 
+        public string GetPath(uint nodeId)
+        {
+            var ptr = Native.alice_vcs_tree_get_path(_handle, nodeId);
+            if (ptr == IntPtr.Zero) return null;
+            var str = Util.PtrToString(ptr);
+            Native.alice_vcs_string_free(ptr);
+            return str;
+        }
+
+        public uint FindPath(string path)
+            => Native.alice_vcs_tree_find_path(_handle, Util.ToNullTerminated(path));
+
         public AstNodeKind GetKind(uint nodeId)
             => (AstNodeKind)Native.alice_vcs_tree_get_kind(_handle, nodeId);
 
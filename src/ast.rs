@@ -1,10 +1,22 @@
 //! Abstract Syntax Tree representation
 //!
 //! Generic tree structure for procedural data (SDF CSG, scene graphs,
-//! panel layouts). Each node has a kind, optional value, and children.
+//! panel layouts). Each node has a kind, optional value, named
+//! attributes, and children.
 //!
 //! Author: Moroya Sakamoto
 
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::path::NodePath;
+use crate::schema::{Schema, Violation};
+use crate::traverse::{
+    self, Ancestors, BreadthFirst, Descendants, PostOrder, PreOrder, Siblings, Visitor,
+};
+
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap;
 #[cfg(not(feature = "std"))]
 use alloc::collections::BTreeMap as HashMap;
 #[cfg(not(feature = "std"))]
@@ -12,6 +24,8 @@ use alloc::collections::BTreeSet as HashSet;
 #[cfg(not(feature = "std"))]
 use alloc::{string::String, vec, vec::Vec};
 #[cfg(feature = "std")]
+use std::collections::BTreeMap;
+#[cfg(feature = "std")]
 use std::collections::HashMap;
 #[cfg(feature = "std")]
 use std::collections::HashSet;
@@ -75,19 +89,217 @@ pub enum NodeValue {
     Ident(String),
     /// Raw bytes
     Bytes(Vec<u8>),
+    /// Boolean flag
+    Bool(bool),
+    /// 2D vector (x, y)
+    Vec2([f64; 2]),
+    /// 3D vector (x, y, z)
+    Vec3([f64; 3]),
+    /// 4D vector (x, y, z, w)
+    Vec4([f64; 4]),
+    /// Rotation quaternion (x, y, z, w)
+    Quat([f64; 4]),
+    /// 4×4 matrix, column-major
+    Mat4([f64; 16]),
+    /// Linear RGBA color
+    Color([f64; 4]),
+    /// List of values of one variant; build with [`NodeValue::list`] to
+    /// have that checked
+    List(Vec<Self>),
+    /// Reference to another node of the same tree — e.g. the prototype an
+    /// instance shares. Follow it with [`AstTree::resolve`].
+    Ref(NodeId),
 }
 
 impl NodeValue {
+    /// Homogeneous list, or `None` if the items are not all the same
+    /// variant
+    #[must_use]
+    pub fn list(items: Vec<Self>) -> Option<Self> {
+        let list = Self::List(items);
+        list.is_homogeneous().then_some(list)
+    }
+
+    /// False only for a `List` whose items — or the items of a list nested
+    /// in it — are not all the same variant
+    #[must_use]
+    pub fn is_homogeneous(&self) -> bool {
+        let mut pending = vec![self];
+        while let Some(value) = pending.pop() {
+            if let Self::List(items) = value {
+                if !items.windows(2).all(|w| w[0].same_variant(&w[1])) {
+                    return false;
+                }
+                pending.extend(items);
+            }
+        }
+        true
+    }
+
+    /// True if `self` and `other` are the same variant
+    #[must_use]
+    pub fn same_variant(&self, other: &Self) -> bool {
+        core::mem::discriminant(self) == core::mem::discriminant(other)
+    }
+
+    /// Components of a vector, quaternion, matrix or color
+    #[must_use]
+    pub const fn components(&self) -> Option<&[f64]> {
+        match self {
+            Self::Vec2(v) => Some(v),
+            Self::Vec3(v) => Some(v),
+            Self::Vec4(v) | Self::Quat(v) | Self::Color(v) => Some(v),
+            Self::Mat4(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// IDs this value refers to, including those inside lists
+    #[must_use]
+    pub fn refs(&self) -> Vec<NodeId> {
+        let mut refs = Vec::new();
+        self.for_each_ref(&mut |id| refs.push(id));
+        refs
+    }
+
+    fn for_each_ref(&self, f: &mut impl FnMut(NodeId)) {
+        match self {
+            Self::Ref(id) => f(*id),
+            Self::List(items) => {
+                for item in items {
+                    item.for_each_ref(f);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Rewrite every ID this value refers to through `f`.
+    pub(crate) fn map_refs(&mut self, f: &mut impl FnMut(NodeId) -> NodeId) {
+        match self {
+            Self::Ref(id) => *id = f(*id),
+            Self::List(items) => {
+                for item in items {
+                    item.map_refs(f);
+                }
+            }
+            _ => {}
+        }
+    }
+
     /// Size in bytes when serialized
     #[must_use]
     pub const fn serialized_size(&self) -> usize {
         match self {
             Self::None => 1,
+            Self::Bool(_) => 2,
             Self::Int(_) | Self::Float(_) => 9,
+            Self::Ref(_) => 5,
             Self::Text(s) | Self::Ident(s) => 3 + s.len(),
             Self::Bytes(b) => 3 + b.len(),
+            Self::List(items) => {
+                let items = items.as_slice();
+                let mut size = 3;
+                let mut i = 0;
+                while i < items.len() {
+                    size += items[i].serialized_size();
+                    i += 1;
+                }
+                size
+            }
+            Self::Vec2(_) => 17,
+            Self::Vec3(_) => 25,
+            Self::Vec4(_) | Self::Quat(_) | Self::Color(_) => 33,
+            Self::Mat4(_) => 129,
         }
     }
+
+    /// Feed the canonical encoding of this value to `w`.
+    ///
+    /// Each variant is prefixed by a tag byte and variable-length payloads
+    /// are length-prefixed. Floats are canonicalised so that `-0.0` encodes
+    /// like `0.0` and every NaN payload encodes alike.
+    pub(crate) fn write_canonical(&self, w: &mut impl FnMut(&[u8])) {
+        match self {
+            Self::None => w(&[0]),
+            Self::Int(v) => {
+                w(&[1]);
+                w(&v.to_le_bytes());
+            }
+            Self::Float(v) => {
+                w(&[2]);
+                w(&canonical_f64_bits(*v).to_le_bytes());
+            }
+            Self::Text(s) => {
+                w(&[3]);
+                write_len_prefixed(w, s.as_bytes());
+            }
+            Self::Ident(s) => {
+                w(&[4]);
+                write_len_prefixed(w, s.as_bytes());
+            }
+            Self::Bytes(b) => {
+                w(&[5]);
+                write_len_prefixed(w, b);
+            }
+            Self::Bool(v) => w(&[6, u8::from(*v)]),
+            Self::Vec2(v) => write_floats(w, 7, v),
+            Self::Vec3(v) => write_floats(w, 8, v),
+            Self::Vec4(v) => write_floats(w, 9, v),
+            Self::Quat(v) => write_floats(w, 10, v),
+            Self::Mat4(m) => write_floats(w, 11, m),
+            Self::Color(v) => write_floats(w, 12, v),
+            Self::List(items) => {
+                w(&[13]);
+                w(&(items.len() as u64).to_le_bytes());
+                for item in items {
+                    item.write_canonical(w);
+                }
+            }
+            Self::Ref(id) => {
+                w(&[14]);
+                w(&id.to_le_bytes());
+            }
+        }
+    }
+
+    /// Equal as the Merkle hash sees it: `-0.0` equals `0.0` and NaN
+    /// equals NaN, in every component.
+    pub(crate) fn canonical_eq(&self, other: &Self) -> bool {
+        let mut a = Vec::new();
+        self.write_canonical(&mut |bytes| a.extend_from_slice(bytes));
+        let mut b = Vec::new();
+        other.write_canonical(&mut |bytes| b.extend_from_slice(bytes));
+        a == b
+    }
+}
+
+/// Length-prefixed so that adjacent fields cannot alias each other.
+#[inline]
+fn write_len_prefixed(w: &mut impl FnMut(&[u8]), bytes: &[u8]) {
+    w(&(bytes.len() as u64).to_le_bytes());
+    w(bytes);
+}
+
+/// Tag byte, then each component canonicalised.
+#[inline]
+fn write_floats(w: &mut impl FnMut(&[u8]), tag: u8, components: &[f64]) {
+    w(&[tag]);
+    for &c in components {
+        w(&canonical_f64_bits(c).to_le_bytes());
+    }
+}
+
+/// Bit pattern of `v` with signed zeros and NaN payloads collapsed.
+#[inline]
+const fn canonical_f64_bits(v: f64) -> u64 {
+    if v.is_nan() {
+        f64::NAN.to_bits()
+    } else if v == 0.0 {
+        0
+    } else {
+        v.to_bits()
+    }
 }
 
 /// AST node
@@ -101,8 +313,14 @@ pub struct AstNode {
     pub label: String,
     /// Attached value
     pub value: NodeValue,
+    /// Named attributes (e.g. "width", "height"), ordered by key
+    pub attrs: BTreeMap<String, NodeValue>,
     /// Child node IDs
     pub children: Vec<NodeId>,
+    /// Cached Merkle hash of this subtree
+    hash: HashCache,
+    /// Cached SHA-256 digest of this subtree, for content IDs
+    digest: DigestCache,
 }
 
 impl AstNode {
@@ -113,7 +331,10 @@ impl AstNode {
             kind,
             label: String::from(label),
             value: NodeValue::None,
+            attrs: BTreeMap::new(),
             children: Vec::new(),
+            hash: HashCache::new(),
+            digest: DigestCache::new(),
         }
     }
 
@@ -123,6 +344,65 @@ impl AstNode {
         self
     }
 
+    #[must_use]
+    pub fn with_attr(mut self, key: &str, value: NodeValue) -> Self {
+        self.attrs.insert(String::from(key), value);
+        self
+    }
+
+    /// Value of the attribute `key`, if set
+    #[must_use]
+    pub fn attr(&self, key: &str) -> Option<&NodeValue> {
+        self.attrs.get(key)
+    }
+
+    /// IDs the node's value and attributes refer to
+    #[must_use]
+    pub fn refs(&self) -> Vec<NodeId> {
+        let mut refs = Vec::new();
+        self.for_each_ref(&mut |id| refs.push(id));
+        refs
+    }
+
+    fn for_each_ref(&self, f: &mut impl FnMut(NodeId)) {
+        self.value.for_each_ref(f);
+        for value in self.attrs.values() {
+            value.for_each_ref(f);
+        }
+    }
+
+    /// Feed the node's own canonical encoding to `w`: its ID, kind, label,
+    /// value, attributes and child count.
+    pub(crate) fn write_canonical(&self, w: &mut impl FnMut(&[u8])) {
+        w(&self.id.to_le_bytes());
+        w(&[self.kind as u8]);
+        write_len_prefixed(w, self.label.as_bytes());
+        self.value.write_canonical(w);
+        self.write_attrs(w);
+        w(&(self.children.len() as u64).to_le_bytes());
+    }
+
+    /// Content digest of this subtree cached by
+    /// [`ContentId::of_tree`](crate::content_id::ContentId::of_tree), or
+    /// `None` if it is dirty.
+    pub(crate) fn cached_digest(&self) -> Option<[u8; 32]> {
+        self.digest.get()
+    }
+
+    pub(crate) fn cache_digest(&self, digest: &[u8; 32]) {
+        self.digest.set(digest);
+    }
+
+    /// Feed the canonical encoding of the attributes to `w`: the count,
+    /// then each key and value in key order.
+    fn write_attrs(&self, w: &mut impl FnMut(&[u8])) {
+        w(&(self.attrs.len() as u64).to_le_bytes());
+        for (key, value) in &self.attrs {
+            write_len_prefixed(w, key.as_bytes());
+            value.write_canonical(w);
+        }
+    }
+
     #[must_use]
     pub fn with_children(mut self, children: Vec<NodeId>) -> Self {
         self.children = children;
@@ -131,6 +411,15 @@ impl AstNode {
 }
 
 /// AST tree — flat storage of nodes with O(1) ID lookup via `HashMap` index
+///
+/// Each node caches its Merkle hash and content digest. Mutation through [`Self::get_node_mut`],
+/// [`Self::add_node`] or [`Self::remove_subtree`] marks only the path from
+/// the changed node to the root dirty, so re-hashing after a local edit
+/// costs O(depth). The cache is filled through `&self` but stays `Sync`,
+/// so trees can still be shared across threads.
+///
+/// References are indexed by target, so finding the nodes that refer into
+/// a subtree costs O(subtree) rather than a scan of the whole tree.
 #[derive(Debug, Clone)]
 pub struct AstTree {
     nodes: Vec<AstNode>,
@@ -138,6 +427,13 @@ pub struct AstTree {
     index: HashMap<NodeId, usize>,
     /// Maps child `NodeId` → parent `NodeId` for O(1) parent lookup
     parent_index: HashMap<NodeId, NodeId>,
+    /// Maps each node holding references → the IDs it refers to
+    ref_targets: HashMap<NodeId, Vec<NodeId>>,
+    /// Maps each referred-to ID → the nodes referring to it
+    referrers: HashMap<NodeId, HashSet<NodeId>>,
+    /// Node last handed out by [`Self::get_node_mut`]; its references may
+    /// have changed since they were indexed
+    ref_pending: Option<NodeId>,
     root_id: NodeId,
     next_id: NodeId,
 }
@@ -158,6 +454,9 @@ impl AstTree {
             nodes: vec![root],
             index,
             parent_index: HashMap::new(),
+            ref_targets: HashMap::new(),
+            referrers: HashMap::new(),
+            ref_pending: None,
             root_id: 0,
             next_id: 1,
         }
@@ -194,6 +493,51 @@ impl AstTree {
         id
     }
 
+    /// Add a node under a caller-chosen ID, appended to `parent_id`'s
+    /// children.
+    ///
+    /// Returns `false` and leaves the tree untouched if `id` is already in
+    /// use or the parent does not exist. Later [`Self::add_node`] calls
+    /// continue past `id`, so chosen and assigned IDs never collide.
+    pub fn insert_with_id(
+        &mut self,
+        id: NodeId,
+        kind: AstNodeKind,
+        label: &str,
+        value: NodeValue,
+        parent_id: NodeId,
+    ) -> bool {
+        self.insert_with_id_at(id, kind, label, value, parent_id, usize::MAX)
+    }
+
+    /// Like [`Self::insert_with_id`], but places the node at `index` among
+    /// the parent's children (clamped to the child count).
+    pub fn insert_with_id_at(
+        &mut self,
+        id: NodeId,
+        kind: AstNodeKind,
+        label: &str,
+        value: NodeValue,
+        parent_id: NodeId,
+        index: usize,
+    ) -> bool {
+        if self.index.contains_key(&id) || !self.index.contains_key(&parent_id) {
+            return false;
+        }
+        let idx = self.nodes.len();
+        self.nodes
+            .push(AstNode::new(id, kind, label).with_value(value));
+        self.index.insert(id, idx);
+        self.parent_index.insert(id, parent_id);
+        if let Some(parent) = self.get_node_mut(parent_id) {
+            let index = index.min(parent.children.len());
+            parent.children.insert(index, id);
+        }
+        self.index_refs(id);
+        self.next_id = self.next_id.max(id.saturating_add(1));
+        true
+    }
+
     /// Get node by ID — O(1) via `HashMap` index
     #[must_use]
     pub fn get_node(&self, id: NodeId) -> Option<&AstNode> {
@@ -201,8 +545,109 @@ impl AstTree {
     }
 
     /// Get mutable node by ID — O(1) via `HashMap` index
+    ///
+    /// Invalidates the cached hashes of the node and its ancestors.
     pub fn get_node_mut(&mut self, id: NodeId) -> Option<&mut AstNode> {
-        self.index.get(&id).map(|&idx| &mut self.nodes[idx])
+        let &idx = self.index.get(&id)?;
+        self.invalidate_hashes(id);
+        // The previous node's borrow has ended; this one's starts now
+        self.settle_refs();
+        self.ref_pending = Some(id);
+        Some(&mut self.nodes[idx])
+    }
+
+    /// Index the references of the node last handed out by
+    /// [`Self::get_node_mut`].
+    fn settle_refs(&mut self) {
+        if let Some(id) = self.ref_pending.take() {
+            self.index_refs(id);
+        }
+    }
+
+    /// Re-index the references `id` holds now — O(refs).
+    fn index_refs(&mut self, id: NodeId) {
+        self.unindex_refs(id);
+        let Some(node) = self.get_node(id) else {
+            return;
+        };
+        let targets = node.refs();
+        if targets.is_empty() {
+            return;
+        }
+        for &target in &targets {
+            self.referrers.entry(target).or_default().insert(id);
+        }
+        self.ref_targets.insert(id, targets);
+    }
+
+    /// Drop `id`'s references from the index.
+    fn unindex_refs(&mut self, id: NodeId) {
+        for target in self.ref_targets.remove(&id).unwrap_or_default() {
+            if let Some(from) = self.referrers.get_mut(&target) {
+                from.remove(&id);
+                if from.is_empty() {
+                    self.referrers.remove(&target);
+                }
+            }
+        }
+    }
+
+    /// Nodes that currently refer to `target`, in ID order.
+    #[must_use]
+    pub fn referrers_of(&self, target: NodeId) -> Vec<NodeId> {
+        let mut from: Vec<NodeId> = self
+            .referrers
+            .get(&target)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|&r| Some(r) != self.ref_pending)
+            .collect();
+        if let Some(pending) = self.ref_pending {
+            if self
+                .get_node(pending)
+                .is_some_and(|n| n.refs().contains(&target))
+            {
+                from.push(pending);
+            }
+        }
+        from.sort_unstable();
+        from
+    }
+
+    /// Every node that currently holds a reference, in ID order.
+    #[must_use]
+    pub fn referring_nodes(&self) -> Vec<NodeId> {
+        let mut nodes: Vec<NodeId> = self
+            .ref_targets
+            .keys()
+            .copied()
+            .filter(|&r| Some(r) != self.ref_pending)
+            .collect();
+        if let Some(pending) = self.ref_pending {
+            if self.get_node(pending).is_some_and(|n| !n.refs().is_empty()) {
+                nodes.push(pending);
+            }
+        }
+        nodes.sort_unstable();
+        nodes
+    }
+
+    /// Mark `id` and its ancestors dirty — O(depth), stops early at the
+    /// first ancestor whose hash and digest are both already dirty (its
+    /// ancestors' are too).
+    fn invalidate_hashes(&self, id: NodeId) {
+        let mut current = Some(id);
+        while let Some(cid) = current {
+            let Some(node) = self.get_node(cid) else {
+                break;
+            };
+            let was_filled = node.hash.clear() | node.digest.clear();
+            if !was_filled && cid != id {
+                break;
+            }
+            current = self.parent_of(cid);
+        }
     }
 
     /// Root node ID
@@ -211,13 +656,27 @@ impl AstTree {
         self.root_id
     }
 
+    /// ID the next [`Self::add_node`] will assign
+    #[must_use]
+    pub const fn next_id(&self) -> NodeId {
+        self.next_id
+    }
+
+    /// Put the ID counter back to `next_id` once the nodes that raised it
+    /// are gone again, as when a patch is rolled back.
+    pub(crate) fn rewind_next_id(&mut self, next_id: NodeId) {
+        debug_assert!(self.index.keys().all(|&id| id < next_id));
+        self.next_id = next_id;
+    }
+
     /// Total node count
     #[must_use]
     pub const fn node_count(&self) -> usize {
         self.nodes.len()
     }
 
-    /// Get all nodes
+    /// Get all nodes, in storage order; removing a subtree moves the last
+    /// stored nodes into the freed slots
     #[must_use]
     pub fn nodes(&self) -> &[AstNode] {
         &self.nodes
@@ -230,13 +689,26 @@ impl AstTree {
     }
 
     /// Remove a node and all its descendants
-    pub fn remove_subtree(&mut self, id: NodeId) {
-        // Collect IDs to remove into a Vec first, then build a HashSet for O(1) membership.
-        let mut to_remove_vec = Vec::new();
-        self.collect_subtree(id, &mut to_remove_vec);
+    ///
+    /// Returns `false` and leaves the tree untouched if `id` does not exist
+    /// or a node outside the subtree still refers into it (see
+    /// [`Self::external_refs`]); redirect or remove those references first.
+    pub fn remove_subtree(&mut self, id: NodeId) -> bool {
+        if self.get_node(id).is_none() || !self.external_refs(id).is_empty() {
+            return false;
+        }
+        self.remove_subtree_unchecked(id);
+        true
+    }
 
-        // Build HashSet for O(1) membership test used in retain() below.
-        let to_remove: HashSet<NodeId> = to_remove_vec.iter().copied().collect();
+    /// [`Self::remove_subtree`] without the reference check. Patches check
+    /// for dangling references once all their ops are applied.
+    pub(crate) fn remove_subtree_unchecked(&mut self, id: NodeId) {
+        let to_remove: Vec<NodeId> = self.pre_order(id).map(|n| n.id).collect();
+        self.settle_refs();
+        for &rid in &to_remove {
+            self.unindex_refs(rid);
+        }
 
         // Remove from parent's children
         if let Some(parent_id) = self.parent_of(id) {
@@ -245,53 +717,608 @@ impl AstTree {
             }
         }
 
-        // Remove from parent_index — O(1) per entry
-        for &rid in &to_remove {
+        // Remove nodes — O(1) each: the last stored node fills the hole,
+        // so only its index entry changes
+        for rid in to_remove {
             self.parent_index.remove(&rid);
+            if let Some(idx) = self.index.remove(&rid) {
+                self.nodes.swap_remove(idx);
+                if let Some(moved) = self.nodes.get(idx) {
+                    self.index.insert(moved.id, idx);
+                }
+            }
         }
+    }
 
-        // Remove nodes — O(1) per node via HashSet lookup instead of O(n) Vec scan
-        self.nodes.retain(|n| !to_remove.contains(&n.id));
-        self.index.clear();
-        for (idx, node) in self.nodes.iter().enumerate() {
-            self.index.insert(node.id, idx);
+    /// References into `id`'s subtree from nodes outside it, as
+    /// `(referrer, target)` pairs in order.
+    ///
+    /// Looks up the referrers of each node in the subtree — O(subtree),
+    /// and O(1) for a tree without references.
+    #[must_use]
+    pub fn external_refs(&self, id: NodeId) -> Vec<(NodeId, NodeId)> {
+        if self.referrers.is_empty() && self.ref_pending.is_none() {
+            return Vec::new();
         }
+        let inside: HashSet<NodeId> = self.pre_order(id).map(|n| n.id).collect();
+        let mut refs: Vec<(NodeId, NodeId)> = inside
+            .iter()
+            .flat_map(|&target| {
+                self.referrers_of(target)
+                    .into_iter()
+                    .map(move |referrer| (referrer, target))
+            })
+            .filter(|(referrer, _)| !inside.contains(referrer))
+            .collect();
+        refs.sort_unstable();
+        refs
     }
 
-    fn collect_subtree(&self, id: NodeId, result: &mut Vec<NodeId>) {
-        result.push(id);
-        if let Some(node) = self.get_node(id) {
-            for &child_id in &node.children {
-                self.collect_subtree(child_id, result);
+    /// Follow `Ref` values from `id` to the node they lead to — `id`
+    /// itself if its value is not a reference.
+    ///
+    /// Returns `None` if `id` does not exist, a reference dangles or the
+    /// references form a cycle.
+    #[must_use]
+    pub fn resolve(&self, id: NodeId) -> Option<NodeId> {
+        let mut current = id;
+        // A chain longer than the node count must revisit a node
+        for _ in 0..=self.nodes.len() {
+            match self.get_node(current)?.value {
+                NodeValue::Ref(target) => current = target,
+                _ => return Some(current),
             }
         }
+        None
+    }
+
+    /// Human-readable path of `id`, e.g. `/root/union/sphere[1]/radius`;
+    /// `None` if `id` does not exist or is cut off from the root.
+    #[must_use]
+    pub fn path_of(&self, id: NodeId) -> Option<NodePath> {
+        NodePath::of(self, id)
+    }
+
+    /// The node at `path`, if there is one — the inverse of
+    /// [`Self::path_of`].
+    #[must_use]
+    pub fn node_at(&self, path: &NodePath) -> Option<NodeId> {
+        path.resolve(self)
+    }
+
+    /// Detach `id` from its parent and insert it into `new_parent_id`'s
+    /// children at `index` (clamped to the child count).
+    ///
+    /// Returns `false` and leaves the tree untouched if either node is
+    /// missing, `id` is the root, or `new_parent_id` lies inside `id`'s
+    /// subtree (the move would create a cycle).
+    pub fn move_node(&mut self, id: NodeId, new_parent_id: NodeId, index: usize) -> bool {
+        if id == self.root_id
+            || self.get_node(id).is_none()
+            || self.is_ancestor_or_self(id, new_parent_id)
+        {
+            return false;
+        }
+        let Some(old_parent_id) = self.parent_of(id) else {
+            return false;
+        };
+        if self.get_node(new_parent_id).is_none() {
+            return false;
+        }
+        if let Some(parent) = self.get_node_mut(old_parent_id) {
+            parent.children.retain(|&c| c != id);
+        }
+        if let Some(parent) = self.get_node_mut(new_parent_id) {
+            let index = index.min(parent.children.len());
+            parent.children.insert(index, id);
+        }
+        self.parent_index.insert(id, new_parent_id);
+        true
+    }
+
+    /// True if `ancestor` is `id` or lies on the path from `id` to the root.
+    #[must_use]
+    pub fn is_ancestor_or_self(&self, ancestor: NodeId, id: NodeId) -> bool {
+        id == ancestor || self.ancestors(id).any(|n| n.id == ancestor)
+    }
+
+    /// The subtree at `id` in pre-order, parents before children; empty if
+    /// `id` does not exist.
+    #[must_use]
+    pub fn pre_order(&self, id: NodeId) -> PreOrder<'_> {
+        PreOrder::new(self, id)
+    }
+
+    /// The subtree at `id` in post-order, children before parents.
+    #[must_use]
+    pub fn post_order(&self, id: NodeId) -> PostOrder<'_> {
+        PostOrder::new(self, id)
+    }
+
+    /// The subtree at `id` level by level.
+    #[must_use]
+    pub fn breadth_first(&self, id: NodeId) -> BreadthFirst<'_> {
+        BreadthFirst::new(self, id)
+    }
+
+    /// `id`'s parent, grandparent and so on up to the root.
+    #[must_use]
+    pub const fn ancestors(&self, id: NodeId) -> Ancestors<'_> {
+        Ancestors::new(self, id)
+    }
+
+    /// Strict descendants of `id` in pre-order, each with its depth below
+    /// `id` (children are at depth 1).
+    #[must_use]
+    pub fn descendants(&self, id: NodeId) -> Descendants<'_> {
+        Descendants::new(self, id)
+    }
+
+    /// The other children of `id`'s parent, in order.
+    #[must_use]
+    pub fn siblings(&self, id: NodeId) -> Siblings<'_> {
+        Siblings::new(self, id)
+    }
+
+    /// Walk the subtree at `id` depth-first, calling `visitor` on the way
+    /// in and out of every node. Does nothing if `id` does not exist.
+    pub fn walk(&self, id: NodeId, visitor: &mut impl Visitor) {
+        traverse::walk(self, id, visitor);
     }
 
     /// Compute Merkle hash of a subtree (FNV-1a)
+    ///
+    /// Covers kind, label, value, attributes and the ordered child hashes,
+    /// so any content change anywhere below `id` changes the result.
+    /// Results are cached per node; only dirty nodes are re-hashed.
     #[must_use]
     pub fn subtree_hash(&self, id: NodeId) -> u64 {
-        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
-        self.hash_node(id, &mut h);
-        h
+        let Some(node) = self.get_node(id) else {
+            return FNV_OFFSET;
+        };
+        if node.hash.get().is_none() {
+            self.walk(id, &mut HashFill { tree: self });
+        }
+        node.hash.get().unwrap_or(FNV_OFFSET)
     }
 
-    fn hash_node(&self, id: NodeId, h: &mut u64) {
-        if let Some(node) = self.get_node(id) {
-            // Hash kind + label
-            *h ^= node.kind as u64;
-            *h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    /// Cached Merkle hash of a subtree, or `None` if it is dirty or absent.
+    ///
+    /// Never computes anything — O(1).
+    #[must_use]
+    pub fn cached_subtree_hash(&self, id: NodeId) -> Option<u64> {
+        self.get_node(id).and_then(|n| n.hash.get())
+    }
+
+    /// Check every node against `schema`; empty if the tree conforms.
+    #[must_use]
+    pub fn validate(&self, schema: &Schema) -> Vec<Violation> {
+        schema.check(self)
+    }
+
+    /// Check that the node storage, ID index, parent index and child lists
+    /// agree and form a single tree, and that every reference names an
+    /// existing node; empty if the tree is intact.
+    ///
+    /// Linear in the node count. Reports every broken invariant rather
+    /// than stopping at the first.
+    #[must_use]
+    pub fn check_invariants(&self) -> Vec<InvariantError> {
+        let mut out = Vec::new();
+        let mut report = |node_id, kind| out.push(InvariantError { node_id, kind });
+
+        // ID index ↔ storage
+        for (slot, node) in self.nodes.iter().enumerate() {
+            if self.index.get(&node.id) != Some(&slot) {
+                report(node.id, InvariantKind::Unindexed { slot });
+            }
+        }
+        let mut entries: Vec<(NodeId, usize)> = self.index.iter().map(|(&i, &s)| (i, s)).collect();
+        entries.sort_unstable();
+        for (id, slot) in entries {
+            if self.nodes.get(slot).map(|n| n.id) != Some(id) {
+                report(id, InvariantKind::BadIndex { slot });
+            }
+        }
+
+        // Root
+        if self.get_node(self.root_id).is_none() {
+            report(self.root_id, InvariantKind::MissingRoot);
+        }
+        if let Some(parent) = self.parent_of(self.root_id) {
+            report(self.root_id, InvariantKind::RootHasParent(parent));
+        }
+
+        // Child lists → parent index
+        let mut listed = HashSet::new();
+        for node in &self.nodes {
+            for &child in &node.children {
+                if self.get_node(child).is_none() {
+                    report(node.id, InvariantKind::MissingChild(child));
+                } else if !listed.insert(child) {
+                    report(child, InvariantKind::ListedTwice { parent: node.id });
+                } else if self.parent_of(child) != Some(node.id) {
+                    let recorded = self.parent_of(child);
+                    report(
+                        child,
+                        InvariantKind::ParentMismatch {
+                            parent: node.id,
+                            recorded,
+                        },
+                    );
+                }
+            }
+        }
+
+        // Parent index → child lists
+        let mut entries: Vec<(NodeId, NodeId)> =
+            self.parent_index.iter().map(|(&c, &p)| (c, p)).collect();
+        entries.sort_unstable();
+        for (child, parent) in entries {
+            if self.get_node(child).is_none() {
+                report(child, InvariantKind::StaleParent { parent });
+            } else if !self
+                .get_node(parent)
+                .is_some_and(|p| p.children.contains(&child))
+            {
+                report(child, InvariantKind::NotListed { parent });
+            }
+        }
+
+        // Every node reaches the root: walk parent chains, remembering
+        // each node's outcome so no chain is walked twice.
+        let mut reach: HashMap<NodeId, Reach> = HashMap::new();
+        reach.insert(self.root_id, Reach::Root);
+        for node in &self.nodes {
+            let mut path = Vec::new();
+            let mut current = node.id;
+            let outcome = loop {
+                match reach.get(&current) {
+                    Some(Reach::Root) => break Reach::Root,
+                    Some(Reach::Broken) => break Reach::Broken,
+                    Some(Reach::OnPath) => {
+                        report(current, InvariantKind::Cycle);
+                        break Reach::Broken;
+                    }
+                    None => {}
+                }
+                reach.insert(current, Reach::OnPath);
+                path.push(current);
+                match self.parent_of(current) {
+                    // A missing parent is already reported as `NotListed`
+                    Some(parent) if self.get_node(parent).is_some() => current = parent,
+                    Some(_) => break Reach::Broken,
+                    None => {
+                        report(current, InvariantKind::Orphan);
+                        break Reach::Broken;
+                    }
+                }
+            };
+            for id in path {
+                reach.insert(id, outcome);
+            }
+        }
+
+        // References
+        for node in &self.nodes {
+            node.for_each_ref(&mut |target| {
+                if self.get_node(target).is_none() {
+                    report(node.id, InvariantKind::DanglingRef(target));
+                }
+            });
+        }
+
+        // ID allocation
+        if let Some(max) = self.nodes.iter().map(|n| n.id).max() {
+            if max >= self.next_id {
+                report(
+                    max,
+                    InvariantKind::NextIdTooLow {
+                        next_id: self.next_id,
+                    },
+                );
+            }
+        }
+        out
+    }
+
+    /// Panic with the diagnostics if [`Self::check_invariants`] finds
+    /// anything; a no-op in release builds.
+    pub(crate) fn debug_assert_intact(&self) {
+        if cfg!(debug_assertions) {
+            let errors = self.check_invariants();
+            assert!(errors.is_empty(), "corrupt AstTree: {errors:?}");
+        }
+    }
+
+    /// Compute a structure-only hash of a subtree (FNV-1a)
+    ///
+    /// Folds in kind and label of every node but ignores values, so two
+    /// subtrees that differ only in parameters hash equal. Kept only for
+    /// compatibility: it reproduces what `subtree_hash` returned before it
+    /// covered values. The fold is flat pre-order, not Merkle-shaped, so a
+    /// nesting `a/b` hashes the same as siblings `a, b`; the diff engine
+    /// does not use it.
+    #[must_use]
+    pub fn structure_hash(&self, id: NodeId) -> u64 {
+        let mut h: u64 = FNV_OFFSET;
+        // Hash kind + label of every node in pre-order
+        for node in self.pre_order(id) {
+            h ^= node.kind as u64;
+            h = h.wrapping_mul(FNV_PRIME);
             for &b in node.label.as_bytes() {
-                *h ^= b as u64;
-                *h = h.wrapping_mul(0x0000_0100_0000_01b3);
+                h ^= b as u64;
+                h = h.wrapping_mul(FNV_PRIME);
+            }
+        }
+        h
+    }
+}
+
+/// Fills the Merkle hash cache bottom-up for [`AstTree::subtree_hash`],
+/// skipping subtrees whose hash is still cached.
+struct HashFill<'a> {
+    tree: &'a AstTree,
+}
+
+impl Visitor for HashFill<'_> {
+    fn enter(&mut self, node: &AstNode, _depth: usize) -> bool {
+        node.hash.get().is_none()
+    }
+
+    fn leave(&mut self, node: &AstNode, _depth: usize) {
+        if node.hash.get().is_some() {
+            return;
+        }
+        let mut h = FNV_OFFSET;
+        let mut w = |bytes: &[u8]| fnv_bytes(&mut h, bytes);
+        w(&[node.kind as u8]);
+        write_len_prefixed(&mut w, node.label.as_bytes());
+        node.value.write_canonical(&mut w);
+        node.write_attrs(&mut w);
+        w(&(node.children.len() as u64).to_le_bytes());
+        for &child_id in &node.children {
+            // Children were left first, so their hashes are cached
+            let child = self
+                .tree
+                .cached_subtree_hash(child_id)
+                .unwrap_or(FNV_OFFSET);
+            w(&child.to_le_bytes());
+        }
+        node.hash.set(h);
+    }
+}
+
+/// A node's cached Merkle hash, empty while dirty
+///
+/// Filled through `&self` by [`AstTree::subtree_hash`] and only cleared
+/// by mutations, which hold `&mut AstTree`. Concurrent fills of a node
+/// store the same value, so plain atomic loads and stores suffice; the
+/// halves are 32-bit so targets without 64-bit atomics keep working.
+#[derive(Debug)]
+struct HashCache {
+    lo: AtomicU32,
+    hi: AtomicU32,
+    filled: AtomicBool,
+}
+
+impl HashCache {
+    const fn new() -> Self {
+        Self {
+            lo: AtomicU32::new(0),
+            hi: AtomicU32::new(0),
+            filled: AtomicBool::new(false),
+        }
+    }
+
+    fn get(&self) -> Option<u64> {
+        self.filled.load(Ordering::Acquire).then(|| {
+            let lo = u64::from(self.lo.load(Ordering::Relaxed));
+            let hi = u64::from(self.hi.load(Ordering::Relaxed));
+            hi << 32 | lo
+        })
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn set(&self, h: u64) {
+        self.lo.store(h as u32, Ordering::Relaxed);
+        self.hi.store((h >> 32) as u32, Ordering::Relaxed);
+        self.filled.store(true, Ordering::Release);
+    }
+
+    /// Empty the cache; returns whether it was filled.
+    fn clear(&self) -> bool {
+        let was = self.filled.load(Ordering::Relaxed);
+        self.filled.store(false, Ordering::Relaxed);
+        was
+    }
+}
+
+impl Clone for HashCache {
+    fn clone(&self) -> Self {
+        let copy = Self::new();
+        if let Some(h) = self.get() {
+            copy.set(h);
+        }
+        copy
+    }
+}
+
+/// A node's cached SHA-256 content digest, empty while dirty
+///
+/// Filled and cleared like [`HashCache`]; kept as 32-bit words for the
+/// same reason.
+#[derive(Debug)]
+struct DigestCache {
+    words: [AtomicU32; 8],
+    filled: AtomicBool,
+}
+
+impl DigestCache {
+    const fn new() -> Self {
+        Self {
+            words: [const { AtomicU32::new(0) }; 8],
+            filled: AtomicBool::new(false),
+        }
+    }
+
+    fn get(&self) -> Option<[u8; 32]> {
+        self.filled.load(Ordering::Acquire).then(|| {
+            let mut digest = [0; 32];
+            for (bytes, word) in digest.chunks_exact_mut(4).zip(&self.words) {
+                bytes.copy_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
             }
-            // Hash children recursively
-            for &child_id in &node.children {
-                self.hash_node(child_id, h);
+            digest
+        })
+    }
+
+    fn set(&self, digest: &[u8; 32]) {
+        for (bytes, word) in digest.chunks_exact(4).zip(&self.words) {
+            word.store(
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                Ordering::Relaxed,
+            );
+        }
+        self.filled.store(true, Ordering::Release);
+    }
+
+    /// Empty the cache; returns whether it was filled.
+    fn clear(&self) -> bool {
+        let was = self.filled.load(Ordering::Relaxed);
+        self.filled.store(false, Ordering::Relaxed);
+        was
+    }
+}
+
+impl Clone for DigestCache {
+    fn clone(&self) -> Self {
+        let copy = Self::new();
+        if let Some(digest) = self.get() {
+            copy.set(&digest);
+        }
+        copy
+    }
+}
+
+// ── Invariants ─────────────────────────────────────────────────────────
+
+/// A broken structural invariant found by [`AstTree::check_invariants`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvariantError {
+    /// The node the diagnostic is about
+    pub node_id: NodeId,
+    /// Which invariant it breaks
+    pub kind: InvariantKind,
+}
+
+/// Which structural invariant an [`InvariantError`] reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvariantKind {
+    /// The node is stored at `slot` but the ID index does not point there
+    /// (a missing entry, or a duplicate ID)
+    Unindexed { slot: usize },
+    /// The ID index maps the node to `slot`, which holds another node or
+    /// nothing
+    BadIndex { slot: usize },
+    /// The root ID names no node
+    MissingRoot,
+    /// The root has a parent entry
+    RootHasParent(NodeId),
+    /// The node lists a child that does not exist
+    MissingChild(NodeId),
+    /// The node appears in a second child list, here `parent`'s
+    ListedTwice { parent: NodeId },
+    /// `parent` lists the node, but its parent entry is `recorded`
+    ParentMismatch {
+        parent: NodeId,
+        recorded: Option<NodeId>,
+    },
+    /// The node's parent entry names `parent`, which is missing or does
+    /// not list it
+    NotListed { parent: NodeId },
+    /// A parent entry (to `parent`) remains for a node that does not exist
+    StaleParent { parent: NodeId },
+    /// A non-root node without a parent entry
+    Orphan,
+    /// Following parent entries from the node leads back to it
+    Cycle,
+    /// The node's ID is not below `next_id`, so [`AstTree::add_node`]
+    /// would hand it out again
+    NextIdTooLow { next_id: NodeId },
+    /// The node refers to a node that does not exist
+    DanglingRef(NodeId),
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "node {}: ", self.node_id)?;
+        match &self.kind {
+            InvariantKind::Unindexed { slot } => {
+                write!(f, "stored at slot {slot} but not indexed there")
+            }
+            InvariantKind::BadIndex { slot } => {
+                write!(f, "indexed at slot {slot}, which holds another node")
+            }
+            InvariantKind::MissingRoot => write!(f, "root does not exist"),
+            InvariantKind::RootHasParent(parent) => write!(f, "root has parent {parent}"),
+            InvariantKind::MissingChild(child) => write!(f, "lists missing child {child}"),
+            InvariantKind::ListedTwice { parent } => {
+                write!(f, "listed again as a child of {parent}")
+            }
+            InvariantKind::ParentMismatch {
+                parent,
+                recorded: Some(recorded),
+            } => {
+                write!(f, "child of {parent} but parent entry is {recorded}")
+            }
+            InvariantKind::ParentMismatch {
+                parent,
+                recorded: None,
+            } => {
+                write!(f, "child of {parent} but has no parent entry")
+            }
+            InvariantKind::NotListed { parent } => {
+                write!(f, "parent entry {parent} does not list it")
+            }
+            InvariantKind::StaleParent { parent } => {
+                write!(f, "does not exist but has parent entry {parent}")
+            }
+            InvariantKind::Orphan => write!(f, "has no parent"),
+            InvariantKind::Cycle => write!(f, "is its own ancestor"),
+            InvariantKind::NextIdTooLow { next_id } => {
+                write!(f, "ID is not below next_id {next_id}")
+            }
+            InvariantKind::DanglingRef(target) => {
+                write!(f, "refers to missing node {target}")
             }
         }
     }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for InvariantError {}
+
+/// Outcome of walking a parent chain in [`AstTree::check_invariants`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Reach {
+    OnPath,
+    Root,
+    Broken,
+}
+
+// ── FNV-1a helpers ─────────────────────────────────────────────────────
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+#[inline]
+fn fnv_bytes(h: &mut u64, bytes: &[u8]) {
+    for &b in bytes {
+        *h ^= b as u64;
+        *h = h.wrapping_mul(FNV_PRIME);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -461,6 +1488,48 @@ mod tests {
         assert_eq!(NodeValue::Text(String::from("hi")).serialized_size(), 5); // 3+2
         assert_eq!(NodeValue::Ident(String::from("abc")).serialized_size(), 6); // 3+3
         assert_eq!(NodeValue::Bytes(vec![1, 2]).serialized_size(), 5); // 3+2
+        assert_eq!(NodeValue::Bool(true).serialized_size(), 2);
+        assert_eq!(NodeValue::Vec2([0.0; 2]).serialized_size(), 17);
+        assert_eq!(NodeValue::Vec3([0.0; 3]).serialized_size(), 25);
+        assert_eq!(NodeValue::Quat([0.0; 4]).serialized_size(), 33);
+        assert_eq!(NodeValue::Mat4([0.0; 16]).serialized_size(), 129);
+        let list = NodeValue::List(vec![NodeValue::Int(1), NodeValue::Int(2)]);
+        assert_eq!(list.serialized_size(), 21); // 3+9+9
+    }
+
+    #[test]
+    fn test_node_value_list_is_homogeneous() {
+        let ints = vec![NodeValue::Int(1), NodeValue::Int(2)];
+        assert!(NodeValue::list(ints).is_some());
+        assert!(NodeValue::list(Vec::new()).is_some());
+        let mixed = vec![NodeValue::Int(1), NodeValue::Float(2.0)];
+        assert!(NodeValue::list(mixed.clone()).is_none());
+        assert!(!NodeValue::List(mixed.clone()).is_homogeneous());
+
+        // Nested lists are checked too
+        let nested = vec![
+            NodeValue::List(vec![NodeValue::Int(1)]),
+            NodeValue::List(mixed),
+        ];
+        assert!(NodeValue::list(nested).is_none());
+        let nested = vec![
+            NodeValue::List(vec![NodeValue::Int(1), NodeValue::Int(2)]),
+            NodeValue::List(Vec::new()),
+        ];
+        assert!(NodeValue::list(nested).is_some());
+    }
+
+    #[test]
+    fn test_node_value_components() {
+        assert_eq!(
+            NodeValue::Vec3([1.0, 2.0, 3.0]).components(),
+            Some([1.0, 2.0, 3.0].as_slice())
+        );
+        assert_eq!(
+            NodeValue::Mat4([0.0; 16]).components().map(<[f64]>::len),
+            Some(16)
+        );
+        assert_eq!(NodeValue::Float(1.0).components(), None);
     }
 
     #[test]
@@ -720,4 +1789,638 @@ mod tests {
         assert_eq!(tree.node_count(), 2);
         assert!(tree.get_node(0).unwrap().children.contains(&new_child));
     }
+
+    // ── Value-aware hashing ────────────────────────────────────────────
+
+    #[test]
+    fn test_subtree_hash_covers_value() {
+        let mut t1 = AstTree::new();
+        t1.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), 0);
+        let mut t2 = AstTree::new();
+        t2.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(2.0), 0);
+        assert_ne!(t1.subtree_hash(0), t2.subtree_hash(0));
+    }
+
+    #[test]
+    fn test_structure_hash_ignores_value() {
+        let mut t1 = AstTree::new();
+        t1.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), 0);
+        let mut t2 = AstTree::new();
+        t2.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(2.0), 0);
+        assert_eq!(t1.structure_hash(0), t2.structure_hash(0));
+
+        let mut t3 = AstTree::new();
+        t3.add_node(AstNodeKind::Primitive, "box", 0);
+        assert_ne!(t1.structure_hash(0), t3.structure_hash(0));
+    }
+
+    #[test]
+    fn test_subtree_hash_signed_zero_is_canonical() {
+        let mut t1 = AstTree::new();
+        t1.add_node_with_value(AstNodeKind::Parameter, "x", NodeValue::Float(0.0), 0);
+        let mut t2 = AstTree::new();
+        t2.add_node_with_value(AstNodeKind::Parameter, "x", NodeValue::Float(-0.0), 0);
+        assert_eq!(t1.subtree_hash(0), t2.subtree_hash(0));
+    }
+
+    #[test]
+    fn test_subtree_hash_nan_payloads_are_canonical() {
+        let quiet = f64::NAN;
+        let other = f64::from_bits(0x7ff0_0000_0000_0001); // signalling NaN
+        assert!(other.is_nan());
+        let mut t1 = AstTree::new();
+        t1.add_node_with_value(AstNodeKind::Parameter, "x", NodeValue::Float(quiet), 0);
+        let mut t2 = AstTree::new();
+        t2.add_node_with_value(AstNodeKind::Parameter, "x", NodeValue::Float(other), 0);
+        assert_eq!(t1.subtree_hash(0), t2.subtree_hash(0));
+    }
+
+    #[test]
+    fn test_subtree_hash_vector_components_are_canonical() {
+        let hash = |v: NodeValue| {
+            let mut t = AstTree::new();
+            t.add_node_with_value(AstNodeKind::Transform, "translate", v, 0);
+            t.subtree_hash(0)
+        };
+        assert_eq!(
+            hash(NodeValue::Vec3([0.0, f64::NAN, 1.0])),
+            hash(NodeValue::Vec3([
+                -0.0,
+                f64::from_bits(0x7ff0_0000_0000_0001),
+                1.0
+            ]))
+        );
+        assert_ne!(
+            hash(NodeValue::Vec3([0.0, 1.0, 2.0])),
+            hash(NodeValue::Vec3([0.0, 2.0, 1.0]))
+        );
+    }
+
+    #[test]
+    fn test_subtree_hash_distinguishes_value_variants() {
+        let values = [
+            NodeValue::None,
+            NodeValue::Int(1),
+            NodeValue::Float(1.0),
+            NodeValue::Text(String::from("a")),
+            NodeValue::Ident(String::from("a")),
+            NodeValue::Bytes(vec![b'a']),
+            NodeValue::Bool(true),
+            NodeValue::Vec2([1.0, 0.0]),
+            NodeValue::Vec3([1.0, 0.0, 0.0]),
+            NodeValue::Vec4([1.0, 0.0, 0.0, 0.0]),
+            NodeValue::Quat([1.0, 0.0, 0.0, 0.0]),
+            NodeValue::Mat4([0.0; 16]),
+            NodeValue::Color([1.0, 0.0, 0.0, 0.0]),
+            NodeValue::List(vec![NodeValue::Int(1)]),
+            NodeValue::List(vec![NodeValue::List(vec![NodeValue::Int(1)])]),
+        ];
+        let hashes: Vec<u64> = values
+            .iter()
+            .map(|v| {
+                let mut t = AstTree::new();
+                t.add_node_with_value(AstNodeKind::Parameter, "p", v.clone(), 0);
+                t.subtree_hash(0)
+            })
+            .collect();
+        for i in 0..hashes.len() {
+            for j in i + 1..hashes.len() {
+                assert_ne!(hashes[i], hashes[j], "{:?} vs {:?}", values[i], values[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_subtree_hash_label_boundaries_do_not_alias() {
+        // "ab" + child "c" must not hash like "a" + child "bc"
+        let mut t1 = AstTree::new();
+        let a = t1.add_node(AstNodeKind::Group, "ab", 0);
+        t1.add_node(AstNodeKind::Group, "c", a);
+        let mut t2 = AstTree::new();
+        let b = t2.add_node(AstNodeKind::Group, "a", 0);
+        t2.add_node(AstNodeKind::Group, "bc", b);
+        assert_ne!(t1.subtree_hash(0), t2.subtree_hash(0));
+    }
+
+    // ── Attributes ─────────────────────────────────────────────────────
+
+    #[test]
+    fn test_attrs_are_ordered_by_key() {
+        let node = AstNode::new(1, AstNodeKind::Primitive, "box")
+            .with_attr("width", NodeValue::Float(2.0))
+            .with_attr("depth", NodeValue::Float(1.0))
+            .with_attr("height", NodeValue::Float(3.0));
+        let keys: Vec<&str> = node.attrs.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["depth", "height", "width"]);
+        assert_eq!(node.attr("height"), Some(&NodeValue::Float(3.0)));
+        assert_eq!(node.attr("radius"), None);
+    }
+
+    #[test]
+    fn test_subtree_hash_covers_attrs() {
+        let mut tree = AstTree::new();
+        let b = tree.add_node(AstNodeKind::Primitive, "box", 0);
+        let plain = tree.subtree_hash(0);
+
+        let width = |tree: &mut AstTree, v: f64| {
+            tree.get_node_mut(b)
+                .unwrap()
+                .attrs
+                .insert(String::from("width"), NodeValue::Float(v));
+        };
+        width(&mut tree, 1.0);
+        let one = tree.subtree_hash(0);
+        assert_ne!(one, plain);
+        width(&mut tree, 2.0);
+        assert_ne!(tree.subtree_hash(0), one);
+
+        tree.get_node_mut(b).unwrap().attrs.clear();
+        assert_eq!(tree.subtree_hash(0), plain);
+    }
+
+    #[test]
+    fn test_subtree_hash_attrs_do_not_alias_value() {
+        // An attribute must not hash like the same bytes in the label
+        let mut t1 = AstTree::new();
+        let a = t1.add_node(AstNodeKind::Primitive, "box", 0);
+        t1.get_node_mut(a)
+            .unwrap()
+            .attrs
+            .insert(String::from("w"), NodeValue::None);
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Primitive, "boxw", 0);
+        assert_ne!(t1.subtree_hash(0), t2.subtree_hash(0));
+    }
+
+    // ── Hash cache ─────────────────────────────────────────────────────
+
+    /// root -> a -> b -> c, plus a sibling branch root -> s -> t
+    fn chain_with_sibling() -> (AstTree, [NodeId; 5]) {
+        let mut tree = AstTree::new();
+        let a = tree.add_node(AstNodeKind::Group, "a", 0);
+        let b = tree.add_node(AstNodeKind::Group, "b", a);
+        let c = tree.add_node_with_value(AstNodeKind::Parameter, "c", NodeValue::Float(1.0), b);
+        let s = tree.add_node(AstNodeKind::Group, "s", 0);
+        let t = tree.add_node(AstNodeKind::Primitive, "t", s);
+        (tree, [a, b, c, s, t])
+    }
+
+    #[test]
+    fn test_hash_cache_filled_by_subtree_hash() {
+        let (tree, ids) = chain_with_sibling();
+        assert!(tree.cached_subtree_hash(0).is_none());
+        let h = tree.subtree_hash(0);
+        assert_eq!(tree.cached_subtree_hash(0), Some(h));
+        for id in ids {
+            assert!(tree.cached_subtree_hash(id).is_some());
+        }
+    }
+
+    #[test]
+    fn test_hash_cache_keeps_tree_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<AstTree>();
+
+        let (tree, _) = chain_with_sibling();
+        let dirty = tree.clone();
+        let h = tree.subtree_hash(0);
+        // Cloning copies the cache as it is
+        let copy = tree.clone();
+        assert_eq!(copy.cached_subtree_hash(0), tree.cached_subtree_hash(0));
+        assert_eq!(copy.cached_subtree_hash(0), Some(h));
+        assert!(dirty.cached_subtree_hash(0).is_none());
+        #[cfg(feature = "std")]
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| dirty.subtree_hash(0)))
+                .collect();
+            for worker in workers {
+                assert_eq!(worker.join().unwrap(), h);
+            }
+        });
+    }
+
+    #[test]
+    fn test_get_node_mut_invalidates_only_ancestor_path() {
+        let (mut tree, [a, b, c, s, t]) = chain_with_sibling();
+        let before = tree.subtree_hash(0);
+        tree.get_node_mut(c).unwrap().value = NodeValue::Float(2.0);
+
+        for dirty in [0, a, b, c] {
+            assert!(tree.cached_subtree_hash(dirty).is_none(), "{dirty}");
+        }
+        for clean in [s, t] {
+            assert!(tree.cached_subtree_hash(clean).is_some(), "{clean}");
+        }
+        assert_ne!(tree.subtree_hash(0), before);
+    }
+
+    #[test]
+    fn test_add_node_invalidates_parent_path() {
+        let (mut tree, [a, b, _c, s, t]) = chain_with_sibling();
+        let before = tree.subtree_hash(0);
+        tree.add_node(AstNodeKind::Parameter, "new", b);
+        assert!(tree.cached_subtree_hash(b).is_none());
+        assert!(tree.cached_subtree_hash(a).is_none());
+        assert!(tree.cached_subtree_hash(s).is_some());
+        assert!(tree.cached_subtree_hash(t).is_some());
+        assert_ne!(tree.subtree_hash(0), before);
+    }
+
+    #[test]
+    fn test_remove_subtree_invalidates_parent_path() {
+        let (mut tree, [a, b, c, s, _t]) = chain_with_sibling();
+        let before = tree.subtree_hash(0);
+        tree.remove_subtree(c);
+        assert!(tree.cached_subtree_hash(b).is_none());
+        assert!(tree.cached_subtree_hash(a).is_none());
+        assert!(tree.cached_subtree_hash(s).is_some());
+        assert_ne!(tree.subtree_hash(0), before);
+    }
+
+    #[test]
+    fn test_cached_hash_matches_fresh_tree() {
+        let (mut tree, [_a, _b, c, _s, t]) = chain_with_sibling();
+        let _ = tree.subtree_hash(0);
+        tree.get_node_mut(c).unwrap().value = NodeValue::Float(5.0);
+        tree.get_node_mut(t).unwrap().label = String::from("u");
+        let incremental = tree.subtree_hash(0);
+
+        let mut fresh = AstTree::new();
+        let a = fresh.add_node(AstNodeKind::Group, "a", 0);
+        let b = fresh.add_node(AstNodeKind::Group, "b", a);
+        fresh.add_node_with_value(AstNodeKind::Parameter, "c", NodeValue::Float(5.0), b);
+        let s = fresh.add_node(AstNodeKind::Group, "s", 0);
+        fresh.add_node(AstNodeKind::Primitive, "u", s);
+        assert_eq!(incremental, fresh.subtree_hash(0));
+    }
+
+    #[test]
+    fn test_clone_carries_hash_cache() {
+        let (tree, _) = chain_with_sibling();
+        let h = tree.subtree_hash(0);
+        let copy = tree.clone();
+        assert_eq!(copy.cached_subtree_hash(0), Some(h));
+        assert_eq!(tree.cached_subtree_hash(0), Some(h));
+    }
+
+    // ── Insert with ID ─────────────────────────────────────────────────
+
+    #[test]
+    fn test_insert_with_id() {
+        let mut tree = AstTree::new();
+        let g = tree.add_node(AstNodeKind::Group, "g", 0);
+        assert!(tree.insert_with_id(10, AstNodeKind::Primitive, "s", NodeValue::Int(3), g));
+        let node = tree.get_node(10).unwrap();
+        assert_eq!(node.label, "s");
+        assert_eq!(node.value, NodeValue::Int(3));
+        assert_eq!(tree.parent_of(10), Some(g));
+        assert_eq!(tree.get_node(g).unwrap().children, vec![10]);
+        // Assigned IDs continue past the chosen one
+        assert_eq!(tree.add_node(AstNodeKind::Primitive, "t", 0), 11);
+    }
+
+    #[test]
+    fn test_insert_with_id_below_next_id_keeps_counter() {
+        let mut tree = AstTree::new();
+        tree.add_node(AstNodeKind::Group, "a", 0);
+        tree.add_node(AstNodeKind::Group, "b", 0);
+        tree.remove_subtree(1);
+        assert!(tree.insert_with_id(1, AstNodeKind::Group, "a", NodeValue::None, 0));
+        assert_eq!(tree.next_id(), 3);
+    }
+
+    #[test]
+    fn test_insert_with_id_rejects_collision_and_missing_parent() {
+        let mut tree = AstTree::new();
+        let g = tree.add_node(AstNodeKind::Group, "g", 0);
+        assert!(!tree.insert_with_id(g, AstNodeKind::Primitive, "x", NodeValue::None, 0));
+        assert!(!tree.insert_with_id(0, AstNodeKind::Primitive, "x", NodeValue::None, g));
+        assert!(!tree.insert_with_id(5, AstNodeKind::Primitive, "x", NodeValue::None, 99));
+        assert_eq!(tree.node_count(), 2);
+        assert_eq!(tree.get_node(g).unwrap().label, "g");
+        assert_eq!(tree.next_id(), 2);
+    }
+
+    #[test]
+    fn test_insert_with_id_at_positions_and_clamps() {
+        let mut tree = AstTree::new();
+        let a = tree.add_node(AstNodeKind::Primitive, "a", 0);
+        let b = tree.add_node(AstNodeKind::Primitive, "b", 0);
+        assert!(tree.insert_with_id_at(10, AstNodeKind::Primitive, "x", NodeValue::None, 0, 1));
+        assert!(tree.insert_with_id_at(11, AstNodeKind::Primitive, "y", NodeValue::None, 0, 0));
+        assert!(tree.insert_with_id_at(12, AstNodeKind::Primitive, "z", NodeValue::None, 0, 99));
+        assert_eq!(tree.get_node(0).unwrap().children, vec![11, a, 10, b, 12]);
+    }
+
+    // ── Move ───────────────────────────────────────────────────────────
+
+    #[test]
+    fn test_move_node_reparents_at_index() {
+        let mut tree = AstTree::new();
+        let g1 = tree.add_node(AstNodeKind::Group, "g1", 0);
+        let g2 = tree.add_node(AstNodeKind::Group, "g2", 0);
+        let a = tree.add_node(AstNodeKind::Primitive, "a", g2);
+        let s = tree.add_node(AstNodeKind::Primitive, "s", g1);
+        assert!(tree.move_node(s, g2, 0));
+        assert!(tree.get_node(g1).unwrap().children.is_empty());
+        assert_eq!(tree.get_node(g2).unwrap().children, vec![s, a]);
+        assert_eq!(tree.parent_of(s), Some(g2));
+    }
+
+    #[test]
+    fn test_move_node_clamps_index() {
+        let mut tree = AstTree::new();
+        let a = tree.add_node(AstNodeKind::Primitive, "a", 0);
+        let b = tree.add_node(AstNodeKind::Primitive, "b", 0);
+        assert!(tree.move_node(a, 0, 99));
+        assert_eq!(tree.get_node(0).unwrap().children, vec![b, a]);
+    }
+
+    #[test]
+    fn test_move_node_rejects_cycle_and_root() {
+        let mut tree = AstTree::new();
+        let g = tree.add_node(AstNodeKind::Group, "g", 0);
+        let inner = tree.add_node(AstNodeKind::Group, "inner", g);
+        assert!(!tree.move_node(g, inner, 0));
+        assert!(!tree.move_node(g, g, 0));
+        assert!(!tree.move_node(0, g, 0));
+        assert!(!tree.move_node(g, 99, 0));
+        assert_eq!(tree.parent_of(inner), Some(g));
+        assert_eq!(tree.get_node(0).unwrap().children, vec![g]);
+    }
+
+    #[test]
+    fn test_move_node_invalidates_both_paths() {
+        let mut tree = AstTree::new();
+        let g1 = tree.add_node(AstNodeKind::Group, "g1", 0);
+        let g2 = tree.add_node(AstNodeKind::Group, "g2", 0);
+        let s = tree.add_node(AstNodeKind::Primitive, "s", g1);
+        let _ = tree.subtree_hash(0);
+        assert!(tree.move_node(s, g2, 0));
+        assert_eq!(tree.cached_subtree_hash(g1), None);
+        assert_eq!(tree.cached_subtree_hash(g2), None);
+        assert!(tree.cached_subtree_hash(s).is_some());
+
+        let mut fresh = AstTree::new();
+        fresh.add_node(AstNodeKind::Group, "g1", 0);
+        let f2 = fresh.add_node(AstNodeKind::Group, "g2", 0);
+        fresh.add_node(AstNodeKind::Primitive, "s", f2);
+        assert_eq!(tree.subtree_hash(0), fresh.subtree_hash(0));
+    }
+
+    // ── References ─────────────────────────────────────────────────────
+
+    /// Root with a prototype sphere and an instance referring to it.
+    fn instanced() -> (AstTree, NodeId, NodeId) {
+        let mut tree = AstTree::new();
+        let proto = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), proto);
+        let inst =
+            tree.add_node_with_value(AstNodeKind::Transform, "instance", NodeValue::Ref(proto), 0);
+        (tree, proto, inst)
+    }
+
+    #[test]
+    fn test_resolve_follows_reference_chains() {
+        let (mut tree, proto, inst) = instanced();
+        let alias = tree.add_node_with_value(AstNodeKind::Group, "alias", NodeValue::Ref(inst), 0);
+        assert_eq!(tree.resolve(proto), Some(proto));
+        assert_eq!(tree.resolve(inst), Some(proto));
+        assert_eq!(tree.resolve(alias), Some(proto));
+        assert_eq!(tree.resolve(99), None);
+
+        // Dangling and cyclic chains do not resolve
+        tree.get_node_mut(alias).unwrap().value = NodeValue::Ref(99);
+        assert_eq!(tree.resolve(alias), None);
+        tree.get_node_mut(alias).unwrap().value = NodeValue::Ref(inst);
+        tree.get_node_mut(inst).unwrap().value = NodeValue::Ref(alias);
+        assert_eq!(tree.resolve(inst), None);
+    }
+
+    #[test]
+    fn test_node_refs_cover_value_attrs_and_lists() {
+        let node = AstNode::new(1, AstNodeKind::Group, "g")
+            .with_value(NodeValue::List(vec![NodeValue::Ref(4), NodeValue::Ref(5)]))
+            .with_attr("target", NodeValue::Ref(6));
+        assert_eq!(node.refs(), vec![4, 5, 6]);
+        assert!(AstNode::new(1, AstNodeKind::Group, "g").refs().is_empty());
+    }
+
+    #[test]
+    fn test_remove_subtree_rejects_referenced_nodes() {
+        let (mut tree, proto, inst) = instanced();
+        let radius = tree.get_node(proto).unwrap().children[0];
+        assert_eq!(tree.external_refs(proto), vec![(inst, proto)]);
+        let hash = tree.subtree_hash(0);
+        assert!(!tree.remove_subtree(proto));
+        assert_eq!(tree.node_count(), 4);
+        assert_eq!(tree.subtree_hash(0), hash);
+
+        // A reference into a subtree blocks it too, through attributes
+        tree.get_node_mut(inst).unwrap().value = NodeValue::None;
+        tree.get_node_mut(inst)
+            .unwrap()
+            .attrs
+            .insert(String::from("radius"), NodeValue::Ref(radius));
+        assert!(!tree.remove_subtree(proto));
+        assert!(!tree.remove_subtree(radius));
+
+        // Once the referrer is gone the prototype can go
+        assert!(tree.remove_subtree(inst));
+        assert!(tree.remove_subtree(proto));
+        assert_eq!(tree.node_count(), 1);
+        assert!(!tree.remove_subtree(proto));
+    }
+
+    #[test]
+    fn test_remove_subtree_allows_references_inside_it() {
+        let (mut tree, proto, inst) = instanced();
+        let g = tree.add_node(AstNodeKind::Group, "g", 0);
+        assert!(tree.move_node(proto, g, 0));
+        assert!(tree.move_node(inst, g, 1));
+        assert!(tree.external_refs(g).is_empty());
+        assert!(tree.remove_subtree(g));
+        assert_eq!(tree.node_count(), 1);
+    }
+
+    #[test]
+    fn test_subtree_hash_covers_ref_target() {
+        let (mut tree, proto, inst) = instanced();
+        let other = tree.add_node(AstNodeKind::Primitive, "box", 0);
+        let before = tree.subtree_hash(inst);
+        tree.get_node_mut(inst).unwrap().value = NodeValue::Ref(other);
+        assert_ne!(tree.subtree_hash(inst), before);
+        tree.get_node_mut(inst).unwrap().value = NodeValue::Ref(proto);
+        assert_eq!(tree.subtree_hash(inst), before);
+        // A reference is not the integer with the same bits
+        tree.get_node_mut(inst).unwrap().value = NodeValue::Int(i64::from(proto));
+        assert_ne!(tree.subtree_hash(inst), before);
+    }
+
+    #[test]
+    fn test_reference_index_follows_edits() {
+        let (mut tree, proto, inst) = instanced();
+        let other = tree.add_node(AstNodeKind::Primitive, "box", 0);
+        assert_eq!(tree.referrers_of(proto), [inst]);
+        assert_eq!(tree.referring_nodes(), [inst]);
+
+        // Seen while the edit is still the latest borrow, and after
+        tree.get_node_mut(inst).unwrap().value = NodeValue::Ref(other);
+        assert!(tree.referrers_of(proto).is_empty());
+        assert_eq!(tree.external_refs(other), [(inst, other)]);
+        tree.add_node(AstNodeKind::Group, "g", 0);
+        assert_eq!(tree.referrers_of(other), [inst]);
+        assert!(tree.remove_subtree(proto));
+
+        // Inserted with a reference, dropped with its subtree
+        assert!(tree.insert_with_id(50, AstNodeKind::Group, "alias", NodeValue::Ref(other), 0));
+        assert_eq!(tree.referrers_of(other), [inst, 50]);
+        assert!(tree.remove_subtree(50));
+        tree.get_node_mut(inst).unwrap().value = NodeValue::None;
+        assert!(tree.referring_nodes().is_empty());
+        assert!(tree.remove_subtree(other));
+    }
+
+    // ── Invariants ─────────────────────────────────────────────────────
+
+    fn kind_of(tree: &AstTree) -> Vec<(NodeId, InvariantKind)> {
+        tree.check_invariants()
+            .into_iter()
+            .map(|e| (e.node_id, e.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_check_invariants_holds_through_edits() {
+        let (mut tree, [a, b, c, s, t]) = chain_with_sibling();
+        assert_eq!(tree.check_invariants(), []);
+        assert!(!tree.move_node(s, t, 0));
+        assert!(tree.move_node(t, a, 0));
+        assert!(tree.insert_with_id_at(40, AstNodeKind::Group, "g", NodeValue::None, a, 0));
+        assert!(tree.move_node(b, 40, 0));
+        assert!(tree.remove_subtree(c));
+        tree.add_node(AstNodeKind::Primitive, "sphere", 40);
+        assert_eq!(tree.check_invariants(), []);
+    }
+
+    #[test]
+    fn test_check_invariants_reports_parent_desync() {
+        let mut tree = AstTree::new();
+        let a = tree.add_node(AstNodeKind::Group, "a", 0);
+        let b = tree.add_node(AstNodeKind::Group, "b", 0);
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", a);
+        // Parent entry moved without updating the child lists
+        tree.parent_index.insert(s, b);
+        assert_eq!(
+            kind_of(&tree),
+            [
+                (
+                    s,
+                    InvariantKind::ParentMismatch {
+                        parent: a,
+                        recorded: Some(b)
+                    }
+                ),
+                (s, InvariantKind::NotListed { parent: b }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_invariants_reports_orphan_and_missing_child() {
+        let mut tree = AstTree::new();
+        let a = tree.add_node(AstNodeKind::Group, "a", 0);
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", a);
+        tree.get_node_mut(a).unwrap().children.clear();
+        tree.parent_index.remove(&s);
+        tree.get_node_mut(0).unwrap().children.push(99);
+        assert_eq!(
+            kind_of(&tree),
+            [
+                (0, InvariantKind::MissingChild(99)),
+                (s, InvariantKind::Orphan),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_invariants_reports_each_cycle_once() {
+        let mut tree = AstTree::new();
+        let a = tree.add_node(AstNodeKind::Group, "a", 0);
+        let b = tree.add_node(AstNodeKind::Group, "b", a);
+        let c = tree.add_node(AstNodeKind::Group, "c", b);
+        let below = tree.add_node(AstNodeKind::Primitive, "sphere", c);
+        // Detach a from the root and hang it under its own grandchild
+        tree.get_node_mut(0).unwrap().children.clear();
+        tree.get_node_mut(c).unwrap().children.push(a);
+        tree.parent_index.insert(a, c);
+        let errors = kind_of(&tree);
+        assert_eq!(errors, [(a, InvariantKind::Cycle)]);
+        assert!(!errors.iter().any(|&(id, _)| id == below));
+    }
+
+    #[test]
+    fn test_check_invariants_reports_index_root_and_next_id() {
+        let mut tree = AstTree::new();
+        let a = tree.add_node(AstNodeKind::Group, "a", 0);
+        let b = tree.add_node(AstNodeKind::Group, "b", 0);
+        tree.index.insert(a, 2);
+        tree.index.insert(b, 1);
+        tree.parent_index.insert(0, a);
+        tree.next_id = b;
+        assert_eq!(
+            kind_of(&tree),
+            [
+                (a, InvariantKind::Unindexed { slot: 1 }),
+                (b, InvariantKind::Unindexed { slot: 2 }),
+                (a, InvariantKind::BadIndex { slot: 2 }),
+                (b, InvariantKind::BadIndex { slot: 1 }),
+                (0, InvariantKind::RootHasParent(a)),
+                (0, InvariantKind::NotListed { parent: a }),
+                (b, InvariantKind::NextIdTooLow { next_id: b }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_invariants_reports_dangling_refs() {
+        let (mut tree, proto, inst) = instanced();
+        tree.remove_subtree_unchecked(proto);
+        assert_eq!(kind_of(&tree), [(inst, InvariantKind::DanglingRef(proto))]);
+    }
+
+    #[test]
+    fn test_invariant_error_display() {
+        let err = InvariantError {
+            node_id: 3,
+            kind: InvariantKind::ParentMismatch {
+                parent: 1,
+                recorded: Some(2),
+            },
+        };
+        assert_eq!(format!("{err}"), "node 3: child of 1 but parent entry is 2");
+    }
+
+    // ── Deep trees ─────────────────────────────────────────────────────
+
+    #[test]
+    fn test_deep_chain_hash_and_remove() {
+        let mut tree = AstTree::new();
+        let mut parent = 0;
+        for _ in 0..100_000 {
+            parent = tree.add_node(AstNodeKind::Group, "key", parent);
+        }
+        let copy = tree.clone();
+        assert_eq!(tree.subtree_hash(0), copy.subtree_hash(0));
+        assert_ne!(tree.structure_hash(0), FNV_OFFSET);
+        assert!(tree.is_ancestor_or_self(1, parent));
+        assert_eq!(tree.check_invariants(), []);
+
+        tree.get_node_mut(parent).unwrap().value = NodeValue::Int(1);
+        assert_ne!(tree.subtree_hash(0), copy.subtree_hash(0));
+        assert!(tree.remove_subtree(1));
+        assert_eq!(tree.node_count(), 1);
+        assert_eq!(tree.check_invariants(), []);
+    }
 }
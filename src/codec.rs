@@ -19,6 +19,12 @@ const OP_DELETE: u8 = 0x01;
 const OP_UPDATE: u8 = 0x02;
 const OP_RELABEL: u8 = 0x03;
 const OP_MOVE: u8 = 0x04;
+const OP_RETYPE: u8 = 0x05;
+const OP_SET_ATTR: u8 = 0x06;
+const OP_REMOVE_ATTR: u8 = 0x07;
+/// An Update between two values of one vector variant, carrying only the
+/// components that changed twice
+const OP_UPDATE_COMPONENTS: u8 = 0x08;
 
 const VAL_NONE: u8 = 0x00;
 const VAL_INT: u8 = 0x01;
@@ -26,6 +32,17 @@ const VAL_FLOAT: u8 = 0x02;
 const VAL_TEXT: u8 = 0x03;
 const VAL_IDENT: u8 = 0x04;
 const VAL_BYTES: u8 = 0x05;
+const VAL_BOOL: u8 = 0x06;
+const VAL_VEC2: u8 = 0x07;
+const VAL_VEC3: u8 = 0x08;
+const VAL_VEC4: u8 = 0x09;
+const VAL_QUAT: u8 = 0x0A;
+const VAL_MAT4: u8 = 0x0B;
+const VAL_COLOR: u8 = 0x0C;
+const VAL_LIST: u8 = 0x0D;
+const VAL_REF: u8 = 0x0E;
+/// Stands in for a value in `SetAttr::old_value` when the attribute was unset
+const VAL_UNSET: u8 = 0xFF;
 
 // ── Varint (LEB128) ───────────────────────────────────────────────────
 
@@ -82,6 +99,53 @@ fn decode_usize(data: &[u8], pos: &mut usize) -> Option<usize> {
 
 // ── NodeValue Codec ────────────────────────────────────────────────────
 
+/// Deepest nesting of `List` values the decoder accepts
+const MAX_LIST_DEPTH: usize = 32;
+
+/// Tag and components of a vector, quaternion, matrix or color.
+fn vector_parts(value: &NodeValue) -> Option<(u8, &[f64])> {
+    let tag = match value {
+        NodeValue::Vec2(_) => VAL_VEC2,
+        NodeValue::Vec3(_) => VAL_VEC3,
+        NodeValue::Vec4(_) => VAL_VEC4,
+        NodeValue::Quat(_) => VAL_QUAT,
+        NodeValue::Mat4(_) => VAL_MAT4,
+        NodeValue::Color(_) => VAL_COLOR,
+        _ => return None,
+    };
+    Some((tag, value.components()?))
+}
+
+/// Vector value of the variant `tag` names, from exactly its components.
+fn vector_value(tag: u8, c: &[f64]) -> Option<NodeValue> {
+    Some(match tag {
+        VAL_VEC2 => NodeValue::Vec2(c.try_into().ok()?),
+        VAL_VEC3 => NodeValue::Vec3(c.try_into().ok()?),
+        VAL_VEC4 => NodeValue::Vec4(c.try_into().ok()?),
+        VAL_QUAT => NodeValue::Quat(c.try_into().ok()?),
+        VAL_MAT4 => NodeValue::Mat4(c.try_into().ok()?),
+        VAL_COLOR => NodeValue::Color(c.try_into().ok()?),
+        _ => return None,
+    })
+}
+
+/// Component count of the vector variant `tag` names.
+const fn vector_len(tag: u8) -> Option<usize> {
+    match tag {
+        VAL_VEC2 => Some(2),
+        VAL_VEC3 => Some(3),
+        VAL_VEC4 | VAL_QUAT | VAL_COLOR => Some(4),
+        VAL_MAT4 => Some(16),
+        _ => None,
+    }
+}
+
+fn decode_f64(data: &[u8], pos: &mut usize) -> Option<f64> {
+    let bytes = data.get(*pos..*pos + 8)?;
+    *pos += 8;
+    Some(f64::from_le_bytes(bytes.try_into().ok()?))
+}
+
 fn encode_value(value: &NodeValue, buf: &mut Vec<u8>) {
     match value {
         NodeValue::None => buf.push(VAL_NONE),
@@ -108,10 +172,42 @@ fn encode_value(value: &NodeValue, buf: &mut Vec<u8>) {
             encode_usize(b.len(), buf);
             buf.extend_from_slice(b);
         }
+        NodeValue::Bool(v) => {
+            buf.push(VAL_BOOL);
+            buf.push(u8::from(*v));
+        }
+        NodeValue::Ref(id) => {
+            buf.push(VAL_REF);
+            encode_varint_u32(*id, buf);
+        }
+        NodeValue::List(items) => {
+            buf.push(VAL_LIST);
+            encode_usize(items.len(), buf);
+            for item in items {
+                encode_value(item, buf);
+            }
+        }
+        NodeValue::Vec2(_)
+        | NodeValue::Vec3(_)
+        | NodeValue::Vec4(_)
+        | NodeValue::Quat(_)
+        | NodeValue::Mat4(_)
+        | NodeValue::Color(_) => {
+            if let Some((tag, components)) = vector_parts(value) {
+                buf.push(tag);
+                for c in components {
+                    buf.extend_from_slice(&c.to_le_bytes());
+                }
+            }
+        }
     }
 }
 
 fn decode_value(data: &[u8], pos: &mut usize) -> Option<NodeValue> {
+    decode_value_nested(data, pos, 0)
+}
+
+fn decode_value_nested(data: &[u8], pos: &mut usize, depth: usize) -> Option<NodeValue> {
     if *pos >= data.len() {
         return None;
     }
@@ -162,7 +258,64 @@ fn decode_value(data: &[u8], pos: &mut usize) -> Option<NodeValue> {
             *pos += len;
             Some(NodeValue::Bytes(b))
         }
-        _ => None,
+        VAL_BOOL => {
+            let v = match *data.get(*pos)? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+            *pos += 1;
+            Some(NodeValue::Bool(v))
+        }
+        VAL_REF => decode_varint_u32(data, pos).map(NodeValue::Ref),
+        VAL_LIST => {
+            if depth >= MAX_LIST_DEPTH {
+                return None;
+            }
+            let len = decode_usize(data, pos)?;
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push(decode_value_nested(data, pos, depth + 1)?);
+            }
+            // Nested lists were checked as they were decoded
+            items
+                .windows(2)
+                .all(|w| w[0].same_variant(&w[1]))
+                .then_some(NodeValue::List(items))
+        }
+        _ => {
+            let len = vector_len(tag)?;
+            let mut components = [0.0; 16];
+            for c in &mut components[..len] {
+                *c = decode_f64(data, pos)?;
+            }
+            vector_value(tag, &components[..len])
+        }
+    }
+}
+
+/// Bit mask of the components that differ between two values of the same
+/// vector variant, or `None` if they are not such a pair or every
+/// component differs (the plain Update is then no larger).
+fn changed_components(old: &NodeValue, new: &NodeValue) -> Option<u32> {
+    let (tag, xs) = vector_parts(old)?;
+    let (new_tag, ys) = vector_parts(new)?;
+    if tag != new_tag {
+        return None;
+    }
+    let mut mask = 0u32;
+    for (i, (x, y)) in xs.iter().zip(ys).enumerate() {
+        if x.to_bits() != y.to_bits() {
+            mask |= 1 << i;
+        }
+    }
+    (mask.count_ones() < xs.len() as u32).then_some(mask)
+}
+
+fn encode_opt_value(value: Option<&NodeValue>, buf: &mut Vec<u8>) {
+    match value {
+        Some(v) => encode_value(v, buf),
+        None => buf.push(VAL_UNSET),
     }
 }
 
@@ -189,6 +342,7 @@ fn decode_string(data: &[u8], pos: &mut usize) -> Option<String> {
 pub fn encode_op(op: &DiffOp, buf: &mut Vec<u8>) {
     match op {
         DiffOp::Insert {
+            node_id,
             parent_id,
             index,
             kind,
@@ -196,6 +350,7 @@ pub fn encode_op(op: &DiffOp, buf: &mut Vec<u8>) {
             value,
         } => {
             buf.push(OP_INSERT);
+            encode_varint_u32(*node_id, buf);
             encode_varint_u32(*parent_id, buf);
             encode_usize(*index, buf);
             buf.push(*kind as u8);
@@ -211,6 +366,10 @@ pub fn encode_op(op: &DiffOp, buf: &mut Vec<u8>) {
             old_value,
             new_value,
         } => {
+            if let Some(mask) = changed_components(old_value, new_value) {
+                encode_component_update(*node_id, old_value, new_value, mask, buf);
+                return;
+            }
             buf.push(OP_UPDATE);
             encode_varint_u32(*node_id, buf);
             encode_value(old_value, buf);
@@ -236,7 +395,86 @@ pub fn encode_op(op: &DiffOp, buf: &mut Vec<u8>) {
             encode_varint_u32(*new_parent_id, buf);
             encode_usize(*new_index, buf);
         }
+        DiffOp::Retype {
+            node_id,
+            old_kind,
+            new_kind,
+        } => {
+            buf.push(OP_RETYPE);
+            encode_varint_u32(*node_id, buf);
+            buf.push(*old_kind as u8);
+            buf.push(*new_kind as u8);
+        }
+        DiffOp::SetAttr {
+            node_id,
+            key,
+            old_value,
+            new_value,
+        } => {
+            buf.push(OP_SET_ATTR);
+            encode_varint_u32(*node_id, buf);
+            encode_string(key, buf);
+            encode_opt_value(old_value.as_ref(), buf);
+            encode_value(new_value, buf);
+        }
+        DiffOp::RemoveAttr {
+            node_id,
+            key,
+            old_value,
+        } => {
+            buf.push(OP_REMOVE_ATTR);
+            encode_varint_u32(*node_id, buf);
+            encode_string(key, buf);
+            encode_value(old_value, buf);
+        }
+    }
+}
+
+/// Component-wise Update: value tag and change mask, then each component —
+/// once if unchanged, old then new if changed.
+fn encode_component_update(
+    node_id: u32,
+    old_value: &NodeValue,
+    new_value: &NodeValue,
+    mask: u32,
+    buf: &mut Vec<u8>,
+) {
+    let (Some((tag, xs)), Some((_, ys))) = (vector_parts(old_value), vector_parts(new_value))
+    else {
+        return;
+    };
+    buf.push(OP_UPDATE_COMPONENTS);
+    encode_varint_u32(node_id, buf);
+    buf.push(tag);
+    encode_varint_u32(mask, buf);
+    for (i, (x, y)) in xs.iter().zip(ys).enumerate() {
+        buf.extend_from_slice(&x.to_le_bytes());
+        if mask & (1 << i) != 0 {
+            buf.extend_from_slice(&y.to_le_bytes());
+        }
+    }
+}
+
+fn decode_component_update(data: &[u8], pos: &mut usize) -> Option<DiffOp> {
+    let node_id = decode_varint_u32(data, pos)?;
+    let tag = *data.get(*pos)?;
+    *pos += 1;
+    let len = vector_len(tag)?;
+    let mask = decode_varint_u32(data, pos)?;
+    let (mut old, mut new) = ([0.0; 16], [0.0; 16]);
+    for i in 0..len {
+        old[i] = decode_f64(data, pos)?;
+        new[i] = if mask & (1 << i) != 0 {
+            decode_f64(data, pos)?
+        } else {
+            old[i]
+        };
     }
+    Some(DiffOp::Update {
+        node_id,
+        old_value: vector_value(tag, &old[..len])?,
+        new_value: vector_value(tag, &new[..len])?,
+    })
 }
 
 /// Decode a single `DiffOp` from the buffer.
@@ -248,6 +486,7 @@ pub fn decode_op(data: &[u8], pos: &mut usize) -> Option<DiffOp> {
     *pos += 1;
     match tag {
         OP_INSERT => {
+            let node_id = decode_varint_u32(data, pos)?;
             let parent_id = decode_varint_u32(data, pos)?;
             let index = decode_usize(data, pos)?;
             if *pos >= data.len() {
@@ -259,6 +498,7 @@ pub fn decode_op(data: &[u8], pos: &mut usize) -> Option<DiffOp> {
             let label = decode_string(data, pos)?;
             let value = decode_value(data, pos)?;
             Some(DiffOp::Insert {
+                node_id,
                 parent_id,
                 index,
                 kind,
@@ -280,6 +520,7 @@ pub fn decode_op(data: &[u8], pos: &mut usize) -> Option<DiffOp> {
                 new_value,
             })
         }
+        OP_UPDATE_COMPONENTS => decode_component_update(data, pos),
         OP_RELABEL => {
             let node_id = decode_varint_u32(data, pos)?;
             let old_label = decode_string(data, pos)?;
@@ -300,6 +541,43 @@ pub fn decode_op(data: &[u8], pos: &mut usize) -> Option<DiffOp> {
                 new_index,
             })
         }
+        OP_RETYPE => {
+            let node_id = decode_varint_u32(data, pos)?;
+            let kinds = data.get(*pos..*pos + 2)?;
+            *pos += 2;
+            Some(DiffOp::Retype {
+                node_id,
+                old_kind: AstNodeKind::from_u8(kinds[0]),
+                new_kind: AstNodeKind::from_u8(kinds[1]),
+            })
+        }
+        OP_SET_ATTR => {
+            let node_id = decode_varint_u32(data, pos)?;
+            let key = decode_string(data, pos)?;
+            let old_value = if data.get(*pos) == Some(&VAL_UNSET) {
+                *pos += 1;
+                None
+            } else {
+                Some(decode_value(data, pos)?)
+            };
+            let new_value = decode_value(data, pos)?;
+            Some(DiffOp::SetAttr {
+                node_id,
+                key,
+                old_value,
+                new_value,
+            })
+        }
+        OP_REMOVE_ATTR => {
+            let node_id = decode_varint_u32(data, pos)?;
+            let key = decode_string(data, pos)?;
+            let old_value = decode_value(data, pos)?;
+            Some(DiffOp::RemoveAttr {
+                node_id,
+                key,
+                old_value,
+            })
+        }
         _ => None,
     }
 }
@@ -426,6 +704,7 @@ mod tests {
     #[test]
     fn insert_roundtrip() {
         let op = DiffOp::Insert {
+            node_id: 7,
             parent_id: 0,
             index: 3,
             kind: AstNodeKind::Primitive,
@@ -479,6 +758,7 @@ mod tests {
                 new_value: NodeValue::Float(2.0),
             },
             DiffOp::Insert {
+                node_id: 7,
                 parent_id: 0,
                 index: 0,
                 kind: AstNodeKind::CsgOp,
@@ -540,6 +820,223 @@ mod tests {
         assert_eq!(decode_value(&buf, &mut pos), Some(val));
     }
 
+    #[test]
+    fn value_rich_variants_roundtrip() {
+        let mut mat = [0.0; 16];
+        mat[0] = 1.0;
+        mat[12] = -3.5;
+        let values = [
+            NodeValue::Bool(true),
+            NodeValue::Bool(false),
+            NodeValue::Vec2([1.0, -2.0]),
+            NodeValue::Vec3([0.5, 1.5, 2.5]),
+            NodeValue::Vec4([1.0, 2.0, 3.0, 4.0]),
+            NodeValue::Quat([0.0, 0.0, 0.0, 1.0]),
+            NodeValue::Mat4(mat),
+            NodeValue::Color([1.0, 0.5, 0.25, 1.0]),
+            NodeValue::List(vec![]),
+            NodeValue::List(vec![
+                NodeValue::Vec2([1.0, 2.0]),
+                NodeValue::Vec2([3.0, 4.0]),
+            ]),
+            NodeValue::List(vec![NodeValue::List(vec![NodeValue::Int(7)])]),
+        ];
+        for val in values {
+            let mut buf = Vec::new();
+            encode_value(&val, &mut buf);
+            let mut pos = 0;
+            assert_eq!(decode_value(&buf, &mut pos), Some(val));
+            assert_eq!(pos, buf.len());
+        }
+    }
+
+    #[test]
+    fn ref_value_is_tag_plus_varint() {
+        let mut buf = Vec::new();
+        encode_value(&NodeValue::Ref(3), &mut buf);
+        assert_eq!(buf, [VAL_REF, 3]);
+        for val in [
+            NodeValue::Ref(300),
+            NodeValue::Ref(u32::MAX),
+            NodeValue::List(vec![NodeValue::Ref(1), NodeValue::Ref(2)]),
+        ] {
+            let mut buf = Vec::new();
+            encode_value(&val, &mut buf);
+            let mut pos = 0;
+            assert_eq!(decode_value(&buf, &mut pos), Some(val));
+            assert_eq!(pos, buf.len());
+        }
+        let mut pos = 0;
+        assert_eq!(decode_value(&[VAL_REF, 0x80], &mut pos), None);
+    }
+
+    #[test]
+    fn decode_invalid_rich_values_returns_none() {
+        let mut pos = 0;
+        assert_eq!(decode_value(&[VAL_BOOL, 2], &mut pos), None);
+        let mut pos = 0;
+        assert_eq!(
+            decode_value(&[VAL_VEC2, 0, 0, 0, 0, 0, 0, 0, 0], &mut pos),
+            None
+        );
+        // Nesting past the depth limit is rejected rather than recursing
+        let deep = [[VAL_LIST, 1]; MAX_LIST_DEPTH + 1].concat();
+        let mut pos = 0;
+        assert_eq!(decode_value(&deep, &mut pos), None);
+    }
+
+    #[test]
+    fn decode_mixed_list_returns_none() {
+        let mixed = NodeValue::List(vec![NodeValue::Int(1), NodeValue::Float(2.0)]);
+        let mut buf = Vec::new();
+        encode_value(&mixed, &mut buf);
+        let mut pos = 0;
+        assert_eq!(decode_value(&buf, &mut pos), None);
+
+        // A mixed list nested inside a homogeneous one is caught too
+        let nested = NodeValue::List(vec![NodeValue::List(Vec::new()), mixed]);
+        let mut buf = Vec::new();
+        encode_value(&nested, &mut buf);
+        let mut pos = 0;
+        assert_eq!(decode_value(&buf, &mut pos), None);
+
+        let op = DiffOp::Update {
+            node_id: 1,
+            old_value: NodeValue::None,
+            new_value: nested,
+        };
+        assert_eq!(decode_patch(&encode_patch(&[op])), None);
+    }
+
+    #[test]
+    fn vector_update_is_component_wise() {
+        let op = DiffOp::Update {
+            node_id: 4,
+            old_value: NodeValue::Vec3([1.0, 2.0, 3.0]),
+            new_value: NodeValue::Vec3([1.0, 2.5, 3.0]),
+        };
+        let mut buf = Vec::new();
+        encode_op(&op, &mut buf);
+        assert_eq!(buf[0], OP_UPDATE_COMPONENTS);
+        // tag + node + value tag + mask + 3 shared/old components + 1 new
+        assert_eq!(buf.len(), 4 + 4 * 8);
+        let mut pos = 0;
+        assert_eq!(decode_op(&buf, &mut pos), Some(op));
+        assert_eq!(pos, buf.len());
+
+        // A translation edit of a matrix sends 18 floats, not 32
+        let mut old = [0.0; 16];
+        old[0] = 1.0;
+        let mut new = old;
+        new[12] = 2.0;
+        new[13] = -0.0;
+        let op = DiffOp::Update {
+            node_id: 4,
+            old_value: NodeValue::Mat4(old),
+            new_value: NodeValue::Mat4(new),
+        };
+        let buf = encode_patch(core::slice::from_ref(&op));
+        // count + tag + node + value tag + 2-byte mask + 16 old + 2 new
+        assert_eq!(buf.len(), 1 + 5 + 18 * 8);
+        let decoded = decode_patch(&buf).unwrap();
+        assert_eq!(decoded, vec![op]);
+        // Signed zero survives: components compare by bits
+        assert!(matches!(&decoded[0], DiffOp::Update {
+            new_value: NodeValue::Mat4(m), ..
+        } if m[13].is_sign_negative()));
+    }
+
+    #[test]
+    fn vector_update_falls_back_when_variant_changes_or_all_differ() {
+        for (old_value, new_value) in [
+            (
+                NodeValue::Vec3([1.0, 2.0, 3.0]),
+                NodeValue::Vec4([1.0, 2.0, 3.0, 4.0]),
+            ),
+            (NodeValue::Vec2([1.0, 2.0]), NodeValue::Vec2([3.0, 4.0])),
+            (NodeValue::Vec4([1.0; 4]), NodeValue::Color([1.0; 4])),
+        ] {
+            let op = DiffOp::Update {
+                node_id: 1,
+                old_value,
+                new_value,
+            };
+            let mut buf = Vec::new();
+            encode_op(&op, &mut buf);
+            assert_eq!(buf[0], OP_UPDATE);
+            let mut pos = 0;
+            assert_eq!(decode_op(&buf, &mut pos), Some(op));
+        }
+    }
+
+    #[test]
+    fn retype_roundtrip() {
+        let op = DiffOp::Retype {
+            node_id: 7,
+            old_kind: AstNodeKind::Primitive,
+            new_kind: AstNodeKind::Group,
+        };
+        let mut buf = Vec::new();
+        encode_op(&op, &mut buf);
+        // Retype: 1 tag + 1 varint + 2 kind bytes = 4 bytes for small IDs
+        assert_eq!(buf, [OP_RETYPE, 7, 2, 5]);
+        let mut pos = 0;
+        assert_eq!(decode_op(&buf, &mut pos), Some(op));
+        assert_eq!(pos, buf.len());
+    }
+
+    #[test]
+    fn decode_truncated_retype_returns_none() {
+        let mut pos = 0;
+        assert_eq!(decode_op(&[OP_RETYPE, 7, 2], &mut pos), None);
+    }
+
+    #[test]
+    fn attr_ops_roundtrip() {
+        let ops = [
+            DiffOp::SetAttr {
+                node_id: 3,
+                key: String::from("width"),
+                old_value: None,
+                new_value: NodeValue::Float(2.0),
+            },
+            DiffOp::SetAttr {
+                node_id: 3,
+                key: String::from("width"),
+                old_value: Some(NodeValue::Float(2.0)),
+                new_value: NodeValue::Float(2.5),
+            },
+            DiffOp::RemoveAttr {
+                node_id: 3,
+                key: String::from("corner"),
+                old_value: NodeValue::Int(4),
+            },
+        ];
+        let buf = encode_patch(&ops);
+        assert_eq!(decode_patch(&buf), Some(ops.to_vec()));
+    }
+
+    #[test]
+    fn set_attr_unset_old_value_is_one_byte() {
+        let op = DiffOp::SetAttr {
+            node_id: 3,
+            key: String::from("w"),
+            old_value: None,
+            new_value: NodeValue::None,
+        };
+        let mut buf = Vec::new();
+        encode_op(&op, &mut buf);
+        assert_eq!(buf, [OP_SET_ATTR, 3, 1, b'w', VAL_UNSET, VAL_NONE]);
+    }
+
+    #[test]
+    fn decode_unset_new_value_returns_none() {
+        // Only old_value may be unset
+        let mut pos = 0;
+        let buf = [OP_SET_ATTR, 3, 1, b'w', VAL_NONE, VAL_UNSET];
+        assert_eq!(decode_op(&buf, &mut pos), None);
+    }
+
     #[test]
     fn decode_truncated_returns_none() {
         // Truncated varint
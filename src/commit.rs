@@ -1,54 +1,90 @@
 //! Commit and branch model
 //!
 //! Git-like commit/branch abstraction over the AST snapshot store.
-//! Commits are immutable, branches are movable pointers.
+//! Commits are immutable, branches are movable pointers. As in git, a
+//! commit ID hashes the whole commit object (tree ID, parents, author,
+//! committer, timestamp, message) and is distinct from its tree ID.
+//! Trees are stored once under their tree ID, however many commits
+//! reference them.
 //!
 //! Author: Moroya Sakamoto
 
 #[cfg(not(feature = "std"))]
-use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec,
+    vec::Vec,
+};
 #[cfg(feature = "std")]
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use crate::ast::AstTree;
+use crate::content_id::ContentId;
 use crate::diff::{apply_patch, diff_trees, DiffOp};
-use crate::merge::{merge_patches, MergeResult};
-use crate::store::{Hash, SnapshotStore};
+use crate::merge::{merge_patches_with_base, MergeResult};
+use crate::schema::{Schema, Violation};
+use crate::store::{unique_prefix_match, SnapshotStore};
 
 /// A commit in the history DAG
 #[derive(Debug, Clone)]
 pub struct Commit {
-    /// Content hash
-    pub hash: Hash,
-    /// Parent commit hash(es)
-    pub parents: Vec<Hash>,
+    /// Commit ID (see [`ContentId::of_commit`])
+    pub hash: ContentId,
+    /// Tree ID (see [`ContentId::of_tree`])
+    pub tree: ContentId,
+    /// Parent commit ID(s)
+    pub parents: Vec<ContentId>,
     /// Commit message
     pub message: String,
     /// Author
     pub author: String,
+    /// Committer
+    pub committer: String,
+    /// Timestamp (caller-defined unit; logical clock by default)
+    pub timestamp: u64,
     /// Diff operations from parent (stored for small patches)
     pub patch: Vec<DiffOp>,
 }
 
+impl Commit {
+    /// Recompute this commit's ID from its fields
+    #[must_use]
+    pub fn compute_id(&self) -> ContentId {
+        ContentId::of_commit(
+            self.tree,
+            &self.parents,
+            &self.author,
+            &self.committer,
+            self.timestamp,
+            &self.message,
+        )
+    }
+}
+
 /// Branch pointer
 #[derive(Debug, Clone)]
 pub struct Branch {
     /// Branch name
     pub name: String,
-    /// Points to current commit hash
-    pub head: Hash,
+    /// Points to current commit ID
+    pub head: ContentId,
 }
 
 /// Repository — manages branches, commits, and snapshots
 pub struct Repository {
     /// Snapshot store
     store: SnapshotStore,
-    /// Commits indexed by hash
-    commits: BTreeMap<Hash, Commit>,
+    /// Commits indexed by content ID
+    commits: BTreeMap<ContentId, Commit>,
     /// Branches
     branches: BTreeMap<String, Branch>,
     /// Current branch name
     current_branch: String,
+    /// Logical clock for commits made without an explicit timestamp
+    clock: u64,
+    /// Rules every committed tree must satisfy
+    schema: Option<Schema>,
 }
 
 impl Default for Repository {
@@ -65,18 +101,24 @@ impl Repository {
             commits: BTreeMap::new(),
             branches: BTreeMap::new(),
             current_branch: String::from("main"),
+            clock: 0,
+            schema: None,
         };
 
         // Create initial empty commit
         let tree = AstTree::new();
-        let hash = repo.store.store(&tree, vec![]);
-        let commit = Commit {
-            hash,
+        let mut commit = Commit {
+            hash: ContentId::ZERO,
+            tree: repo.store.store_tree(&tree),
             parents: vec![],
             message: String::from("initial commit"),
             author: String::from("system"),
+            committer: String::from("system"),
+            timestamp: 0,
             patch: vec![],
         };
+        let hash = commit.compute_id();
+        commit.hash = hash;
         repo.commits.insert(hash, commit);
         repo.branches.insert(
             String::from("main"),
@@ -88,31 +130,113 @@ impl Repository {
         repo
     }
 
+    /// Set the schema every later commit and merge result is validated
+    /// against, or `None` to accept any tree
+    pub fn set_schema(&mut self, schema: Option<Schema>) {
+        self.schema = schema;
+    }
+
+    /// Schema commits are validated against, if any
+    #[must_use]
+    pub const fn schema(&self) -> Option<&Schema> {
+        self.schema.as_ref()
+    }
+
     /// Commit a new tree state
-    pub fn commit(&mut self, tree: &AstTree, message: &str, author: &str) -> Hash {
+    ///
+    /// The author is also recorded as committer, and the timestamp comes
+    /// from the repository's logical clock. Use [`Self::commit_at`] to
+    /// supply wall-clock time.
+    ///
+    /// # Errors
+    ///
+    /// Returns the tree's violations of the repository schema, committing
+    /// nothing.
+    pub fn commit(
+        &mut self,
+        tree: &AstTree,
+        message: &str,
+        author: &str,
+    ) -> Result<ContentId, Vec<Violation>> {
+        let timestamp = self.clock + 1;
+        self.commit_at(tree, message, author, author, timestamp)
+    }
+
+    /// Commit a new tree state with explicit committer and timestamp
+    ///
+    /// # Errors
+    ///
+    /// Returns the tree's violations of the repository schema, committing
+    /// nothing.
+    pub fn commit_at(
+        &mut self,
+        tree: &AstTree,
+        message: &str,
+        author: &str,
+        committer: &str,
+        timestamp: u64,
+    ) -> Result<ContentId, Vec<Violation>> {
         let parent_hash = self.head_hash();
-        let parent_tree = self.store.get(parent_hash).cloned();
+        self.commit_with_parents(tree, &[parent_hash], message, author, committer, timestamp)
+    }
 
-        let patch = parent_tree
-            .as_ref()
+    /// Commit a new tree state with explicit, ordered parents and advance
+    /// the current branch to it
+    ///
+    /// The stored patch is the diff from the first parent, as in git; a
+    /// merge commit lists the branch merged into first and the merged
+    /// branch second.
+    ///
+    /// The tree ID reuses the content digests cached in `tree`, so
+    /// committing a tree again after a local edit re-hashes only the path
+    /// to the edit. The store keeps its own copy of every committed tree,
+    /// which costs a clone linear in the tree's size.
+    ///
+    /// # Errors
+    ///
+    /// Returns the tree's violations of the repository schema, committing
+    /// nothing.
+    pub fn commit_with_parents(
+        &mut self,
+        tree: &AstTree,
+        parents: &[ContentId],
+        message: &str,
+        author: &str,
+        committer: &str,
+        timestamp: u64,
+    ) -> Result<ContentId, Vec<Violation>> {
+        if let Some(schema) = &self.schema {
+            let violations = tree.validate(schema);
+            if !violations.is_empty() {
+                return Err(violations);
+            }
+        }
+        let patch = parents
+            .first()
+            .and_then(|&p| self.tree_of(p))
             .map_or_else(Vec::new, |parent| diff_trees(parent, tree));
 
-        let hash = self.store.store(tree, vec![parent_hash]);
-        let commit = Commit {
-            hash,
-            parents: vec![parent_hash],
+        let mut commit = Commit {
+            hash: ContentId::ZERO,
+            tree: self.store.store_tree(tree),
+            parents: parents.to_vec(),
             message: String::from(message),
             author: String::from(author),
+            committer: String::from(committer),
+            timestamp,
             patch,
         };
+        let hash = commit.compute_id();
+        commit.hash = hash;
         self.commits.insert(hash, commit);
+        self.clock = self.clock.max(timestamp);
 
         // Advance branch
         if let Some(branch) = self.branches.get_mut(&self.current_branch) {
             branch.head = hash;
         }
 
-        hash
+        Ok(hash)
     }
 
     /// Create a new branch at current HEAD
@@ -138,57 +262,119 @@ impl Repository {
     }
 
     /// Merge another branch into current
+    ///
+    /// Both branches are diffed against their [merge base](Self::merge_base).
+    /// A clean merge is committed with the current and the other branch's
+    /// heads as parents, unless the merged tree violates the repository
+    /// schema; its violations are then returned in
+    /// [`MergeResult::violations`] and nothing is committed.
     pub fn merge(&mut self, other_branch: &str) -> Option<MergeResult> {
         let current_hash = self.head_hash();
         let other_hash = self.branches.get(other_branch)?.head;
+        let ancestor_hash = self.merge_base(current_hash, other_hash)?;
 
-        // Find common ancestor (simplified: assume parent of current)
-        let current_commit = self.commits.get(&current_hash)?;
-        let ancestor_hash = current_commit.parents.first().copied()?;
+        let ancestor_tree = self.tree_of(ancestor_hash)?;
+        let patch_a = diff_trees(ancestor_tree, self.tree_of(current_hash)?);
+        let patch_b = diff_trees(ancestor_tree, self.tree_of(other_hash)?);
+        let ancestor_tree = ancestor_tree.clone();
 
-        let ancestor_tree = self.store.get(ancestor_hash)?.clone();
-        let current_tree = self.store.get(current_hash)?.clone();
-        let other_tree = self.store.get(other_hash)?.clone();
-
-        let patch_a = diff_trees(&ancestor_tree, &current_tree);
-        let patch_b = diff_trees(&ancestor_tree, &other_tree);
-
-        let merge_result = merge_patches(&patch_a, &patch_b);
+        let mut merge_result = merge_patches_with_base(&ancestor_tree, &patch_a, &patch_b);
 
         if merge_result.is_clean() {
             // Apply merged patch to ancestor
             let mut result_tree = ancestor_tree;
             apply_patch(&mut result_tree, &merge_result.merged_ops);
-            self.commit(
+            let timestamp = self.clock + 1;
+            if let Err(violations) = self.commit_with_parents(
                 &result_tree,
+                &[current_hash, other_hash],
                 &alloc_format("merge branch '{}'", other_branch),
                 "system",
-            );
+                "system",
+                timestamp,
+            ) {
+                merge_result.violations = violations;
+            }
         }
 
         Some(merge_result)
     }
 
-    /// Get current HEAD hash
+    /// Best common ancestor of two commits: a commit reachable from both
+    /// that is not an ancestor of another such commit
+    ///
+    /// Of several best candidates (criss-cross history) the latest by
+    /// timestamp, then ID, wins. Returns `None` if the commits share no
+    /// history or either is unknown.
     #[must_use]
-    pub fn head_hash(&self) -> Hash {
+    pub fn merge_base(&self, a: ContentId, b: ContentId) -> Option<ContentId> {
+        let from_a = self.reachable(&[a]);
+        let common: BTreeSet<ContentId> = self
+            .reachable(&[b])
+            .into_iter()
+            .filter(|id| from_a.contains(id))
+            .collect();
+        // Anything reachable from a common ancestor's parents is worse
+        let below: Vec<ContentId> = common
+            .iter()
+            .filter_map(|id| self.commits.get(id))
+            .flat_map(|c| c.parents.iter().copied())
+            .collect();
+        let dominated = self.reachable(&below);
+        common
+            .into_iter()
+            .filter(|id| !dominated.contains(id))
+            .max_by_key(|id| (self.commits.get(id).map(|c| c.timestamp), *id))
+    }
+
+    /// Known commits reachable from `starts` through parent links,
+    /// including the starts themselves
+    fn reachable(&self, starts: &[ContentId]) -> BTreeSet<ContentId> {
+        let mut seen = BTreeSet::new();
+        let mut queue: Vec<ContentId> = starts.to_vec();
+        while let Some(id) = queue.pop() {
+            if let Some(commit) = self.commits.get(&id) {
+                if seen.insert(id) {
+                    queue.extend(&commit.parents);
+                }
+            }
+        }
+        seen
+    }
+
+    /// Get current HEAD commit ID
+    #[must_use]
+    pub fn head_hash(&self) -> ContentId {
         self.branches
             .get(&self.current_branch)
-            .map_or(0, |b| b.head)
+            .map_or(ContentId::ZERO, |b| b.head)
     }
 
     /// Get current HEAD tree
     #[must_use]
     pub fn head_tree(&self) -> Option<&AstTree> {
-        self.store.get(self.head_hash())
+        self.tree_of(self.head_hash())
+    }
+
+    /// Tree a commit records
+    fn tree_of(&self, commit: ContentId) -> Option<&AstTree> {
+        self.store.get(self.commits.get(&commit)?.tree)
     }
 
-    /// Get commit by hash
+    /// Get commit by ID
     #[must_use]
-    pub fn get_commit(&self, hash: Hash) -> Option<&Commit> {
+    pub fn get_commit(&self, hash: ContentId) -> Option<&Commit> {
         self.commits.get(&hash)
     }
 
+    /// Resolve an abbreviated hex commit ID.
+    ///
+    /// Returns `None` if no commit or more than one commit matches.
+    #[must_use]
+    pub fn resolve(&self, prefix: &str) -> Option<ContentId> {
+        unique_prefix_match(self.commits.keys().copied(), prefix)
+    }
+
     /// List branches
     #[must_use]
     pub fn branch_names(&self) -> Vec<&str> {
@@ -209,9 +395,9 @@ impl Repository {
 
     /// Get diff between two commits
     #[must_use]
-    pub fn diff(&self, from: Hash, to: Hash) -> Option<Vec<DiffOp>> {
-        let from_tree = self.store.get(from)?;
-        let to_tree = self.store.get(to)?;
+    pub fn diff(&self, from: ContentId, to: ContentId) -> Option<Vec<DiffOp>> {
+        let from_tree = self.tree_of(from)?;
+        let to_tree = self.tree_of(to)?;
         Some(diff_trees(from_tree, to_tree))
     }
 }
@@ -229,6 +415,7 @@ fn alloc_format(template: &str, arg: &str) -> String {
 mod tests {
     use super::*;
     use crate::ast::{AstNodeKind, AstTree, NodeValue};
+    use crate::schema::{NodeRule, ViolationKind};
 
     #[test]
     fn test_repository_init() {
@@ -243,7 +430,7 @@ mod tests {
         let mut tree = AstTree::new();
         tree.add_node(AstNodeKind::Primitive, "sphere", 0);
 
-        let hash = repo.commit(&tree, "add sphere", "test");
+        let hash = repo.commit(&tree, "add sphere", "test").unwrap();
         assert_eq!(repo.commit_count(), 2);
 
         let commit = repo.get_commit(hash).unwrap();
@@ -266,7 +453,7 @@ mod tests {
 
         let mut tree = AstTree::new();
         tree.add_node(AstNodeKind::Primitive, "sphere", 0);
-        let h2 = repo.commit(&tree, "add sphere", "test");
+        let h2 = repo.commit(&tree, "add sphere", "test").unwrap();
 
         let ops = repo.diff(h1, h2).unwrap();
         assert!(!ops.is_empty());
@@ -279,7 +466,7 @@ mod tests {
         let s = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
         tree.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
 
-        let hash = repo.commit(&tree, "add sphere", "test");
+        let hash = repo.commit(&tree, "add sphere", "test").unwrap();
         let commit = repo.get_commit(hash).unwrap();
         assert!(!commit.patch.is_empty());
     }
@@ -311,7 +498,7 @@ mod tests {
         let initial_head = repo.head_hash();
         let mut tree = AstTree::new();
         tree.add_node(AstNodeKind::Primitive, "sphere", 0);
-        repo.commit(&tree, "add sphere", "alice");
+        repo.commit(&tree, "add sphere", "alice").unwrap();
         assert_ne!(repo.head_hash(), initial_head);
     }
 
@@ -320,7 +507,7 @@ mod tests {
         let mut repo = Repository::new();
         let mut tree = AstTree::new();
         tree.add_node(AstNodeKind::Primitive, "sphere", 0);
-        repo.commit(&tree, "add sphere", "alice");
+        repo.commit(&tree, "add sphere", "alice").unwrap();
         let head = repo.head_tree().unwrap();
         assert_eq!(head.node_count(), tree.node_count());
     }
@@ -329,7 +516,7 @@ mod tests {
     fn test_commit_records_author() {
         let mut repo = Repository::new();
         let tree = AstTree::new();
-        let hash = repo.commit(&tree, "msg", "bob");
+        let hash = repo.commit(&tree, "msg", "bob").unwrap();
         assert_eq!(repo.get_commit(hash).unwrap().author, "bob");
     }
 
@@ -337,7 +524,7 @@ mod tests {
     fn test_commit_records_message() {
         let mut repo = Repository::new();
         let tree = AstTree::new();
-        let hash = repo.commit(&tree, "hello world", "x");
+        let hash = repo.commit(&tree, "hello world", "x").unwrap();
         assert_eq!(repo.get_commit(hash).unwrap().message, "hello world");
     }
 
@@ -346,7 +533,7 @@ mod tests {
         let mut repo = Repository::new();
         let initial_head = repo.head_hash();
         let tree = AstTree::new();
-        let hash = repo.commit(&tree, "c2", "x");
+        let hash = repo.commit(&tree, "c2", "x").unwrap();
         let commit = repo.get_commit(hash).unwrap();
         assert!(commit.parents.contains(&initial_head));
     }
@@ -366,7 +553,7 @@ mod tests {
         let before = repo.head_hash();
         let mut tree = AstTree::new();
         tree.add_node(AstNodeKind::Group, "g", 0);
-        repo.commit(&tree, "on feat", "x");
+        repo.commit(&tree, "on feat", "x").unwrap();
         assert_ne!(repo.head_hash(), before);
     }
 
@@ -381,6 +568,288 @@ mod tests {
     #[test]
     fn test_get_commit_nonexistent_returns_none() {
         let repo = Repository::new();
-        assert!(repo.get_commit(0xDEAD_BEEF_CAFE_BABE).is_none());
+        assert!(repo.get_commit(ContentId([0xDE; 32])).is_none());
+    }
+
+    #[test]
+    fn test_resolve_short_commit_id() {
+        let mut repo = Repository::new();
+        let mut tree = AstTree::new();
+        tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let hash = repo.commit(&tree, "add sphere", "alice").unwrap();
+        assert_eq!(repo.resolve(&hash.short()), Some(hash));
+        assert_eq!(repo.resolve(&hash.to_hex()), Some(hash));
+        assert_eq!(repo.resolve("not-hex"), None);
+    }
+
+    #[test]
+    fn test_value_only_change_gets_new_commit_id() {
+        let mut repo = Repository::new();
+        let mut tree = AstTree::new();
+        let r = tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), 0);
+        let h1 = repo.commit(&tree, "r=1", "alice").unwrap();
+        tree.get_node_mut(r).unwrap().value = NodeValue::Float(2.0);
+        let h2 = repo.commit(&tree, "r=2", "alice").unwrap();
+        assert_ne!(h1, h2);
+        assert_eq!(repo.commit_count(), 3);
+    }
+
+    #[test]
+    fn test_commit_id_differs_from_tree_id() {
+        let mut repo = Repository::new();
+        let tree = AstTree::new();
+        let hash = repo.commit(&tree, "empty", "alice").unwrap();
+        let commit = repo.get_commit(hash).unwrap();
+        assert_eq!(commit.tree, ContentId::of_tree(&tree));
+        assert_ne!(commit.hash, commit.tree);
+        assert_eq!(commit.compute_id(), commit.hash);
+    }
+
+    #[test]
+    fn test_same_tree_same_parent_on_two_branches_both_kept() {
+        let mut repo = Repository::new();
+        let mut tree = AstTree::new();
+        tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        repo.create_branch("feat");
+        let on_main = repo.commit(&tree, "add sphere", "alice").unwrap();
+        repo.checkout("feat");
+        let on_feat = repo.commit(&tree, "add sphere too", "bob").unwrap();
+
+        assert_ne!(on_main, on_feat);
+        assert_eq!(repo.commit_count(), 3);
+        assert_eq!(repo.get_commit(on_main).unwrap().author, "alice");
+        assert_eq!(repo.get_commit(on_feat).unwrap().author, "bob");
+        assert_eq!(
+            repo.get_commit(on_main).unwrap().tree,
+            repo.get_commit(on_feat).unwrap().tree
+        );
+    }
+
+    #[test]
+    fn test_commits_of_the_same_tree_share_one_stored_copy() {
+        let mut repo = Repository::new();
+        let mut tree = AstTree::new();
+        tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let a = repo.commit(&tree, "add sphere", "alice").unwrap();
+        let b = repo.commit(&tree, "again", "alice").unwrap();
+        let tree_id = repo.get_commit(a).unwrap().tree;
+        assert_eq!(repo.get_commit(b).unwrap().tree, tree_id);
+        assert!(repo.store.contains(tree_id));
+        assert!(!repo.store.contains(a));
+        // The initial empty tree plus the sphere tree
+        assert_eq!(repo.store.len(), 2);
+        assert_eq!(repo.diff(a, b), Some(vec![]));
+    }
+
+    #[test]
+    fn test_identical_metadata_distinguished_by_logical_clock() {
+        let mut repo = Repository::new();
+        let tree = AstTree::new();
+        repo.create_branch("feat");
+        let a = repo.commit(&tree, "same", "alice").unwrap();
+        repo.checkout("feat");
+        let b = repo.commit(&tree, "same", "alice").unwrap();
+        assert_ne!(a, b);
+        assert!(repo.get_commit(b).unwrap().timestamp > repo.get_commit(a).unwrap().timestamp);
+    }
+
+    #[test]
+    fn test_commit_at_records_committer_and_timestamp() {
+        let mut repo = Repository::new();
+        let tree = AstTree::new();
+        let hash = repo
+            .commit_at(&tree, "msg", "alice", "ci-bot", 1_700_000_000)
+            .unwrap();
+        let commit = repo.get_commit(hash).unwrap();
+        assert_eq!(commit.author, "alice");
+        assert_eq!(commit.committer, "ci-bot");
+        assert_eq!(commit.timestamp, 1_700_000_000);
+
+        // The logical clock never runs backwards past an explicit timestamp
+        let next = repo.commit(&tree, "after", "alice").unwrap();
+        assert!(repo.get_commit(next).unwrap().timestamp > 1_700_000_000);
+    }
+
+    #[test]
+    fn test_commit_id_is_deterministic_across_repositories() {
+        let build = || {
+            let mut repo = Repository::new();
+            let mut tree = AstTree::new();
+            tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+            repo.commit_at(&tree, "add sphere", "alice", "alice", 42)
+                .unwrap()
+        };
+        assert_eq!(build(), build());
+    }
+
+    // ── Merge ──────────────────────────────────────────────────────────
+
+    /// Radius edited and a box added on `main`, a material added on `feat`
+    fn diverged() -> (Repository, ContentId, ContentId, ContentId) {
+        let mut repo = Repository::new();
+        let mut tree = AstTree::new();
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let r =
+            tree.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
+        let fork = repo.commit(&tree, "add sphere", "alice").unwrap();
+        repo.create_branch("feat");
+
+        let mut ours = tree.clone();
+        ours.get_node_mut(r).unwrap().value = NodeValue::Float(2.0);
+        repo.commit(&ours, "grow", "alice").unwrap();
+        ours.add_node(AstNodeKind::Primitive, "box", 0);
+        let main_head = repo.commit(&ours, "add box", "alice").unwrap();
+
+        repo.checkout("feat");
+        tree.add_node(AstNodeKind::Material, "gold", s);
+        let feat_head = repo.commit(&tree, "add gold", "bob").unwrap();
+        repo.checkout("main");
+        (repo, fork, main_head, feat_head)
+    }
+
+    #[test]
+    fn test_merge_base_is_the_fork_point() {
+        let (repo, fork, main_head, feat_head) = diverged();
+        assert_eq!(repo.merge_base(main_head, feat_head), Some(fork));
+        assert_eq!(repo.merge_base(feat_head, main_head), Some(fork));
+        assert_eq!(repo.merge_base(main_head, fork), Some(fork));
+        assert_eq!(repo.merge_base(main_head, main_head), Some(main_head));
+        assert_eq!(repo.merge_base(main_head, ContentId([0xDE; 32])), None);
+    }
+
+    #[test]
+    fn test_merge_commit_records_both_parents() {
+        let (mut repo, _, main_head, feat_head) = diverged();
+        let result = repo.merge("feat").unwrap();
+        assert!(result.is_clean());
+
+        let merge = repo.get_commit(repo.head_hash()).unwrap();
+        assert_eq!(merge.parents, [main_head, feat_head]);
+        assert_eq!(merge.compute_id(), merge.hash);
+        assert_eq!(
+            repo.merge_base(repo.head_hash(), feat_head),
+            Some(feat_head)
+        );
+
+        // Both commits on main survive, not just the latest
+        let head = repo.head_tree().unwrap();
+        let labels: Vec<&str> = head.nodes().iter().map(|n| n.label.as_str()).collect();
+        assert!(labels.contains(&"box"));
+        assert!(labels.contains(&"gold"));
+        let radius = head.nodes().iter().find(|n| n.label == "radius").unwrap();
+        assert_eq!(radius.value, NodeValue::Float(2.0));
+    }
+
+    #[test]
+    fn test_commit_with_parents_diffs_against_the_first() {
+        let (mut repo, fork, main_head, feat_head) = diverged();
+        let tree = repo.head_tree().unwrap().clone();
+        let hash = repo
+            .commit_with_parents(&tree, &[main_head, feat_head], "tie", "alice", "alice", 99)
+            .unwrap();
+        let commit = repo.get_commit(hash).unwrap();
+        assert!(commit.patch.is_empty());
+        assert_eq!(commit.parents, [main_head, feat_head]);
+        assert_eq!(repo.head_hash(), hash);
+        assert_eq!(repo.merge_base(hash, fork), Some(fork));
+    }
+
+    #[test]
+    fn test_commit_and_merge_deep_chain() {
+        let mut tree = AstTree::new();
+        let mut ids = vec![tree.root_id()];
+        for i in 0..100_000 {
+            let parent = ids[ids.len() - 1];
+            ids.push(tree.add_node_with_value(
+                AstNodeKind::Group,
+                "key",
+                NodeValue::Int(i),
+                parent,
+            ));
+        }
+        let mut repo = Repository::new();
+        repo.commit(&tree, "keys", "alice").unwrap();
+        repo.create_branch("feat");
+
+        let mut ours = tree.clone();
+        ours.get_node_mut(ids[50_000]).unwrap().label = "mid".into();
+        let main_head = repo.commit(&ours, "rename", "alice").unwrap();
+        assert_eq!(repo.get_commit(main_head).unwrap().patch.len(), 1);
+
+        repo.checkout("feat");
+        tree.get_node_mut(ids[1]).unwrap().value = NodeValue::Int(-5);
+        repo.commit(&tree, "retime", "bob").unwrap();
+        repo.checkout("main");
+
+        let result = repo.merge("feat").unwrap();
+        assert!(result.is_clean());
+        let head = repo.head_tree().unwrap();
+        assert_eq!(head.nodes().len(), 100_001);
+        assert_eq!(head.get_node(ids[50_000]).unwrap().label, "mid");
+        assert_eq!(head.get_node(ids[1]).unwrap().value, NodeValue::Int(-5));
+    }
+
+    // ── Schema ─────────────────────────────────────────────────────────
+
+    #[test]
+    fn test_commit_rejects_schema_violations() {
+        let mut repo = Repository::new();
+        repo.set_schema(Some(
+            Schema::new().with_kind(AstNodeKind::Parameter, NodeRule::leaf()),
+        ));
+        let mut tree = AstTree::new();
+        let r = tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), 0);
+        tree.add_node(AstNodeKind::Group, "stray", r);
+
+        let head = repo.head_hash();
+        let violations = repo.commit(&tree, "bad", "mallory").unwrap_err();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].node_id, r);
+        assert_eq!(repo.head_hash(), head);
+        assert_eq!(repo.commit_count(), 1);
+
+        repo.set_schema(None);
+        assert!(repo.schema().is_none());
+        assert!(repo.commit(&tree, "bad", "mallory").is_ok());
+    }
+
+    #[test]
+    fn test_merge_result_is_checked_against_schema() {
+        let mut repo = Repository::new();
+        repo.set_schema(Some(Schema::new().with_kind(
+            AstNodeKind::CsgOp,
+            NodeRule::new().with_child_count(2, None),
+        )));
+        let mut base = AstTree::new();
+        let union = base.add_node(AstNodeKind::CsgOp, "union", 0);
+        let sphere = base.add_node(AstNodeKind::Primitive, "sphere", union);
+        let cube = base.add_node(AstNodeKind::Primitive, "box", union);
+        base.add_node(AstNodeKind::Primitive, "cylinder", union);
+        repo.commit(&base, "base", "alice").unwrap();
+        repo.create_branch("feat");
+
+        // Each branch keeps two operands; together they leave one
+        let mut ours = base.clone();
+        assert!(ours.remove_subtree(sphere));
+        repo.commit(&ours, "drop sphere", "alice").unwrap();
+        repo.checkout("feat");
+        let mut theirs = base;
+        assert!(theirs.remove_subtree(cube));
+        repo.commit(&theirs, "drop box", "bob").unwrap();
+        repo.checkout("main");
+
+        let head = repo.head_hash();
+        let result = repo.merge("feat").unwrap();
+        assert!(result.conflicts.is_empty());
+        assert!(!result.is_clean());
+        assert_eq!(
+            result.violations,
+            vec![Violation {
+                node_id: union,
+                kind: ViolationKind::TooFewChildren { count: 1, min: 2 },
+            }]
+        );
+        assert_eq!(repo.head_hash(), head);
     }
 }
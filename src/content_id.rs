@@ -0,0 +1,570 @@
+//! 256-bit content identifiers
+//!
+//! SHA-256 over a canonical encoding of the addressed object. Used as
+//! the key for snapshots and commits, where the 64-bit FNV-1a hash is
+//! too short to rule out accidental or forged collisions. The FNV-1a
+//! hash remains the fast in-memory pre-check.
+//!
+//! Author: Moroya Sakamoto
+
+#[cfg(not(feature = "std"))]
+use alloc::string::String;
+use core::fmt;
+
+use crate::ast::{AstNode, AstTree};
+use crate::traverse::Visitor;
+
+/// Number of hex digits shown by [`ContentId::short`].
+pub const SHORT_HEX_LEN: usize = 12;
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// 256-bit content identifier (SHA-256)
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct ContentId(pub [u8; 32]);
+
+impl ContentId {
+    /// All-zero ID, never produced by hashing in practice
+    pub const ZERO: Self = Self([0; 32]);
+
+    /// Hash raw bytes
+    #[must_use]
+    pub fn of_bytes(data: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        Self(hasher.finalize())
+    }
+
+    /// Content ID of a tree (IDs, kinds, labels, values and child order)
+    ///
+    /// Merkle-shaped: each node's digest covers its own fields and its
+    /// children's digests. Digests are cached per node alongside
+    /// [`AstTree::subtree_hash`], so after a local edit only the path to
+    /// the root is re-hashed — O(depth).
+    #[must_use]
+    pub fn of_tree(tree: &AstTree) -> Self {
+        let root = tree.root_id();
+        tree.walk(root, &mut DigestFill { tree });
+        let mut hasher = Sha256::new();
+        hasher.update(b"tree\0");
+        if let Some(digest) = tree.get_node(root).and_then(AstNode::cached_digest) {
+            hasher.update(&digest);
+        }
+        Self(hasher.finalize())
+    }
+
+    /// Content ID of a snapshot: the tree plus its ordered parent IDs
+    #[must_use]
+    pub fn of_snapshot(tree: &AstTree, parents: &[Self]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"snapshot\0");
+        hasher.update(&Self::of_tree(tree).0);
+        hasher.update(&(parents.len() as u64).to_le_bytes());
+        for p in parents {
+            hasher.update(&p.0);
+        }
+        Self(hasher.finalize())
+    }
+
+    /// Content ID of a commit object.
+    ///
+    /// Covers the tree ID, ordered parent commit IDs, author, committer,
+    /// timestamp and message, so commits of the same tree stay distinct.
+    #[must_use]
+    pub fn of_commit(
+        tree: Self,
+        parents: &[Self],
+        author: &str,
+        committer: &str,
+        timestamp: u64,
+        message: &str,
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"commit\0");
+        hasher.update(&tree.0);
+        hasher.update(&(parents.len() as u64).to_le_bytes());
+        for p in parents {
+            hasher.update(&p.0);
+        }
+        for field in [author, committer] {
+            hasher.update(&(field.len() as u64).to_le_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher.update(&timestamp.to_le_bytes());
+        hasher.update(&(message.len() as u64).to_le_bytes());
+        hasher.update(message.as_bytes());
+        Self(hasher.finalize())
+    }
+
+    /// Raw bytes
+    #[must_use]
+    pub const fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// First 8 bytes as a big-endian `u64` (for C callers and logs)
+    #[must_use]
+    pub const fn prefix_u64(&self) -> u64 {
+        let b = &self.0;
+        u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+    }
+
+    /// Full 64-digit lowercase hex
+    #[must_use]
+    pub fn to_hex(&self) -> String {
+        let mut s = String::with_capacity(64);
+        for &b in &self.0 {
+            s.push(HEX[(b >> 4) as usize] as char);
+            s.push(HEX[(b & 0x0F) as usize] as char);
+        }
+        s
+    }
+
+    /// Abbreviated hex for display (first [`SHORT_HEX_LEN`] digits)
+    #[must_use]
+    pub fn short(&self) -> String {
+        let mut s = self.to_hex();
+        s.truncate(SHORT_HEX_LEN);
+        s
+    }
+
+    /// Parse a full 64-digit hex string (either case)
+    #[must_use]
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let bytes = hex.as_bytes();
+        if bytes.len() != 64 {
+            return None;
+        }
+        let mut out = [0u8; 32];
+        for (i, pair) in bytes.chunks_exact(2).enumerate() {
+            out[i] = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+        }
+        Some(Self(out))
+    }
+
+    /// True if the hex form of this ID starts with `prefix` (either case).
+    ///
+    /// An empty or non-hex prefix never matches.
+    #[must_use]
+    pub fn has_hex_prefix(&self, prefix: &str) -> bool {
+        let p = prefix.as_bytes();
+        if p.is_empty() || p.len() > 64 {
+            return false;
+        }
+        p.iter().enumerate().all(|(i, &c)| {
+            let byte = self.0[i / 2];
+            let nibble = if i % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+            hex_digit(c) == Some(nibble)
+        })
+    }
+}
+
+impl fmt::Display for ContentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for ContentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ContentId({})", self.short())
+    }
+}
+
+/// Fills the content digest cache bottom-up for [`ContentId::of_tree`],
+/// skipping subtrees whose digest is still cached.
+struct DigestFill<'a> {
+    tree: &'a AstTree,
+}
+
+impl Visitor for DigestFill<'_> {
+    fn enter(&mut self, node: &AstNode, _depth: usize) -> bool {
+        node.cached_digest().is_none()
+    }
+
+    fn leave(&mut self, node: &AstNode, _depth: usize) {
+        if node.cached_digest().is_some() {
+            return;
+        }
+        let mut hasher = Sha256::new();
+        node.write_canonical(&mut |b| hasher.update(b));
+        for &child_id in &node.children {
+            // Children were left first, so their digests are cached
+            if let Some(digest) = self
+                .tree
+                .get_node(child_id)
+                .and_then(AstNode::cached_digest)
+            {
+                hasher.update(&digest);
+            }
+        }
+        node.cache_digest(&hasher.finalize());
+    }
+}
+
+#[inline]
+const fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+// ── SHA-256 (FIPS 180-4) ───────────────────────────────────────────────
+
+const K: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+/// Streaming SHA-256
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    const fn new() -> Self {
+        Self {
+            state: H0,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len = self.total_len.wrapping_add(data.len() as u64);
+        while !data.is_empty() {
+            let take = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len == 64 {
+                compress(&mut self.state, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn finalize(mut self) -> [u8; 32] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        self.block[self.block_len] = 0x80;
+        self.block_len += 1;
+        if self.block_len > 56 {
+            self.block[self.block_len..].fill(0);
+            compress(&mut self.state, &self.block);
+            self.block_len = 0;
+        }
+        self.block[self.block_len..56].fill(0);
+        self.block[56..].copy_from_slice(&bit_len.to_be_bytes());
+        compress(&mut self.state, &self.block);
+
+        let mut out = [0u8; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8; 64]) {
+    let mut w = [0u32; 64];
+    for (i, chunk) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+// ── Tests ──────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{AstNodeKind, NodeValue};
+    #[cfg(not(feature = "std"))]
+    use alloc::{format, vec::Vec};
+
+    #[test]
+    fn sha256_empty() {
+        assert_eq!(
+            ContentId::of_bytes(b"").to_hex(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn sha256_abc() {
+        assert_eq!(
+            ContentId::of_bytes(b"abc").to_hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn sha256_two_blocks() {
+        let msg = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(
+            ContentId::of_bytes(msg).to_hex(),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn sha256_streaming_matches_one_shot() {
+        let data: Vec<u8> = (0u16..1000).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Sha256::new();
+        for chunk in data.chunks(37) {
+            hasher.update(chunk);
+        }
+        assert_eq!(ContentId(hasher.finalize()), ContentId::of_bytes(&data));
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        let id = ContentId::of_bytes(b"alice");
+        assert_eq!(ContentId::from_hex(&id.to_hex()), Some(id));
+        assert_eq!(ContentId::from_hex(&id.to_hex().to_uppercase()), Some(id));
+        assert_eq!(ContentId::from_hex("abc"), None);
+        assert_eq!(ContentId::from_hex(&"g".repeat(64)), None);
+    }
+
+    #[test]
+    fn short_is_prefix_of_hex() {
+        let id = ContentId::of_bytes(b"alice");
+        assert_eq!(id.short().len(), SHORT_HEX_LEN);
+        assert!(id.to_hex().starts_with(&id.short()));
+        assert_eq!(format!("{id}"), id.to_hex());
+    }
+
+    #[test]
+    fn hex_prefix_matching() {
+        let id = ContentId::of_bytes(b"abc"); // ba7816bf...
+        assert!(id.has_hex_prefix("b"));
+        assert!(id.has_hex_prefix("ba7816"));
+        assert!(id.has_hex_prefix("BA78"));
+        assert!(!id.has_hex_prefix("ba7817"));
+        assert!(!id.has_hex_prefix(""));
+        assert!(!id.has_hex_prefix("zz"));
+        assert!(id.has_hex_prefix(&id.to_hex()));
+    }
+
+    #[test]
+    fn prefix_u64_is_big_endian_head() {
+        let id = ContentId::of_bytes(b"abc");
+        assert_eq!(id.prefix_u64(), 0xba78_16bf_8f01_cfea);
+    }
+
+    #[test]
+    fn tree_id_covers_values_and_ids() {
+        let mut t1 = AstTree::new();
+        t1.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), 0);
+        let mut t2 = AstTree::new();
+        t2.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(2.0), 0);
+        assert_ne!(ContentId::of_tree(&t1), ContentId::of_tree(&t2));
+
+        // Same content under a different node ID is a different tree
+        let mut t3 = AstTree::new();
+        let tmp = t3.add_node(AstNodeKind::Group, "tmp", 0);
+        t3.remove_subtree(tmp);
+        t3.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), 0);
+        assert_eq!(t1.subtree_hash(0), t3.subtree_hash(0));
+        assert_ne!(ContentId::of_tree(&t1), ContentId::of_tree(&t3));
+    }
+
+    #[test]
+    fn tree_id_covers_nesting() {
+        // IDs 1 and 2 either as parent and child or as siblings
+        let mut nested = AstTree::new();
+        let a = nested.add_node(AstNodeKind::Group, "a", 0);
+        nested.add_node(AstNodeKind::Group, "b", a);
+        let mut flat = AstTree::new();
+        flat.add_node(AstNodeKind::Group, "a", 0);
+        flat.add_node(AstNodeKind::Group, "b", 0);
+        assert_ne!(ContentId::of_tree(&nested), ContentId::of_tree(&flat));
+    }
+
+    #[test]
+    fn tree_id_cache_follows_edits() {
+        let build = |radius: f64, extra: bool| {
+            let mut tree = AstTree::new();
+            let u = tree.add_node(AstNodeKind::CsgOp, "union", 0);
+            let s = tree.add_node(AstNodeKind::Primitive, "sphere", u);
+            let v = NodeValue::Float(radius);
+            tree.add_node_with_value(AstNodeKind::Parameter, "r", v, s);
+            tree.add_node(AstNodeKind::Primitive, "box", u);
+            if extra {
+                tree.add_node(AstNodeKind::Group, "lights", 0);
+            }
+            tree
+        };
+        let mut tree = build(1.0, false);
+        let before = ContentId::of_tree(&tree);
+        assert_eq!(before, ContentId::of_tree(&tree.clone()));
+
+        // Ancestors of an edit are re-hashed, and only they need be
+        tree.get_node_mut(3).unwrap().value = NodeValue::Float(2.0);
+        assert_eq!(
+            ContentId::of_tree(&tree),
+            ContentId::of_tree(&build(2.0, false))
+        );
+        tree.get_node_mut(3).unwrap().value = NodeValue::Float(1.0);
+        assert_eq!(ContentId::of_tree(&tree), before);
+
+        let lights = tree.add_node(AstNodeKind::Group, "lights", 0);
+        assert_eq!(
+            ContentId::of_tree(&tree),
+            ContentId::of_tree(&build(1.0, true))
+        );
+        tree.remove_subtree(lights);
+        assert_eq!(ContentId::of_tree(&tree), before);
+    }
+
+    #[test]
+    fn snapshot_id_covers_parents() {
+        let tree = AstTree::new();
+        let p = ContentId::of_bytes(b"parent");
+        assert_ne!(
+            ContentId::of_snapshot(&tree, &[]),
+            ContentId::of_snapshot(&tree, &[p])
+        );
+        assert_ne!(
+            ContentId::of_snapshot(&tree, &[]),
+            ContentId::of_tree(&tree)
+        );
+    }
+
+    #[test]
+    fn commit_id_covers_every_field() {
+        let tree = ContentId::of_tree(&AstTree::new());
+        let parent = ContentId::of_bytes(b"parent");
+        let base = ContentId::of_commit(tree, &[parent], "alice", "bob", 7, "msg");
+        let variants = [
+            ContentId::of_commit(ContentId::ZERO, &[parent], "alice", "bob", 7, "msg"),
+            ContentId::of_commit(tree, &[], "alice", "bob", 7, "msg"),
+            ContentId::of_commit(tree, &[parent, parent], "alice", "bob", 7, "msg"),
+            ContentId::of_commit(tree, &[parent], "carol", "bob", 7, "msg"),
+            ContentId::of_commit(tree, &[parent], "alice", "carol", 7, "msg"),
+            ContentId::of_commit(tree, &[parent], "alice", "bob", 8, "msg"),
+            ContentId::of_commit(tree, &[parent], "alice", "bob", 7, "other"),
+        ];
+        for v in variants {
+            assert_ne!(base, v);
+        }
+        // Field boundaries are length-prefixed
+        assert_ne!(
+            ContentId::of_commit(tree, &[], "ab", "c", 0, ""),
+            ContentId::of_commit(tree, &[], "a", "bc", 0, "")
+        );
+    }
+}
@@ -1,25 +1,40 @@
 //! AST diff engine
 //!
 //! Computes minimal edit operations between two AST trees.
-//! Produces operation-based patches: Insert, Delete, Update, Move.
-//! Each operation is 4-12 bytes — vs 50 KB for binary diffs.
+//! Produces operation-based patches: Insert, Delete, Update, Relabel,
+//! Move, Retype, `SetAttr`, `RemoveAttr`.
+//! Node edits typically take 4-12 bytes per operation — vs 50 KB for
+//! binary diffs; ops carrying large values (`Mat4`, lists) take more, see
+//! [`DiffOp::serialized_size`].
 //!
 //! Author: Moroya Sakamoto
 
 #[cfg(not(feature = "std"))]
 use alloc::collections::BTreeMap as HashMap;
 #[cfg(not(feature = "std"))]
-use alloc::{string::String, vec, vec::Vec};
+use alloc::collections::BTreeSet as HashSet;
+#[cfg(not(feature = "std"))]
+use alloc::{format, string::String};
+#[cfg(not(feature = "std"))]
+use alloc::{vec, vec::Vec};
 #[cfg(feature = "std")]
 use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
+
+use core::fmt;
 
-use crate::ast::{AstNodeKind, AstTree, NodeId, NodeValue};
+use crate::ast::{AstNode, AstNodeKind, AstTree, NodeId, NodeValue};
+use crate::edit_distance::{exact_matching, EditCosts};
+use crate::patch::invert_op;
 
 /// Diff operation on AST nodes
 #[derive(Debug, Clone, PartialEq)]
 pub enum DiffOp {
-    /// Insert a new node
+    /// Insert a new node under the ID `node_id`, at position `index`
+    /// among the parent's children
     Insert {
+        node_id: NodeId,
         parent_id: NodeId,
         index: usize,
         kind: AstNodeKind,
@@ -40,12 +55,33 @@ pub enum DiffOp {
         old_label: String,
         new_label: String,
     },
-    /// Move a node to a new parent
+    /// Move a node to a new parent, at position `new_index` among its
+    /// children once the node has been detached from its old place
     Move {
         node_id: NodeId,
         new_parent_id: NodeId,
         new_index: usize,
     },
+    /// Change a node's kind, keeping its label, value and subtree
+    Retype {
+        node_id: NodeId,
+        old_kind: AstNodeKind,
+        new_kind: AstNodeKind,
+    },
+    /// Add or change the attribute `key` of a node; `old_value` is `None`
+    /// if the attribute was unset
+    SetAttr {
+        node_id: NodeId,
+        key: String,
+        old_value: Option<NodeValue>,
+        new_value: NodeValue,
+    },
+    /// Remove the attribute `key` of a node
+    RemoveAttr {
+        node_id: NodeId,
+        key: String,
+        old_value: NodeValue,
+    },
 }
 
 impl DiffOp {
@@ -53,705 +89,4369 @@ impl DiffOp {
     #[must_use]
     pub const fn serialized_size(&self) -> usize {
         match self {
-            Self::Insert { label, value, .. } => 8 + label.len() + value.serialized_size(),
+            Self::Insert { label, value, .. } => 12 + label.len() + value.serialized_size(),
             Self::Delete { .. } => 5,
             Self::Update { new_value, .. } => 5 + new_value.serialized_size(),
             Self::Relabel { new_label, .. } => 5 + new_label.len(),
             Self::Move { .. } => 12,
+            Self::Retype { .. } => 7,
+            Self::SetAttr { key, new_value, .. } => 5 + key.len() + new_value.serialized_size(),
+            Self::RemoveAttr { key, .. } => 5 + key.len(),
+        }
+    }
+}
+
+impl DiffOp {
+    /// Rewrite every node ID the op refers to through `f`, including
+    /// references inside its values.
+    pub(crate) fn map_node_ids(&mut self, mut f: impl FnMut(NodeId) -> NodeId) {
+        match self {
+            Self::Insert { value, .. } => value.map_refs(&mut f),
+            Self::Update {
+                old_value,
+                new_value,
+                ..
+            } => {
+                old_value.map_refs(&mut f);
+                new_value.map_refs(&mut f);
+            }
+            Self::SetAttr {
+                old_value,
+                new_value,
+                ..
+            } => {
+                if let Some(old_value) = old_value {
+                    old_value.map_refs(&mut f);
+                }
+                new_value.map_refs(&mut f);
+            }
+            Self::RemoveAttr { old_value, .. } => old_value.map_refs(&mut f),
+            _ => {}
+        }
+        match self {
+            Self::Insert {
+                node_id, parent_id, ..
+            } => {
+                *node_id = f(*node_id);
+                *parent_id = f(*parent_id);
+            }
+            Self::Delete { node_id }
+            | Self::Update { node_id, .. }
+            | Self::Relabel { node_id, .. }
+            | Self::Retype { node_id, .. }
+            | Self::SetAttr { node_id, .. }
+            | Self::RemoveAttr { node_id, .. } => *node_id = f(*node_id),
+            Self::Move {
+                node_id,
+                new_parent_id,
+                ..
+            } => {
+                *node_id = f(*node_id);
+                *new_parent_id = f(*new_parent_id);
+            }
+        }
+    }
+}
+
+// ── Options ────────────────────────────────────────────────────────────
+
+/// Tuning for [`diff_trees_with`]
+///
+/// Options only shape which ops are produced; the ops themselves, their
+/// encoding and how they merge are the same as for [`diff_trees`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffOptions {
+    /// Minimum descendant similarity (Dice coefficient, 0.0–1.0) for two
+    /// nodes to be matched across a changed parent or label: a subtree
+    /// with the same `(kind, label)` is reported as moved, and a node whose
+    /// children mostly match as relabeled, rather than deleted and
+    /// inserted.
+    pub similarity_threshold: f64,
+    /// When two `Float` values count as unchanged
+    pub float_tolerance: FloatTolerance,
+    /// Node kinds left out of the diff, with their subtrees
+    pub ignore_kinds: Vec<AstNodeKind>,
+    /// Node labels left out of the diff, with their subtrees
+    pub ignore_labels: Vec<String>,
+    /// How old and new nodes are matched
+    pub strategy: MatchStrategy,
+}
+
+impl DiffOptions {
+    /// Default [`similarity_threshold`](Self::similarity_threshold)
+    pub const DEFAULT_SIMILARITY_THRESHOLD: f64 = 0.5;
+
+    /// True if `node` and its subtree are left out of the diff.
+    ///
+    /// Ignored nodes are never inserted, deleted, updated or moved; in the
+    /// old tree they stay where they are unless an ancestor is deleted.
+    #[must_use]
+    pub fn ignores(&self, node: &AstNode) -> bool {
+        self.ignore_kinds.contains(&node.kind) || self.ignore_labels.contains(&node.label)
+    }
+
+    /// True if `a` and `b` count as the same value under
+    /// [`float_tolerance`](Self::float_tolerance), which applies to every
+    /// component of vectors, quaternions, matrices and colors and to every
+    /// list item.
+    #[must_use]
+    pub fn values_match(&self, a: &NodeValue, b: &NodeValue) -> bool {
+        let tolerance = self.float_tolerance;
+        match (a, b) {
+            (NodeValue::Float(x), NodeValue::Float(y)) => tolerance.matches(*x, *y),
+            (NodeValue::List(xs), NodeValue::List(ys)) => {
+                xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| self.values_match(x, y))
+            }
+            _ => match (a.components(), b.components()) {
+                (Some(xs), Some(ys)) if a.same_variant(b) => {
+                    xs.iter().zip(ys).all(|(x, y)| tolerance.matches(*x, *y))
+                }
+                _ => same_value(a, b),
+            },
+        }
+    }
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            similarity_threshold: Self::DEFAULT_SIMILARITY_THRESHOLD,
+            float_tolerance: FloatTolerance::Exact,
+            ignore_kinds: Vec::new(),
+            ignore_labels: Vec::new(),
+            strategy: MatchStrategy::Heuristic,
+        }
+    }
+}
+
+/// Float comparison for [`DiffOptions::float_tolerance`]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FloatTolerance {
+    /// Bit-for-bit equal (NaN equals NaN)
+    #[default]
+    Exact,
+    /// `|a - b| <= epsilon`
+    Absolute(f64),
+    /// `|a - b| <= epsilon * max(|a|, |b|)`
+    Relative(f64),
+}
+
+impl FloatTolerance {
+    /// True if `a` and `b` count as equal.
+    #[must_use]
+    pub fn matches(self, a: f64, b: f64) -> bool {
+        if same_value(&NodeValue::Float(a), &NodeValue::Float(b)) {
+            return true;
+        }
+        let diff = (a - b).abs();
+        match self {
+            Self::Exact => false,
+            Self::Absolute(epsilon) => diff <= epsilon,
+            Self::Relative(epsilon) => diff <= epsilon * a.abs().max(b.abs()),
         }
     }
 }
 
+/// Node matching algorithm for [`DiffOptions::strategy`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum MatchStrategy {
+    /// Top-down `(kind, label)` matching only: fastest, but moved or
+    /// renamed subtrees become Delete + Insert
+    Greedy,
+    /// Top-down matching plus move and relabel detection — what
+    /// [`diff_trees`] uses
+    #[default]
+    Heuristic,
+    /// Optimal Zhang–Shasha edit mapping under the given costs; see
+    /// [`crate::edit_distance`]
+    Exact(EditCosts),
+}
+
 /// Compute diff operations to transform `old` tree into `new` tree
 ///
 /// Uses a simplified tree-edit approach:
 /// 1. Match nodes by (kind, label) between old and new using O(1) `HashMap` lookup
-/// 2. Unmatched in old -> Delete
-/// 3. Unmatched in new -> Insert
-/// 4. Matched but changed value -> Update
+/// 2. Match leftover new nodes against leftover old nodes anywhere in the
+///    tree — first by identical subtree hash, then by (kind, label) with
+///    enough shared descendants — and emit Move for those
+/// 3. Bottom-up, match leftover new nodes against leftover old nodes of
+///    the same kind that hold enough of their matched descendants, and
+///    emit Relabel for those whose label changed
+/// 4. Unmatched in old -> Delete
+/// 5. Unmatched in new -> Insert, with nested inserts for its descendants
+/// 6. Matched but changed value -> Update; changed kind -> Retype
+/// 7. Matched children out of order -> Move within the same parent; only
+///    children outside the longest order-preserving run are moved
+///
+/// Matched pairs whose Merkle subtree hashes are equal are pruned without
+/// descending, so an edit deep in a large tree only visits its path.
 #[must_use]
 pub fn diff_trees(old: &AstTree, new: &AstTree) -> Vec<DiffOp> {
-    let mut ops = Vec::new();
-    diff_subtree(old, new, old.root_id(), new.root_id(), &mut ops);
-    ops
-}
-
-fn diff_subtree(
-    old: &AstTree,
-    new: &AstTree,
-    old_id: NodeId,
-    new_id: NodeId,
-    ops: &mut Vec<DiffOp>,
-) {
-    let Some(old_node) = old.get_node(old_id) else {
-        return;
-    };
-    let Some(new_node) = new.get_node(new_id) else {
-        return;
-    };
+    diff_trees_with(old, new, &DiffOptions::default())
+}
 
-    // Check for label change
-    if old_node.label != new_node.label {
-        ops.push(DiffOp::Relabel {
-            node_id: old_id,
-            old_label: old_node.label.clone(),
-            new_label: new_node.label.clone(),
-        });
+/// [`diff_trees`] with explicit [`DiffOptions`]
+#[must_use]
+pub fn diff_trees_with(old: &AstTree, new: &AstTree, options: &DiffOptions) -> Vec<DiffOp> {
+    let mut differ = Differ::new(old, new, options);
+    match &options.strategy {
+        MatchStrategy::Greedy => {
+            differ.match_pair(old.root_id(), new.root_id(), false);
+            differ.emit()
+        }
+        MatchStrategy::Heuristic => differ.run(),
+        MatchStrategy::Exact(costs) => {
+            for (old_id, new_id) in exact_matching(old, new, costs, options) {
+                differ.link(old_id, new_id);
+            }
+            differ.emit()
+        }
     }
+}
 
-    // Check for value change
-    if old_node.value != new_node.value {
-        ops.push(DiffOp::Update {
-            node_id: old_id,
-            old_value: old_node.value.clone(),
-            new_value: new_node.value.clone(),
-        });
+/// Diff two trees that share a node numbering — `new` was derived from
+/// `old` by a patch — matching nodes by ID instead of by content.
+///
+/// Inserts keep the IDs the nodes have in `new`.
+pub(crate) fn diff_by_id(old: &AstTree, new: &AstTree) -> Vec<DiffOp> {
+    let options = DiffOptions::default();
+    let mut differ = Differ::new(old, new, &options);
+    differ.keep_ids = true;
+    for node in new.nodes() {
+        if old.get_node(node.id).is_some() {
+            differ.link(node.id, node.id);
+        }
+    }
+    differ.emit()
+}
+
+/// Diff state: the node matching between `old` and `new`
+struct Differ<'a> {
+    old: &'a AstTree,
+    new: &'a AstTree,
+    /// Matched old node → new node
+    old_to_new: HashMap<NodeId, NodeId>,
+    /// Matched new node → old node
+    new_to_old: HashMap<NodeId, NodeId>,
+    /// Old side of matched pairs with identical subtrees; never expanded
+    identical: HashSet<NodeId>,
+    /// Matched pairs actually expanded (hashes differed)
+    visited: usize,
+    /// Insert new nodes under their IDs in `new` rather than fresh ones
+    keep_ids: bool,
+    /// Old side of every newly linked pair, in the order they were linked
+    journal: Vec<NodeId>,
+    /// Descendants per node, filled by [`Self::run`]
+    old_sizes: HashMap<NodeId, usize>,
+    new_sizes: HashMap<NodeId, usize>,
+    /// Descendant profiles of old nodes already compared by
+    /// [`Self::most_similar`]
+    old_profiles: HashMap<NodeId, Profile<'a>>,
+    options: &'a DiffOptions,
+}
+
+impl<'a> Differ<'a> {
+    fn new(old: &'a AstTree, new: &'a AstTree, options: &'a DiffOptions) -> Self {
+        Self {
+            old,
+            new,
+            old_to_new: HashMap::new(),
+            new_to_old: HashMap::new(),
+            identical: HashSet::new(),
+            visited: 0,
+            keep_ids: false,
+            journal: Vec::new(),
+            old_sizes: HashMap::new(),
+            new_sizes: HashMap::new(),
+            old_profiles: HashMap::new(),
+            options,
+        }
+    }
+
+    fn run(&mut self) -> Vec<DiffOp> {
+        self.old_sizes = Self::sizes(self.old);
+        self.new_sizes = Self::sizes(self.new);
+        self.match_pair(self.old.root_id(), self.new.root_id(), false);
+        self.match_moves();
+        self.match_containers();
+        self.emit()
     }
 
-    // Diff children
-    let old_children = old_node.children.clone();
-    let new_children = new_node.children.clone();
+    fn link(&mut self, old_id: NodeId, new_id: NodeId) {
+        if self.old_to_new.insert(old_id, new_id).is_none() {
+            self.journal.push(old_id);
+        }
+        self.new_to_old.insert(new_id, old_id);
+    }
+
+    /// Number of descendants of every node of `tree`, in one post-order
+    /// pass.
+    fn sizes(tree: &AstTree) -> HashMap<NodeId, usize> {
+        let mut sizes: HashMap<NodeId, usize> = HashMap::new();
+        for node in tree.post_order(tree.root_id()) {
+            let size = node
+                .children
+                .iter()
+                .filter_map(|c| sizes.get(c))
+                .map(|size| size + 1)
+                .sum();
+            sizes.insert(node.id, size);
+        }
+        sizes
+    }
 
-    // Build a HashMap from (kind, label) -> list of new child indices for O(1) matching.
-    // The previous implementation used nested loops: O(m*n) per level.
-    // This HashMap approach reduces child matching to O(m+n) per level.
-    let mut new_key_to_indices: HashMap<(AstNodeKind, String), Vec<usize>> = HashMap::new();
-    for (ni, &new_child_id) in new_children.iter().enumerate() {
-        if let Some(new_child) = new.get_node(new_child_id) {
-            new_key_to_indices
-                .entry((new_child.kind, new_child.label.clone()))
-                .or_default()
-                .push(ni);
+    /// Match `old_id` with `new_id`, then their children top-down: by
+    /// subtree hash, then by `(kind, label)`.
+    ///
+    /// With `claim` set, identical subtrees have all their descendants
+    /// linked too, so move detection cannot pick nodes out of them.
+    ///
+    /// Matched child pairs go on a worklist rather than the call stack, so
+    /// arbitrarily deep trees are fine. Sibling subtrees never share nodes,
+    /// so the order they are expanded in does not affect the matching.
+    fn match_pair(&mut self, old_id: NodeId, new_id: NodeId, claim: bool) {
+        let mut pending = vec![(old_id, new_id)];
+        while let Some((old_id, new_id)) = pending.pop() {
+            let first = pending.len();
+            self.match_children(old_id, new_id, claim, &mut pending);
+            // Expand children in order, like the recursion this replaces
+            pending[first..].reverse();
         }
     }
 
-    let mut matched_new: Vec<bool> = vec![false; new_children.len()];
-    let mut matched_old: Vec<bool> = vec![false; old_children.len()];
+    /// Link `old_id` with `new_id`, then link their matched children and
+    /// push them onto `pending`. Children are linked straight away so the
+    /// retype pass below cannot pick them a second time.
+    fn match_children(
+        &mut self,
+        old_id: NodeId,
+        new_id: NodeId,
+        claim: bool,
+        pending: &mut Vec<(NodeId, NodeId)>,
+    ) {
+        let (old, new) = (self.old, self.new);
+        let Some(old_node) = old.get_node(old_id) else {
+            return;
+        };
+        let Some(new_node) = new.get_node(new_id) else {
+            return;
+        };
+        self.link(old_id, new_id);
+
+        // Identical subtrees need no ops — O(1) once hashes are cached.
+        // A claimed subtree is only whole if move detection has not
+        // already taken one of its nodes elsewhere.
+        if old.subtree_hash(old_id) == new.subtree_hash(new_id)
+            && (!claim
+                || old
+                    .descendants(old_id)
+                    .all(|(_, n)| !self.old_to_new.contains_key(&n.id)))
+        {
+            self.identical.insert(old_id);
+            if claim {
+                self.link_identical(old_id, new_id);
+            }
+            return;
+        }
+        self.visited += 1;
+
+        let new_children = &new_node.children;
+        let mut matched_new: Vec<bool> = vec![false; new_children.len()];
+
+        // Identical siblings first, so a same-label neighbour of a deleted
+        // or edited child cannot take its partner. A hash that occurs a
+        // different number of times on each side is ambiguous and left to
+        // the `(kind, label)` pass, which pairs in order.
+        let mut old_hash_counts: HashMap<u64, usize> = HashMap::new();
+        for &old_child_id in &old_node.children {
+            if !self.old_to_new.contains_key(&old_child_id)
+                && old
+                    .get_node(old_child_id)
+                    .is_some_and(|c| !self.options.ignores(c))
+            {
+                *old_hash_counts
+                    .entry(old.subtree_hash(old_child_id))
+                    .or_default() += 1;
+            }
+        }
+        let mut new_hash_to_indices: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (ni, &new_child_id) in new_children.iter().enumerate().rev() {
+            if !self.new_to_old.contains_key(&new_child_id)
+                && new
+                    .get_node(new_child_id)
+                    .is_some_and(|c| !self.options.ignores(c))
+            {
+                new_hash_to_indices
+                    .entry(new.subtree_hash(new_child_id))
+                    .or_default()
+                    .push(ni);
+            }
+        }
+        new_hash_to_indices
+            .retain(|hash, indices| old_hash_counts.get(hash) == Some(&indices.len()));
+        for &old_child_id in &old_node.children {
+            if self.old_to_new.contains_key(&old_child_id)
+                || old
+                    .get_node(old_child_id)
+                    .is_none_or(|c| self.options.ignores(c))
+            {
+                continue;
+            }
+            // Candidates are stored last-first, so pop takes the first
+            if let Some(ni) = new_hash_to_indices
+                .get_mut(&old.subtree_hash(old_child_id))
+                .and_then(Vec::pop)
+            {
+                matched_new[ni] = true;
+                self.link(old_child_id, new_children[ni]);
+                pending.push((old_child_id, new_children[ni]));
+            }
+        }
+
+        // Build a HashMap from (kind, label) -> list of new child indices for O(1) matching.
+        // The previous implementation used nested loops: O(m*n) per level.
+        // This HashMap approach reduces child matching to O(m+n) per level.
+        let mut new_key_to_indices: HashMap<(AstNodeKind, &str), Vec<usize>> = HashMap::new();
+        for (ni, &new_child_id) in new_children.iter().enumerate().rev() {
+            if self.new_to_old.contains_key(&new_child_id) {
+                continue;
+            }
+            if let Some(new_child) = new
+                .get_node(new_child_id)
+                .filter(|c| !self.options.ignores(c))
+            {
+                new_key_to_indices
+                    .entry((new_child.kind, new_child.label.as_str()))
+                    .or_default()
+                    .push(ni);
+            }
+        }
 
-    // First pass: match old children to new children via HashMap lookup — O(n)
-    for (oi, &old_child_id) in old_children.iter().enumerate() {
-        if let Some(old_child) = old.get_node(old_child_id) {
-            let key = (old_child.kind, old_child.label.clone());
-            if let Some(candidates) = new_key_to_indices.get_mut(&key) {
-                // Find the first unmatched candidate for this key
-                if let Some(pos) = candidates.iter().position(|&ni| !matched_new[ni]) {
-                    let ni = candidates[pos];
-                    matched_old[oi] = true;
+        // Match old children to new children via HashMap lookup — O(n)
+        for &old_child_id in &old_node.children {
+            if self.old_to_new.contains_key(&old_child_id) {
+                continue;
+            }
+            if let Some(old_child) = old
+                .get_node(old_child_id)
+                .filter(|c| !self.options.ignores(c))
+            {
+                let key = (old_child.kind, old_child.label.as_str());
+                // The first unmatched candidate for this key
+                if let Some(ni) = new_key_to_indices.get_mut(&key).and_then(Vec::pop) {
                     matched_new[ni] = true;
-                    // Recurse into matched pair
-                    diff_subtree(old, new, old_child_id, new_children[ni], ops);
+                    self.link(old_child_id, new_children[ni]);
+                    pending.push((old_child_id, new_children[ni]));
                 }
             }
         }
+
+        // Same label, different kind: a retype if the subtrees agree.
+        // Only leftover new children with the old child's label are scored.
+        let mut new_label_to_indices: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (ni, &new_child_id) in new_children.iter().enumerate() {
+            if matched_new[ni] || self.new_to_old.contains_key(&new_child_id) {
+                continue;
+            }
+            if let Some(new_child) = new
+                .get_node(new_child_id)
+                .filter(|c| !self.options.ignores(c))
+            {
+                new_label_to_indices
+                    .entry(new_child.label.as_str())
+                    .or_default()
+                    .push(ni);
+            }
+        }
+        if new_label_to_indices.is_empty() {
+            return;
+        }
+        for &old_child_id in &old_node.children {
+            if self.old_to_new.contains_key(&old_child_id) {
+                continue;
+            }
+            let Some(old_child) = old
+                .get_node(old_child_id)
+                .filter(|c| !self.options.ignores(c))
+            else {
+                continue;
+            };
+            let Some(indices) = new_label_to_indices.get_mut(old_child.label.as_str()) else {
+                continue;
+            };
+            let old_size = self.old_sizes.get(&old_child_id).copied().unwrap_or(0);
+            let mut retyped = None;
+            for (pos, &ni) in indices.iter().enumerate() {
+                let new_child_id = new_children[ni];
+                let new_size = self.new_sizes.get(&new_child_id).copied().unwrap_or(0);
+                if new
+                    .get_node(new_child_id)
+                    .is_some_and(|n| n.kind != old_child.kind)
+                    && dice_bound(old_size, new_size) >= self.options.similarity_threshold
+                    && self.similarity(old_child_id, new_child_id)
+                        >= self.options.similarity_threshold
+                {
+                    retyped = Some(pos);
+                    break;
+                }
+            }
+            if let Some(pos) = retyped {
+                let new_child_id = new_children[indices.remove(pos)];
+                self.link(old_child_id, new_child_id);
+                pending.push((old_child_id, new_child_id));
+            }
+        }
     }
 
-    // Deleted: unmatched in old
-    for (oi, &old_child_id) in old_children.iter().enumerate() {
-        if !matched_old[oi] {
-            ops.push(DiffOp::Delete {
-                node_id: old_child_id,
-            });
+    /// Link the descendants of two identical subtrees position by position.
+    fn link_identical(&mut self, old_id: NodeId, new_id: NodeId) {
+        let pairs = self
+            .old
+            .descendants(old_id)
+            .zip(self.new.descendants(new_id));
+        for ((_, o), (_, n)) in pairs {
+            self.link(o.id, n.id);
         }
     }
 
-    // Inserted: unmatched in new
-    for (ni, &new_child_id) in new_children.iter().enumerate() {
-        if !matched_new[ni] {
-            if let Some(new_child) = new.get_node(new_child_id) {
-                ops.push(DiffOp::Insert {
-                    parent_id: old_id,
-                    index: ni,
-                    kind: new_child.kind,
-                    label: new_child.label.clone(),
-                    value: new_child.value.clone(),
-                });
+    /// Match new nodes left over by the top-down pass against leftover old
+    /// nodes anywhere in the tree, so reparented subtrees become moves.
+    fn match_moves(&mut self) {
+        let (old, new) = (self.old, self.new);
+
+        // Index every unmatched old node by subtree hash and by (kind, label)
+        let mut by_hash: HashMap<u64, Vec<NodeId>> = HashMap::new();
+        let mut by_key: HashMap<(AstNodeKind, &str), Vec<NodeId>> = HashMap::new();
+        let mut stack = vec![old.root_id()];
+        while let Some(id) = stack.pop() {
+            if self.identical.contains(&id) {
+                continue;
+            }
+            let Some(node) = old.get_node(id).filter(|n| !self.options.ignores(n)) else {
+                continue;
+            };
+            if !self.old_to_new.contains_key(&id) {
+                by_hash.entry(old.subtree_hash(id)).or_default().push(id);
+                by_key
+                    .entry((node.kind, node.label.as_str()))
+                    .or_default()
+                    .push(id);
+            }
+            stack.extend(node.children.iter().rev());
+        }
+        if by_hash.is_empty() {
+            return;
+        }
+
+        // Pre-order over new so a moved parent is matched before its children
+        let mut stack = vec![new.root_id()];
+        while let Some(id) = stack.pop() {
+            let Some(node) = new.get_node(id).filter(|n| !self.options.ignores(n)) else {
+                continue;
+            };
+            match self.new_to_old.get(&id) {
+                Some(old_id) if self.identical.contains(old_id) => continue,
+                Some(_) => {}
+                None => {
+                    let exact = by_hash.get(&new.subtree_hash(id)).and_then(|ids| {
+                        ids.iter()
+                            .copied()
+                            .find(|o| !self.old_to_new.contains_key(o))
+                    });
+                    let candidate = exact.or_else(|| {
+                        by_key
+                            .get(&(node.kind, node.label.as_str()))
+                            .and_then(|ids| self.most_similar(ids, id))
+                    });
+                    if let Some(old_id) = candidate {
+                        self.match_pair(old_id, id, true);
+                    }
+                }
             }
+            stack.extend(node.children.iter().rev());
         }
     }
-}
 
-/// Apply diff operations to an AST tree
-pub fn apply_patch(tree: &mut AstTree, ops: &[DiffOp]) {
-    for op in ops {
-        match op {
-            DiffOp::Insert {
-                parent_id,
-                kind,
-                label,
-                value,
-                ..
-            } => {
-                tree.add_node_with_value(*kind, label, value.clone(), *parent_id);
+    /// Bottom-up pass: match each leftover new node with children to the
+    /// leftover old node of the same kind holding the largest share of its
+    /// matched descendants, if that share reaches the similarity threshold.
+    ///
+    /// Runs children before parents, so a renamed node whose subtree was
+    /// also renamed is matched level by level. Shares are gathered in the
+    /// same pass: a matched node credits the leftover old ancestors of its
+    /// partner up to the first matched one whose partner is above it in
+    /// the new tree, which then carries its weight on, and every node
+    /// inherits its children's credits. A chain of matched nodes thus
+    /// costs O(1) per node instead of a walk to the root.
+    fn match_containers(&mut self) {
+        let (old, new) = (self.old, self.new);
+
+        // Leftover old nodes per kind: a new node of a kind with none left
+        // has no candidate
+        let mut leftover: HashMap<AstNodeKind, usize> = HashMap::new();
+        let mut stack = vec![old.root_id()];
+        while let Some(id) = stack.pop() {
+            if self.identical.contains(&id) {
+                continue;
             }
-            DiffOp::Delete { node_id } => {
-                tree.remove_subtree(*node_id);
+            let Some(node) = old.get_node(id).filter(|n| !self.options.ignores(n)) else {
+                continue;
+            };
+            if !self.old_to_new.contains_key(&id) {
+                *leftover.entry(node.kind).or_default() += 1;
             }
-            DiffOp::Update {
-                node_id, new_value, ..
-            } => {
-                if let Some(node) = tree.get_node_mut(*node_id) {
-                    node.value = new_value.clone();
-                }
+            stack.extend(&node.children);
+        }
+        if leftover.is_empty() {
+            return;
+        }
+
+        // Pre-order positions and subtree ends, to tell ancestors in O(1)
+        let mut spans: HashMap<NodeId, (usize, usize)> = HashMap::new();
+        for (i, node) in new.pre_order(new.root_id()).enumerate() {
+            spans.insert(node.id, (i, i));
+        }
+        for node in new.post_order(new.root_id()) {
+            let end = node
+                .children
+                .iter()
+                .filter_map(|c| spans.get(c))
+                .map(|&(_, end)| end)
+                .max();
+            if let (Some(end), Some(span)) = (end, spans.get_mut(&node.id)) {
+                span.1 = end;
             }
-            DiffOp::Relabel {
-                node_id, new_label, ..
-            } => {
-                if let Some(node) = tree.get_node_mut(*node_id) {
-                    node.label.clone_from(new_label);
+        }
+        let is_above = |upper: NodeId, lower: NodeId| match (spans.get(&upper), spans.get(&lower)) {
+            (Some(&(start, end)), Some(&(pos, _))) => start < pos && pos <= end,
+            _ => false,
+        };
+
+        // Reverse pre-order visits every child before its parent
+        let mut order = Vec::new();
+        let mut stack = vec![new.root_id()];
+        while let Some(id) = stack.pop() {
+            if self
+                .new_to_old
+                .get(&id)
+                .is_some_and(|o| self.identical.contains(o))
+            {
+                continue;
+            }
+            let Some(node) = new.get_node(id).filter(|n| !self.options.ignores(n)) else {
+                continue;
+            };
+            order.push(node);
+            stack.extend(node.children.iter().rev());
+        }
+
+        // Leftover old node → matched descendants crediting it, per new node
+        let mut credits: HashMap<NodeId, HashMap<NodeId, usize>> = HashMap::new();
+        // Matched descendants a matched new node passes its weight on for
+        let mut carried: HashMap<NodeId, usize> = HashMap::new();
+
+        for node in order.into_iter().rev() {
+            let mut shared: HashMap<NodeId, usize> = HashMap::new();
+            for child_id in &node.children {
+                if let Some(mut below) = credits.remove(child_id) {
+                    // Merge the smaller map into the larger
+                    if below.len() > shared.len() {
+                        core::mem::swap(&mut below, &mut shared);
+                    }
+                    for (ancestor, weight) in below {
+                        *shared.entry(ancestor).or_default() += weight;
+                    }
+                }
+                let Some(&partner) = self.new_to_old.get(child_id) else {
+                    continue;
+                };
+                let mut weight = 1 + carried.remove(child_id).unwrap_or(0);
+                if self.identical.contains(&partner) {
+                    // Identical subtrees link only their roots
+                    weight += self.new_sizes.get(child_id).copied().unwrap_or(0);
+                }
+                let mut up = old.parent_of(partner);
+                while let Some(ancestor) = up {
+                    match self.old_to_new.get(&ancestor) {
+                        Some(&above) if is_above(above, *child_id) => {
+                            *carried.entry(above).or_default() += weight;
+                            break;
+                        }
+                        Some(_) => {}
+                        None => *shared.entry(ancestor).or_default() += weight,
+                    }
+                    up = old.parent_of(ancestor);
                 }
             }
-            DiffOp::Move {
-                node_id,
-                new_parent_id,
-                ..
-            } => {
-                // Remove from old parent
-                if let Some(old_parent_id) = tree.parent_of(*node_id) {
-                    if let Some(parent) = tree.get_node_mut(old_parent_id) {
-                        parent.children.retain(|&c| c != *node_id);
+
+            let candidate = !node.children.is_empty()
+                && !self.new_to_old.contains_key(&node.id)
+                && leftover.get(&node.kind).is_some_and(|&n| n > 0);
+            if candidate {
+                shared.retain(|id, _| !self.old_to_new.contains_key(id));
+                let new_size = self.new_sizes.get(&node.id).copied().unwrap_or(0);
+                let mut best: Option<(f64, NodeId)> = None;
+                for (&old_id, &weight) in &shared {
+                    if old.get_node(old_id).is_none_or(|a| a.kind != node.kind) {
+                        continue;
+                    }
+                    let old_size = self.old_sizes.get(&old_id).copied().unwrap_or(0);
+                    let score = (2 * weight) as f64 / (old_size + new_size) as f64;
+                    // Highest score, then lowest ID, so the pick does not
+                    // depend on map order
+                    let better = match best {
+                        Some((s, id)) => score.total_cmp(&s).then(id.cmp(&old_id)).is_gt(),
+                        None => true,
+                    };
+                    if score >= self.options.similarity_threshold && better {
+                        best = Some((score, old_id));
                     }
                 }
-                // Add to new parent
-                if let Some(parent) = tree.get_node_mut(*new_parent_id) {
-                    parent.children.push(*node_id);
+                if let Some((_, old_id)) = best {
+                    let first = self.journal.len();
+                    self.match_pair(old_id, node.id, true);
+                    for linked in &self.journal[first..] {
+                        if let Some(n) = old.get_node(*linked) {
+                            if let Some(count) = leftover.get_mut(&n.kind) {
+                                *count = count.saturating_sub(1);
+                            }
+                        }
+                    }
+                    // Descendants linked along with it count towards it
+                    let below = self.journal.len() - first - 1;
+                    *carried.entry(node.id).or_default() += below;
                 }
             }
+            credits.insert(node.id, shared);
         }
     }
-}
 
-/// Total patch size in bytes
-#[must_use]
-pub fn patch_size_bytes(ops: &[DiffOp]) -> usize {
-    ops.iter().map(DiffOp::serialized_size).sum()
-}
+    /// The unmatched old node in `candidates` most similar to `new_id`, if
+    /// any reaches the similarity threshold.
+    ///
+    /// Candidates are tried by the best score their subtree size allows,
+    /// so the search stops once no remaining one can win, and old profiles
+    /// are kept for later calls.
+    fn most_similar(&mut self, candidates: &[NodeId], new_id: NodeId) -> Option<NodeId> {
+        let new_size = self.new_sizes.get(&new_id).copied().unwrap_or(0);
+        let mut ranked: Vec<(f64, NodeId)> = candidates
+            .iter()
+            .filter(|o| !self.old_to_new.contains_key(o))
+            .map(|&o| {
+                let old_size = self.old_sizes.get(&o).copied().unwrap_or(0);
+                (dice_bound(old_size, new_size), o)
+            })
+            .collect();
+        // Stable, so equal bounds keep the candidates' order
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ast::{AstNodeKind, AstTree, NodeValue};
-    #[cfg(not(feature = "std"))]
-    use alloc::format;
+        let new_profile = Profile::of(self.new, new_id);
+        let mut best = None;
+        let mut best_score = self.options.similarity_threshold;
+        for (bound, old_id) in ranked {
+            if bound < best_score || (best.is_some() && bound <= best_score) {
+                break;
+            }
+            let old = self.old;
+            let score = self
+                .old_profiles
+                .entry(old_id)
+                .or_insert_with(|| Profile::of(old, old_id))
+                .dice(&new_profile);
+            if score > best_score || (best.is_none() && score >= best_score) {
+                best = Some(old_id);
+                best_score = score;
+            }
+        }
+        best
+    }
 
-    // ── Original tests ─────────────────────────────────────────────────
+    /// Dice coefficient over the `(kind, label)` multisets of the two
+    /// nodes' descendants; two leaves are fully similar. The old profile
+    /// is kept, as in [`Self::most_similar`].
+    fn similarity(&mut self, old_id: NodeId, new_id: NodeId) -> f64 {
+        let old = self.old;
+        self.old_profiles
+            .entry(old_id)
+            .or_insert_with(|| Profile::of(old, old_id))
+            .dice(&Profile::of(self.new, new_id))
+    }
 
-    #[test]
-    fn test_no_diff_identical_trees() {
-        let mut t1 = AstTree::new();
-        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
-        let mut t2 = AstTree::new();
-        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+    /// Turn the matching into ops: relabels and updates first, then moves
+    /// and inserts in new-tree pre-order, then deletes.
+    ///
+    /// Inserted nodes take the old tree's next free IDs, counting up in
+    /// emission order, and their children are placed under those IDs — so
+    /// whole new subtrees travel as nested inserts.
+    ///
+    /// Placing parents before their children means every node's ancestors
+    /// are final by the time it is moved, so no move can create a cycle.
+    fn emit(&mut self) -> Vec<DiffOp> {
+        self.split_ref_mismatches();
+        let (old, new) = (self.old, self.new);
+        let mut ops = Vec::new();
+        let mut placements = Vec::new();
+        let mut deletes = Vec::new();
+        let mut sim = ChildLists::new(old);
+        if self.keep_ids {
+            sim.keep_ids(new);
+        }
 
-        let ops = diff_trees(&t1, &t2);
-        assert!(ops.is_empty(), "identical trees should have no diff");
-    }
+        let mut stack = vec![new.root_id()];
+        while let Some(new_id) = stack.pop() {
+            let Some(new_node) = new.get_node(new_id) else {
+                continue;
+            };
+            let target = if let Some(&old_id) = self.new_to_old.get(&new_id) {
+                if self.identical.contains(&old_id) {
+                    continue;
+                }
+                let Some(old_node) = old.get_node(old_id) else {
+                    continue;
+                };
 
-    #[test]
-    fn test_diff_value_change() {
-        let mut t1 = AstTree::new();
-        let s = t1.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t1.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
+                // Check for kind change
+                if old_node.kind != new_node.kind {
+                    ops.push(DiffOp::Retype {
+                        node_id: old_id,
+                        old_kind: old_node.kind,
+                        new_kind: new_node.kind,
+                    });
+                }
 
-        let mut t2 = AstTree::new();
-        let s2 = t2.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t2.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.5), s2);
+                // Check for label change
+                if old_node.label != new_node.label {
+                    ops.push(DiffOp::Relabel {
+                        node_id: old_id,
+                        old_label: old_node.label.clone(),
+                        new_label: new_node.label.clone(),
+                    });
+                }
 
-        let ops = diff_trees(&t1, &t2);
-        assert_eq!(ops.len(), 1);
-        assert!(matches!(&ops[0], DiffOp::Update { .. }));
-    }
+                // Check for value change
+                if !self.values_match(&old_node.value, &new_node.value) {
+                    ops.push(DiffOp::Update {
+                        node_id: old_id,
+                        old_value: old_node.value.clone(),
+                        new_value: new_node.value.clone(),
+                    });
+                }
 
-    #[test]
-    fn test_diff_insert() {
-        let mut t1 = AstTree::new();
-        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+                // Check for attribute changes
+                self.emit_attrs(old_node, new_node, &mut ops);
 
-        let mut t2 = AstTree::new();
-        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t2.add_node(AstNodeKind::Primitive, "box", 0);
+                // Deleted: unmatched in old
+                for &old_child_id in &old_node.children {
+                    let ignored = old
+                        .get_node(old_child_id)
+                        .is_some_and(|c| self.options.ignores(c));
+                    if !ignored && !self.old_to_new.contains_key(&old_child_id) {
+                        deletes.push(DiffOp::Delete {
+                            node_id: old_child_id,
+                        });
+                    }
+                }
+                old_id
+            } else if let Some(id) = sim.inserted_id(new_id) {
+                // Inserted earlier in this patch: its children follow it
+                id
+            } else {
+                continue;
+            };
 
-        let ops = diff_trees(&t1, &t2);
-        assert_eq!(
-            ops.iter()
-                .filter(|o| matches!(o, DiffOp::Insert { .. }))
-                .count(),
-            1
-        );
+            self.place_children(target, new_node, &mut sim, &mut placements);
+            stack.extend(new_node.children.iter().rev());
+        }
+
+        sim.resolve_indices(&mut placements);
+        ops.append(&mut placements);
+        ops.append(&mut deletes);
+        self.remap_refs(&mut ops, &sim);
+        ops
     }
 
-    #[test]
-    fn test_diff_delete() {
-        let mut t1 = AstTree::new();
-        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t1.add_node(AstNodeKind::Primitive, "box", 0);
+    /// [`DiffOptions::values_match`] with the references in `new` read
+    /// through the matching; a reference to an unmatched node never
+    /// matches.
+    fn values_match(&self, old: &NodeValue, new: &NodeValue) -> bool {
+        if !matches!(new, NodeValue::Ref(_) | NodeValue::List(_)) {
+            return self.options.values_match(old, new);
+        }
+        let mut matched = true;
+        let mut new = new.clone();
+        new.map_refs(&mut |id| {
+            self.ref_in_old(id).unwrap_or_else(|| {
+                matched = false;
+                id
+            })
+        });
+        matched && self.options.values_match(old, &new)
+    }
 
-        let mut t2 = AstTree::new();
-        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+    /// The old node matched with `new_id`. Nodes inside an identical
+    /// subtree are not linked one by one; they are found by their child
+    /// positions below the subtree's root — O(depth).
+    fn old_partner(&self, new_id: NodeId) -> Option<NodeId> {
+        if let Some(&old_id) = self.new_to_old.get(&new_id) {
+            return Some(old_id);
+        }
+        let (root, path) = self.identical_root(new_id)?;
+        descend(self.old, root, &path)
+    }
+
+    /// What a reference to `new_id` reads as in the old tree: its matched
+    /// node, or the same ID if it dangles in both trees.
+    fn ref_in_old(&self, new_id: NodeId) -> Option<NodeId> {
+        self.old_partner(new_id).or_else(|| {
+            (self.new.get_node(new_id).is_none() && self.old.get_node(new_id).is_none())
+                .then_some(new_id)
+        })
+    }
+
+    /// The old root of the identical subtree holding `new_id`, and the
+    /// child positions leading from its new partner down to `new_id`.
+    fn identical_root(&self, new_id: NodeId) -> Option<(NodeId, Vec<usize>)> {
+        let new = self.new;
+        let mut path = Vec::new();
+        let mut current = new_id;
+        loop {
+            if let Some(&old_id) = self.new_to_old.get(&current) {
+                if !self.identical.contains(&old_id) {
+                    return None;
+                }
+                path.reverse();
+                return Some((old_id, path));
+            }
+            let parent = new.parent_of(current)?;
+            let position = new
+                .get_node(parent)?
+                .children
+                .iter()
+                .position(|&c| c == current)?;
+            path.push(position);
+            current = parent;
+        }
+    }
+
+    /// Expand identical subtrees whose raw reference IDs agree but whose
+    /// references lead to nodes that do not match each other, so `emit`
+    /// updates them.
+    ///
+    /// The subtree hash covers the raw IDs, not what they name: after a
+    /// renumbering, equal IDs can name different nodes. Only nodes that
+    /// hold references are checked, through the tree's reference index.
+    fn split_ref_mismatches(&mut self) {
+        if self.identical.is_empty() {
+            return;
+        }
+        let (old, new) = (self.old, self.new);
+        for new_id in new.referring_nodes() {
+            let Some((root, path)) = self.identical_root(new_id) else {
+                continue;
+            };
+            let Some(old_node) = descend(old, root, &path).and_then(|o| old.get_node(o)) else {
+                continue;
+            };
+            let Some(new_node) = new.get_node(new_id) else {
+                continue;
+            };
+            let old_refs = old_node.refs();
+            let new_refs = new_node.refs();
+            let agree = old_refs.len() == new_refs.len()
+                && old_refs
+                    .iter()
+                    .zip(&new_refs)
+                    .all(|(&o, &n)| self.ref_in_old(n) == Some(o));
+            if !agree {
+                self.split_identical(root, &path);
+            }
+        }
+    }
+
+    /// Turn the identical pair at old `root` into a compared one, and
+    /// likewise every node on `path` below it; their siblings are linked
+    /// as identical pairs in turn.
+    fn split_identical(&mut self, root: NodeId, path: &[usize]) {
+        let (old, new) = (self.old, self.new);
+        let Some(&new_root) = self.old_to_new.get(&root) else {
+            return;
+        };
+        let (mut old_id, mut new_id) = (root, new_root);
+        self.identical.remove(&old_id);
+        for &position in path {
+            let (Some(old_node), Some(new_node)) = (old.get_node(old_id), new.get_node(new_id))
+            else {
+                return;
+            };
+            for (&o, &n) in old_node.children.iter().zip(&new_node.children) {
+                self.link(o, n);
+                self.identical.insert(o);
+            }
+            let (Some(&o), Some(&n)) = (
+                old_node.children.get(position),
+                new_node.children.get(position),
+            ) else {
+                return;
+            };
+            (old_id, new_id) = (o, n);
+            self.identical.remove(&old_id);
+        }
+        // The mismatched node is compared; its children stay identical
+        if let (Some(old_node), Some(new_node)) = (old.get_node(old_id), new.get_node(new_id)) {
+            for (&o, &n) in old_node.children.iter().zip(&new_node.children) {
+                self.link(o, n);
+                self.identical.insert(o);
+            }
+        }
+    }
+
+    /// Point the references in the values `ops` write, which still use the
+    /// new tree's IDs, at the matched old nodes or the IDs their inserts
+    /// create.
+    fn remap_refs(&self, ops: &mut [DiffOp], sim: &ChildLists<'_>) {
+        let mut to_patch_id = |id| {
+            self.old_partner(id)
+                .or_else(|| sim.inserted_id(id))
+                .unwrap_or(id)
+        };
+        for op in ops {
+            match op {
+                DiffOp::Insert { value, .. }
+                | DiffOp::Update {
+                    new_value: value, ..
+                }
+                | DiffOp::SetAttr {
+                    new_value: value, ..
+                } => value.map_refs(&mut to_patch_id),
+                _ => {}
+            }
+        }
+    }
+
+    /// Emit the `SetAttr`/`RemoveAttr` ops that give `old_node` the
+    /// attributes of `new_node`, in key order.
+    fn emit_attrs(&self, old_node: &AstNode, new_node: &AstNode, ops: &mut Vec<DiffOp>) {
+        for (key, new_value) in &new_node.attrs {
+            let old_value = old_node.attrs.get(key);
+            if old_value.is_some_and(|v| self.values_match(v, new_value)) {
+                continue;
+            }
+            ops.push(DiffOp::SetAttr {
+                node_id: old_node.id,
+                key: key.clone(),
+                old_value: old_value.cloned(),
+                new_value: new_value.clone(),
+            });
+        }
+        for (key, old_value) in &old_node.attrs {
+            if !new_node.attrs.contains_key(key) {
+                ops.push(DiffOp::RemoveAttr {
+                    node_id: old_node.id,
+                    key: key.clone(),
+                    old_value: old_value.clone(),
+                });
+            }
+        }
+    }
+
+    /// Emit the moves and inserts that give `parent_id` the children of
+    /// `new_node`, in order. `parent_id` is an old node, or the ID an
+    /// earlier insert in the patch creates.
+    ///
+    /// Children that stay under the same parent keep their place if they
+    /// belong to the longest subsequence whose old order survived (the LCS
+    /// of old and new positions); every other child is moved or inserted
+    /// directly after its new predecessor. Indices come from `sim`, so they
+    /// are exact at the point each op is applied.
+    fn place_children(
+        &self,
+        parent_id: NodeId,
+        new_node: &AstNode,
+        sim: &mut ChildLists<'_>,
+        placements: &mut Vec<DiffOp>,
+    ) {
+        let (old, new) = (self.old, self.new);
+        let old_positions: HashMap<NodeId, usize> = old
+            .get_node(parent_id)
+            .map(|n| {
+                n.children
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &c)| (c, i))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Children that stayed under this parent, in new order
+        let stayed: Vec<(usize, usize)> = new_node
+            .children
+            .iter()
+            .enumerate()
+            .filter_map(|(ni, c)| {
+                let oc = self.new_to_old.get(c)?;
+                old_positions.get(oc).map(|&oi| (ni, oi))
+            })
+            .collect();
+        let old_order: Vec<usize> = stayed.iter().map(|&(_, oi)| oi).collect();
+        let placed = |c: &NodeId| {
+            self.new_to_old.contains_key(c)
+                || new.get_node(*c).is_some_and(|n| !self.options.ignores(n))
+        };
+        if stayed.len() == new_node.children.iter().filter(|c| placed(c)).count()
+            && is_sorted(&old_order)
+        {
+            return;
+        }
+        let mut keep = vec![false; new_node.children.len()];
+        for k in longest_increasing(&old_order) {
+            keep[stayed[k].0] = true;
+        }
+
+        let mut prev = None;
+        for (ni, &new_child_id) in new_node.children.iter().enumerate() {
+            if !placed(&new_child_id) {
+                continue;
+            }
+            let slot = if let Some(&old_child_id) = self.new_to_old.get(&new_child_id) {
+                let slot = Slot::Old(old_child_id);
+                if !keep[ni] {
+                    sim.place(parent_id, slot, prev, placements.len());
+                    placements.push(DiffOp::Move {
+                        node_id: old_child_id,
+                        new_parent_id: parent_id,
+                        new_index: 0,
+                    });
+                }
+                slot
+            } else {
+                let slot = Slot::New(new_child_id);
+                if let Some(new_child) = new.get_node(new_child_id) {
+                    let node_id = sim.allocate(new_child_id);
+                    sim.place(parent_id, slot, prev, placements.len());
+                    placements.push(DiffOp::Insert {
+                        node_id,
+                        parent_id,
+                        index: 0,
+                        kind: new_child.kind,
+                        label: new_child.label.clone(),
+                        value: new_child.value.clone(),
+                    });
+                    placements.extend(attr_inserts(node_id, new_child));
+                }
+                slot
+            };
+            prev = Some(slot);
+        }
+    }
+}
+
+/// The `(kind, label)` multiset of a node's descendants, for
+/// [`Differ::similarity`]
+struct Profile<'a> {
+    counts: HashMap<(AstNodeKind, &'a str), usize>,
+    total: usize,
+}
+
+impl<'a> Profile<'a> {
+    fn of(tree: &'a AstTree, id: NodeId) -> Self {
+        let mut counts: HashMap<(AstNodeKind, &str), usize> = HashMap::new();
+        let mut total = 0usize;
+        for (_, node) in tree.descendants(id) {
+            *counts.entry((node.kind, node.label.as_str())).or_default() += 1;
+            total += 1;
+        }
+        Self { counts, total }
+    }
+
+    /// Dice coefficient of the two multisets; 1 if both are empty.
+    fn dice(&self, other: &Self) -> f64 {
+        if self.total + other.total == 0 {
+            return 1.0;
+        }
+        let (small, large) = if self.counts.len() <= other.counts.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let common: usize = small
+            .counts
+            .iter()
+            .map(|(key, &n)| n.min(large.counts.get(key).copied().unwrap_or(0)))
+            .sum();
+        (2 * common) as f64 / (self.total + other.total) as f64
+    }
+}
+
+/// The node reached from `id` by following child `positions` in `tree`.
+fn descend(tree: &AstTree, id: NodeId, positions: &[usize]) -> Option<NodeId> {
+    positions.iter().try_fold(id, |current, &i| {
+        tree.get_node(current)?.children.get(i).copied()
+    })
+}
+
+/// Highest Dice coefficient two multisets of these sizes can reach.
+fn dice_bound(a: usize, b: usize) -> f64 {
+    if a + b == 0 {
+        return 1.0;
+    }
+    (2 * a.min(b)) as f64 / (a + b) as f64
+}
+
+/// A child-list entry during emission: an old node, or a new node that
+/// an earlier op inserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Slot {
+    Old(NodeId),
+    New(NodeId),
+}
+
+/// Child lists of the old tree as the emitted ops so far leave them.
+///
+/// Lists are copied from the old tree on first touch, so only parents
+/// that receive or lose a child pay for the simulation. Each list is a
+/// linked list in which detached entries stay behind as tombstones, so
+/// placing a child is O(1); the index each placement op reports is
+/// filled in afterwards by [`ChildLists::resolve_indices`].
+struct ChildLists<'a> {
+    old: &'a AstTree,
+    /// First entry of every list touched so far
+    heads: HashMap<NodeId, Option<usize>>,
+    entries: Vec<Entry>,
+    /// Entry each slot currently occupies
+    entry_of: HashMap<Slot, usize>,
+    /// Detachments and placements, in op order
+    events: Vec<ListEvent>,
+    /// New node → ID its insert will create
+    inserted: HashMap<NodeId, NodeId>,
+    next_id: NodeId,
+    keep_ids: bool,
+}
+
+/// One position in a child list; it stays linked after it is detached
+struct Entry {
+    parent: NodeId,
+    next: Option<usize>,
+    /// Copied from the old tree rather than placed by an op
+    initial: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ListEvent {
+    Detach(usize),
+    /// An entry placed by the placement op at this index of the patch
+    Place(usize, usize),
+}
+
+impl<'a> ChildLists<'a> {
+    fn new(old: &'a AstTree) -> Self {
+        Self {
+            old,
+            heads: HashMap::new(),
+            entries: Vec::new(),
+            entry_of: HashMap::new(),
+            events: Vec::new(),
+            inserted: HashMap::new(),
+            next_id: old.next_id(),
+            keep_ids: false,
+        }
+    }
+
+    /// Let inserts keep their ID in `new` when the old tree does not use it.
+    fn keep_ids(&mut self, new: &AstTree) {
+        self.keep_ids = true;
+        self.next_id = self.next_id.max(new.next_id());
+    }
+
+    /// Reserve the ID the insert of `new_id` will create.
+    fn allocate(&mut self, new_id: NodeId) -> NodeId {
+        let id = if self.keep_ids && self.old.get_node(new_id).is_none() {
+            new_id
+        } else {
+            self.next_id += 1;
+            self.next_id - 1
+        };
+        self.inserted.insert(new_id, id);
+        id
+    }
+
+    fn inserted_id(&self, new_id: NodeId) -> Option<NodeId> {
+        self.inserted.get(&new_id).copied()
+    }
+
+    /// Copy the old children of `parent` on first touch.
+    fn touch(&mut self, parent: NodeId) {
+        if self.heads.contains_key(&parent) {
+            return;
+        }
+        let children = self
+            .old
+            .get_node(parent)
+            .map_or(&[][..], |n| n.children.as_slice());
+        let first = self.entries.len();
+        for (i, &c) in children.iter().enumerate() {
+            self.entry_of.insert(Slot::Old(c), first + i);
+            self.entries.push(Entry {
+                parent,
+                next: (i + 1 < children.len()).then_some(first + i + 1),
+                initial: true,
+            });
+        }
+        self.heads
+            .insert(parent, (!children.is_empty()).then_some(first));
+    }
+
+    /// Detach `slot` if it is an old node, then insert it under `parent`
+    /// directly after `prev` (first if `None`), on behalf of the placement
+    /// op at index `op` of the patch.
+    fn place(&mut self, parent: NodeId, slot: Slot, prev: Option<Slot>, op: usize) {
+        if let Slot::Old(id) = slot {
+            if !self.entry_of.contains_key(&slot) {
+                if let Some(from) = self.old.parent_of(id) {
+                    self.touch(from);
+                }
+            }
+            if let Some(entry) = self.entry_of.remove(&slot) {
+                self.events.push(ListEvent::Detach(entry));
+            }
+        }
+        self.touch(parent);
+        let after = prev
+            .and_then(|p| self.entry_of.get(&p).copied())
+            .filter(|&e| self.entries[e].parent == parent);
+        let entry = self.entries.len();
+        let next = match after {
+            Some(e) => self.entries[e].next.replace(entry),
+            None => self.heads.insert(parent, Some(entry)).flatten(),
+        };
+        self.entries.push(Entry {
+            parent,
+            next,
+            initial: false,
+        });
+        self.entry_of.insert(slot, entry);
+        self.events.push(ListEvent::Place(entry, op));
+    }
+
+    /// Fill in the `index`/`new_index` of every placement op: the number
+    /// of live entries before its own when it applies.
+    ///
+    /// Entries are ranked by walking the lists, tombstones included, then
+    /// the events are replayed against a Fenwick tree of live entries.
+    fn resolve_indices(&self, ops: &mut [DiffOp]) {
+        let mut rank = vec![0; self.entries.len()];
+        let mut list_start: HashMap<NodeId, usize> = HashMap::new();
+        let mut next_rank = 0;
+        for (&parent, &head) in &self.heads {
+            list_start.insert(parent, next_rank);
+            let mut cursor = head;
+            while let Some(e) = cursor {
+                rank[e] = next_rank;
+                next_rank += 1;
+                cursor = self.entries[e].next;
+            }
+        }
+
+        let mut live = Fenwick::new(next_rank);
+        for (e, entry) in self.entries.iter().enumerate() {
+            if entry.initial {
+                live.insert(rank[e]);
+            }
+        }
+        for &event in &self.events {
+            match event {
+                ListEvent::Detach(e) => live.remove(rank[e]),
+                ListEvent::Place(e, op) => {
+                    let start = list_start[&self.entries[e].parent];
+                    let at = live.before(rank[e]) - live.before(start);
+                    match &mut ops[op] {
+                        DiffOp::Insert { index, .. } => *index = at,
+                        DiffOp::Move { new_index, .. } => *new_index = at,
+                        _ => {}
+                    }
+                    live.insert(rank[e]);
+                }
+            }
+        }
+    }
+}
+
+/// Counts over positions `0..n` with O(log n) updates and prefix sums
+struct Fenwick {
+    sums: Vec<usize>,
+}
+
+impl Fenwick {
+    fn new(n: usize) -> Self {
+        Self {
+            sums: vec![0; n + 1],
+        }
+    }
+
+    fn insert(&mut self, pos: usize) {
+        let mut i = pos + 1;
+        while i < self.sums.len() {
+            self.sums[i] += 1;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    fn remove(&mut self, pos: usize) {
+        let mut i = pos + 1;
+        while i < self.sums.len() {
+            self.sums[i] -= 1;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    /// Count at positions below `pos`
+    fn before(&self, pos: usize) -> usize {
+        let mut i = pos;
+        let mut sum = 0;
+        while i > 0 {
+            sum += self.sums[i];
+            i &= i - 1;
+        }
+        sum
+    }
+}
+
+fn is_sorted(seq: &[usize]) -> bool {
+    seq.windows(2).all(|w| w[0] <= w[1])
+}
+
+/// Indices into `seq` of one longest strictly increasing subsequence —
+/// O(n log n) patience sorting.
+fn longest_increasing(seq: &[usize]) -> Vec<usize> {
+    // tails[k]: index of the smallest tail of an increasing run of length k+1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut prev: Vec<Option<usize>> = vec![None; seq.len()];
+    for (i, &x) in seq.iter().enumerate() {
+        let k = tails.partition_point(|&t| seq[t] < x);
+        if k > 0 {
+            prev[i] = Some(tails[k - 1]);
+        }
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+    let mut result = Vec::with_capacity(tails.len());
+    let mut current = tails.last().copied();
+    while let Some(i) = current {
+        result.push(i);
+        current = prev[i];
+    }
+    result.reverse();
+    result
+}
+
+/// `SetAttr` ops that give a freshly inserted `node_id` the attributes of
+/// `node`.
+pub(crate) fn attr_inserts(node_id: NodeId, node: &AstNode) -> impl Iterator<Item = DiffOp> + '_ {
+    node.attrs.iter().map(move |(key, value)| DiffOp::SetAttr {
+        node_id,
+        key: key.clone(),
+        old_value: None,
+        new_value: value.clone(),
+    })
+}
+
+/// Apply diff operations to an AST tree
+///
+/// Lenient: ops whose target is missing, and Deletes or Retypes of the
+/// root, are skipped; `old_value` / `old_label` are not checked and
+/// references may be left dangling.
+/// Use [`try_apply_patch`] to have every precondition verified.
+///
+/// Inserts and moves land at the op's index; an index past the end of the
+/// parent's child list is clamped, appending the node.
+pub fn apply_patch(tree: &mut AstTree, ops: &[DiffOp]) {
+    for op in ops {
+        apply_op(tree, op);
+    }
+}
+
+/// Apply diff operations, checking each op's preconditions first
+///
+/// On the first op that does not fit the tree, the ops applied so far are
+/// undone, restoring the tree to its state before the call, and the op's
+/// position and the reason are returned. Unlike [`apply_patch`],
+/// out-of-range indices are errors.
+///
+/// References only have to resolve once the whole patch is applied: a
+/// Delete may remove nodes that others still refer to, as long as a later
+/// op redirects or deletes those references, and an Insert, Update or
+/// `SetAttr` may refer to a node a later op inserts. Any that still dangle
+/// at the end fail the patch at the op that cut or wrote them.
+///
+/// # Errors
+///
+/// Returns [`ApplyError`] naming the first op that could not be applied.
+pub fn try_apply_patch(tree: &mut AstTree, ops: &[DiffOp]) -> Result<(), ApplyError> {
+    let next_id = tree.next_id();
+    // Inverse of each op applied so far, in the order they were applied
+    let mut undo: Vec<Vec<DiffOp>> = Vec::new();
+    // (op's position, referrer, target) for references an op cut or wrote
+    let mut touched = Vec::new();
+    for (op_index, op) in ops.iter().enumerate() {
+        if let Err(kind) = check_op(tree, op) {
+            roll_back(tree, undo, next_id);
+            return Err(ApplyError { op_index, kind });
+        }
+        match op {
+            DiffOp::Delete { node_id } => touched.extend(
+                tree.external_refs(*node_id)
+                    .into_iter()
+                    .map(|(referrer, target)| (op_index, referrer, target)),
+            ),
+            DiffOp::Insert { node_id, value, .. }
+            | DiffOp::Update {
+                node_id,
+                new_value: value,
+                ..
+            }
+            | DiffOp::SetAttr {
+                node_id,
+                new_value: value,
+                ..
+            } => touched.extend(
+                value
+                    .refs()
+                    .into_iter()
+                    .map(|target| (op_index, *node_id, target)),
+            ),
+            _ => {}
+        }
+        undo.push(invert_op(tree, op));
+        apply_op(tree, op);
+    }
+    let dangling = touched.into_iter().find(|&(_, referrer, target)| {
+        tree.get_node(target).is_none()
+            && tree
+                .get_node(referrer)
+                .is_some_and(|n| n.refs().contains(&target))
+    });
+    if let Some((op_index, node_id, target)) = dangling {
+        roll_back(tree, undo, next_id);
+        return Err(ApplyError {
+            op_index,
+            kind: ApplyErrorKind::DanglingRef { node_id, target },
+        });
+    }
+    Ok(())
+}
+
+/// Undo the ops [`try_apply_patch`] applied, last first.
+fn roll_back(tree: &mut AstTree, undo: Vec<Vec<DiffOp>>, next_id: NodeId) {
+    for ops in undo.into_iter().rev() {
+        apply_patch(tree, &ops);
+    }
+    tree.rewind_next_id(next_id);
+}
+
+/// A patch op that could not be applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplyError {
+    /// Position of the failing op in the patch
+    pub op_index: usize,
+    /// Why it failed
+    pub kind: ApplyErrorKind,
+}
+
+/// Reason a patch op could not be applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyErrorKind {
+    /// The node the op targets does not exist
+    MissingNode(NodeId),
+    /// The parent an Insert or Move targets does not exist
+    MissingParent(NodeId),
+    /// An Insert's ID is already in use
+    NodeExists(NodeId),
+    /// The root cannot be deleted, moved or retyped
+    RootNode,
+    /// An Update's `old_value` does not match the node
+    ValueMismatch(NodeId),
+    /// A Relabel's `old_label` does not match the node
+    LabelMismatch(NodeId),
+    /// A Retype's `old_kind` does not match the node
+    KindMismatch(NodeId),
+    /// A `SetAttr`'s or `RemoveAttr`'s `old_value` does not match the
+    /// node's attribute `key`
+    AttrMismatch { node_id: NodeId, key: String },
+    /// A Move would put the node inside its own subtree
+    MoveIntoDescendant {
+        node_id: NodeId,
+        new_parent_id: NodeId,
+    },
+    /// An Insert or Move index is past the end of the child list
+    IndexOutOfRange {
+        parent_id: NodeId,
+        index: usize,
+        len: usize,
+    },
+    /// `node_id` refers to `target`, which does not exist once the patch
+    /// is applied: the op deleted it or wrote the reference
+    DanglingRef { node_id: NodeId, target: NodeId },
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "op {}: ", self.op_index)?;
+        match &self.kind {
+            ApplyErrorKind::MissingNode(id) => write!(f, "node {id} does not exist"),
+            ApplyErrorKind::MissingParent(id) => write!(f, "parent {id} does not exist"),
+            ApplyErrorKind::NodeExists(id) => write!(f, "node {id} already exists"),
+            ApplyErrorKind::RootNode => f.write_str("cannot delete, move or retype the root"),
+            ApplyErrorKind::ValueMismatch(id) => {
+                write!(f, "node {id} value differs from old_value")
+            }
+            ApplyErrorKind::LabelMismatch(id) => {
+                write!(f, "node {id} label differs from old_label")
+            }
+            ApplyErrorKind::KindMismatch(id) => {
+                write!(f, "node {id} kind differs from old_kind")
+            }
+            ApplyErrorKind::AttrMismatch { node_id, key } => {
+                write!(f, "node {node_id} attribute {key:?} differs from old_value")
+            }
+            ApplyErrorKind::MoveIntoDescendant {
+                node_id,
+                new_parent_id,
+            } => write!(
+                f,
+                "cannot move node {node_id} under its descendant {new_parent_id}"
+            ),
+            ApplyErrorKind::IndexOutOfRange {
+                parent_id,
+                index,
+                len,
+            } => write!(
+                f,
+                "index {index} out of range for {len} children of node {parent_id}"
+            ),
+            ApplyErrorKind::DanglingRef { node_id, target } => {
+                write!(f, "node {node_id} refers to missing node {target}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ApplyError {}
+
+/// Verify that `op` fits `tree` as it stands.
+fn check_op(tree: &AstTree, op: &DiffOp) -> Result<(), ApplyErrorKind> {
+    let node = |id: NodeId| tree.get_node(id).ok_or(ApplyErrorKind::MissingNode(id));
+    let child_count = |id: NodeId| {
+        tree.get_node(id)
+            .map(|n| n.children.len())
+            .ok_or(ApplyErrorKind::MissingParent(id))
+    };
+    let check_index = |parent_id: NodeId, index: usize, len: usize| {
+        if index > len {
+            Err(ApplyErrorKind::IndexOutOfRange {
+                parent_id,
+                index,
+                len,
+            })
+        } else {
+            Ok(())
+        }
+    };
+
+    match op {
+        DiffOp::Insert {
+            node_id,
+            parent_id,
+            index,
+            ..
+        } => {
+            let len = child_count(*parent_id)?;
+            if tree.get_node(*node_id).is_some() {
+                return Err(ApplyErrorKind::NodeExists(*node_id));
+            }
+            check_index(*parent_id, *index, len)
+        }
+        DiffOp::Delete { node_id } => {
+            node(*node_id)?;
+            if *node_id == tree.root_id() {
+                return Err(ApplyErrorKind::RootNode);
+            }
+            Ok(())
+        }
+        DiffOp::Update {
+            node_id, old_value, ..
+        } => {
+            if same_value(&node(*node_id)?.value, old_value) {
+                Ok(())
+            } else {
+                Err(ApplyErrorKind::ValueMismatch(*node_id))
+            }
+        }
+        DiffOp::Relabel {
+            node_id, old_label, ..
+        } => {
+            if node(*node_id)?.label == *old_label {
+                Ok(())
+            } else {
+                Err(ApplyErrorKind::LabelMismatch(*node_id))
+            }
+        }
+        DiffOp::Retype {
+            node_id, old_kind, ..
+        } => {
+            let current = node(*node_id)?.kind;
+            if *node_id == tree.root_id() {
+                return Err(ApplyErrorKind::RootNode);
+            }
+            if current == *old_kind {
+                Ok(())
+            } else {
+                Err(ApplyErrorKind::KindMismatch(*node_id))
+            }
+        }
+        DiffOp::SetAttr {
+            node_id,
+            key,
+            old_value,
+            ..
+        } => {
+            let current = node(*node_id)?.attrs.get(key);
+            match (current, old_value) {
+                (None, None) => Ok(()),
+                (Some(a), Some(b)) if same_value(a, b) => Ok(()),
+                _ => Err(ApplyErrorKind::AttrMismatch {
+                    node_id: *node_id,
+                    key: key.clone(),
+                }),
+            }
+        }
+        DiffOp::RemoveAttr {
+            node_id,
+            key,
+            old_value,
+        } => {
+            if node(*node_id)?
+                .attrs
+                .get(key)
+                .is_some_and(|v| same_value(v, old_value))
+            {
+                Ok(())
+            } else {
+                Err(ApplyErrorKind::AttrMismatch {
+                    node_id: *node_id,
+                    key: key.clone(),
+                })
+            }
+        }
+        DiffOp::Move {
+            node_id,
+            new_parent_id,
+            new_index,
+        } => {
+            node(*node_id)?;
+            if *node_id == tree.root_id() {
+                return Err(ApplyErrorKind::RootNode);
+            }
+            let mut len = child_count(*new_parent_id)?;
+            if tree.is_ancestor_or_self(*node_id, *new_parent_id) {
+                return Err(ApplyErrorKind::MoveIntoDescendant {
+                    node_id: *node_id,
+                    new_parent_id: *new_parent_id,
+                });
+            }
+            if tree.parent_of(*node_id) == Some(*new_parent_id) {
+                len -= 1;
+            }
+            check_index(*new_parent_id, *new_index, len)
+        }
+    }
+}
+
+/// Value equality as the Merkle hash sees it: NaN equals NaN, also in
+/// vector components and list items.
+pub(crate) fn same_value(a: &NodeValue, b: &NodeValue) -> bool {
+    a == b || (a.same_variant(b) && a.canonical_eq(b))
+}
+
+fn apply_op(tree: &mut AstTree, op: &DiffOp) {
+    match op {
+        DiffOp::Insert {
+            node_id,
+            parent_id,
+            index,
+            kind,
+            label,
+            value,
+        } => {
+            tree.insert_with_id_at(*node_id, *kind, label, value.clone(), *parent_id, *index);
+        }
+        DiffOp::Delete { node_id } => {
+            if *node_id != tree.root_id() {
+                tree.remove_subtree_unchecked(*node_id);
+            }
+        }
+        DiffOp::Update {
+            node_id, new_value, ..
+        } => {
+            if let Some(node) = tree.get_node_mut(*node_id) {
+                node.value = new_value.clone();
+            }
+        }
+        DiffOp::Relabel {
+            node_id, new_label, ..
+        } => {
+            if let Some(node) = tree.get_node_mut(*node_id) {
+                node.label.clone_from(new_label);
+            }
+        }
+        DiffOp::Move {
+            node_id,
+            new_parent_id,
+            new_index,
+        } => {
+            tree.move_node(*node_id, *new_parent_id, *new_index);
+        }
+        DiffOp::Retype {
+            node_id, new_kind, ..
+        } => {
+            if *node_id != tree.root_id() {
+                if let Some(node) = tree.get_node_mut(*node_id) {
+                    node.kind = *new_kind;
+                }
+            }
+        }
+        DiffOp::SetAttr {
+            node_id,
+            key,
+            new_value,
+            ..
+        } => {
+            if let Some(node) = tree.get_node_mut(*node_id) {
+                node.attrs.insert(key.clone(), new_value.clone());
+            }
+        }
+        DiffOp::RemoveAttr { node_id, key, .. } => {
+            if let Some(node) = tree.get_node_mut(*node_id) {
+                node.attrs.remove(key);
+            }
+        }
+    }
+}
+
+/// Total patch size in bytes
+#[must_use]
+pub fn patch_size_bytes(ops: &[DiffOp]) -> usize {
+    ops.iter().map(DiffOp::serialized_size).sum()
+}
+
+/// One line per op of `ops`, naming nodes by path (see
+/// [`AstTree::path_of`]) instead of ID, e.g.
+/// `update /root/union/sphere/radius: Float(1.0) -> Float(2.0)`.
+///
+/// Each path is taken from `tree` as patched up to that op, so nodes the
+/// patch inserts are named too; a node that cannot be reached shows as
+/// `#<id>`.
+#[must_use]
+pub fn describe_patch(tree: &AstTree, ops: &[DiffOp]) -> Vec<String> {
+    let path = |tree: &AstTree, id: NodeId| {
+        tree.path_of(id)
+            .map_or_else(|| format!("#{id}"), |p| format!("{p}"))
+    };
+    let mut state = tree.clone();
+    let mut lines = Vec::with_capacity(ops.len());
+    for op in ops {
+        let node_id = match op {
+            DiffOp::Insert { node_id, .. }
+            | DiffOp::Delete { node_id }
+            | DiffOp::Update { node_id, .. }
+            | DiffOp::Relabel { node_id, .. }
+            | DiffOp::Move { node_id, .. }
+            | DiffOp::Retype { node_id, .. }
+            | DiffOp::SetAttr { node_id, .. }
+            | DiffOp::RemoveAttr { node_id, .. } => *node_id,
+        };
+        let before = path(&state, node_id);
+        apply_op(&mut state, op);
+        let after = || path(&state, node_id);
+        lines.push(match op {
+            DiffOp::Insert { kind, value, .. } => format!("insert {}: {kind:?} {value:?}", after()),
+            DiffOp::Delete { .. } => format!("delete {before}"),
+            DiffOp::Update {
+                old_value,
+                new_value,
+                ..
+            } => format!("update {before}: {old_value:?} -> {new_value:?}"),
+            DiffOp::Relabel { new_label, .. } => format!("relabel {before} -> {new_label:?}"),
+            DiffOp::Move { .. } => format!("move {before} -> {}", after()),
+            DiffOp::Retype {
+                old_kind, new_kind, ..
+            } => format!("retype {before}: {old_kind:?} -> {new_kind:?}"),
+            DiffOp::SetAttr {
+                key,
+                old_value,
+                new_value,
+                ..
+            } => old_value.as_ref().map_or_else(
+                || format!("set {before} @{key}: {new_value:?}"),
+                |old| format!("set {before} @{key}: {old:?} -> {new_value:?}"),
+            ),
+            DiffOp::RemoveAttr { key, .. } => format!("remove {before} @{key}"),
+        });
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{AstNodeKind, AstTree, NodeValue};
+    #[cfg(not(feature = "std"))]
+    use alloc::format;
+
+    // ── Original tests ─────────────────────────────────────────────────
+
+    #[test]
+    fn test_no_diff_identical_trees() {
+        let mut t1 = AstTree::new();
+        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        let ops = diff_trees(&t1, &t2);
+        assert!(ops.is_empty(), "identical trees should have no diff");
+    }
+
+    #[test]
+    fn test_diff_value_change() {
+        let mut t1 = AstTree::new();
+        let s = t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t1.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
+
+        let mut t2 = AstTree::new();
+        let s2 = t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t2.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.5), s2);
+
+        let ops = diff_trees(&t1, &t2);
+        assert_eq!(ops.len(), 1);
+        assert!(matches!(&ops[0], DiffOp::Update { .. }));
+    }
+
+    #[test]
+    fn test_diff_insert() {
+        let mut t1 = AstTree::new();
+        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t2.add_node(AstNodeKind::Primitive, "box", 0);
+
+        let ops = diff_trees(&t1, &t2);
+        assert_eq!(
+            ops.iter()
+                .filter(|o| matches!(o, DiffOp::Insert { .. }))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_diff_delete() {
+        let mut t1 = AstTree::new();
+        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t1.add_node(AstNodeKind::Primitive, "box", 0);
+
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        let ops = diff_trees(&t1, &t2);
+        assert_eq!(
+            ops.iter()
+                .filter(|o| matches!(o, DiffOp::Delete { .. }))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_patch_size_small() {
+        let mut t1 = AstTree::new();
+        let s = t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t1.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
+
+        let mut t2 = AstTree::new();
+        let s2 = t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t2.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.5), s2);
+
+        let ops = diff_trees(&t1, &t2);
+        let size = patch_size_bytes(&ops);
+        assert!(
+            size < 20,
+            "value change patch should be < 20 bytes, got {size}"
+        );
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let mut tree = AstTree::new();
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        tree.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
+
+        let ops = vec![DiffOp::Update {
+            node_id: 2,
+            old_value: NodeValue::Float(1.0),
+            new_value: NodeValue::Float(2.0),
+        }];
+        apply_patch(&mut tree, &ops);
+        let node = tree.get_node(2).unwrap();
+        assert_eq!(node.value, NodeValue::Float(2.0));
+    }
+
+    // ── New tests ──────────────────────────────────────────────────────
+
+    #[test]
+    fn test_empty_trees_no_diff() {
+        let t1 = AstTree::new();
+        let t2 = AstTree::new();
+        assert!(diff_trees(&t1, &t2).is_empty());
+    }
+
+    #[test]
+    fn test_diff_label_swap_produces_delete_and_insert() {
+        // sphere -> cylinder: no match by (kind,label) -> delete + insert
+        let mut t1 = AstTree::new();
+        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Primitive, "cylinder", 0);
+
+        let ops = diff_trees(&t1, &t2);
+        let deletes = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::Delete { .. }))
+            .count();
+        let inserts = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::Insert { .. }))
+            .count();
+        assert_eq!(deletes, 1);
+        assert_eq!(inserts, 1);
+    }
+
+    #[test]
+    fn test_diff_multiple_inserts() {
+        let t1 = AstTree::new(); // root only
+
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Primitive, "a", 0);
+        t2.add_node(AstNodeKind::Primitive, "b", 0);
+        t2.add_node(AstNodeKind::Primitive, "c", 0);
+
+        let ops = diff_trees(&t1, &t2);
+        let inserts = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::Insert { .. }))
+            .count();
+        assert_eq!(inserts, 3);
+    }
+
+    #[test]
+    fn test_diff_multiple_deletes() {
+        let mut t1 = AstTree::new();
+        t1.add_node(AstNodeKind::Primitive, "a", 0);
+        t1.add_node(AstNodeKind::Primitive, "b", 0);
+        t1.add_node(AstNodeKind::Primitive, "c", 0);
+
+        let t2 = AstTree::new(); // root only
+
+        let ops = diff_trees(&t1, &t2);
+        let deletes = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::Delete { .. }))
+            .count();
+        assert_eq!(deletes, 3);
+    }
+
+    #[test]
+    fn test_diff_update_int_value() {
+        let mut t1 = AstTree::new();
+        let g = t1.add_node(AstNodeKind::Group, "g", 0);
+        t1.add_node_with_value(AstNodeKind::Parameter, "count", NodeValue::Int(10), g);
+
+        let mut t2 = AstTree::new();
+        let g2 = t2.add_node(AstNodeKind::Group, "g", 0);
+        t2.add_node_with_value(AstNodeKind::Parameter, "count", NodeValue::Int(99), g2);
+
+        let ops = diff_trees(&t1, &t2);
+        assert_eq!(ops.len(), 1);
+        match &ops[0] {
+            DiffOp::Update { new_value, .. } => assert_eq!(*new_value, NodeValue::Int(99)),
+            _ => panic!("expected Update"),
+        }
+    }
+
+    #[test]
+    fn test_diff_update_text_value() {
+        let mut t1 = AstTree::new();
+        let g = t1.add_node(AstNodeKind::Material, "mat", 0);
+        t1.add_node_with_value(
+            AstNodeKind::Parameter,
+            "name",
+            NodeValue::Text(String::from("old")),
+            g,
+        );
+
+        let mut t2 = AstTree::new();
+        let g2 = t2.add_node(AstNodeKind::Material, "mat", 0);
+        t2.add_node_with_value(
+            AstNodeKind::Parameter,
+            "name",
+            NodeValue::Text(String::from("new")),
+            g2,
+        );
+
+        let ops = diff_trees(&t1, &t2);
+        assert_eq!(ops.len(), 1);
+        assert!(matches!(&ops[0], DiffOp::Update { .. }));
+    }
+
+    #[test]
+    fn test_diff_deep_nested_no_change() {
+        let mut t1 = AstTree::new();
+        let g = t1.add_node(AstNodeKind::Group, "g", 0);
+        let p = t1.add_node(AstNodeKind::Primitive, "sphere", g);
+        t1.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), p);
+
+        let mut t2 = AstTree::new();
+        let g2 = t2.add_node(AstNodeKind::Group, "g", 0);
+        let p2 = t2.add_node(AstNodeKind::Primitive, "sphere", g2);
+        t2.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), p2);
+
+        assert!(diff_trees(&t1, &t2).is_empty());
+    }
+
+    #[test]
+    fn test_diff_deep_nested_value_change() {
+        let mut t1 = AstTree::new();
+        let g = t1.add_node(AstNodeKind::Group, "g", 0);
+        let p = t1.add_node(AstNodeKind::Primitive, "sphere", g);
+        t1.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), p);
+
+        let mut t2 = AstTree::new();
+        let g2 = t2.add_node(AstNodeKind::Group, "g", 0);
+        let p2 = t2.add_node(AstNodeKind::Primitive, "sphere", g2);
+        t2.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(5.0), p2);
+
+        let ops = diff_trees(&t1, &t2);
+        let updates = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::Update { .. }))
+            .count();
+        assert_eq!(updates, 1);
+    }
+
+    #[test]
+    fn test_diff_same_label_different_kind_is_retype() {
+        // Same label but different kind: the node is retyped in place
+        let mut t1 = AstTree::new();
+        t1.add_node(AstNodeKind::Primitive, "x", 0);
+
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Group, "x", 0);
+
+        let ops = diff_trees(&t1, &t2);
+        assert_eq!(
+            ops,
+            vec![DiffOp::Retype {
+                node_id: 1,
+                old_kind: AstNodeKind::Primitive,
+                new_kind: AstNodeKind::Group,
+            }]
+        );
+        assert_eq!(
+            count_ops(&ops, |o| matches!(
+                o,
+                DiffOp::Delete { .. } | DiffOp::Insert { .. }
+            )),
+            0
+        );
+    }
+
+    #[test]
+    fn test_diff_duplicate_labels_match_once_each() {
+        // Two identical children in old; two identical in new — zero ops
+        let mut t1 = AstTree::new();
+        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        assert!(diff_trees(&t1, &t2).is_empty());
+    }
+
+    #[test]
+    fn test_diff_one_duplicate_added() {
+        // Old has one "sphere"; new has two — one Insert expected
+        let mut t1 = AstTree::new();
+        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        let mut t2 = AstTree::new();
+        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        let ops = diff_trees(&t1, &t2);
+        let inserts = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::Insert { .. }))
+            .count();
+        assert_eq!(inserts, 1);
+    }
+
+    #[test]
+    fn test_diff_removing_first_same_label_sibling_is_one_delete() {
+        // The other spheres differ only in their radius; each must keep
+        // its own partner rather than shift onto its neighbour
+        let mut old = AstTree::new();
+        let spheres: Vec<NodeId> = (1..=4)
+            .map(|r| {
+                let s = old.add_node(AstNodeKind::Primitive, "sphere", 0);
+                old.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(r.into()), s);
+                s
+            })
+            .collect();
+        let mut new = old.clone();
+        assert!(new.remove_subtree(spheres[0]));
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(
+            ops,
+            vec![DiffOp::Delete {
+                node_id: spheres[0]
+            }]
+        );
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_apply_patch_delete() {
+        let mut tree = AstTree::new();
+        let id = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        assert_eq!(tree.node_count(), 2);
+
+        apply_patch(&mut tree, &[DiffOp::Delete { node_id: id }]);
+        assert_eq!(tree.node_count(), 1);
+    }
+
+    #[test]
+    fn test_apply_patch_skips_root_delete() {
+        let mut tree = AstTree::new();
+        tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        apply_patch(&mut tree, &[DiffOp::Delete { node_id: 0 }]);
+        assert_eq!(tree.node_count(), 2);
+        assert_eq!(tree.check_invariants(), []);
+    }
+
+    #[test]
+    fn test_apply_patch_insert() {
+        let mut tree = AstTree::new();
+        assert_eq!(tree.node_count(), 1);
+
+        apply_patch(
+            &mut tree,
+            &[DiffOp::Insert {
+                node_id: 1,
+                parent_id: 0,
+                index: 0,
+                kind: AstNodeKind::Primitive,
+                label: String::from("box"),
+                value: NodeValue::None,
+            }],
+        );
+        assert_eq!(tree.node_count(), 2);
+    }
+
+    #[test]
+    fn test_apply_patch_relabel() {
+        let mut tree = AstTree::new();
+        let id = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+
+        apply_patch(
+            &mut tree,
+            &[DiffOp::Relabel {
+                node_id: id,
+                old_label: String::from("sphere"),
+                new_label: String::from("cylinder"),
+            }],
+        );
+        assert_eq!(tree.get_node(id).unwrap().label, "cylinder");
+    }
+
+    #[test]
+    fn test_apply_patch_move() {
+        let mut tree = AstTree::new();
+        let g1 = tree.add_node(AstNodeKind::Group, "g1", 0);
+        let g2 = tree.add_node(AstNodeKind::Group, "g2", 0);
+        let child = tree.add_node(AstNodeKind::Primitive, "s", g1);
+
+        apply_patch(
+            &mut tree,
+            &[DiffOp::Move {
+                node_id: child,
+                new_parent_id: g2,
+                new_index: 0,
+            }],
+        );
+        assert!(!tree.get_node(g1).unwrap().children.contains(&child));
+        assert!(tree.get_node(g2).unwrap().children.contains(&child));
+    }
+
+    #[test]
+    fn test_apply_patch_multiple_ops() {
+        let mut tree = AstTree::new();
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+
+        let ops = vec![
+            DiffOp::Insert {
+                node_id: 3,
+                parent_id: 0,
+                index: 1,
+                kind: AstNodeKind::Primitive,
+                label: String::from("box"),
+                value: NodeValue::None,
+            },
+            DiffOp::Update {
+                node_id: 2,
+                old_value: NodeValue::Float(1.0),
+                new_value: NodeValue::Float(3.0),
+            },
+        ];
+        apply_patch(&mut tree, &ops);
+        assert_eq!(tree.node_count(), 4); // root + sphere + r + box
+        assert_eq!(tree.get_node(2).unwrap().value, NodeValue::Float(3.0));
+    }
+
+    #[test]
+    fn test_patch_size_bytes_empty() {
+        assert_eq!(patch_size_bytes(&[]), 0);
+    }
+
+    #[test]
+    fn test_patch_size_bytes_delete_is_5() {
+        let ops = vec![DiffOp::Delete { node_id: 1 }];
+        assert_eq!(patch_size_bytes(&ops), 5);
+    }
+
+    #[test]
+    fn test_patch_size_bytes_move_is_12() {
+        let ops = vec![DiffOp::Move {
+            node_id: 1,
+            new_parent_id: 2,
+            new_index: 0,
+        }];
+        assert_eq!(patch_size_bytes(&ops), 12);
+    }
+
+    #[test]
+    fn test_diff_csg_tree_no_change() {
+        let build = |tree: &mut AstTree| {
+            let u = tree.add_node(AstNodeKind::CsgOp, "union", 0);
+            tree.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), u);
+            tree.add_node_with_value(AstNodeKind::Primitive, "box", NodeValue::Float(0.5), u);
+        };
+        let mut t1 = AstTree::new();
+        build(&mut t1);
+        let mut t2 = AstTree::new();
+        build(&mut t2);
+        assert!(diff_trees(&t1, &t2).is_empty());
+    }
+
+    #[test]
+    fn test_diff_csg_tree_one_param_changed() {
+        let mut t1 = AstTree::new();
+        let u = t1.add_node(AstNodeKind::CsgOp, "union", 0);
+        let s = t1.add_node(AstNodeKind::Primitive, "sphere", u);
+        t1.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+
+        let mut t2 = AstTree::new();
+        let u2 = t2.add_node(AstNodeKind::CsgOp, "union", 0);
+        let s2 = t2.add_node(AstNodeKind::Primitive, "sphere", u2);
+        t2.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(2.0), s2);
+
+        let ops = diff_trees(&t1, &t2);
+        assert_eq!(ops.len(), 1);
+        assert!(matches!(&ops[0], DiffOp::Update { .. }));
+    }
+
+    #[test]
+    fn test_serialized_size_insert() {
+        let op = DiffOp::Insert {
+            node_id: 1,
+            parent_id: 0,
+            index: 0,
+            kind: AstNodeKind::Primitive,
+            label: String::from("ab"), // 2 bytes
+            value: NodeValue::None,    // 1 byte
+        };
+        // 12 + label.len() + value.serialized_size() = 12 + 2 + 1 = 15
+        assert_eq!(op.serialized_size(), 15);
+    }
+
+    #[test]
+    fn test_serialized_size_relabel() {
+        let op = DiffOp::Relabel {
+            node_id: 1,
+            old_label: String::from("sphere"),
+            new_label: String::from("cylinder"), // 8 bytes
+        };
+        // 5 + new_label.len() = 5 + 8 = 13
+        assert_eq!(op.serialized_size(), 13);
+    }
+
+    #[test]
+    fn test_diff_and_apply_roundtrip() {
+        let mut t1 = AstTree::new();
+        let s = t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t1.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+
+        let mut t2 = AstTree::new();
+        let s2 = t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+        t2.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(5.0), s2);
+
+        let ops = diff_trees(&t1, &t2);
+        apply_patch(&mut t1, &ops);
+
+        // The radius parameter node id is 2 in t1
+        assert_eq!(t1.get_node(2).unwrap().value, NodeValue::Float(5.0));
+    }
+
+    #[test]
+    fn test_diff_large_flat_tree_no_change() {
+        // 50 children: verifies HashMap path is correct at scale
+        let build = |tree: &mut AstTree| {
+            for i in 0u32..50 {
+                tree.add_node(AstNodeKind::Primitive, &format!("node_{i}"), 0);
+            }
+        };
+        let mut t1 = AstTree::new();
+        build(&mut t1);
+        let mut t2 = AstTree::new();
+        build(&mut t2);
+        assert!(diff_trees(&t1, &t2).is_empty());
+    }
+
+    #[test]
+    fn test_diff_large_flat_tree_one_changed() {
+        // 50 children; only node_25's child value changes
+        let mut t1 = AstTree::new();
+        let mut t2 = AstTree::new();
+        for i in 0u32..50 {
+            let label = format!("node_{i}");
+            let n1 = t1.add_node(AstNodeKind::Primitive, &label, 0);
+            let n2 = t2.add_node(AstNodeKind::Primitive, &label, 0);
+            let val1 = if i == 25 {
+                NodeValue::Float(1.0)
+            } else {
+                NodeValue::None
+            };
+            let val2 = if i == 25 {
+                NodeValue::Float(9.9)
+            } else {
+                NodeValue::None
+            };
+            t1.add_node_with_value(AstNodeKind::Parameter, "v", val1, n1);
+            t2.add_node_with_value(AstNodeKind::Parameter, "v", val2, n2);
+        }
+
+        let ops = diff_trees(&t1, &t2);
+        let updates = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::Update { .. }))
+            .count();
+        assert_eq!(updates, 1, "only one node value changed");
+    }
+
+    // ── Hash pruning ───────────────────────────────────────────────────
+
+    /// Balanced binary CSG tree: `depth` levels of `union` ops over
+    /// `sphere` primitives, each carrying a `radius` parameter.
+    fn build_csg(tree: &mut AstTree, parent: NodeId, depth: u32, leaves: &mut Vec<NodeId>) {
+        if depth == 0 {
+            let s = tree.add_node(AstNodeKind::Primitive, "sphere", parent);
+            let r = tree.add_node_with_value(
+                AstNodeKind::Parameter,
+                "radius",
+                NodeValue::Float(1.0),
+                s,
+            );
+            leaves.push(r);
+            return;
+        }
+        let u = tree.add_node(AstNodeKind::CsgOp, "union", parent);
+        build_csg(tree, u, depth - 1, leaves);
+        build_csg(tree, u, depth - 1, leaves);
+    }
+
+    #[test]
+    fn test_diff_prunes_identical_subtrees() {
+        // 2^15 leaves * 2 nodes + 2^15 - 1 ops + root ≈ 98k nodes
+        let mut old = AstTree::new();
+        let mut leaves = Vec::new();
+        build_csg(&mut old, 0, 15, &mut leaves);
+        assert!(old.node_count() > 95_000);
+
+        let mut new = old.clone();
+        let target = leaves[leaves.len() / 3];
+        new.get_node_mut(target).unwrap().value = NodeValue::Float(2.0);
+
+        let options = DiffOptions::default();
+        let mut differ = Differ::new(&old, &new, &options);
+        let ops = differ.run();
+        assert_eq!(ops.len(), 1);
+        assert!(matches!(&ops[0], DiffOp::Update { node_id, .. } if *node_id == target));
+
+        // root + 15 unions + sphere + radius on the changed path
+        assert_eq!(differ.visited, 18);
+    }
+
+    #[test]
+    fn test_diff_identical_trees_visits_nothing() {
+        let mut old = AstTree::new();
+        build_csg(&mut old, 0, 6, &mut Vec::new());
+        let new = old.clone();
+        let options = DiffOptions::default();
+        let mut differ = Differ::new(&old, &new, &options);
+        assert!(differ.run().is_empty());
+        assert_eq!(differ.visited, 0);
+    }
+
+    // ── Move detection ─────────────────────────────────────────────────
+
+    fn count_ops(ops: &[DiffOp], pred: fn(&DiffOp) -> bool) -> usize {
+        ops.iter().filter(|o| pred(o)).count()
+    }
+
+    fn assert_roundtrip(old: &AstTree, new: &AstTree, ops: &[DiffOp]) {
+        let mut patched = old.clone();
+        apply_patch(&mut patched, ops);
+        assert_eq!(patched.check_invariants(), []);
+        assert_eq!(patched.subtree_hash(0), new.subtree_hash(0));
+
+        // Diff output must also pass every strict precondition
+        let mut checked = old.clone();
+        assert_eq!(try_apply_patch(&mut checked, ops), Ok(()));
+        assert_eq!(checked.check_invariants(), []);
+        assert_eq!(checked.subtree_hash(0), new.subtree_hash(0));
+
+        // Diff output is already in normal form
+        assert_eq!(crate::patch::normalize_patch(old, ops), ops.to_vec());
+    }
+
+    #[test]
+    fn test_diff_reparented_primitive_is_single_move() {
+        let mut old = AstTree::new();
+        let g1 = old.add_node(AstNodeKind::Group, "g1", 0);
+        let g2 = old.add_node(AstNodeKind::Group, "g2", 0);
+        let s = old.add_node(AstNodeKind::Primitive, "sphere", g1);
+        old.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Group, "g1", 0);
+        let h2 = new.add_node(AstNodeKind::Group, "g2", 0);
+        let s2 = new.add_node(AstNodeKind::Primitive, "sphere", h2);
+        new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s2);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(
+            ops,
+            vec![DiffOp::Move {
+                node_id: s,
+                new_parent_id: g2,
+                new_index: 0,
+            }]
+        );
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_moved_and_edited_subtree() {
+        let mut old = AstTree::new();
+        let g1 = old.add_node(AstNodeKind::Group, "g1", 0);
+        old.add_node(AstNodeKind::Group, "g2", 0);
+        let s = old.add_node(AstNodeKind::Primitive, "sphere", g1);
+        let r = old.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        old.add_node_with_value(AstNodeKind::Parameter, "h", NodeValue::Float(2.0), s);
+
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Group, "g1", 0);
+        let h2 = new.add_node(AstNodeKind::Group, "g2", 0);
+        let s2 = new.add_node(AstNodeKind::Primitive, "sphere", h2);
+        new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(3.0), s2);
+        new.add_node_with_value(AstNodeKind::Parameter, "h", NodeValue::Float(2.0), s2);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(&ops[0], DiffOp::Update { node_id, .. } if *node_id == r));
+        assert!(matches!(&ops[1], DiffOp::Move { node_id, .. } if *node_id == s));
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_moves_out_of_deleted_subtree() {
+        let mut old = AstTree::new();
+        let g = old.add_node(AstNodeKind::Group, "g", 0);
+        let s = old.add_node(AstNodeKind::Primitive, "sphere", g);
+        old.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        let h = old.add_node(AstNodeKind::Group, "h", 0);
+
+        let mut new = AstTree::new();
+        let h2 = new.add_node(AstNodeKind::Group, "h", 0);
+        let s2 = new.add_node(AstNodeKind::Primitive, "sphere", h2);
+        new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s2);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(
+            ops,
+            vec![
+                DiffOp::Move {
+                    node_id: s,
+                    new_parent_id: h,
+                    new_index: 0,
+                },
+                DiffOp::Delete { node_id: g },
+            ]
+        );
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_renamed_leaf_in_other_parent_is_not_a_move() {
+        let mut old = AstTree::new();
+        let g1 = old.add_node(AstNodeKind::Group, "g1", 0);
+        old.add_node(AstNodeKind::Group, "g2", 0);
+        old.add_node(AstNodeKind::Primitive, "sphere", g1);
+
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Group, "g1", 0);
+        let h2 = new.add_node(AstNodeKind::Group, "g2", 0);
+        new.add_node(AstNodeKind::Primitive, "box", h2);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Move { .. })), 0);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Insert { .. })), 1);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Delete { .. })), 1);
+    }
+
+    #[test]
+    fn test_diff_dissimilar_subtree_is_not_a_move() {
+        let mut old = AstTree::new();
+        let g1 = old.add_node(AstNodeKind::Group, "g1", 0);
+        old.add_node(AstNodeKind::Group, "g2", 0);
+        let u = old.add_node(AstNodeKind::CsgOp, "union", g1);
+        for label in ["a", "b", "c"] {
+            old.add_node(AstNodeKind::Primitive, label, u);
+        }
+
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Group, "g1", 0);
+        let h2 = new.add_node(AstNodeKind::Group, "g2", 0);
+        let u2 = new.add_node(AstNodeKind::CsgOp, "union", h2);
+        for label in ["x", "y", "z"] {
+            new.add_node(AstNodeKind::Primitive, label, u2);
+        }
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Move { .. })), 0);
+    }
+
+    #[test]
+    fn test_diff_similar_subtree_moves_with_child_edits() {
+        let mut old = AstTree::new();
+        let g1 = old.add_node(AstNodeKind::Group, "g1", 0);
+        old.add_node(AstNodeKind::Group, "g2", 0);
+        let u = old.add_node(AstNodeKind::CsgOp, "union", g1);
+        for label in ["a", "b", "c"] {
+            old.add_node(AstNodeKind::Primitive, label, u);
+        }
+
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Group, "g1", 0);
+        let h2 = new.add_node(AstNodeKind::Group, "g2", 0);
+        let u2 = new.add_node(AstNodeKind::CsgOp, "union", h2);
+        for label in ["a", "b", "d"] {
+            new.add_node(AstNodeKind::Primitive, label, u2);
+        }
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Move { .. })), 1);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Insert { .. })), 1);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Delete { .. })), 1);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_identical_subtree_nodes_are_not_stolen() {
+        // "s" under g1 is unchanged; the new copy under g2 must be an insert,
+        // not a move out of the identical g1 subtree
+        let mut old = AstTree::new();
+        let g1 = old.add_node(AstNodeKind::Group, "g1", 0);
+        old.add_node(AstNodeKind::Group, "g2", 0);
+        old.add_node(AstNodeKind::Primitive, "s", g1);
+
+        let mut new = AstTree::new();
+        let h1 = new.add_node(AstNodeKind::Group, "g1", 0);
+        let h2 = new.add_node(AstNodeKind::Group, "g2", 0);
+        new.add_node(AstNodeKind::Primitive, "s", h1);
+        new.add_node(AstNodeKind::Primitive, "s", h2);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Move { .. })), 0);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Insert { .. })), 1);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    // ── Insert IDs ─────────────────────────────────────────────────────
+
+    #[test]
+    fn test_apply_patch_insert_uses_op_node_id() {
+        let mut tree = AstTree::new();
+        apply_patch(
+            &mut tree,
+            &[DiffOp::Insert {
+                node_id: 42,
+                parent_id: 0,
+                index: 0,
+                kind: AstNodeKind::Primitive,
+                label: String::from("box"),
+                value: NodeValue::None,
+            }],
+        );
+        assert_eq!(tree.get_node(42).unwrap().label, "box");
+        assert_eq!(tree.add_node(AstNodeKind::Primitive, "next", 0), 43);
+    }
+
+    #[test]
+    fn test_apply_patch_insert_skips_id_collision() {
+        let mut tree = AstTree::new();
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        apply_patch(
+            &mut tree,
+            &[DiffOp::Insert {
+                node_id: s,
+                parent_id: 0,
+                index: 0,
+                kind: AstNodeKind::Primitive,
+                label: String::from("box"),
+                value: NodeValue::None,
+            }],
+        );
+        assert_eq!(tree.node_count(), 2);
+        assert_eq!(tree.get_node(s).unwrap().label, "sphere");
+    }
+
+    #[test]
+    fn test_patch_gives_replicas_identical_ids() {
+        let mut old = AstTree::new();
+        old.add_node(AstNodeKind::Group, "g", 0);
+        let mut new = old.clone();
+        let u = new.add_node(AstNodeKind::CsgOp, "union", 1);
+        new.add_node(AstNodeKind::Primitive, "sphere", u);
+        let ops = diff_trees(&old, &new);
+
+        // A replica that added and removed a node locally has a different
+        // next_id, but the patch still creates the same IDs
+        let mut replica_a = old.clone();
+        let mut replica_b = old.clone();
+        let tmp = replica_b.add_node(AstNodeKind::Primitive, "scratch", 0);
+        replica_b.remove_subtree(tmp);
+        apply_patch(&mut replica_a, &ops);
+        apply_patch(&mut replica_b, &ops);
+        for node in new.nodes() {
+            assert_eq!(replica_a.get_node(node.id).unwrap().label, node.label);
+            assert_eq!(replica_b.get_node(node.id).unwrap().label, node.label);
+        }
+    }
+
+    // ── Positional apply ───────────────────────────────────────────────
+
+    fn insert_op(node_id: NodeId, parent_id: NodeId, index: usize, label: &str) -> DiffOp {
+        DiffOp::Insert {
+            node_id,
+            parent_id,
+            index,
+            kind: AstNodeKind::Primitive,
+            label: String::from(label),
+            value: NodeValue::None,
+        }
+    }
+
+    #[test]
+    fn test_apply_patch_insert_honors_index() {
+        let mut tree = AstTree::new();
+        flat(&mut tree, 0, &["a", "b", "c"]);
+        apply_patch(
+            &mut tree,
+            &[insert_op(10, 0, 1, "x"), insert_op(11, 0, 0, "y")],
+        );
+        assert_eq!(child_labels(&tree, 0), ["y", "a", "x", "b", "c"]);
+    }
+
+    #[test]
+    fn test_apply_patch_move_honors_new_index() {
+        let mut tree = AstTree::new();
+        let ids = flat(&mut tree, 0, &["a", "b", "c", "d"]);
+        apply_patch(
+            &mut tree,
+            &[DiffOp::Move {
+                node_id: ids[3],
+                new_parent_id: 0,
+                new_index: 1,
+            }],
+        );
+        assert_eq!(child_labels(&tree, 0), ["a", "d", "b", "c"]);
+    }
+
+    #[test]
+    fn test_apply_patch_clamps_out_of_range_indices() {
+        let mut tree = AstTree::new();
+        let ids = flat(&mut tree, 0, &["a", "b"]);
+        apply_patch(
+            &mut tree,
+            &[
+                insert_op(10, 0, 99, "x"),
+                DiffOp::Move {
+                    node_id: ids[0],
+                    new_parent_id: 0,
+                    new_index: usize::MAX,
+                },
+            ],
+        );
+        assert_eq!(child_labels(&tree, 0), ["b", "x", "a"]);
+    }
+
+    // ── Checked apply ──────────────────────────────────────────────────
+
+    fn sample_tree() -> (AstTree, NodeId, NodeId, NodeId) {
+        let mut tree = AstTree::new();
+        let g = tree.add_node(AstNodeKind::Group, "g", 0);
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", g);
+        let r = tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        (tree, g, s, r)
+    }
+
+    fn try_apply_err(tree: &mut AstTree, ops: &[DiffOp]) -> ApplyError {
+        let before = tree.clone();
+        let err = try_apply_patch(tree, ops).unwrap_err();
+        assert_eq!(tree.check_invariants(), []);
+        assert_eq!(tree.subtree_hash(0), before.subtree_hash(0));
+        assert_eq!(
+            crate::content_id::ContentId::of_tree(tree),
+            crate::content_id::ContentId::of_tree(&before)
+        );
+        assert_eq!(tree.node_count(), before.node_count());
+        assert_eq!(tree.next_id(), before.next_id());
+        err
+    }
+
+    #[test]
+    fn test_try_apply_patch_ok() {
+        let (mut tree, g, s, r) = sample_tree();
+        let ops = vec![
+            DiffOp::Update {
+                node_id: r,
+                old_value: NodeValue::Float(1.0),
+                new_value: NodeValue::Float(2.0),
+            },
+            DiffOp::Relabel {
+                node_id: s,
+                old_label: String::from("sphere"),
+                new_label: String::from("ball"),
+            },
+            insert_op(10, g, 1, "box"),
+            DiffOp::Move {
+                node_id: 10,
+                new_parent_id: 0,
+                new_index: 0,
+            },
+        ];
+        assert_eq!(try_apply_patch(&mut tree, &ops), Ok(()));
+        assert_eq!(tree.get_node(r).unwrap().value, NodeValue::Float(2.0));
+        assert_eq!(tree.get_node(s).unwrap().label, "ball");
+        assert_eq!(tree.parent_of(10), Some(0));
+        assert_eq!(child_labels(&tree, 0), ["box", "g"]);
+    }
+
+    #[test]
+    fn test_try_apply_patch_rolls_back_on_failure() {
+        let (mut tree, g, s, r) = sample_tree();
+        let ops = vec![
+            DiffOp::Update {
+                node_id: r,
+                old_value: NodeValue::Float(1.0),
+                new_value: NodeValue::Float(2.0),
+            },
+            insert_op(10, s, 0, "extra"),
+            DiffOp::Delete { node_id: 99 },
+        ];
+        let err = try_apply_err(&mut tree, &ops);
+        assert_eq!(
+            err,
+            ApplyError {
+                op_index: 2,
+                kind: ApplyErrorKind::MissingNode(99),
+            }
+        );
+        assert_eq!(tree.get_node(r).unwrap().value, NodeValue::Float(1.0));
+        assert!(tree.get_node(10).is_none());
+
+        // Structural and attribute edits are undone too, last first
+        tree.get_node_mut(s)
+            .unwrap()
+            .attrs
+            .insert(String::from("tag"), NodeValue::Int(1));
+        let ops = vec![
+            insert_op(10, 0, 0, "extra"),
+            DiffOp::Move {
+                node_id: r,
+                new_parent_id: 10,
+                new_index: 0,
+            },
+            DiffOp::Delete { node_id: s },
+            DiffOp::Retype {
+                node_id: g,
+                old_kind: AstNodeKind::Group,
+                new_kind: AstNodeKind::CsgOp,
+            },
+            DiffOp::Delete { node_id: s },
+        ];
+        let err = try_apply_err(&mut tree, &ops);
+        assert_eq!(err.op_index, 4);
+        assert_eq!(tree.parent_of(r), Some(s));
+        assert_eq!(
+            tree.get_node(s).unwrap().attr("tag"),
+            Some(&NodeValue::Int(1))
+        );
+    }
+
+    #[test]
+    fn test_try_apply_patch_checks_old_value_and_label() {
+        let (mut tree, _g, s, r) = sample_tree();
+        let update = DiffOp::Update {
+            node_id: r,
+            old_value: NodeValue::Float(7.0),
+            new_value: NodeValue::Float(2.0),
+        };
+        assert_eq!(
+            try_apply_err(&mut tree, &[update]).kind,
+            ApplyErrorKind::ValueMismatch(r)
+        );
+        let relabel = DiffOp::Relabel {
+            node_id: s,
+            old_label: String::from("box"),
+            new_label: String::from("ball"),
+        };
+        assert_eq!(
+            try_apply_err(&mut tree, &[relabel]).kind,
+            ApplyErrorKind::LabelMismatch(s)
+        );
+    }
+
+    #[test]
+    fn test_try_apply_patch_nan_old_value_matches() {
+        let mut tree = AstTree::new();
+        let p =
+            tree.add_node_with_value(AstNodeKind::Parameter, "p", NodeValue::Float(f64::NAN), 0);
+        let ops = [DiffOp::Update {
+            node_id: p,
+            old_value: NodeValue::Float(f64::NAN),
+            new_value: NodeValue::Float(0.0),
+        }];
+        assert_eq!(try_apply_patch(&mut tree, &ops), Ok(()));
+    }
+
+    #[test]
+    fn test_try_apply_patch_rejects_bad_structure() {
+        let (mut tree, g, s, r) = sample_tree();
+        let cases = [
+            (insert_op(10, 99, 0, "x"), ApplyErrorKind::MissingParent(99)),
+            (insert_op(s, g, 0, "x"), ApplyErrorKind::NodeExists(s)),
+            (
+                insert_op(10, g, 2, "x"),
+                ApplyErrorKind::IndexOutOfRange {
+                    parent_id: g,
+                    index: 2,
+                    len: 1,
+                },
+            ),
+            (DiffOp::Delete { node_id: 0 }, ApplyErrorKind::RootNode),
+            (
+                DiffOp::Move {
+                    node_id: g,
+                    new_parent_id: r,
+                    new_index: 0,
+                },
+                ApplyErrorKind::MoveIntoDescendant {
+                    node_id: g,
+                    new_parent_id: r,
+                },
+            ),
+            (
+                DiffOp::Move {
+                    node_id: s,
+                    new_parent_id: 77,
+                    new_index: 0,
+                },
+                ApplyErrorKind::MissingParent(77),
+            ),
+            (
+                DiffOp::Move {
+                    node_id: 0,
+                    new_parent_id: g,
+                    new_index: 0,
+                },
+                ApplyErrorKind::RootNode,
+            ),
+            (
+                // Within the same parent the node itself does not count
+                DiffOp::Move {
+                    node_id: s,
+                    new_parent_id: g,
+                    new_index: 1,
+                },
+                ApplyErrorKind::IndexOutOfRange {
+                    parent_id: g,
+                    index: 1,
+                    len: 0,
+                },
+            ),
+        ];
+        for (op, kind) in cases {
+            let err = try_apply_err(&mut tree, &[op]);
+            assert_eq!(err.op_index, 0);
+            assert_eq!(err.kind, kind);
+        }
+    }
+
+    #[test]
+    fn test_try_apply_patch_move_updates_parent_index() {
+        let (mut tree, g, s, _r) = sample_tree();
+        let h = tree.add_node(AstNodeKind::Group, "h", 0);
+        let ops = [
+            DiffOp::Move {
+                node_id: s,
+                new_parent_id: h,
+                new_index: 0,
+            },
+            // Only valid if the first move left the parent index correct
+            DiffOp::Move {
+                node_id: h,
+                new_parent_id: g,
+                new_index: 0,
+            },
+        ];
+        assert_eq!(try_apply_patch(&mut tree, &ops), Ok(()));
+        assert_eq!(tree.parent_of(s), Some(h));
+        assert_eq!(tree.parent_of(h), Some(g));
+    }
+
+    #[test]
+    fn test_apply_error_display() {
+        let err = ApplyError {
+            op_index: 3,
+            kind: ApplyErrorKind::MissingNode(7),
+        };
+        assert_eq!(format!("{err}"), "op 3: node 7 does not exist");
+    }
+
+    // ── Child order ────────────────────────────────────────────────────
+
+    fn child_labels(tree: &AstTree, id: NodeId) -> Vec<String> {
+        tree.get_node(id)
+            .unwrap()
+            .children
+            .iter()
+            .map(|&c| tree.get_node(c).unwrap().label.clone())
+            .collect()
+    }
+
+    fn flat(tree: &mut AstTree, parent: NodeId, labels: &[&str]) -> Vec<NodeId> {
+        labels
+            .iter()
+            .map(|l| tree.add_node(AstNodeKind::Primitive, l, parent))
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_swapped_subtract_operands() {
+        let mut old = AstTree::new();
+        let sub = old.add_node(AstNodeKind::CsgOp, "subtract", 0);
+        let ids = flat(&mut old, sub, &["box", "sphere"]);
+
+        let mut new = AstTree::new();
+        let sub2 = new.add_node(AstNodeKind::CsgOp, "subtract", 0);
+        flat(&mut new, sub2, &["sphere", "box"]);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(
+            ops,
+            vec![DiffOp::Move {
+                node_id: ids[1],
+                new_parent_id: sub,
+                new_index: 0,
+            }]
+        );
+        assert_roundtrip(&old, &new, &ops);
+        let mut patched = old.clone();
+        apply_patch(&mut patched, &ops);
+        assert_eq!(child_labels(&patched, sub), ["sphere", "box"]);
+    }
+
+    #[test]
+    fn test_diff_reversed_children_moves_all_but_one() {
+        let labels = ["a", "b", "c", "d", "e", "f"];
+        let mut old = AstTree::new();
+        flat(&mut old, 0, &labels);
+        let mut new = AstTree::new();
+        let reversed: Vec<&str> = labels.iter().rev().copied().collect();
+        flat(&mut new, 0, &reversed);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), labels.len() - 1);
+        assert!(ops.iter().all(|o| matches!(o, DiffOp::Move { .. })));
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_single_child_moved_to_end() {
+        let mut old = AstTree::new();
+        let ids = flat(&mut old, 0, &["a", "b", "c", "d"]);
+        let mut new = AstTree::new();
+        flat(&mut new, 0, &["b", "c", "d", "a"]);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(
+            ops,
+            vec![DiffOp::Move {
+                node_id: ids[0],
+                new_parent_id: 0,
+                new_index: 3,
+            }]
+        );
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_reorder_with_deletes_and_moves_in() {
+        let mut old = AstTree::new();
+        let g = old.add_node(AstNodeKind::Group, "g", 0);
+        let h = old.add_node(AstNodeKind::Group, "h", 0);
+        flat(&mut old, g, &["a", "x", "b", "c"]);
+        flat(&mut old, h, &["d", "e"]);
+
+        let mut new = AstTree::new();
+        let g2 = new.add_node(AstNodeKind::Group, "g", 0);
+        let h2 = new.add_node(AstNodeKind::Group, "h", 0);
+        flat(&mut new, g2, &["c", "d", "a", "b"]);
+        flat(&mut new, h2, &["e"]);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Insert { .. })), 0);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Move { .. })), 2);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Delete { .. })), 1);
+        let mut patched = old.clone();
+        apply_patch(&mut patched, &ops);
+        assert_eq!(child_labels(&patched, g), ["c", "d", "a", "b"]);
+        assert_eq!(child_labels(&patched, h), ["e"]);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_nested_reorder_under_moved_parent() {
+        let mut old = AstTree::new();
+        let g = old.add_node(AstNodeKind::Group, "g", 0);
+        let h = old.add_node(AstNodeKind::Group, "h", 0);
+        let u = old.add_node(AstNodeKind::CsgOp, "subtract", g);
+        flat(&mut old, u, &["a", "b", "c"]);
+
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Group, "g", 0);
+        let h2 = new.add_node(AstNodeKind::Group, "h", 0);
+        let u2 = new.add_node(AstNodeKind::CsgOp, "subtract", h2);
+        flat(&mut new, u2, &["c", "a", "b"]);
+
+        let ops = diff_trees(&old, &new);
+        assert_roundtrip(&old, &new, &ops);
+        let mut patched = old.clone();
+        apply_patch(&mut patched, &ops);
+        assert_eq!(child_labels(&patched, h), ["subtract"]);
+        assert_eq!(child_labels(&patched, u), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn test_longest_increasing() {
+        assert!(longest_increasing(&[]).is_empty());
+        assert_eq!(longest_increasing(&[5]), vec![0]);
+        assert_eq!(longest_increasing(&[0, 1, 2]), vec![0, 1, 2]);
+        assert_eq!(longest_increasing(&[2, 1, 0]).len(), 1);
+        let seq = [3, 0, 1, 4, 2, 5];
+        let lis = longest_increasing(&seq);
+        assert_eq!(lis.len(), 4);
+        assert!(lis.windows(2).all(|w| w[0] < w[1] && seq[w[0]] < seq[w[1]]));
+    }
+
+    // ── Subtree inserts and round trips ────────────────────────────────
+
+    #[test]
+    fn test_diff_inserted_subtree_carries_children() {
+        let old = AstTree::new();
+        let mut new = AstTree::new();
+        let u = new.add_node(AstNodeKind::CsgOp, "union", 0);
+        let s = new.add_node(AstNodeKind::Primitive, "sphere", u);
+        new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        new.add_node(AstNodeKind::Primitive, "box", u);
+
+        let ops = diff_trees(&old, &new);
+        let parents: Vec<NodeId> = ops
+            .iter()
+            .map(|o| match o {
+                DiffOp::Insert { parent_id, .. } => *parent_id,
+                _ => panic!("expected only inserts"),
+            })
+            .collect();
+        // union → root, sphere and box → union (id 1), r → sphere (id 2)
+        assert_eq!(parents, vec![0, 1, 1, 2]);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_nested_insert_ids_follow_old_next_id() {
+        let mut old = AstTree::new();
+        let a = old.add_node(AstNodeKind::Group, "a", 0);
+        old.add_node(AstNodeKind::Group, "gone", 0);
+        old.remove_subtree(2);
+        assert_eq!(old.next_id(), 3);
+
+        let mut new = AstTree::new();
+        let a2 = new.add_node(AstNodeKind::Group, "a", 0);
+        let g = new.add_node(AstNodeKind::Group, "g", a2);
+        new.add_node(AstNodeKind::Primitive, "s", g);
+
+        let ops = diff_trees(&old, &new);
+        assert!(matches!(&ops[0], DiffOp::Insert { parent_id, .. } if *parent_id == a));
+        assert!(matches!(&ops[1], DiffOp::Insert { parent_id: 3, .. }));
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_moves_existing_node_into_inserted_parent() {
+        let mut old = AstTree::new();
+        let s = old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        old.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+
+        let mut new = AstTree::new();
+        let t = new.add_node(AstNodeKind::Transform, "translate", 0);
+        let s2 = new.add_node(AstNodeKind::Primitive, "sphere", t);
+        new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s2);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(&ops[0], DiffOp::Insert { parent_id: 0, .. }));
+        assert!(
+            matches!(&ops[1], DiffOp::Move { node_id, new_parent_id, .. } if *node_id == s && *new_parent_id == old.next_id())
+        );
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_insert_at_front_keeps_order() {
+        let mut old = AstTree::new();
+        flat(&mut old, 0, &["b", "c"]);
+        let mut new = AstTree::new();
+        flat(&mut new, 0, &["a", "b", "x", "c"]);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Insert { .. })), 2);
+        let mut patched = old.clone();
+        apply_patch(&mut patched, &ops);
+        assert_eq!(child_labels(&patched, 0), ["a", "b", "x", "c"]);
+    }
+
+    /// Small xorshift PRNG so the round-trip tests are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    const KINDS: [AstNodeKind; 4] = [
+        AstNodeKind::CsgOp,
+        AstNodeKind::Primitive,
+        AstNodeKind::Group,
+        AstNodeKind::Parameter,
+    ];
+    const LABELS: [&str; 4] = ["a", "b", "c", "d"];
+    const ATTRS: [&str; 2] = ["w", "h"];
+
+    fn random_tree(rng: &mut Rng, size: usize) -> AstTree {
+        let mut tree = AstTree::new();
+        let mut ids = vec![tree.root_id()];
+        for _ in 0..size {
+            let parent = ids[rng.below(ids.len())];
+            let value = NodeValue::Int(rng.below(3) as i64);
+            let id = tree.add_node_with_value(
+                KINDS[rng.below(KINDS.len())],
+                LABELS[rng.below(LABELS.len())],
+                value,
+                parent,
+            );
+            if rng.below(3) == 0 {
+                let attr = NodeValue::Int(rng.below(3) as i64);
+                let node = tree.get_node_mut(id).unwrap();
+                node.attrs.insert(String::from(ATTRS[rng.below(2)]), attr);
+            }
+            ids.push(id);
+        }
+        tree
+    }
 
-        let ops = diff_trees(&t1, &t2);
+    /// Apply a handful of random edits of every kind to a copy of `tree`.
+    fn mutate(rng: &mut Rng, tree: &AstTree, edits: usize) -> AstTree {
+        let mut out = tree.clone();
+        for _ in 0..edits {
+            let ids: Vec<NodeId> = out.nodes().iter().map(|n| n.id).collect();
+            let id = ids[rng.below(ids.len())];
+            let other = ids[rng.below(ids.len())];
+            match rng.below(8) {
+                0 => out.get_node_mut(id).unwrap().label = String::from(LABELS[rng.below(4)]),
+                1 => out.get_node_mut(id).unwrap().value = NodeValue::Int(rng.below(9) as i64),
+                2 if id != out.root_id() => {
+                    out.remove_subtree(id);
+                }
+                3 => {
+                    out.add_node(KINDS[rng.below(4)], LABELS[rng.below(4)], id);
+                }
+                4 => {
+                    let len = out.get_node(other).unwrap().children.len();
+                    out.move_node(id, other, rng.below(len + 1));
+                }
+                5 if id != out.root_id() => {
+                    out.get_node_mut(id).unwrap().kind = KINDS[rng.below(4)];
+                }
+                6 => {
+                    let key = String::from(ATTRS[rng.below(2)]);
+                    let attrs = &mut out.get_node_mut(id).unwrap().attrs;
+                    if rng.below(3) == 0 {
+                        attrs.remove(&key);
+                    } else {
+                        attrs.insert(key, NodeValue::Int(rng.below(3) as i64));
+                    }
+                }
+                _ => {
+                    let len = out.get_node(other).unwrap().children.len();
+                    if len > 1 {
+                        let child = out.get_node(other).unwrap().children[rng.below(len)];
+                        out.move_node(child, other, rng.below(len));
+                    }
+                }
+            }
+        }
+        assert_eq!(out.check_invariants(), []);
+        out
+    }
+
+    #[test]
+    fn test_diff_roundtrip_random_edits() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..300 {
+            let size = 1 + rng.below(40);
+            let old = random_tree(&mut rng, size);
+            let edits = 1 + rng.below(8);
+            let new = mutate(&mut rng, &old, edits);
+            let ops = diff_trees(&old, &new);
+            assert_roundtrip(&old, &new, &ops);
+        }
+    }
+
+    #[test]
+    fn test_diff_roundtrip_unrelated_trees() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200 {
+            let (a, b) = (rng.below(30), rng.below(30));
+            let old = random_tree(&mut rng, a);
+            let new = random_tree(&mut rng, b);
+            let ops = diff_trees(&old, &new);
+            assert_roundtrip(&old, &new, &ops);
+        }
+    }
+
+    // ── Container matching ─────────────────────────────────────────────
+
+    /// `kind label` with parameters `r` = 1.0 and `h` = 2.0 under `parent`.
+    fn shape(tree: &mut AstTree, parent: NodeId, kind: AstNodeKind, label: &str) -> NodeId {
+        let id = tree.add_node(kind, label, parent);
+        tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), id);
+        tree.add_node_with_value(AstNodeKind::Parameter, "h", NodeValue::Float(2.0), id);
+        id
+    }
+
+    #[test]
+    fn test_diff_renamed_primitive_is_relabel() {
+        let mut old = AstTree::new();
+        let s = shape(&mut old, 0, AstNodeKind::Primitive, "sphere");
+        let mut new = AstTree::new();
+        shape(&mut new, 0, AstNodeKind::Primitive, "ball");
+
+        let ops = diff_trees(&old, &new);
         assert_eq!(
-            ops.iter()
-                .filter(|o| matches!(o, DiffOp::Delete { .. }))
-                .count(),
-            1
+            ops,
+            vec![DiffOp::Relabel {
+                node_id: s,
+                old_label: "sphere".into(),
+                new_label: "ball".into(),
+            }]
         );
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_patch_size_small() {
-        let mut t1 = AstTree::new();
-        let s = t1.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t1.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
+    fn test_diff_renamed_nested_containers() {
+        // union(sphere, box) -> subtract(ball, box)
+        let mut old = AstTree::new();
+        let u = old.add_node(AstNodeKind::CsgOp, "union", 0);
+        shape(&mut old, u, AstNodeKind::Primitive, "sphere");
+        shape(&mut old, u, AstNodeKind::Primitive, "box");
+        let mut new = AstTree::new();
+        let u2 = new.add_node(AstNodeKind::CsgOp, "subtract", 0);
+        shape(&mut new, u2, AstNodeKind::Primitive, "ball");
+        shape(&mut new, u2, AstNodeKind::Primitive, "box");
 
-        let mut t2 = AstTree::new();
-        let s2 = t2.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t2.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.5), s2);
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Relabel { .. })), 2);
+        assert_roundtrip(&old, &new, &ops);
+    }
 
-        let ops = diff_trees(&t1, &t2);
-        let size = patch_size_bytes(&ops);
-        assert!(
-            size < 20,
-            "value change patch should be < 20 bytes, got {size}"
-        );
+    #[test]
+    fn test_diff_renamed_and_moved_container() {
+        let mut old = AstTree::new();
+        let g = old.add_node(AstNodeKind::Group, "g", 0);
+        let s = shape(&mut old, 0, AstNodeKind::Primitive, "sphere");
+        let mut new = AstTree::new();
+        let g2 = new.add_node(AstNodeKind::Group, "g", 0);
+        shape(&mut new, g2, AstNodeKind::Primitive, "ball");
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 2);
+        assert!(ops.contains(&DiffOp::Move {
+            node_id: s,
+            new_parent_id: g,
+            new_index: 0,
+        }));
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_apply_patch() {
+    fn test_diff_container_kind_must_match() {
+        let mut old = AstTree::new();
+        shape(&mut old, 0, AstNodeKind::Primitive, "sphere");
+        let mut new = AstTree::new();
+        shape(&mut new, 0, AstNodeKind::Group, "ball");
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Relabel { .. })), 0);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Delete { .. })), 1);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_similarity_threshold_option() {
+        // One of three parameters survives the rename: Dice = 2·1 / (3 + 3)
+        let mut old = AstTree::new();
+        let s = shape(&mut old, 0, AstNodeKind::Primitive, "sphere");
+        old.add_node(AstNodeKind::Parameter, "seg", s);
+        let mut new = AstTree::new();
+        let b = new.add_node(AstNodeKind::Primitive, "ball", 0);
+        new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), b);
+        new.add_node(AstNodeKind::Parameter, "x", b);
+        new.add_node(AstNodeKind::Parameter, "y", b);
+
+        let relabels = |ops: &[DiffOp]| count_ops(ops, |o| matches!(o, DiffOp::Relabel { .. }));
+        let strict = diff_trees(&old, &new);
+        assert_eq!(relabels(&strict), 0);
+        assert_roundtrip(&old, &new, &strict);
+
+        let loose = DiffOptions {
+            similarity_threshold: 0.3,
+            ..DiffOptions::default()
+        };
+        let ops = diff_trees_with(&old, &new, &loose);
+        assert_eq!(relabels(&ops), 1);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_with_default_options_matches_diff_trees() {
+        let mut rng = Rng(0x1234_5678_9abc_def0);
+        for _ in 0..50 {
+            let size = 1 + rng.below(30);
+            let old = random_tree(&mut rng, size);
+            let new = mutate(&mut rng, &old, 4);
+            assert_eq!(
+                diff_trees_with(&old, &new, &DiffOptions::default()),
+                diff_trees(&old, &new)
+            );
+        }
+    }
+
+    // ── Diff options ───────────────────────────────────────────────────
+
+    fn param_tree(value: f64) -> AstTree {
         let mut tree = AstTree::new();
         let s = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
-        tree.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
+        tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(value), s);
+        tree
+    }
 
-        let ops = vec![DiffOp::Update {
-            node_id: 2,
-            old_value: NodeValue::Float(1.0),
-            new_value: NodeValue::Float(2.0),
-        }];
-        apply_patch(&mut tree, &ops);
-        let node = tree.get_node(2).unwrap();
-        assert_eq!(node.value, NodeValue::Float(2.0));
+    #[test]
+    fn test_float_tolerance_matches() {
+        assert!(FloatTolerance::Exact.matches(1.0, 1.0));
+        assert!(FloatTolerance::Exact.matches(f64::NAN, f64::NAN));
+        assert!(!FloatTolerance::Exact.matches(1.0, 1.000_000_000_1));
+        assert!(FloatTolerance::Absolute(1e-6).matches(1.0, 1.000_000_000_1));
+        assert!(!FloatTolerance::Absolute(1e-6).matches(1.0, 1.001));
+        assert!(FloatTolerance::Relative(1e-5).matches(1000.0, 1000.001));
+        assert!(!FloatTolerance::Relative(1e-5).matches(0.001, 0.002));
+        assert!(!FloatTolerance::Absolute(1.0).matches(1.0, f64::NAN));
     }
 
-    // ── New tests ──────────────────────────────────────────────────────
+    #[test]
+    fn test_diff_with_float_tolerance_skips_jitter() {
+        let old = param_tree(1.0);
+        let jittered = param_tree(1.000_000_000_1);
+        assert_eq!(diff_trees(&old, &jittered).len(), 1);
+
+        let options = DiffOptions {
+            float_tolerance: FloatTolerance::Absolute(1e-9),
+            ..DiffOptions::default()
+        };
+        assert!(diff_trees_with(&old, &jittered, &options).is_empty());
+        assert_eq!(diff_trees_with(&old, &param_tree(1.5), &options).len(), 1);
+
+        let relative = DiffOptions {
+            float_tolerance: FloatTolerance::Relative(1e-6),
+            ..DiffOptions::default()
+        };
+        assert!(diff_trees_with(&param_tree(1000.0), &param_tree(1000.0001), &relative).is_empty());
+    }
 
     #[test]
-    fn test_empty_trees_no_diff() {
-        let t1 = AstTree::new();
-        let t2 = AstTree::new();
-        assert!(diff_trees(&t1, &t2).is_empty());
+    fn test_diff_with_ignored_kinds() {
+        let mut old = param_tree(1.0);
+        let gizmo = old.add_node_with_value(AstNodeKind::Custom, "gizmo", NodeValue::Int(1), 0);
+        old.add_node(AstNodeKind::Primitive, "box", 0);
+
+        let mut new = old.clone();
+        new.get_node_mut(gizmo).unwrap().value = NodeValue::Int(2);
+        new.add_node(AstNodeKind::Custom, "handle", 1);
+        new.get_node_mut(2).unwrap().value = NodeValue::Float(2.0);
+
+        let options = DiffOptions {
+            ignore_kinds: vec![AstNodeKind::Custom],
+            ..DiffOptions::default()
+        };
+        let ops = diff_trees_with(&old, &new, &options);
+        assert_eq!(
+            ops,
+            vec![DiffOp::Update {
+                node_id: 2,
+                old_value: NodeValue::Float(1.0),
+                new_value: NodeValue::Float(2.0),
+            }]
+        );
+
+        // Deleting an ignored node is not reported either
+        let mut pruned = old.clone();
+        pruned.remove_subtree(gizmo);
+        assert!(diff_trees_with(&old, &pruned, &options).is_empty());
     }
 
     #[test]
-    fn test_diff_label_swap_produces_delete_and_insert() {
-        // sphere -> cylinder: no match by (kind,label) -> delete + insert
-        let mut t1 = AstTree::new();
-        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+    fn test_diff_with_ignored_labels_keeps_order_around_them() {
+        let mut old = AstTree::new();
+        let a = old.add_node(AstNodeKind::Primitive, "a", 0);
+        old.add_node(AstNodeKind::Group, "editor_state", 0);
+        let b = old.add_node(AstNodeKind::Primitive, "b", 0);
 
-        let mut t2 = AstTree::new();
-        t2.add_node(AstNodeKind::Primitive, "cylinder", 0);
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Primitive, "b", 0);
+        new.add_node(AstNodeKind::Group, "editor_state", 0);
+        new.add_node(AstNodeKind::Primitive, "a", 0);
+        new.add_node(AstNodeKind::Primitive, "c", 0);
 
-        let ops = diff_trees(&t1, &t2);
-        let deletes = ops
-            .iter()
-            .filter(|o| matches!(o, DiffOp::Delete { .. }))
-            .count();
-        let inserts = ops
+        let options = DiffOptions {
+            ignore_labels: vec![String::from("editor_state")],
+            ..DiffOptions::default()
+        };
+        let ops = diff_trees_with(&old, &new, &options);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Move { .. })), 1);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Insert { .. })), 1);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Delete { .. })), 0);
+
+        let mut patched = old.clone();
+        apply_patch(&mut patched, &ops);
+        let root = patched.get_node(0).unwrap();
+        let labels: Vec<&str> = root
+            .children
             .iter()
-            .filter(|o| matches!(o, DiffOp::Insert { .. }))
-            .count();
-        assert_eq!(deletes, 1);
-        assert_eq!(inserts, 1);
+            .filter_map(|&c| patched.get_node(c))
+            .map(|n| n.label.as_str())
+            .filter(|l| *l != "editor_state")
+            .collect();
+        assert_eq!(labels, ["b", "a", "c"]);
+        assert!(patched.get_node(a).is_some() && patched.get_node(b).is_some());
     }
 
     #[test]
-    fn test_diff_multiple_inserts() {
-        let t1 = AstTree::new(); // root only
+    fn test_diff_with_greedy_strategy_skips_move_detection() {
+        let mut old = AstTree::new();
+        let g = old.add_node(AstNodeKind::Group, "g", 0);
+        let s = old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        old.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        let mut new = old.clone();
+        new.move_node(s, g, 0);
 
-        let mut t2 = AstTree::new();
-        t2.add_node(AstNodeKind::Primitive, "a", 0);
-        t2.add_node(AstNodeKind::Primitive, "b", 0);
-        t2.add_node(AstNodeKind::Primitive, "c", 0);
+        let greedy = DiffOptions {
+            strategy: MatchStrategy::Greedy,
+            ..DiffOptions::default()
+        };
+        let ops = diff_trees_with(&old, &new, &greedy);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Move { .. })), 0);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Delete { .. })), 1);
+        assert_roundtrip(&old, &new, &ops);
 
-        let ops = diff_trees(&t1, &t2);
-        let inserts = ops
-            .iter()
-            .filter(|o| matches!(o, DiffOp::Insert { .. }))
-            .count();
-        assert_eq!(inserts, 3);
+        assert_eq!(diff_trees(&old, &new).len(), 1);
     }
 
     #[test]
-    fn test_diff_multiple_deletes() {
-        let mut t1 = AstTree::new();
-        t1.add_node(AstNodeKind::Primitive, "a", 0);
-        t1.add_node(AstNodeKind::Primitive, "b", 0);
-        t1.add_node(AstNodeKind::Primitive, "c", 0);
+    fn test_diff_with_exact_strategy() {
+        let mut old = AstTree::new();
+        old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Primitive, "cylinder", 0);
 
-        let t2 = AstTree::new(); // root only
+        let costs = EditCosts::default();
+        let options = DiffOptions {
+            strategy: MatchStrategy::Exact(costs.clone()),
+            ..DiffOptions::default()
+        };
+        let ops = diff_trees_with(&old, &new, &options);
+        assert_eq!(ops.len(), 1);
+        assert_eq!(
+            ops,
+            crate::edit_distance::diff_trees_exact(&old, &new, &costs)
+        );
+        assert_roundtrip(&old, &new, &ops);
+    }
 
-        let ops = diff_trees(&t1, &t2);
-        let deletes = ops
-            .iter()
-            .filter(|o| matches!(o, DiffOp::Delete { .. }))
-            .count();
-        assert_eq!(deletes, 3);
+    #[test]
+    fn test_diff_with_exact_strategy_honours_tolerance_and_ignores() {
+        let mut old = param_tree(1.0);
+        old.add_node(AstNodeKind::Custom, "gizmo", 0);
+        let mut new = param_tree(1.000_000_000_1);
+        new.add_node(AstNodeKind::Custom, "handle", 0);
+
+        let options = DiffOptions {
+            float_tolerance: FloatTolerance::Absolute(1e-6),
+            ignore_kinds: vec![AstNodeKind::Custom],
+            strategy: MatchStrategy::Exact(EditCosts::default()),
+            ..DiffOptions::default()
+        };
+        assert!(diff_trees_with(&old, &new, &options).is_empty());
     }
 
     #[test]
-    fn test_diff_update_int_value() {
-        let mut t1 = AstTree::new();
-        let g = t1.add_node(AstNodeKind::Group, "g", 0);
-        t1.add_node_with_value(AstNodeKind::Parameter, "count", NodeValue::Int(10), g);
+    fn test_diff_with_options_output_encodes_like_any_patch() {
+        let old = param_tree(1.0);
+        let mut new = param_tree(2.0);
+        new.add_node(AstNodeKind::Custom, "gizmo", 0);
+        let options = DiffOptions {
+            ignore_kinds: vec![AstNodeKind::Custom],
+            float_tolerance: FloatTolerance::Relative(1e-9),
+            ..DiffOptions::default()
+        };
+        let ops = diff_trees_with(&old, &new, &options);
+        let bytes = crate::codec::encode_patch(&ops);
+        assert_eq!(crate::codec::decode_patch(&bytes), Some(ops));
+    }
 
-        let mut t2 = AstTree::new();
-        let g2 = t2.add_node(AstNodeKind::Group, "g", 0);
-        t2.add_node_with_value(AstNodeKind::Parameter, "count", NodeValue::Int(99), g2);
+    // ── Retype ─────────────────────────────────────────────────────────
 
-        let ops = diff_trees(&t1, &t2);
-        assert_eq!(ops.len(), 1);
-        match &ops[0] {
-            DiffOp::Update { new_value, .. } => assert_eq!(*new_value, NodeValue::Int(99)),
-            _ => panic!("expected Update"),
+    fn retype(node_id: NodeId, old_kind: AstNodeKind, new_kind: AstNodeKind) -> DiffOp {
+        DiffOp::Retype {
+            node_id,
+            old_kind,
+            new_kind,
         }
     }
 
     #[test]
-    fn test_diff_update_text_value() {
-        let mut t1 = AstTree::new();
-        let g = t1.add_node(AstNodeKind::Material, "mat", 0);
-        t1.add_node_with_value(
-            AstNodeKind::Parameter,
-            "name",
-            NodeValue::Text(String::from("old")),
-            g,
-        );
+    fn test_diff_retyped_container_keeps_subtree() {
+        let mut old = AstTree::new();
+        let s = shape(&mut old, 0, AstNodeKind::Primitive, "sphere");
+        let mut new = AstTree::new();
+        shape(&mut new, 0, AstNodeKind::Group, "sphere");
 
-        let mut t2 = AstTree::new();
-        let g2 = t2.add_node(AstNodeKind::Material, "mat", 0);
-        t2.add_node_with_value(
-            AstNodeKind::Parameter,
-            "name",
-            NodeValue::Text(String::from("new")),
-            g2,
+        let ops = diff_trees(&old, &new);
+        assert_eq!(
+            ops,
+            vec![retype(s, AstNodeKind::Primitive, AstNodeKind::Group)]
         );
+        assert_roundtrip(&old, &new, &ops);
+    }
 
-        let ops = diff_trees(&t1, &t2);
-        assert_eq!(ops.len(), 1);
-        assert!(matches!(&ops[0], DiffOp::Update { .. }));
+    #[test]
+    fn test_diff_retype_needs_matching_structure() {
+        let mut old = AstTree::new();
+        shape(&mut old, 0, AstNodeKind::Primitive, "sphere");
+        let mut new = AstTree::new();
+        let g = new.add_node(AstNodeKind::Group, "sphere", 0);
+        new.add_node(AstNodeKind::Primitive, "a", g);
+        new.add_node(AstNodeKind::Primitive, "b", g);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |o| matches!(o, DiffOp::Retype { .. })), 0);
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_diff_deep_nested_no_change() {
-        let mut t1 = AstTree::new();
-        let g = t1.add_node(AstNodeKind::Group, "g", 0);
-        let p = t1.add_node(AstNodeKind::Primitive, "sphere", g);
-        t1.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), p);
+    fn test_diff_retype_with_value_change() {
+        let mut old = AstTree::new();
+        let k = old.add_node_with_value(AstNodeKind::Parameter, "t", NodeValue::Float(0.5), 0);
+        let mut new = AstTree::new();
+        new.add_node_with_value(AstNodeKind::Keyframe, "t", NodeValue::Float(0.75), 0);
 
-        let mut t2 = AstTree::new();
-        let g2 = t2.add_node(AstNodeKind::Group, "g", 0);
-        let p2 = t2.add_node(AstNodeKind::Primitive, "sphere", g2);
-        t2.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), p2);
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(
+            ops[0],
+            retype(k, AstNodeKind::Parameter, AstNodeKind::Keyframe)
+        );
+        assert_roundtrip(&old, &new, &ops);
+    }
 
-        assert!(diff_trees(&t1, &t2).is_empty());
+    #[test]
+    fn test_apply_retype() {
+        let (mut tree, g, ..) = sample_tree();
+        let before = tree.subtree_hash(0);
+        apply_patch(
+            &mut tree,
+            &[retype(g, AstNodeKind::Group, AstNodeKind::CsgOp)],
+        );
+        assert_eq!(tree.get_node(g).unwrap().kind, AstNodeKind::CsgOp);
+        assert_ne!(tree.subtree_hash(0), before);
+
+        // Lenient apply leaves the root alone
+        apply_patch(
+            &mut tree,
+            &[retype(0, AstNodeKind::Root, AstNodeKind::Group)],
+        );
+        assert_eq!(tree.get_node(0).unwrap().kind, AstNodeKind::Root);
     }
 
     #[test]
-    fn test_diff_deep_nested_value_change() {
-        let mut t1 = AstTree::new();
-        let g = t1.add_node(AstNodeKind::Group, "g", 0);
-        let p = t1.add_node(AstNodeKind::Primitive, "sphere", g);
-        t1.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), p);
+    fn test_try_apply_retype_checks_old_kind_and_root() {
+        let (mut tree, _, s, _) = sample_tree();
+        assert_eq!(
+            try_apply_err(
+                &mut tree,
+                &[retype(s, AstNodeKind::Group, AstNodeKind::CsgOp)]
+            )
+            .kind,
+            ApplyErrorKind::KindMismatch(s)
+        );
+        assert_eq!(
+            try_apply_err(
+                &mut tree,
+                &[retype(0, AstNodeKind::Root, AstNodeKind::Group)]
+            )
+            .kind,
+            ApplyErrorKind::RootNode
+        );
+        assert_eq!(
+            try_apply_err(
+                &mut tree,
+                &[retype(99, AstNodeKind::Group, AstNodeKind::CsgOp)]
+            )
+            .kind,
+            ApplyErrorKind::MissingNode(99)
+        );
+        let ok = [retype(s, AstNodeKind::Primitive, AstNodeKind::Group)];
+        assert_eq!(try_apply_patch(&mut tree, &ok), Ok(()));
+    }
 
-        let mut t2 = AstTree::new();
-        let g2 = t2.add_node(AstNodeKind::Group, "g", 0);
-        let p2 = t2.add_node(AstNodeKind::Primitive, "sphere", g2);
-        t2.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(5.0), p2);
+    #[test]
+    fn test_serialized_size_retype() {
+        assert_eq!(
+            retype(1, AstNodeKind::Primitive, AstNodeKind::Group).serialized_size(),
+            7
+        );
+    }
 
-        let ops = diff_trees(&t1, &t2);
-        let updates = ops
-            .iter()
-            .filter(|o| matches!(o, DiffOp::Update { .. }))
-            .count();
-        assert_eq!(updates, 1);
+    // ── Attributes ─────────────────────────────────────────────────────
+
+    fn set_attr(node_id: NodeId, key: &str, old: Option<f64>, new: f64) -> DiffOp {
+        DiffOp::SetAttr {
+            node_id,
+            key: String::from(key),
+            old_value: old.map(NodeValue::Float),
+            new_value: NodeValue::Float(new),
+        }
+    }
+
+    /// A box with `width` 1.0 and `height` 2.0
+    fn attr_box() -> (AstTree, NodeId) {
+        let mut tree = AstTree::new();
+        let b = tree.add_node(AstNodeKind::Primitive, "box", 0);
+        let node = tree.get_node_mut(b).unwrap();
+        node.attrs
+            .insert(String::from("width"), NodeValue::Float(1.0));
+        node.attrs
+            .insert(String::from("height"), NodeValue::Float(2.0));
+        (tree, b)
     }
 
     #[test]
-    fn test_diff_same_label_different_kind_is_unmatched() {
-        // Same label but different kind must NOT match
-        let mut t1 = AstTree::new();
-        t1.add_node(AstNodeKind::Primitive, "x", 0);
+    fn test_diff_attr_changes() {
+        let (old, b) = attr_box();
+        let mut new = old.clone();
+        let attrs = &mut new.get_node_mut(b).unwrap().attrs;
+        attrs.insert(String::from("width"), NodeValue::Float(3.0));
+        attrs.insert(String::from("depth"), NodeValue::Float(4.0));
+        attrs.remove("height");
 
-        let mut t2 = AstTree::new();
-        t2.add_node(AstNodeKind::Group, "x", 0);
+        let ops = diff_trees(&old, &new);
+        assert_eq!(
+            ops,
+            vec![
+                set_attr(b, "depth", None, 4.0),
+                set_attr(b, "width", Some(1.0), 3.0),
+                DiffOp::RemoveAttr {
+                    node_id: b,
+                    key: String::from("height"),
+                    old_value: NodeValue::Float(2.0),
+                },
+            ]
+        );
+        assert_roundtrip(&old, &new, &ops);
+    }
 
-        let ops = diff_trees(&t1, &t2);
-        let deletes = ops
-            .iter()
-            .filter(|o| matches!(o, DiffOp::Delete { .. }))
-            .count();
-        let inserts = ops
-            .iter()
-            .filter(|o| matches!(o, DiffOp::Insert { .. }))
-            .count();
-        assert_eq!(deletes, 1);
-        assert_eq!(inserts, 1);
+    #[test]
+    fn test_diff_inserted_node_carries_attrs() {
+        let old = AstTree::new();
+        let (new, _) = attr_box();
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 3);
+        assert!(matches!(ops[0], DiffOp::Insert { node_id: 1, .. }));
+        assert_eq!(ops[1], set_attr(1, "height", None, 2.0));
+        assert_eq!(ops[2], set_attr(1, "width", None, 1.0));
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn test_diff_attr_float_tolerance() {
+        let (old, b) = attr_box();
+        let mut new = old.clone();
+        new.get_node_mut(b)
+            .unwrap()
+            .attrs
+            .insert(String::from("width"), NodeValue::Float(1.0 + 1e-9));
+        let options = DiffOptions {
+            float_tolerance: FloatTolerance::Absolute(1e-6),
+            ..DiffOptions::default()
+        };
+        assert!(diff_trees_with(&old, &new, &options).is_empty());
+        assert_eq!(diff_trees(&old, &new).len(), 1);
     }
 
     #[test]
-    fn test_diff_duplicate_labels_match_once_each() {
-        // Two identical children in old; two identical in new — zero ops
-        let mut t1 = AstTree::new();
-        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+    fn test_values_match_per_component() {
+        let options = DiffOptions {
+            float_tolerance: FloatTolerance::Absolute(1e-6),
+            ..DiffOptions::default()
+        };
+        let v = NodeValue::Vec3([1.0, 2.0, 3.0]);
+        assert!(options.values_match(&v, &NodeValue::Vec3([1.0, 2.0 + 1e-9, 3.0])));
+        assert!(!options.values_match(&v, &NodeValue::Vec3([1.0, 2.1, 3.0])));
+        // Same components, different meaning
+        assert!(!options.values_match(
+            &NodeValue::Vec4([0.0, 0.0, 0.0, 1.0]),
+            &NodeValue::Quat([0.0, 0.0, 0.0, 1.0])
+        ));
+        let list = |x| NodeValue::List(vec![NodeValue::Float(1.0), NodeValue::Float(x)]);
+        assert!(options.values_match(&list(2.0), &list(2.0 + 1e-9)));
+        assert!(!options.values_match(&list(2.0), &list(3.0)));
+        assert!(!options.values_match(&list(2.0), &NodeValue::List(vec![NodeValue::Float(1.0)])));
+        // Exact comparison is canonical: -0.0 and NaN payloads collapse
+        let exact = DiffOptions::default();
+        assert!(exact.values_match(
+            &NodeValue::Vec2([0.0, f64::NAN]),
+            &NodeValue::Vec2([-0.0, -f64::NAN])
+        ));
+    }
 
-        let mut t2 = AstTree::new();
-        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+    #[test]
+    fn test_try_apply_attr_checks_old_value() {
+        let (mut tree, b) = attr_box();
+        let mismatch = ApplyErrorKind::AttrMismatch {
+            node_id: b,
+            key: String::from("width"),
+        };
+        // Stale old value, and "unset" for an attribute that is set
+        for op in [
+            set_attr(b, "width", Some(5.0), 3.0),
+            set_attr(b, "width", None, 3.0),
+        ] {
+            assert_eq!(try_apply_err(&mut tree, &[op]).kind, mismatch);
+        }
+        let remove_missing = DiffOp::RemoveAttr {
+            node_id: b,
+            key: String::from("depth"),
+            old_value: NodeValue::None,
+        };
+        assert!(matches!(
+            try_apply_err(&mut tree, &[remove_missing]).kind,
+            ApplyErrorKind::AttrMismatch { .. }
+        ));
 
-        assert!(diff_trees(&t1, &t2).is_empty());
+        let ok = [
+            set_attr(b, "width", Some(1.0), 3.0),
+            set_attr(b, "depth", None, 4.0),
+        ];
+        assert_eq!(try_apply_patch(&mut tree, &ok), Ok(()));
+        let node = tree.get_node(b).unwrap();
+        assert_eq!(node.attr("width"), Some(&NodeValue::Float(3.0)));
+        assert_eq!(node.attr("depth"), Some(&NodeValue::Float(4.0)));
     }
 
     #[test]
-    fn test_diff_one_duplicate_added() {
-        // Old has one "sphere"; new has two — one Insert expected
-        let mut t1 = AstTree::new();
-        t1.add_node(AstNodeKind::Primitive, "sphere", 0);
+    fn test_serialized_size_attr_ops() {
+        assert_eq!(set_attr(1, "width", None, 2.0).serialized_size(), 5 + 5 + 9);
+        let remove = DiffOp::RemoveAttr {
+            node_id: 1,
+            key: String::from("width"),
+            old_value: NodeValue::Float(2.0),
+        };
+        assert_eq!(remove.serialized_size(), 5 + 5);
+    }
 
-        let mut t2 = AstTree::new();
-        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t2.add_node(AstNodeKind::Primitive, "sphere", 0);
+    // ── References ─────────────────────────────────────────────────────
 
-        let ops = diff_trees(&t1, &t2);
-        let inserts = ops
-            .iter()
-            .filter(|o| matches!(o, DiffOp::Insert { .. }))
-            .count();
-        assert_eq!(inserts, 1);
+    /// `(tree, proto, inst)`: a sphere prototype and an instance of it,
+    /// numbered after `pad` throwaway nodes so IDs differ between trees.
+    fn instanced(pad: usize) -> (AstTree, NodeId, NodeId) {
+        let mut tree = AstTree::new();
+        for _ in 0..pad {
+            let tmp = tree.add_node(AstNodeKind::Group, "tmp", 0);
+            tree.remove_subtree(tmp);
+        }
+        let proto = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let inst =
+            tree.add_node_with_value(AstNodeKind::Transform, "instance", NodeValue::Ref(proto), 0);
+        (tree, proto, inst)
+    }
+
+    fn label_of(tree: &AstTree, id: Option<NodeId>) -> Option<&str> {
+        tree.get_node(id?).map(|n| n.label.as_str())
     }
 
     #[test]
-    fn test_apply_patch_delete() {
-        let mut tree = AstTree::new();
-        let id = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
-        assert_eq!(tree.node_count(), 2);
+    fn test_diff_reads_refs_through_matching() {
+        let (old, _, _) = instanced(0);
+        let (new, _, _) = instanced(3);
+        assert_ne!(old.subtree_hash(0), new.subtree_hash(0));
+        assert!(diff_trees(&old, &new).is_empty());
+    }
 
-        apply_patch(&mut tree, &[DiffOp::Delete { node_id: id }]);
-        assert_eq!(tree.node_count(), 1);
+    #[test]
+    fn test_diff_remaps_refs_to_inserted_nodes() {
+        let old = AstTree::new();
+        let (new, _, _) = instanced(5);
+        let ops = diff_trees(&old, &new);
+        let inserted: Vec<NodeId> = ops
+            .iter()
+            .filter_map(|op| match op {
+                DiffOp::Insert { node_id, .. } => Some(*node_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(inserted, vec![1, 2]);
+        let mut patched = old;
+        assert_eq!(try_apply_patch(&mut patched, &ops), Ok(()));
+        assert_eq!(label_of(&patched, patched.resolve(2)), Some("sphere"));
     }
 
     #[test]
-    fn test_apply_patch_insert() {
-        let mut tree = AstTree::new();
-        assert_eq!(tree.node_count(), 1);
+    fn test_diff_redirects_ref_before_deleting_target() {
+        let (old, proto, inst) = instanced(0);
+        let mut new = old.clone();
+        let other = new.add_node(AstNodeKind::Primitive, "box", 0);
+        new.get_node_mut(inst).unwrap().value = NodeValue::Ref(other);
+        assert!(new.remove_subtree(proto));
 
-        apply_patch(
-            &mut tree,
-            &[DiffOp::Insert {
-                parent_id: 0,
-                index: 0,
-                kind: AstNodeKind::Primitive,
-                label: String::from("box"),
-                value: NodeValue::None,
-            }],
-        );
-        assert_eq!(tree.node_count(), 2);
+        let ops = diff_trees(&old, &new);
+        assert!(ops.contains(&DiffOp::Update {
+            node_id: inst,
+            old_value: NodeValue::Ref(proto),
+            new_value: NodeValue::Ref(other),
+        }));
+        assert_roundtrip(&old, &new, &ops);
+        let mut patched = old;
+        apply_patch(&mut patched, &ops);
+        assert_eq!(label_of(&patched, patched.resolve(inst)), Some("box"));
     }
 
     #[test]
-    fn test_apply_patch_relabel() {
-        let mut tree = AstTree::new();
-        let id = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+    fn test_diff_sees_same_ref_id_naming_another_node() {
+        // inst keeps the raw ID 1, but in `new` that is a freshly added box
+        let mut old = AstTree::new();
+        let proto = old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let inst =
+            old.add_node_with_value(AstNodeKind::Transform, "instance", NodeValue::Ref(proto), 0);
+        let mut new = AstTree::new();
+        let extra = new.add_node(AstNodeKind::Primitive, "box", 0);
+        new.add_node(AstNodeKind::Primitive, "sphere", 0);
+        new.add_node_with_value(AstNodeKind::Transform, "instance", NodeValue::Ref(extra), 0);
+        assert_eq!(extra, proto);
 
-        apply_patch(
-            &mut tree,
-            &[DiffOp::Relabel {
-                node_id: id,
-                old_label: String::from("sphere"),
-                new_label: String::from("cylinder"),
-            }],
-        );
-        assert_eq!(tree.get_node(id).unwrap().label, "cylinder");
+        let ops = diff_trees(&old, &new);
+        assert_eq!(count_ops(&ops, |op| matches!(op, DiffOp::Update { .. })), 1);
+        let mut patched = old;
+        assert_eq!(try_apply_patch(&mut patched, &ops), Ok(()));
+        assert_eq!(label_of(&patched, patched.resolve(inst)), Some("box"));
     }
 
     #[test]
-    fn test_apply_patch_move() {
-        let mut tree = AstTree::new();
-        let g1 = tree.add_node(AstNodeKind::Group, "g1", 0);
-        let g2 = tree.add_node(AstNodeKind::Group, "g2", 0);
-        let child = tree.add_node(AstNodeKind::Primitive, "s", g1);
+    fn test_diff_maps_refs_into_renumbered_identical_subtree() {
+        // inst refers to the sphere's radius, which moves to a new ID
+        let mut old = AstTree::new();
+        let proto = old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let r = old.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), proto);
+        let inst =
+            old.add_node_with_value(AstNodeKind::Transform, "instance", NodeValue::Ref(r), 0);
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Group, "pad", 0);
+        let proto2 = new.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let r2 =
+            new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), proto2);
+        new.add_node_with_value(AstNodeKind::Transform, "instance", NodeValue::Ref(r2), 0);
+        assert_eq!(r2, inst);
 
-        apply_patch(
-            &mut tree,
-            &[DiffOp::Move {
-                node_id: child,
-                new_parent_id: g2,
-                new_index: 0,
-            }],
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 1);
+        assert!(matches!(ops[0], DiffOp::Insert { .. }));
+        let mut patched = old;
+        assert_eq!(try_apply_patch(&mut patched, &ops), Ok(()));
+        assert_eq!(patched.resolve(inst), Some(r));
+    }
+
+    #[test]
+    fn test_try_apply_rejects_dangling_refs() {
+        let (mut tree, proto, inst) = instanced(0);
+        let err = try_apply_err(&mut tree, &[DiffOp::Delete { node_id: proto }]);
+        assert_eq!(err.op_index, 0);
+        assert_eq!(
+            err.kind,
+            ApplyErrorKind::DanglingRef {
+                node_id: inst,
+                target: proto
+            }
         );
-        assert!(!tree.get_node(g1).unwrap().children.contains(&child));
-        assert!(tree.get_node(g2).unwrap().children.contains(&child));
+        assert_eq!(
+            format!("{err}"),
+            format!("op 0: node {inst} refers to missing node {proto}")
+        );
+
+        // Fine once a later op deletes or redirects the referrer
+        let redirect = DiffOp::Update {
+            node_id: inst,
+            old_value: NodeValue::Ref(proto),
+            new_value: NodeValue::None,
+        };
+        for later in [DiffOp::Delete { node_id: inst }, redirect] {
+            let mut t = tree.clone();
+            let ops = [DiffOp::Delete { node_id: proto }, later];
+            assert_eq!(try_apply_patch(&mut t, &ops), Ok(()));
+            assert!(t.get_node(proto).is_none());
+        }
+
+        // The lenient path deletes regardless
+        apply_patch(&mut tree, &[DiffOp::Delete { node_id: proto }]);
+        assert_eq!(tree.resolve(inst), None);
     }
 
     #[test]
-    fn test_apply_patch_multiple_ops() {
-        let mut tree = AstTree::new();
-        let s = tree.add_node(AstNodeKind::Primitive, "sphere", 0);
-        tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+    fn test_try_apply_rejects_written_dangling_refs() {
+        let (tree, proto, inst) = instanced(0);
+        let writes = [
+            DiffOp::Update {
+                node_id: inst,
+                old_value: NodeValue::Ref(proto),
+                new_value: NodeValue::Ref(999),
+            },
+            DiffOp::SetAttr {
+                node_id: inst,
+                key: "target".into(),
+                old_value: None,
+                new_value: NodeValue::List(vec![NodeValue::Ref(999)]),
+            },
+            DiffOp::Insert {
+                node_id: 7,
+                parent_id: 0,
+                index: 0,
+                kind: AstNodeKind::Transform,
+                label: "copy".into(),
+                value: NodeValue::Ref(999),
+            },
+        ];
+        for write in writes {
+            let mut t = tree.clone();
+            let err = try_apply_err(&mut t, &[write]);
+            assert_eq!(err.op_index, 0);
+            assert!(matches!(
+                err.kind,
+                ApplyErrorKind::DanglingRef { target: 999, .. }
+            ));
+        }
 
-        let ops = vec![
+        // A reference to a node a later op inserts is fine
+        let mut t = tree.clone();
+        let ops = [
+            DiffOp::Update {
+                node_id: inst,
+                old_value: NodeValue::Ref(proto),
+                new_value: NodeValue::Ref(7),
+            },
             DiffOp::Insert {
+                node_id: 7,
                 parent_id: 0,
-                index: 1,
+                index: 0,
                 kind: AstNodeKind::Primitive,
-                label: String::from("box"),
+                label: "box".into(),
                 value: NodeValue::None,
             },
-            DiffOp::Update {
-                node_id: 2,
-                old_value: NodeValue::Float(1.0),
-                new_value: NodeValue::Float(3.0),
-            },
         ];
-        apply_patch(&mut tree, &ops);
-        assert_eq!(tree.node_count(), 4); // root + sphere + r + box
-        assert_eq!(tree.get_node(2).unwrap().value, NodeValue::Float(3.0));
+        assert_eq!(try_apply_patch(&mut t, &ops), Ok(()));
+        assert_eq!(label_of(&t, t.resolve(inst)), Some("box"));
     }
 
     #[test]
-    fn test_patch_size_bytes_empty() {
-        assert_eq!(patch_size_bytes(&[]), 0);
+    fn test_map_node_ids_rewrites_refs() {
+        let mut op = DiffOp::Update {
+            node_id: 4,
+            old_value: NodeValue::Ref(4),
+            new_value: NodeValue::List(vec![NodeValue::Ref(4), NodeValue::Ref(5)]),
+        };
+        op.map_node_ids(|id| if id == 4 { 9 } else { id });
+        assert_eq!(
+            op,
+            DiffOp::Update {
+                node_id: 9,
+                old_value: NodeValue::Ref(9),
+                new_value: NodeValue::List(vec![NodeValue::Ref(9), NodeValue::Ref(5)]),
+            }
+        );
+    }
+
+    // ── Deep trees ─────────────────────────────────────────────────────
+
+    /// A chain of `depth` keyframe groups under the root, with its IDs
+    /// from the root down.
+    fn key_chain(depth: i64) -> (AstTree, Vec<NodeId>) {
+        let mut tree = AstTree::new();
+        let mut ids = vec![tree.root_id()];
+        for i in 0..depth {
+            let parent = ids[ids.len() - 1];
+            ids.push(tree.add_node_with_value(
+                AstNodeKind::Group,
+                "key",
+                NodeValue::Int(i),
+                parent,
+            ));
+        }
+        (tree, ids)
     }
 
     #[test]
-    fn test_patch_size_bytes_delete_is_5() {
-        let ops = vec![DiffOp::Delete { node_id: 1 }];
-        assert_eq!(patch_size_bytes(&ops), 5);
+    fn test_diff_deep_chain_roundtrip() {
+        let (old, ids) = key_chain(100_000);
+        let leaf = ids[ids.len() - 1];
+        let mut new = old.clone();
+        new.get_node_mut(leaf).unwrap().value = NodeValue::Int(-1);
+        new.add_node(AstNodeKind::Primitive, "sphere", leaf);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 2);
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_patch_size_bytes_move_is_12() {
-        let ops = vec![DiffOp::Move {
-            node_id: 1,
-            new_parent_id: 2,
-            new_index: 0,
-        }];
-        assert_eq!(patch_size_bytes(&ops), 12);
+    fn test_diff_empty_against_deep_chain() {
+        // Every new node is a container candidate; none may rescan its
+        // subtree
+        let old = AstTree::new();
+        let (new, _) = key_chain(100_000);
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 100_000);
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_diff_csg_tree_no_change() {
-        let build = |tree: &mut AstTree| {
-            let u = tree.add_node(AstNodeKind::CsgOp, "union", 0);
-            tree.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), u);
-            tree.add_node_with_value(AstNodeKind::Primitive, "box", NodeValue::Float(0.5), u);
-        };
-        let mut t1 = AstTree::new();
-        build(&mut t1);
-        let mut t2 = AstTree::new();
-        build(&mut t2);
-        assert!(diff_trees(&t1, &t2).is_empty());
+    fn test_diff_deep_chain_relabel_is_rematched() {
+        // Every ancestor of the renamed node changes hash and goes through
+        // child matching; the container pass must not rescan below them
+        let (old, ids) = key_chain(100_000);
+        let (mid, leaf) = (ids[50_000], ids[ids.len() - 1]);
+        let mut new = old.clone();
+        new.get_node_mut(mid).unwrap().label = "mid".into();
+        new.get_node_mut(leaf).unwrap().value = NodeValue::Int(-1);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(
+            count_ops(&ops, |op| matches!(op, DiffOp::Relabel { .. })),
+            1
+        );
+        assert_eq!(count_ops(&ops, |op| matches!(op, DiffOp::Update { .. })), 1);
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_diff_csg_tree_one_param_changed() {
-        let mut t1 = AstTree::new();
-        let u = t1.add_node(AstNodeKind::CsgOp, "union", 0);
-        let s = t1.add_node(AstNodeKind::Primitive, "sphere", u);
-        t1.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+    fn test_diff_deep_chain_inserted_wrapper() {
+        // The lower half of the chain moves under a new node halfway down
+        let (old, ids) = key_chain(100_000);
+        let (mid, leaf) = (ids[50_000], ids[ids.len() - 1]);
+        let mut new = old.clone();
+        let wrap = new.add_node(AstNodeKind::Transform, "wrap", ids[49_999]);
+        new.move_node(mid, wrap, 0);
+        new.get_node_mut(leaf).unwrap().value = NodeValue::Int(-1);
 
-        let mut t2 = AstTree::new();
-        let u2 = t2.add_node(AstNodeKind::CsgOp, "union", 0);
-        let s2 = t2.add_node(AstNodeKind::Primitive, "sphere", u2);
-        t2.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(2.0), s2);
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 3);
+        assert_eq!(count_ops(&ops, |op| matches!(op, DiffOp::Insert { .. })), 1);
+        assert_eq!(count_ops(&ops, |op| matches!(op, DiffOp::Move { .. })), 1);
+        assert_eq!(count_ops(&ops, |op| matches!(op, DiffOp::Update { .. })), 1);
+        assert_roundtrip(&old, &new, &ops);
+    }
 
-        let ops = diff_trees(&t1, &t2);
-        assert_eq!(ops.len(), 1);
-        assert!(matches!(&ops[0], DiffOp::Update { .. }));
+    // ── Wide trees ─────────────────────────────────────────────────────
+
+    /// Keyframe parameters under the root, one per entry of `values`,
+    /// each labelled after its value.
+    fn key_list(values: impl Iterator<Item = i64>) -> AstTree {
+        let mut tree = AstTree::new();
+        for i in values {
+            let label = format!("key{i}");
+            tree.add_node_with_value(AstNodeKind::Parameter, &label, NodeValue::Int(i), 0);
+        }
+        tree
     }
 
     #[test]
-    fn test_serialized_size_insert() {
-        let op = DiffOp::Insert {
-            parent_id: 0,
-            index: 0,
-            kind: AstNodeKind::Primitive,
-            label: String::from("ab"), // 2 bytes
-            value: NodeValue::None,    // 1 byte
-        };
-        // 8 + label.len() + value.serialized_size() = 8 + 2 + 1 = 11
-        assert_eq!(op.serialized_size(), 11);
+    fn test_diff_empty_against_wide_list() {
+        // Each insert lands after the previous one; resolving its index
+        // must not rescan the list
+        let old = AstTree::new();
+        let new = key_list(0..20_000);
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 20_000);
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_serialized_size_relabel() {
-        let op = DiffOp::Relabel {
-            node_id: 1,
-            old_label: String::from("sphere"),
-            new_label: String::from("cylinder"), // 8 bytes
-        };
-        // 5 + new_label.len() = 5 + 8 = 13
-        assert_eq!(op.serialized_size(), 13);
+    fn test_diff_wide_list_reversed() {
+        let old = key_list(0..20_000);
+        let new = key_list((0..20_000).rev());
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 19_999);
+        assert!(ops.iter().all(|o| matches!(o, DiffOp::Move { .. })));
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_diff_and_apply_roundtrip() {
-        let mut t1 = AstTree::new();
-        let s = t1.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t1.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
-
-        let mut t2 = AstTree::new();
-        let s2 = t2.add_node(AstNodeKind::Primitive, "sphere", 0);
-        t2.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(5.0), s2);
-
-        let ops = diff_trees(&t1, &t2);
-        apply_patch(&mut t1, &ops);
+    fn test_diff_wide_same_label_list_first_removed() {
+        let mut old = AstTree::new();
+        for i in 0..20_000 {
+            old.add_node_with_value(AstNodeKind::Parameter, "key", NodeValue::Int(i), 0);
+        }
+        let first = old.get_node(0).unwrap().children[0];
+        let mut new = old.clone();
+        assert!(new.remove_subtree(first));
 
-        // The radius parameter node id is 2 in t1
-        assert_eq!(t1.get_node(2).unwrap().value, NodeValue::Float(5.0));
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops, vec![DiffOp::Delete { node_id: first }]);
+        assert_roundtrip(&old, &new, &ops);
     }
 
     #[test]
-    fn test_diff_large_flat_tree_no_change() {
-        // 50 children: verifies HashMap path is correct at scale
-        let build = |tree: &mut AstTree| {
-            for i in 0u32..50 {
-                tree.add_node(AstNodeKind::Primitive, &format!("node_{i}"), 0);
-            }
-        };
-        let mut t1 = AstTree::new();
-        build(&mut t1);
-        let mut t2 = AstTree::new();
-        build(&mut t2);
-        assert!(diff_trees(&t1, &t2).is_empty());
+    fn test_diff_wide_list_replaced_and_retyped() {
+        // No leftover old child shares a label with a leftover new one, or
+        // every one does; neither may compare each pair of children
+        let old = key_list(0..20_000);
+        let mut new = AstTree::new();
+        for i in 0..20_000 {
+            let label = format!("other{i}");
+            new.add_node_with_value(AstNodeKind::Primitive, &label, NodeValue::Int(i), 0);
+        }
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 40_000);
+
+        let mut new = AstTree::new();
+        for i in 0..20_000 {
+            let label = format!("key{i}");
+            new.add_node_with_value(AstNodeKind::Keyframe, &label, NodeValue::Int(i), 0);
+        }
+        let ops = diff_trees(&old, &new);
+        assert_eq!(ops.len(), 20_000);
+        assert!(ops.iter().all(|o| matches!(o, DiffOp::Retype { .. })));
+        assert_roundtrip(&old, &new, &ops);
     }
 
+    // ── Describing patches ─────────────────────────────────────────────
+
     #[test]
-    fn test_diff_large_flat_tree_one_changed() {
-        // 50 children; only node_25's child value changes
-        let mut t1 = AstTree::new();
-        let mut t2 = AstTree::new();
-        for i in 0u32..50 {
-            let label = format!("node_{i}");
-            let n1 = t1.add_node(AstNodeKind::Primitive, &label, 0);
-            let n2 = t2.add_node(AstNodeKind::Primitive, &label, 0);
-            let val1 = if i == 25 {
-                NodeValue::Float(1.0)
-            } else {
-                NodeValue::None
-            };
-            let val2 = if i == 25 {
-                NodeValue::Float(9.9)
-            } else {
-                NodeValue::None
-            };
-            t1.add_node_with_value(AstNodeKind::Parameter, "v", val1, n1);
-            t2.add_node_with_value(AstNodeKind::Parameter, "v", val2, n2);
-        }
+    fn test_describe_patch_names_nodes_by_path() {
+        let mut old = AstTree::new();
+        let u = old.add_node(AstNodeKind::CsgOp, "union", 0);
+        let s = old.add_node(AstNodeKind::Primitive, "sphere", u);
+        let r = old.add_node_with_value(AstNodeKind::Parameter, "radius", NodeValue::Float(1.0), s);
+        let g = old.add_node(AstNodeKind::Group, "lights", 0);
+        let mut new = old.clone();
+        new.get_node_mut(r).unwrap().value = NodeValue::Float(2.0);
+        let b = new.add_node(AstNodeKind::Primitive, "sphere", u);
+        new.move_node(g, b, 0);
 
-        let ops = diff_trees(&t1, &t2);
-        let updates = ops
-            .iter()
-            .filter(|o| matches!(o, DiffOp::Update { .. }))
-            .count();
-        assert_eq!(updates, 1, "only one node value changed");
+        let ops = diff_trees(&old, &new);
+        assert_eq!(
+            describe_patch(&old, &ops),
+            [
+                "update /root/union/sphere/radius: Float(1.0) -> Float(2.0)",
+                "insert /root/union/sphere[1]: Primitive None",
+                "move /root/lights -> /root/union/sphere[1]/lights",
+            ]
+        );
+        let missing = [DiffOp::Delete { node_id: 99 }];
+        assert_eq!(describe_patch(&old, &missing), ["delete #99"]);
     }
 }
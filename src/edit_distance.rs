@@ -0,0 +1,568 @@
+//! Exact tree edit distance
+//!
+//! Zhang–Shasha ordered tree edit distance with configurable costs per op
+//! type and per `AstNodeKind`. O(n²) memory and up to O(n⁴) time, so it is
+//! meant for reviews and archival; `diff_trees` stays the fast heuristic
+//! for interactive use.
+//!
+//! Author: Moroya Sakamoto
+
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
+#[cfg(not(feature = "std"))]
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::ast::{AstNode, AstNodeKind, AstTree, NodeId};
+use crate::diff::{diff_trees_with, DiffOp, DiffOptions, MatchStrategy};
+
+/// Cost standing in for an impossible edit (deleting or inserting the
+/// root). Large, but far enough from `u64::MAX` that sums of a few never
+/// saturate.
+const FORBIDDEN: u64 = u64::MAX / 8;
+
+// ── Costs ──────────────────────────────────────────────────────────────
+
+/// Cost model for [`tree_edit_distance`] and [`diff_trees_exact`]
+///
+/// Every edit on a node costs its op cost times the weight of the node's
+/// kind (1 unless set); a retyped node is weighted by its old kind.
+/// Renaming a node costs `retype` if its kind changes, plus `relabel` if
+/// its label changes, plus `update` if its value changes and `update` for
+/// each attribute set, changed or removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditCosts {
+    /// Cost of inserting a node
+    pub insert: u64,
+    /// Cost of deleting a node
+    pub delete: u64,
+    /// Cost of changing a node's label
+    pub relabel: u64,
+    /// Cost of changing a node's value or one of its attributes
+    pub update: u64,
+    /// Cost of changing a node's kind
+    pub retype: u64,
+    /// Per-kind multipliers
+    pub kind_weights: HashMap<AstNodeKind, u64>,
+}
+
+impl Default for EditCosts {
+    /// Unit cost for every op and kind.
+    fn default() -> Self {
+        Self {
+            insert: 1,
+            delete: 1,
+            relabel: 1,
+            update: 1,
+            retype: 1,
+            kind_weights: HashMap::new(),
+        }
+    }
+}
+
+impl EditCosts {
+    /// Set the multiplier for edits on nodes of `kind`.
+    #[must_use]
+    pub fn with_kind_weight(mut self, kind: AstNodeKind, weight: u64) -> Self {
+        self.kind_weights.insert(kind, weight);
+        self
+    }
+
+    /// Multiplier for edits on nodes of `kind`.
+    #[must_use]
+    pub fn weight(&self, kind: AstNodeKind) -> u64 {
+        self.kind_weights.get(&kind).copied().unwrap_or(1)
+    }
+
+    fn delete_cost(&self, node: &AstNode, is_root: bool) -> u64 {
+        if is_root {
+            return FORBIDDEN;
+        }
+        self.delete.saturating_mul(self.weight(node.kind))
+    }
+
+    fn insert_cost(&self, node: &AstNode, is_root: bool) -> u64 {
+        if is_root {
+            return FORBIDDEN;
+        }
+        self.insert.saturating_mul(self.weight(node.kind))
+    }
+
+    fn rename_cost(&self, a: &AstNode, b: &AstNode, options: &DiffOptions) -> u64 {
+        let mut cost = 0u64;
+        if a.kind != b.kind {
+            cost = cost.saturating_add(self.retype);
+        }
+        if a.label != b.label {
+            cost = cost.saturating_add(self.relabel);
+        }
+        if !options.values_match(&a.value, &b.value) {
+            cost = cost.saturating_add(self.update);
+        }
+        let set = b
+            .attrs
+            .iter()
+            .filter(|(k, v)| !a.attrs.get(*k).is_some_and(|o| options.values_match(o, v)));
+        let removed = a.attrs.keys().filter(|k| !b.attrs.contains_key(*k));
+        let changed_attrs = (set.count() + removed.count()) as u64;
+        cost = cost.saturating_add(self.update.saturating_mul(changed_attrs));
+        cost.saturating_mul(self.weight(a.kind))
+    }
+}
+
+// ── Public API ─────────────────────────────────────────────────────────
+
+/// Minimal total cost of the inserts, deletes and renames (retype,
+/// relabel, update) that turn `old`
+/// into `new`, under `costs`
+///
+/// Roots are always matched with each other. Identical trees are at
+/// distance 0.
+#[must_use]
+pub fn tree_edit_distance(old: &AstTree, new: &AstTree, costs: &EditCosts) -> u64 {
+    let options = DiffOptions::default();
+    let zs = ZhangShasha::new(old, new, costs, &options);
+    zs.tree_dist[zs.a.len() - 1][zs.b.len() - 1]
+}
+
+/// Diff `old` against `new` through an optimal edit mapping
+///
+/// Where `diff_trees` matches greedily by `(kind, label)`, this matches
+/// the nodes an optimal edit script keeps, so a renamed node becomes a
+/// `Relabel` rather than a `Delete` plus an `Insert` when that is cheaper.
+/// Children of a deleted node that the mapping keeps are moved to their
+/// new parent before the delete. The result applies like any other patch.
+///
+/// Shorthand for [`diff_trees_with`] using [`MatchStrategy::Exact`].
+#[must_use]
+pub fn diff_trees_exact(old: &AstTree, new: &AstTree, costs: &EditCosts) -> Vec<DiffOp> {
+    let options = DiffOptions {
+        strategy: MatchStrategy::Exact(costs.clone()),
+        ..DiffOptions::default()
+    };
+    diff_trees_with(old, new, &options)
+}
+
+/// Old → new node pairs an optimal edit script keeps; nodes `options`
+/// ignores are left out, and values compare under its float tolerance.
+pub(crate) fn exact_matching(
+    old: &AstTree,
+    new: &AstTree,
+    costs: &EditCosts,
+    options: &DiffOptions,
+) -> Vec<(NodeId, NodeId)> {
+    let mut zs = ZhangShasha::new(old, new, costs, options);
+    zs.mapping()
+        .into_iter()
+        .map(|(i, j)| (zs.a.nodes[i].id, zs.b.nodes[j].id))
+        .collect()
+}
+
+// ── Zhang–Shasha ───────────────────────────────────────────────────────
+
+/// A tree in post-order, with each node's leftmost leaf descendant.
+struct Postorder<'a> {
+    nodes: Vec<&'a AstNode>,
+    /// Post-order index of each node's leftmost leaf descendant
+    leftmost: Vec<usize>,
+    /// Key roots — the root and every node with a left sibling — in
+    /// increasing order
+    keyroots: Vec<usize>,
+}
+
+impl<'a> Postorder<'a> {
+    fn new(tree: &'a AstTree, options: &DiffOptions) -> Self {
+        let mut nodes = Vec::with_capacity(tree.node_count());
+        let mut leftmost = Vec::with_capacity(tree.node_count());
+        // (node, index of its first post-order descendant)
+        let mut stack: Vec<(&AstNode, usize)> = Vec::new();
+        let mut pending: Vec<(&AstNode, usize)> = Vec::new();
+        if let Some(root) = tree.get_node(tree.root_id()) {
+            pending.push((root, 0));
+        }
+        // Iterative post-order: expand a node's children before emitting it
+        while let Some((node, next_child)) = pending.pop() {
+            if next_child == 0 {
+                stack.push((node, nodes.len()));
+            }
+            if let Some(&c) = node.children.get(next_child) {
+                pending.push((node, next_child + 1));
+                if let Some(child) = tree.get_node(c).filter(|n| !options.ignores(n)) {
+                    pending.push((child, 0));
+                }
+            } else if let Some((done, first)) = stack.pop() {
+                nodes.push(done);
+                leftmost.push(first);
+            }
+        }
+
+        let mut keyroots = Vec::new();
+        let mut seen = vec![false; nodes.len()];
+        for i in (0..nodes.len()).rev() {
+            if !seen[leftmost[i]] {
+                seen[leftmost[i]] = true;
+                keyroots.push(i);
+            }
+        }
+        keyroots.reverse();
+
+        Self {
+            nodes,
+            leftmost,
+            keyroots,
+        }
+    }
+
+    const fn len(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+struct ZhangShasha<'a> {
+    a: Postorder<'a>,
+    b: Postorder<'a>,
+    costs: &'a EditCosts,
+    options: &'a DiffOptions,
+    /// Edit distance between the subtrees rooted at each pair of nodes
+    tree_dist: Vec<Vec<u64>>,
+}
+
+impl<'a> ZhangShasha<'a> {
+    fn new(
+        old: &'a AstTree,
+        new: &'a AstTree,
+        costs: &'a EditCosts,
+        options: &'a DiffOptions,
+    ) -> Self {
+        let a = Postorder::new(old, options);
+        let b = Postorder::new(new, options);
+        let mut zs = Self {
+            tree_dist: vec![vec![0; b.len()]; a.len()],
+            a,
+            b,
+            costs,
+            options,
+        };
+        for ki in 0..zs.a.keyroots.len() {
+            for kj in 0..zs.b.keyroots.len() {
+                let (i, j) = (zs.a.keyroots[ki], zs.b.keyroots[kj]);
+                zs.forest_dist(i, j);
+            }
+        }
+        zs
+    }
+
+    fn delete(&self, i: usize) -> u64 {
+        self.costs
+            .delete_cost(self.a.nodes[i], i + 1 == self.a.len())
+    }
+
+    fn insert(&self, j: usize) -> u64 {
+        self.costs
+            .insert_cost(self.b.nodes[j], j + 1 == self.b.len())
+    }
+
+    fn rename(&self, i: usize, j: usize) -> u64 {
+        self.costs
+            .rename_cost(self.a.nodes[i], self.b.nodes[j], self.options)
+    }
+
+    /// Forest distances between the post-order ranges ending at `i` and
+    /// `j` (from their leftmost leaves), recording the subtree distances
+    /// found on the way into `tree_dist`.
+    fn forest_dist(&mut self, i: usize, j: usize) -> Vec<Vec<u64>> {
+        let (li, lj) = (self.a.leftmost[i], self.b.leftmost[j]);
+        let (rows, cols) = (i - li + 2, j - lj + 2);
+        let mut fd = vec![vec![0u64; cols]; rows];
+        for x in 1..rows {
+            fd[x][0] = fd[x - 1][0].saturating_add(self.delete(li + x - 1));
+        }
+        for y in 1..cols {
+            fd[0][y] = fd[0][y - 1].saturating_add(self.insert(lj + y - 1));
+        }
+        for x in 1..rows {
+            for y in 1..cols {
+                let (p, q) = (li + x - 1, lj + y - 1);
+                let del = fd[x - 1][y].saturating_add(self.delete(p));
+                let ins = fd[x][y - 1].saturating_add(self.insert(q));
+                if self.a.leftmost[p] == li && self.b.leftmost[q] == lj {
+                    let ren = fd[x - 1][y - 1].saturating_add(self.rename(p, q));
+                    fd[x][y] = del.min(ins).min(ren);
+                    self.tree_dist[p][q] = fd[x][y];
+                } else {
+                    let (px, qy) = (self.a.leftmost[p] - li, self.b.leftmost[q] - lj);
+                    let sub = fd[px][qy].saturating_add(self.tree_dist[p][q]);
+                    fd[x][y] = del.min(ins).min(sub);
+                }
+            }
+        }
+        fd
+    }
+
+    /// Post-order index pairs an optimal edit script keeps, by
+    /// backtracking through the forest distances.
+    ///
+    /// Recomputing a forest table rewrites the subtree distances it
+    /// covers with the values they already hold.
+    fn mapping(&mut self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let mut todo = vec![(self.a.len() - 1, self.b.len() - 1)];
+        while let Some((i, j)) = todo.pop() {
+            let fd = self.forest_dist(i, j);
+            let (li, lj) = (self.a.leftmost[i], self.b.leftmost[j]);
+            let (mut x, mut y) = (i - li + 1, j - lj + 1);
+            while x > 0 || y > 0 {
+                if x > 0 && fd[x][y] == fd[x - 1][y].saturating_add(self.delete(li + x - 1)) {
+                    x -= 1;
+                    continue;
+                }
+                if y > 0 && fd[x][y] == fd[x][y - 1].saturating_add(self.insert(lj + y - 1)) {
+                    y -= 1;
+                    continue;
+                }
+                let (p, q) = (li + x - 1, lj + y - 1);
+                if self.a.leftmost[p] == li && self.b.leftmost[q] == lj {
+                    pairs.push((p, q));
+                    x -= 1;
+                    y -= 1;
+                } else {
+                    todo.push((p, q));
+                    x = self.a.leftmost[p] - li;
+                    y = self.b.leftmost[q] - lj;
+                }
+            }
+        }
+        pairs
+    }
+}
+
+// ── Tests ──────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::NodeValue;
+    use crate::diff::{diff_trees, try_apply_patch};
+    #[cfg(not(feature = "std"))]
+    use alloc::string::String;
+
+    fn assert_roundtrip(old: &AstTree, new: &AstTree, ops: &[DiffOp]) {
+        let mut t = old.clone();
+        assert_eq!(try_apply_patch(&mut t, ops), Ok(()));
+        assert_eq!(t.subtree_hash(0), new.subtree_hash(0));
+    }
+
+    fn scene() -> AstTree {
+        let mut tree = AstTree::new();
+        let u = tree.add_node(AstNodeKind::CsgOp, "union", 0);
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", u);
+        tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        let b = tree.add_node(AstNodeKind::Primitive, "box", u);
+        tree.add_node_with_value(AstNodeKind::Parameter, "w", NodeValue::Float(2.0), b);
+        tree
+    }
+
+    #[test]
+    fn identical_trees_are_at_distance_zero() {
+        let tree = scene();
+        assert_eq!(tree_edit_distance(&tree, &tree, &EditCosts::default()), 0);
+        assert!(diff_trees_exact(&tree, &tree, &EditCosts::default()).is_empty());
+    }
+
+    #[test]
+    fn empty_trees() {
+        let t = AstTree::new();
+        assert_eq!(tree_edit_distance(&t, &t, &EditCosts::default()), 0);
+    }
+
+    #[test]
+    fn single_insert_and_delete() {
+        let old = scene();
+        let mut new = old.clone();
+        new.add_node(AstNodeKind::Primitive, "cone", 1);
+        let costs = EditCosts::default();
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 1);
+        assert_eq!(tree_edit_distance(&new, &old, &costs), 1);
+        assert_roundtrip(&old, &new, &diff_trees_exact(&old, &new, &costs));
+        assert_roundtrip(&new, &old, &diff_trees_exact(&new, &old, &costs));
+    }
+
+    #[test]
+    fn relabel_instead_of_delete_and_insert() {
+        let mut old = AstTree::new();
+        old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Primitive, "cylinder", 0);
+
+        let costs = EditCosts::default();
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 1);
+        let ops = diff_trees_exact(&old, &new, &costs);
+        assert_eq!(
+            ops,
+            vec![DiffOp::Relabel {
+                node_id: 1,
+                old_label: "sphere".into(),
+                new_label: "cylinder".into(),
+            }]
+        );
+        assert_eq!(diff_trees(&old, &new).len(), 2);
+    }
+
+    #[test]
+    fn renamed_parent_keeps_its_subtree() {
+        let old = scene();
+        let mut new = old.clone();
+        new.get_node_mut(2).unwrap().label = "ball".into();
+        new.get_node_mut(3).unwrap().value = NodeValue::Float(1.5);
+
+        let costs = EditCosts {
+            insert: 5,
+            delete: 5,
+            ..EditCosts::default()
+        };
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 2);
+        let ops = diff_trees_exact(&old, &new, &costs);
+        assert_eq!(ops.len(), 2);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn expensive_relabel_prefers_delete_and_insert() {
+        let mut old = AstTree::new();
+        old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Primitive, "cylinder", 0);
+
+        let costs = EditCosts {
+            relabel: 5,
+            ..EditCosts::default()
+        };
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 2);
+        let ops = diff_trees_exact(&old, &new, &costs);
+        assert_eq!(ops.len(), 2);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn each_changed_attr_costs_an_update() {
+        let mut old = AstTree::new();
+        let b = old.add_node(AstNodeKind::Primitive, "box", 0);
+        let mut new = old.clone();
+        let attrs = &mut new.get_node_mut(b).unwrap().attrs;
+        attrs.insert(String::from("w"), NodeValue::Float(1.0));
+        attrs.insert(String::from("h"), NodeValue::Float(2.0));
+
+        let costs = EditCosts {
+            insert: 5,
+            delete: 5,
+            ..EditCosts::default()
+        };
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 2);
+        let ops = diff_trees_exact(&old, &new, &costs);
+        assert_eq!(ops.len(), 2);
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn kind_change_is_retype() {
+        let mut old = AstTree::new();
+        old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let mut new = AstTree::new();
+        new.add_node(AstNodeKind::Group, "sphere", 0);
+
+        let costs = EditCosts::default();
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 1);
+        let ops = diff_trees_exact(&old, &new, &costs);
+        assert_eq!(
+            ops,
+            vec![DiffOp::Retype {
+                node_id: 1,
+                old_kind: AstNodeKind::Primitive,
+                new_kind: AstNodeKind::Group,
+            }]
+        );
+        assert_roundtrip(&old, &new, &ops);
+
+        // Expensive enough, a retype loses to delete + insert
+        let costs = EditCosts {
+            retype: 5,
+            ..EditCosts::default()
+        };
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 2);
+        let ops = diff_trees_exact(&old, &new, &costs);
+        assert!(!ops.iter().any(|o| matches!(o, DiffOp::Retype { .. })));
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn kind_weights_scale_costs() {
+        let old = scene();
+        let mut new = old.clone();
+        new.get_node_mut(3).unwrap().value = NodeValue::Float(1.5);
+        new.add_node(AstNodeKind::Primitive, "cone", 1);
+
+        let costs = EditCosts::default()
+            .with_kind_weight(AstNodeKind::Parameter, 10)
+            .with_kind_weight(AstNodeKind::Primitive, 3);
+        assert_eq!(costs.weight(AstNodeKind::Group), 1);
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 13);
+    }
+
+    #[test]
+    fn deleted_node_keeps_mapped_children() {
+        // union(sphere, box) -> sphere, box directly under root
+        let old = scene();
+        let mut new = AstTree::new();
+        let s = new.add_node(AstNodeKind::Primitive, "sphere", 0);
+        new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        let b = new.add_node(AstNodeKind::Primitive, "box", 0);
+        new.add_node_with_value(AstNodeKind::Parameter, "w", NodeValue::Float(2.0), b);
+
+        let costs = EditCosts::default();
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 1);
+        let ops = diff_trees_exact(&old, &new, &costs);
+        assert_eq!(
+            ops.iter()
+                .filter(|o| matches!(o, DiffOp::Delete { .. }))
+                .count(),
+            1
+        );
+        assert!(!ops.iter().any(|o| matches!(o, DiffOp::Insert { .. })));
+        assert_roundtrip(&old, &new, &ops);
+    }
+
+    #[test]
+    fn exact_never_costlier_than_greedy() {
+        let old = scene();
+        let mut new = AstTree::new();
+        let u = new.add_node(AstNodeKind::CsgOp, "subtract", 0);
+        let s = new.add_node(AstNodeKind::Primitive, "ball", u);
+        new.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        let c = new.add_node(AstNodeKind::Primitive, "cube", u);
+        new.add_node_with_value(AstNodeKind::Parameter, "w", NodeValue::Float(3.0), c);
+
+        let costs = EditCosts::default();
+        let exact = diff_trees_exact(&old, &new, &costs);
+        assert_roundtrip(&old, &new, &exact);
+        assert_eq!(tree_edit_distance(&old, &new, &costs), 4);
+        assert_eq!(exact.len(), 4);
+        assert!(exact.len() <= diff_trees(&old, &new).len());
+    }
+
+    #[test]
+    fn distance_is_symmetric_under_symmetric_costs() {
+        let old = scene();
+        let mut new = AstTree::new();
+        let g = new.add_node(AstNodeKind::Group, "g", 0);
+        let b = new.add_node(AstNodeKind::Primitive, "box", g);
+        new.add_node_with_value(AstNodeKind::Parameter, "w", NodeValue::Float(2.0), b);
+        new.add_node(AstNodeKind::Primitive, "cone", 0);
+
+        let costs = EditCosts::default();
+        let d = tree_edit_distance(&old, &new, &costs);
+        assert_eq!(d, tree_edit_distance(&new, &old, &costs));
+        assert_roundtrip(&old, &new, &diff_trees_exact(&old, &new, &costs));
+        assert_roundtrip(&new, &old, &diff_trees_exact(&new, &old, &costs));
+    }
+}
@@ -1,12 +1,13 @@
 //! C-ABI FFI bindings for ALICE-VCS
 //!
-//! 20 `extern "C"` functions for AST tree, diff, commit, and repository.
+//! 22 `extern "C"` functions for AST tree, diff, commit, and repository.
 //!
 //! Author: Moroya Sakamoto
 
 use crate::ast::{AstNodeKind, AstTree, NodeValue};
 use crate::commit::Repository;
 use crate::diff::{diff_trees, patch_size_bytes};
+use crate::path::NodePath;
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 
@@ -34,6 +35,9 @@ pub struct AliceVcsDiffStats {
     pub move_count: u32,
     pub total_ops: u32,
     pub patch_bytes: u32,
+    pub retype_count: u32,
+    pub set_attr_count: u32,
+    pub remove_attr_count: u32,
 }
 
 // ============================================================================
@@ -165,6 +169,54 @@ pub unsafe extern "C" fn alice_vcs_tree_get_label(
     }
 }
 
+/// Get a node's path, e.g. `/root/union/sphere[1]/radius`. Caller must
+/// free with `alice_vcs_string_free`.
+///
+/// # Safety
+///
+/// `handle` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn alice_vcs_tree_get_path(
+    handle: AliceAstTreeHandle,
+    node_id: u32,
+) -> *mut c_char {
+    if handle.is_null() {
+        return std::ptr::null_mut();
+    }
+    let tree = unsafe { &*handle };
+    match tree.path_of(node_id) {
+        Some(path) => match CString::new(path.to_string()) {
+            Ok(c) => c.into_raw(),
+            Err(_) => std::ptr::null_mut(),
+        },
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Find the node at a path. Returns `u32::MAX` if the path is malformed
+/// or leads nowhere.
+///
+/// # Safety
+///
+/// `handle` must be valid. `path` must be null-terminated UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn alice_vcs_tree_find_path(
+    handle: AliceAstTreeHandle,
+    path: *const c_char,
+) -> u32 {
+    if handle.is_null() || path.is_null() {
+        return u32::MAX;
+    }
+    let tree = unsafe { &*handle };
+    let Ok(path_str) = unsafe { CStr::from_ptr(path) }.to_str() else {
+        return u32::MAX;
+    };
+    NodePath::parse(path_str)
+        .ok()
+        .and_then(|p| tree.node_at(&p))
+        .unwrap_or(u32::MAX)
+}
+
 /// Get node kind as u8.
 ///
 /// # Safety
@@ -196,7 +248,7 @@ pub unsafe extern "C" fn alice_vcs_tree_subtree_hash(
     tree.subtree_hash(node_id)
 }
 
-/// Remove a subtree.
+/// Remove a subtree. Does nothing if another node still refers into it.
 ///
 /// # Safety
 ///
@@ -255,6 +307,18 @@ pub unsafe extern "C" fn alice_vcs_diff(
             .count() as u32;
         (*out).total_ops = ops.len() as u32;
         (*out).patch_bytes = patch_size_bytes(&ops) as u32;
+        (*out).retype_count = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::Retype { .. }))
+            .count() as u32;
+        (*out).set_attr_count = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::SetAttr { .. }))
+            .count() as u32;
+        (*out).remove_attr_count = ops
+            .iter()
+            .filter(|o| matches!(o, DiffOp::RemoveAttr { .. }))
+            .count() as u32;
     }
     1
 }
@@ -281,7 +345,9 @@ pub unsafe extern "C" fn alice_vcs_repo_destroy(handle: AliceRepoHandle) {
     }
 }
 
-/// Commit a tree to the repository. Returns the commit hash.
+/// Commit a tree to the repository. Returns the first 8 bytes of the
+/// 256-bit commit ID (see `ContentId::prefix_u64`), or 0 if the
+/// repository schema rejects the tree.
 ///
 /// # Safety
 ///
@@ -307,9 +373,10 @@ pub unsafe extern "C" fn alice_vcs_repo_commit(
         Err(_) => return 0,
     };
     repo.commit(tree_ref, msg, auth)
+        .map_or(0, |hash| hash.prefix_u64())
 }
 
-/// Get current HEAD hash.
+/// Get the first 8 bytes of the current HEAD commit ID.
 ///
 /// # Safety
 ///
@@ -320,7 +387,7 @@ pub unsafe extern "C" fn alice_vcs_repo_head_hash(handle: AliceRepoHandle) -> u6
         return 0;
     }
     let repo = unsafe { &*handle };
-    repo.head_hash()
+    repo.head_hash().prefix_u64()
 }
 
 /// Get commit count.
@@ -433,6 +500,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)] // 3.14 is a sample radius, not PI
     fn test_tree_add_node_float() {
         let handle = alice_vcs_tree_create();
         let label = CString::new("radius").unwrap();
@@ -463,6 +531,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tree_path_roundtrip() {
+        let handle = alice_vcs_tree_create();
+        let label = CString::new("sphere").unwrap();
+        unsafe { alice_vcs_tree_add_node(handle, 2, label.as_ptr(), 0) };
+        let id = unsafe { alice_vcs_tree_add_node(handle, 2, label.as_ptr(), 0) };
+        let got = unsafe { alice_vcs_tree_get_path(handle, id) };
+        assert!(!got.is_null());
+        assert_eq!(
+            unsafe { CStr::from_ptr(got) }.to_str().unwrap(),
+            "/root/sphere[1]"
+        );
+        assert_eq!(unsafe { alice_vcs_tree_find_path(handle, got) }, id);
+        let missing = CString::new("/root/sphere[2]").unwrap();
+        let bad = CString::new("root").unwrap();
+        unsafe {
+            assert_eq!(alice_vcs_tree_find_path(handle, missing.as_ptr()), u32::MAX);
+            assert_eq!(alice_vcs_tree_find_path(handle, bad.as_ptr()), u32::MAX);
+            assert!(alice_vcs_tree_get_path(handle, 99).is_null());
+            alice_vcs_string_free(got);
+            alice_vcs_tree_destroy(handle);
+        }
+    }
+
     #[test]
     fn test_tree_get_kind() {
         let handle = alice_vcs_tree_create();
@@ -511,6 +603,9 @@ mod tests {
             move_count: 0,
             total_ops: 0,
             patch_bytes: 0,
+            retype_count: 0,
+            set_attr_count: 0,
+            remove_attr_count: 0,
         };
         let ok = unsafe { alice_vcs_diff(t1, t2, &mut stats) };
         assert_eq!(ok, 1);
@@ -521,6 +616,81 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_diff_counts_retype() {
+        let t1 = alice_vcs_tree_create();
+        let t2 = alice_vcs_tree_create();
+        let label = CString::new("sphere").unwrap();
+        unsafe {
+            alice_vcs_tree_add_node(t1, 2, label.as_ptr(), 0);
+            alice_vcs_tree_add_node(t2, 5, label.as_ptr(), 0);
+        }
+        let mut stats = AliceVcsDiffStats {
+            insert_count: 0,
+            delete_count: 0,
+            update_count: 0,
+            relabel_count: 0,
+            move_count: 0,
+            total_ops: 0,
+            patch_bytes: 0,
+            retype_count: 0,
+            set_attr_count: 0,
+            remove_attr_count: 0,
+        };
+        unsafe { alice_vcs_diff(t1, t2, &mut stats) };
+        assert_eq!(stats.retype_count, 1);
+        assert_eq!(stats.total_ops, 1);
+        unsafe {
+            alice_vcs_tree_destroy(t1);
+            alice_vcs_tree_destroy(t2);
+        }
+    }
+
+    #[test]
+    fn test_diff_counts_attr_edits() {
+        let mut old = AstTree::new();
+        let s = old.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let attrs = &mut old.get_node_mut(s).unwrap().attrs;
+        attrs.insert("color".into(), NodeValue::Int(1));
+        attrs.insert("tag".into(), NodeValue::Int(2));
+        let mut new = old.clone();
+        let attrs = &mut new.get_node_mut(s).unwrap().attrs;
+        attrs.insert("color".into(), NodeValue::Int(3));
+        attrs.remove("tag");
+        let t1 = Box::into_raw(Box::new(old));
+        let t2 = Box::into_raw(Box::new(new));
+        let mut stats = AliceVcsDiffStats {
+            insert_count: 0,
+            delete_count: 0,
+            update_count: 0,
+            relabel_count: 0,
+            move_count: 0,
+            total_ops: 0,
+            patch_bytes: 0,
+            retype_count: 0,
+            set_attr_count: 0,
+            remove_attr_count: 0,
+        };
+        unsafe { alice_vcs_diff(t1, t2, &mut stats) };
+        assert_eq!(stats.set_attr_count, 1);
+        assert_eq!(stats.remove_attr_count, 1);
+        assert_eq!(stats.total_ops, 2);
+        unsafe {
+            alice_vcs_tree_destroy(t1);
+            alice_vcs_tree_destroy(t2);
+        }
+    }
+
+    #[test]
+    fn test_diff_stats_layout_keeps_original_offsets() {
+        use core::mem::offset_of;
+        assert_eq!(offset_of!(AliceVcsDiffStats, total_ops), 20);
+        assert_eq!(offset_of!(AliceVcsDiffStats, patch_bytes), 24);
+        assert_eq!(offset_of!(AliceVcsDiffStats, retype_count), 28);
+        assert_eq!(offset_of!(AliceVcsDiffStats, set_attr_count), 32);
+        assert_eq!(offset_of!(AliceVcsDiffStats, remove_attr_count), 36);
+    }
+
     #[test]
     fn test_diff_with_insert() {
         let t1 = alice_vcs_tree_create();
@@ -535,6 +705,9 @@ mod tests {
             move_count: 0,
             total_ops: 0,
             patch_bytes: 0,
+            retype_count: 0,
+            set_attr_count: 0,
+            remove_attr_count: 0,
         };
         unsafe { alice_vcs_diff(t1, t2, &mut stats) };
         assert_eq!(stats.insert_count, 1);
@@ -12,7 +12,8 @@ use alloc::vec::Vec;
 #[cfg(feature = "std")]
 use std::collections::HashSet;
 
-use crate::store::{Hash, SnapshotStore};
+use crate::content_id::ContentId;
+use crate::store::SnapshotStore;
 
 // ── GC Result ──────────────────────────────────────────────────────────
 
@@ -49,7 +50,7 @@ impl GcResult {
 /// 1. **Mark**: BFS from each root hash, following parent links.
 ///    All visited hashes are added to the reachable set.
 /// 2. **Sweep**: Remove all snapshots not in the reachable set.
-pub fn collect_garbage(store: &mut SnapshotStore, root_hashes: &[Hash]) -> GcResult {
+pub fn collect_garbage(store: &mut SnapshotStore, root_hashes: &[ContentId]) -> GcResult {
     let all_hashes = store.all_hashes();
     let total_before = all_hashes.len();
 
@@ -73,9 +74,9 @@ pub fn collect_garbage(store: &mut SnapshotStore, root_hashes: &[Hash]) -> GcRes
 }
 
 /// Mark all reachable snapshots via BFS from root hashes.
-fn mark(store: &SnapshotStore, root_hashes: &[Hash]) -> HashSet<Hash> {
+fn mark(store: &SnapshotStore, root_hashes: &[ContentId]) -> HashSet<ContentId> {
     let mut reachable = HashSet::new();
-    let mut queue: Vec<Hash> = Vec::new();
+    let mut queue: Vec<ContentId> = Vec::new();
 
     // Seed with roots
     for &root in root_hashes {
@@ -102,7 +103,7 @@ fn mark(store: &SnapshotStore, root_hashes: &[Hash]) -> HashSet<Hash> {
 
 /// Dry-run: compute what would be collected without actually removing.
 #[must_use]
-pub fn dry_run(store: &SnapshotStore, root_hashes: &[Hash]) -> GcResult {
+pub fn dry_run(store: &SnapshotStore, root_hashes: &[ContentId]) -> GcResult {
     let total_before = store.len();
     let reachable = mark(store, root_hashes);
     let retained = reachable.len();
@@ -245,8 +246,8 @@ mod tests {
         let t = make_tree("x");
         let h = store.store(&t, vec![]);
 
-        // Root 0xDEAD doesn't exist in store — should not panic
-        let result = collect_garbage(&mut store, &[0xDEAD]);
+        // Root doesn't exist in store — should not panic
+        let result = collect_garbage(&mut store, &[ContentId([0xAD; 32])]);
         assert_eq!(result.collected, 1); // h is unreachable
         assert!(!store.contains(h));
     }
@@ -18,20 +18,26 @@
 //! Semantic diff and merge for tree-structured procedural data:
 //! - AST node-level diff (12-byte patches vs 50 KB binary diffs)
 //! - Structural 3-way merge with conflict detection
-//! - Content-addressed snapshot store (Merkle DAG)
+//! - Content-addressed snapshot store (Merkle DAG, SHA-256 IDs)
 //! - Commit/branch model for procedural collaboration
 //!
 //! # Modules
 //!
 //! | Module | Description |
 //! |--------|-------------|
-//! | [`ast`] | Generic AST tree with node kinds, values, and O(1) lookup |
-//! | [`codec`] | Binary patch encoding/decoding (4-12 bytes per op) |
+//! | [`ast`] | Generic AST tree with node kinds, typed values (scalars, vectors, matrices, lists), attributes, O(1) lookup and invariant checks |
+//! | [`codec`] | Binary patch encoding/decoding (typically 4-12 bytes per op) |
 //! | [`commit`] | Commit, branch, and repository model |
-//! | [`diff`] | Minimal AST diff engine (Insert, Delete, Update, Move, Relabel) |
+//! | [`content_id`] | 256-bit SHA-256 content IDs with short-hex display |
+//! | [`diff`] | Minimal AST diff engine (Insert, Delete, Update, Move, Relabel, Retype, attributes) |
+//! | [`edit_distance`] | Exact Zhang–Shasha tree edit distance and optimal diff |
 //! | [`gc`] | Garbage collection for unreachable snapshots |
 //! | [`merge`] | Structural 3-way merge with conflict detection |
+//! | [`patch`] | Patch algebra: inversion for undo/revert, composition, normalization |
+//! | [`path`] | Label paths like `/root/union/sphere[1]/radius` for addressing nodes |
+//! | [`schema`] | Per-kind rules for children, parameters and value types; tree validation |
 //! | [`store`] | Content-addressed Merkle DAG snapshot store |
+//! | [`traverse`] | Non-recursive tree iterators and enter/leave visitors |
 //!
 //! # Feature flags
 //!
@@ -74,21 +80,36 @@ extern crate alloc;
 pub mod ast;
 pub mod codec;
 pub mod commit;
+pub mod content_id;
 pub mod diff;
+pub mod edit_distance;
 #[cfg(feature = "ffi")]
 pub mod ffi;
 pub mod gc;
 pub mod merge;
+pub mod patch;
+pub mod path;
 pub mod resolve;
+pub mod schema;
 pub mod store;
+pub mod traverse;
 
-pub use ast::{AstNode, AstNodeKind, AstTree, NodeId, NodeValue};
+pub use ast::{AstNode, AstNodeKind, AstTree, InvariantError, InvariantKind, NodeId, NodeValue};
 pub use codec::{decode_patch, encode_patch, encoded_patch_size};
 pub use commit::{Branch, Commit, Repository};
-pub use diff::{diff_trees, DiffOp};
+pub use content_id::ContentId;
+pub use diff::{
+    describe_patch, diff_trees, diff_trees_with, try_apply_patch, ApplyError, ApplyErrorKind,
+    DiffOp, DiffOptions,
+};
+pub use edit_distance::{diff_trees_exact, tree_edit_distance, EditCosts};
 pub use gc::{collect_garbage, dry_run, GcResult};
-pub use merge::{merge_patches, Conflict, MergeResult};
+pub use merge::{merge_patches, merge_patches_with_base, Conflict, MergeResult};
+pub use patch::{compose_patches, invert_patch, normalize_patch, patches_equivalent};
+pub use path::{NodePath, PathError, PathErrorKind, PathSegment};
 pub use resolve::{
     resolve_all, resolve_conflict, resolve_merge, resolve_selective, ResolutionStrategy,
 };
+pub use schema::{NodeRule, Schema, ValueType, Violation, ViolationKind};
 pub use store::{Hash, SnapshotStore};
+pub use traverse::Visitor;
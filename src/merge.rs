@@ -2,25 +2,38 @@
 //!
 //! Merges patches from two branches against a common ancestor.
 //! Non-overlapping subtree edits merge cleanly; overlapping
-//! edits on the same node produce conflicts.
+//! edits on the same node produce conflicts. Attributes are merged one
+//! key at a time, so edits to different attributes of a node combine.
+//! A reference one branch writes to a node the other branch deletes is a
+//! conflict too.
 //!
 //! Author: Moroya Sakamoto
 
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
 #[cfg(not(feature = "std"))]
 use alloc::collections::BTreeSet as HashSet;
 #[cfg(not(feature = "std"))]
-use alloc::{string::String, vec::Vec};
+use alloc::{format, string::String, vec, vec::Vec};
 #[cfg(feature = "std")]
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+
+use core::fmt;
 
-use crate::ast::NodeId;
-use crate::diff::DiffOp;
+use crate::ast::{AstTree, NodeId};
+use crate::diff::{apply_patch, DiffOp};
+use crate::patch::{compose_patches, normalize_patch};
+use crate::path::NodePath;
+use crate::schema::Violation;
 
 /// Merge conflict
 #[derive(Debug, Clone)]
 pub struct Conflict {
     /// Node that has conflicting edits
     pub node_id: NodeId,
+    /// Path of that node in the common ancestor — known only when
+    /// merging against a base ([`merge_patches_with_base`])
+    pub path: Option<NodePath>,
     /// Description of the conflict
     pub description: String,
     /// Operations from branch A
@@ -29,6 +42,15 @@ pub struct Conflict {
     pub ops_b: Vec<DiffOp>,
 }
 
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{path}: {}", self.description),
+            None => write!(f, "node {}: {}", self.node_id, self.description),
+        }
+    }
+}
+
 /// Merge result
 #[derive(Debug)]
 pub struct MergeResult {
@@ -36,13 +58,16 @@ pub struct MergeResult {
     pub merged_ops: Vec<DiffOp>,
     /// Conflicts that need manual resolution
     pub conflicts: Vec<Conflict>,
+    /// Schema violations of the merged tree; only
+    /// [`Repository::merge`](crate::Repository::merge) checks them
+    pub violations: Vec<Violation>,
 }
 
 impl MergeResult {
-    /// True if merge is clean (no conflicts)
+    /// True if merge is clean (no conflicts, no schema violations)
     #[must_use]
     pub const fn is_clean(&self) -> bool {
-        self.conflicts.is_empty()
+        self.conflicts.is_empty() && self.violations.is_empty()
     }
 }
 
@@ -52,84 +77,430 @@ impl MergeResult {
 /// `patch_b`: operations from branch B (relative to common ancestor)
 ///
 /// Non-overlapping edits are combined. Overlapping edits on the
-/// same node produce Conflict entries.
+/// same node produce Conflict entries. Attribute edits overlap only
+/// with edits of the same attribute, or with a Delete of the node. An op
+/// writing a `NodeValue::Ref` to a node the other patch deletes conflicts
+/// with that Delete; without the ancestor tree only direct Deletes of the
+/// target are seen, see [`merge_patches_with_base`].
+///
+/// Both branches number their inserted nodes from the ancestor's next free
+/// ID, so the same ID can name two different new nodes. Such inserts in
+/// `patch_b` (and every reference to them) are renumbered past all IDs
+/// either patch inserts before merging.
+///
+/// Merged ops keep their order: `patch_a`'s first, then `patch_b`'s,
+/// including ops that auto-resolve. Insert and Move indices are kept as
+/// they are; without the ancestor's child lists they cannot be rebased, so
+/// they may be off wherever the other branch changed the same list.
 #[must_use]
 pub fn merge_patches(patch_a: &[DiffOp], patch_b: &[DiffOp]) -> MergeResult {
-    let mut merged_ops = Vec::new();
+    merge(patch_a, patch_b, None)
+}
+
+/// Merge patches from two branches, normalized against their common
+/// ancestor first
+///
+/// Both patches go through [`normalize_patch`], so branches that made the
+/// same change — in a different op order, or through redundant steps —
+/// auto-resolve instead of conflicting. The ancestor also shows which
+/// nodes a Delete removes with its subtree, so a reference to any of them
+/// from the other branch is flagged.
+///
+/// Insert and Move indices are rebased onto the tree the merged ops leave:
+/// each node lands after the sibling its own branch put it after, or the
+/// nearest earlier sibling the other branch kept.
+#[must_use]
+pub fn merge_patches_with_base(
+    ancestor: &AstTree,
+    patch_a: &[DiffOp],
+    patch_b: &[DiffOp],
+) -> MergeResult {
+    merge(
+        &normalize_patch(ancestor, patch_a),
+        &normalize_patch(ancestor, patch_b),
+        Some(ancestor),
+    )
+}
+
+fn merge(patch_a: &[DiffOp], patch_b: &[DiffOp], ancestor: Option<&AstTree>) -> MergeResult {
+    let patch_b = &renumber_clashing_inserts(patch_a, patch_b);
+    // Merged ops keyed by their place in A followed by B, so an op that
+    // depends on an earlier one (a child's Insert on its parent's) still
+    // follows it when the earlier one auto-resolves
+    let mut merged: Vec<(usize, DiffOp)> = Vec::new();
     let mut conflicts = Vec::new();
 
-    // Index: which nodes are affected by each patch — O(1) HashSet lookup
-    let affected_a = affected_nodes(patch_a);
-    let affected_b = affected_nodes(patch_b);
+    // Index: what each patch touches — O(1) HashSet lookup
+    let touched_a = Touched::new(patch_a, ancestor);
+    let touched_b = Touched::new(patch_b, ancestor);
+    // Deleted subtree roots the other branch refers into
+    let mut cut: HashSet<NodeId> = HashSet::new();
+
+    // Overlapping ops, grouped by the node or attribute they fight over
+    let mut groups: Vec<Group<'_>> = Vec::new();
+    let mut group_of: HashMap<Target<'_>, usize> = HashMap::new();
+    let mut overlap = |target, order, op: &DiffOp, from_a| {
+        // A Delete pulls every overlapping op on its node into one group
+        let (node, _) = target;
+        let key = if touched_a.deleted.contains(&node) || touched_b.deleted.contains(&node) {
+            (node, None)
+        } else {
+            target
+        };
+        let g = *group_of.entry(key).or_insert_with(|| {
+            groups.push((key, Vec::new(), Vec::new(), Vec::new()));
+            groups.len() - 1
+        });
+        if from_a {
+            groups[g].1.push(op.clone());
+            groups[g].3.push(order);
+        } else {
+            groups[g].2.push(op.clone());
+        }
+    };
+
+    // Deletes inside a subtree the other branch deletes whole
+    let mut subsumed: Vec<(NodeId, usize, &DiffOp, bool)> = Vec::new();
 
     // Non-conflicting ops from A
-    for op in patch_a {
-        let node = op_target_node(op);
-        if !affected_b.contains(&node) {
-            merged_ops.push(op.clone());
+    for (order, op) in patch_a.iter().enumerate() {
+        let target = op_target(op);
+        if let Some(root) = cut_reference(op, &touched_a, &touched_b) {
+            cut.insert(root);
+            overlap((root, None), order, op, true);
+        } else if let Some(root) = subsumed_delete(op, &touched_b) {
+            subsumed.push((root, order, op, true));
+        } else if let Some(root) = edit_in_deleted(op, &touched_a, &touched_b) {
+            overlap((root, None), order, op, true);
+        } else if touched_b.overlaps(op, target) {
+            overlap(target, order, op, true);
+        } else {
+            merged.push((order, op.clone()));
         }
     }
 
     // Non-conflicting ops from B
-    for op in patch_b {
-        let node = op_target_node(op);
-        if !affected_a.contains(&node) {
-            merged_ops.push(op.clone());
+    for (order, op) in (patch_a.len()..).zip(patch_b) {
+        let target = op_target(op);
+        if let Some(root) = cut_reference(op, &touched_b, &touched_a) {
+            cut.insert(root);
+            overlap((root, None), order, op, false);
+        } else if let Some(root) = subsumed_delete(op, &touched_a) {
+            subsumed.push((root, order, op, false));
+        } else if let Some(root) = edit_in_deleted(op, &touched_b, &touched_a) {
+            overlap((root, None), order, op, false);
+        } else if touched_a.overlaps(op, target) {
+            overlap(target, order, op, false);
+        } else {
+            merged.push((order, op.clone()));
         }
     }
 
-    // Conflicting nodes
-    for &node_id in &affected_a {
-        if affected_b.contains(&node_id) {
-            let ops_a: Vec<_> = patch_a
-                .iter()
-                .filter(|o| op_target_node(o) == node_id)
-                .cloned()
-                .collect();
-            let ops_b: Vec<_> = patch_b
-                .iter()
-                .filter(|o| op_target_node(o) == node_id)
-                .cloned()
-                .collect();
-
-            // Check if both patches do the same thing (auto-resolve)
-            if ops_a == ops_b {
-                merged_ops.extend(ops_a);
+    // A subsumed Delete joins its subtree's conflict, if any; otherwise the
+    // larger Delete covers it
+    for (root, order, op, from_a) in subsumed {
+        if let Some(&g) = group_of.get(&(root, None)) {
+            let (_, ops_a, ops_b, orders_a) = &mut groups[g];
+            if from_a {
+                ops_a.push(op.clone());
+                orders_a.push(order);
             } else {
-                conflicts.push(Conflict {
-                    node_id,
-                    description: String::from("conflicting edits on same node"),
-                    ops_a,
-                    ops_b,
-                });
+                ops_b.push(op.clone());
             }
         }
     }
 
+    // Conflicting nodes and attributes
+    for ((node_id, key), ops_a, ops_b, orders_a) in groups {
+        // Check if both patches do the same thing (auto-resolve)
+        if ops_a == ops_b || compose_patches(&ops_a, &[]) == compose_patches(&ops_b, &[]) {
+            merged.extend(orders_a.into_iter().zip(ops_a));
+        } else {
+            let description = match key {
+                Some(key) => format!("conflicting edits on attribute {key:?}"),
+                None if cut.contains(&node_id) => {
+                    String::from("node deleted on one branch is referenced on the other")
+                }
+                None => String::from("conflicting edits on same node"),
+            };
+            conflicts.push(Conflict {
+                node_id,
+                path: ancestor.and_then(|t| t.path_of(node_id)),
+                description,
+                ops_a,
+                ops_b,
+            });
+        }
+    }
+
+    merged.sort_by_key(|&(order, _)| order);
+    if let Some(ancestor) = ancestor {
+        rebase_positions(ancestor, patch_a, patch_b, &mut merged);
+    }
     MergeResult {
-        merged_ops,
+        merged_ops: merged.into_iter().map(|(_, op)| op).collect(),
         conflicts,
+        violations: Vec::new(),
+    }
+}
+
+/// Rewrite the index of every merged Insert and Move so it lands where
+/// its own branch put it, in the tree the merged ops before it leave.
+///
+/// Each branch is replayed on its own copy of the ancestor alongside the
+/// merged result. An op's position is taken as "after the sibling its
+/// branch had before it"; when the other branch deleted or moved that
+/// sibling away, the nearest earlier one still present is used instead.
+fn rebase_positions(
+    ancestor: &AstTree,
+    patch_a: &[DiffOp],
+    patch_b: &[DiffOp],
+    merged: &mut [(usize, DiffOp)],
+) {
+    let mut views = [ancestor.clone(), ancestor.clone()];
+    let mut result = ancestor.clone();
+    let mut pending = merged.iter_mut().peekable();
+    for (order, op) in patch_a.iter().chain(patch_b).enumerate() {
+        let view = &mut views[usize::from(order >= patch_a.len())];
+        if let Some((_, merged_op)) = pending.next_if(|(o, _)| *o == order) {
+            let placement = match merged_op {
+                DiffOp::Insert {
+                    node_id,
+                    parent_id,
+                    index,
+                    ..
+                } => Some((*node_id, *parent_id, index)),
+                DiffOp::Move {
+                    node_id,
+                    new_parent_id,
+                    new_index,
+                } => Some((*node_id, *new_parent_id, new_index)),
+                _ => None,
+            };
+            if let Some((node_id, parent_id, index)) = placement {
+                *index = rebased_index(view, &result, node_id, parent_id, *index);
+            }
+            apply_patch(&mut result, core::slice::from_ref(merged_op));
+        }
+        apply_patch(view, core::slice::from_ref(op));
+    }
+}
+
+/// Index in `result` that puts `node_id` under `parent_id` after the same
+/// sibling as `index` does in `view`. Both child lists are taken without
+/// `node_id`, as a Move's index is.
+fn rebased_index(
+    view: &AstTree,
+    result: &AstTree,
+    node_id: NodeId,
+    parent_id: NodeId,
+    index: usize,
+) -> usize {
+    let siblings = |tree: &AstTree| -> Vec<NodeId> {
+        tree.get_node(parent_id)
+            .map(|p| {
+                p.children
+                    .iter()
+                    .copied()
+                    .filter(|&c| c != node_id)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let before = siblings(view);
+    let anchor = before[..index.min(before.len())]
+        .iter()
+        .rev()
+        .find(|&&c| result.parent_of(c) == Some(parent_id));
+    anchor.map_or(0, |anchor| {
+        siblings(result)
+            .iter()
+            .position(|c| c == anchor)
+            .map_or(0, |i| i + 1)
+    })
+}
+
+/// Copy of `patch_b` in which nodes inserted under an ID that `patch_a`
+/// uses for a different insert get fresh IDs.
+fn renumber_clashing_inserts(patch_a: &[DiffOp], patch_b: &[DiffOp]) -> Vec<DiffOp> {
+    let inserts_a: HashMap<NodeId, &DiffOp> = patch_a
+        .iter()
+        .filter_map(|op| match op {
+            DiffOp::Insert { node_id, .. } => Some((*node_id, op)),
+            _ => None,
+        })
+        .collect();
+    let mut next = patch_a
+        .iter()
+        .chain(patch_b)
+        .filter_map(|op| match op {
+            DiffOp::Insert { node_id, .. } => Some(node_id.saturating_add(1)),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut renumbered: HashMap<NodeId, NodeId> = HashMap::new();
+    for op in patch_b {
+        if let DiffOp::Insert { node_id, .. } = op {
+            if inserts_a.get(node_id).is_some_and(|a| *a != op) {
+                renumbered.insert(*node_id, next);
+                next = next.saturating_add(1);
+            }
+        }
+    }
+
+    let mut ops = patch_b.to_vec();
+    if !renumbered.is_empty() {
+        for op in &mut ops {
+            op.map_node_ids(|id| renumbered.get(&id).copied().unwrap_or(id));
+        }
     }
+    ops
 }
 
-/// Get the target node of an operation
-const fn op_target_node(op: &DiffOp) -> NodeId {
+/// What an op edits: a node, or one attribute of it
+type Target<'a> = (NodeId, Option<&'a str>);
+
+/// Ops of both patches that fight over one target, with the merge keys of
+/// A's ops
+type Group<'a> = (Target<'a>, Vec<DiffOp>, Vec<DiffOp>, Vec<usize>);
+
+/// Get the target of an operation
+const fn op_target(op: &DiffOp) -> Target<'_> {
     match op {
-        DiffOp::Insert { parent_id, .. } => *parent_id,
+        DiffOp::Insert { parent_id, .. } => (*parent_id, None),
         DiffOp::Delete { node_id }
         | DiffOp::Update { node_id, .. }
         | DiffOp::Relabel { node_id, .. }
-        | DiffOp::Move { node_id, .. } => *node_id,
+        | DiffOp::Move { node_id, .. }
+        | DiffOp::Retype { node_id, .. } => (*node_id, None),
+        DiffOp::SetAttr { node_id, key, .. } | DiffOp::RemoveAttr { node_id, key, .. } => {
+            (*node_id, Some(key.as_str()))
+        }
     }
 }
 
-/// Collect all unique node IDs affected by a patch — O(1) insert via `HashSet`
-fn affected_nodes(ops: &[DiffOp]) -> HashSet<NodeId> {
-    let mut nodes = HashSet::new();
-    for op in ops {
-        nodes.insert(op_target_node(op));
+/// Nodes whose ID `op` writes into a value as a reference
+fn written_refs(op: &DiffOp) -> Vec<NodeId> {
+    match op {
+        DiffOp::Insert { value, .. }
+        | DiffOp::Update {
+            new_value: value, ..
+        }
+        | DiffOp::SetAttr {
+            new_value: value, ..
+        } => value.refs(),
+        _ => Vec::new(),
+    }
+}
+
+/// Root of a subtree that is deleted on one branch and referred into on
+/// the other, if `op` (from the patch `own`) is either side of that.
+fn cut_reference(op: &DiffOp, own: &Touched<'_>, other: &Touched<'_>) -> Option<NodeId> {
+    if let DiffOp::Delete { node_id } = op {
+        if other
+            .refs
+            .iter()
+            .any(|target| own.removed.get(target) == Some(node_id))
+        {
+            return Some(*node_id);
+        }
+    }
+    written_refs(op)
+        .into_iter()
+        .find_map(|target| other.removed.get(&target).copied())
+}
+
+/// Root of a subtree that is deleted on one branch while the other edits,
+/// moves or inserts inside it, if `op` (from the patch `own`) is either
+/// side of that.
+fn edit_in_deleted(op: &DiffOp, own: &Touched<'_>, other: &Touched<'_>) -> Option<NodeId> {
+    if let DiffOp::Delete { node_id } = op {
+        return other
+            .edited
+            .iter()
+            .any(|n| own.removed.get(n) == Some(node_id))
+            .then_some(*node_id);
+    }
+    edited_nodes(op).find_map(|n| other.removed.get(&n).copied())
+}
+
+/// Root of the larger subtree the other branch deletes, if `op` deletes a
+/// node strictly inside it.
+fn subsumed_delete(op: &DiffOp, other: &Touched<'_>) -> Option<NodeId> {
+    match op {
+        DiffOp::Delete { node_id } => other.removed.get(node_id).copied().filter(|r| r != node_id),
+        _ => None,
+    }
+}
+
+/// Nodes a non-Delete op changes or puts something under: its target,
+/// and a Move's new parent.
+fn edited_nodes(op: &DiffOp) -> impl Iterator<Item = NodeId> {
+    let new_parent = match op {
+        DiffOp::Move { new_parent_id, .. } => Some(*new_parent_id),
+        _ => None,
+    };
+    core::iter::once(op_target(op).0).chain(new_parent)
+}
+
+/// Everything one patch touches
+struct Touched<'a> {
+    targets: HashSet<Target<'a>>,
+    nodes: HashSet<NodeId>,
+    /// Nodes of every op but Delete, as [`edited_nodes`] lists them
+    edited: HashSet<NodeId>,
+    deleted: HashSet<NodeId>,
+    /// Every node a Delete removes → the root that Delete names; only the
+    /// root itself without an ancestor tree
+    removed: HashMap<NodeId, NodeId>,
+    /// Nodes the patch writes references to
+    refs: HashSet<NodeId>,
+}
+
+impl<'a> Touched<'a> {
+    /// Collect the targets of a patch — O(1) insert via `HashSet`
+    fn new(ops: &'a [DiffOp], ancestor: Option<&AstTree>) -> Self {
+        let mut touched = Self {
+            targets: HashSet::new(),
+            nodes: HashSet::new(),
+            edited: HashSet::new(),
+            deleted: HashSet::new(),
+            removed: HashMap::new(),
+            refs: HashSet::new(),
+        };
+        for op in ops {
+            let target = op_target(op);
+            touched.targets.insert(target);
+            touched.nodes.insert(target.0);
+            touched.refs.extend(written_refs(op));
+            if !matches!(op, DiffOp::Delete { .. }) {
+                touched.edited.extend(edited_nodes(op));
+            }
+            if let DiffOp::Delete { node_id } = op {
+                touched.deleted.insert(*node_id);
+                let mut stack = vec![*node_id];
+                while let Some(id) = stack.pop() {
+                    touched.removed.insert(id, *node_id);
+                    if let Some(node) = ancestor.and_then(|t| t.get_node(id)) {
+                        stack.extend(&node.children);
+                    }
+                }
+            }
+        }
+        touched
+    }
+
+    /// True if `op`, with target `target`, overlaps an edit in this patch:
+    /// the same target, a Delete that removes its node, or — for a Delete
+    /// — any edit of the node.
+    fn overlaps(&self, op: &DiffOp, target: Target<'_>) -> bool {
+        let (node, _) = target;
+        self.targets.contains(&target)
+            || self.removed.contains_key(&node)
+            || (matches!(op, DiffOp::Delete { .. }) && self.nodes.contains(&node))
     }
-    nodes
 }
 
 #[cfg(test)]
@@ -281,6 +652,7 @@ mod tests {
         // Inserts go to the same parent but are different nodes; parent_id is the target
         // node for Insert, so both land on parent 0 -> they conflict under current model
         let patch_a = vec![DiffOp::Insert {
+            node_id: 3,
             parent_id: 1,
             index: 0,
             kind: AstNodeKind::Primitive,
@@ -288,6 +660,7 @@ mod tests {
             value: NodeValue::None,
         }];
         let patch_b = vec![DiffOp::Insert {
+            node_id: 4,
             parent_id: 2,
             index: 0,
             kind: AstNodeKind::Primitive,
@@ -323,4 +696,508 @@ mod tests {
             matches!(&c.ops_b[0], DiffOp::Update { new_value, .. } if *new_value == NodeValue::Float(99.0))
         );
     }
+
+    // ── Insert ID clashes ──────────────────────────────────────────────
+
+    fn insert(node_id: NodeId, parent_id: NodeId, label: &str) -> DiffOp {
+        DiffOp::Insert {
+            node_id,
+            parent_id,
+            index: 0,
+            kind: AstNodeKind::Primitive,
+            label: String::from(label),
+            value: NodeValue::None,
+        }
+    }
+
+    #[test]
+    fn test_merge_renumbers_clashing_insert_ids() {
+        // Both branches create node 3 (with a child 4) under different parents
+        let patch_a = vec![insert(3, 1, "sphere"), insert(4, 3, "r")];
+        let patch_b = vec![insert(3, 2, "box"), insert(4, 3, "w")];
+        let result = merge_patches(&patch_a, &patch_b);
+        assert!(result.is_clean());
+        assert_eq!(
+            result.merged_ops,
+            vec![
+                insert(3, 1, "sphere"),
+                insert(4, 3, "r"),
+                insert(5, 2, "box"),
+                insert(6, 5, "w"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_conflicting_retypes() {
+        let retype = |new_kind| DiffOp::Retype {
+            node_id: 2,
+            old_kind: AstNodeKind::Primitive,
+            new_kind,
+        };
+        let result = merge_patches(&[retype(AstNodeKind::Group)], &[retype(AstNodeKind::CsgOp)]);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].node_id, 2);
+
+        let same = merge_patches(&[retype(AstNodeKind::Group)], &[retype(AstNodeKind::Group)]);
+        assert!(same.is_clean());
+        assert_eq!(same.merged_ops, vec![retype(AstNodeKind::Group)]);
+    }
+
+    #[test]
+    fn test_merge_retype_conflicts_with_other_edit_of_node() {
+        let patch_a = vec![DiffOp::Retype {
+            node_id: 2,
+            old_kind: AstNodeKind::Primitive,
+            new_kind: AstNodeKind::Group,
+        }];
+        let patch_b = vec![DiffOp::Delete { node_id: 2 }];
+        assert!(!merge_patches(&patch_a, &patch_b).is_clean());
+    }
+
+    #[test]
+    fn test_merge_identical_inserts_keep_their_id() {
+        let patch_a = vec![insert(3, 1, "sphere")];
+        let patch_b = vec![insert(3, 1, "sphere")];
+        let result = merge_patches(&patch_a, &patch_b);
+        assert!(result.is_clean());
+        assert_eq!(result.merged_ops, vec![insert(3, 1, "sphere")]);
+    }
+
+    #[test]
+    fn test_merge_renumbered_inserts_apply_cleanly() {
+        use crate::ast::AstTree;
+        use crate::diff::{apply_patch, diff_trees};
+
+        let mut base = AstTree::new();
+        let g1 = base.add_node(AstNodeKind::Group, "g1", 0);
+        let g2 = base.add_node(AstNodeKind::Group, "g2", 0);
+
+        let mut a = base.clone();
+        let s = a.add_node(AstNodeKind::Primitive, "sphere", g1);
+        a.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        let mut b = base.clone();
+        let bx = b.add_node(AstNodeKind::Primitive, "box", g2);
+        b.add_node_with_value(AstNodeKind::Parameter, "w", NodeValue::Float(2.0), bx);
+
+        let result = merge_patches(&diff_trees(&base, &a), &diff_trees(&base, &b));
+        assert!(result.is_clean());
+        let mut merged = base.clone();
+        apply_patch(&mut merged, &result.merged_ops);
+        assert_eq!(merged.node_count(), 7);
+        assert_eq!(merged.subtree_hash(g1), a.subtree_hash(g1));
+        assert_eq!(merged.subtree_hash(g2), b.subtree_hash(g2));
+    }
+
+    // ── Normalized auto-resolution ──
+
+    fn update(node_id: NodeId, old: f64, new: f64) -> DiffOp {
+        DiffOp::Update {
+            node_id,
+            old_value: NodeValue::Float(old),
+            new_value: NodeValue::Float(new),
+        }
+    }
+
+    #[test]
+    fn test_merge_auto_resolves_squashable_updates() {
+        let patch_a = vec![update(1, 1.0, 2.0), update(1, 2.0, 3.0)];
+        let patch_b = vec![update(1, 1.0, 3.0)];
+        let result = merge_patches(&patch_a, &patch_b);
+        assert!(result.is_clean());
+    }
+
+    #[test]
+    fn test_merge_with_base_resolves_reordered_changes() {
+        let mut base = AstTree::new();
+        let s =
+            base.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), 0);
+        let relabel = DiffOp::Relabel {
+            node_id: s,
+            old_label: String::from("sphere"),
+            new_label: String::from("ball"),
+        };
+        let patch_a = vec![relabel.clone(), update(s, 1.0, 2.0)];
+        let patch_b = vec![update(s, 1.0, 2.0), relabel];
+
+        assert!(!merge_patches(&patch_a, &patch_b).is_clean());
+        let result = merge_patches_with_base(&base, &patch_a, &patch_b);
+        assert!(result.is_clean());
+        assert_eq!(result.merged_ops, patch_a);
+    }
+
+    #[test]
+    fn test_merge_with_base_still_conflicts_on_different_changes() {
+        let mut base = AstTree::new();
+        let s =
+            base.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), 0);
+        let result = merge_patches_with_base(&base, &[update(s, 1.0, 2.0)], &[update(s, 1.0, 3.0)]);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(
+            format!("{}", result.conflicts[0]),
+            "/root/sphere: conflicting edits on same node"
+        );
+        // Without a base only the ID is known
+        let result = merge_patches(&[update(s, 1.0, 2.0)], &[update(s, 1.0, 3.0)]);
+        assert!(result.conflicts[0].path.is_none());
+        assert_eq!(
+            format!("{}", result.conflicts[0]),
+            format!("node {s}: conflicting edits on same node")
+        );
+    }
+
+    // ── Attributes ──
+
+    fn set_attr(node_id: NodeId, key: &str, old: f64, new: f64) -> DiffOp {
+        DiffOp::SetAttr {
+            node_id,
+            key: String::from(key),
+            old_value: Some(NodeValue::Float(old)),
+            new_value: NodeValue::Float(new),
+        }
+    }
+
+    #[test]
+    fn test_merge_different_attrs_of_same_node() {
+        let patch_a = vec![set_attr(2, "width", 1.0, 2.0)];
+        let patch_b = vec![set_attr(2, "height", 1.0, 3.0), update(2, 0.0, 1.0)];
+        let result = merge_patches(&patch_a, &patch_b);
+        assert!(result.is_clean());
+        assert_eq!(result.merged_ops.len(), 3);
+    }
+
+    #[test]
+    fn test_merge_same_attr_conflicts() {
+        let patch_a = vec![
+            set_attr(2, "width", 1.0, 2.0),
+            set_attr(2, "depth", 1.0, 2.0),
+        ];
+        let patch_b = vec![
+            set_attr(2, "width", 1.0, 3.0),
+            set_attr(2, "depth", 1.0, 2.0),
+        ];
+        let result = merge_patches(&patch_a, &patch_b);
+        assert_eq!(result.conflicts.len(), 1);
+        let conflict = &result.conflicts[0];
+        assert_eq!(conflict.node_id, 2);
+        assert_eq!(
+            conflict.description,
+            "conflicting edits on attribute \"width\""
+        );
+        assert_eq!(conflict.ops_a, vec![set_attr(2, "width", 1.0, 2.0)]);
+        // The identical "depth" edit auto-resolves
+        assert_eq!(result.merged_ops, vec![set_attr(2, "depth", 1.0, 2.0)]);
+    }
+
+    #[test]
+    fn test_merge_attr_edit_conflicts_with_delete() {
+        let patch_a = vec![
+            set_attr(2, "width", 1.0, 2.0),
+            set_attr(2, "height", 1.0, 2.0),
+        ];
+        let patch_b = vec![DiffOp::Delete { node_id: 2 }];
+        let result = merge_patches(&patch_a, &patch_b);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].ops_a, patch_a);
+        assert_eq!(result.conflicts[0].ops_b, patch_b);
+        assert!(result.merged_ops.is_empty());
+    }
+
+    #[test]
+    fn test_merge_with_base_combines_attr_edits() {
+        let mut base = AstTree::new();
+        let b = base.add_node(AstNodeKind::Primitive, "box", 0);
+        let attrs = &mut base.get_node_mut(b).unwrap().attrs;
+        attrs.insert(String::from("width"), NodeValue::Float(1.0));
+        attrs.insert(String::from("height"), NodeValue::Float(1.0));
+
+        let mut ours = base.clone();
+        let attrs = &mut ours.get_node_mut(b).unwrap().attrs;
+        attrs.insert(String::from("width"), NodeValue::Float(2.0));
+        let mut theirs = base.clone();
+        let attrs = &mut theirs.get_node_mut(b).unwrap().attrs;
+        attrs.remove("height");
+
+        let patch_a = crate::diff::diff_trees(&base, &ours);
+        let patch_b = crate::diff::diff_trees(&base, &theirs);
+        let result = merge_patches_with_base(&base, &patch_a, &patch_b);
+        assert!(result.is_clean());
+
+        let mut merged = base;
+        crate::diff::apply_patch(&mut merged, &result.merged_ops);
+        let node = merged.get_node(b).unwrap();
+        assert_eq!(node.attr("width"), Some(&NodeValue::Float(2.0)));
+        assert_eq!(node.attr("height"), None);
+    }
+
+    // ── References ──
+
+    fn instance(node_id: NodeId, target: NodeId) -> DiffOp {
+        DiffOp::Insert {
+            node_id,
+            parent_id: 0,
+            index: 0,
+            kind: AstNodeKind::Transform,
+            label: String::from("instance"),
+            value: NodeValue::Ref(target),
+        }
+    }
+
+    #[test]
+    fn test_merge_flags_ref_to_node_deleted_on_other_branch() {
+        let patch_a = vec![DiffOp::Delete { node_id: 2 }];
+        let patch_b = vec![instance(5, 2), instance(6, 3)];
+        let result = merge_patches(&patch_a, &patch_b);
+        assert_eq!(result.conflicts.len(), 1);
+        let conflict = &result.conflicts[0];
+        assert_eq!(conflict.node_id, 2);
+        assert_eq!(
+            conflict.description,
+            "node deleted on one branch is referenced on the other"
+        );
+        assert_eq!(conflict.ops_a, patch_a);
+        assert_eq!(conflict.ops_b, vec![instance(5, 2)]);
+        assert_eq!(result.merged_ops, vec![instance(6, 3)]);
+    }
+
+    #[test]
+    fn test_merge_renumbering_rewrites_refs() {
+        // B's instance refers to the node B inserted as 3, renumbered to 5
+        let patch_a = vec![insert(3, 1, "sphere")];
+        let patch_b = vec![insert(3, 2, "box"), instance(4, 3)];
+        let result = merge_patches(&patch_a, &patch_b);
+        assert!(result.is_clean());
+        assert_eq!(
+            result.merged_ops,
+            vec![insert(3, 1, "sphere"), insert(5, 2, "box"), instance(4, 5)]
+        );
+    }
+
+    #[test]
+    fn test_merge_with_base_flags_ref_into_deleted_subtree() {
+        let mut base = AstTree::new();
+        let g = base.add_node(AstNodeKind::Group, "g", 0);
+        let proto = base.add_node(AstNodeKind::Primitive, "sphere", g);
+
+        let mut ours = base.clone();
+        assert!(ours.remove_subtree(g));
+        let mut theirs = base.clone();
+        theirs.add_node_with_value(AstNodeKind::Transform, "instance", NodeValue::Ref(proto), 0);
+
+        let patch_a = crate::diff::diff_trees(&base, &ours);
+        let patch_b = crate::diff::diff_trees(&base, &theirs);
+        // Only the ancestor shows that deleting `g` removes `proto`
+        assert!(merge_patches(&patch_a, &patch_b).is_clean());
+        let result = merge_patches_with_base(&base, &patch_a, &patch_b);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].node_id, g);
+        assert!(result.merged_ops.is_empty());
+    }
+
+    #[test]
+    fn test_merge_keeps_child_insert_after_auto_resolved_parent() {
+        // Both branches add the same sphere; only A gives it a radius
+        let mut base = AstTree::new();
+        let g = base.add_node(AstNodeKind::Group, "g", 0);
+        let mut theirs = base.clone();
+        theirs.add_node(AstNodeKind::Primitive, "sphere", g);
+        let mut ours = theirs.clone();
+        let sphere = ours
+            .nodes()
+            .iter()
+            .find(|n| n.label == "sphere")
+            .unwrap()
+            .id;
+        ours.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), sphere);
+
+        let patch_a = crate::diff::diff_trees(&base, &ours);
+        let patch_b = crate::diff::diff_trees(&base, &theirs);
+        let result = merge_patches_with_base(&base, &patch_a, &patch_b);
+        assert!(result.is_clean());
+        let mut merged = base.clone();
+        crate::diff::try_apply_patch(&mut merged, &result.merged_ops).unwrap();
+        assert_eq!(merged.nodes().len(), 4);
+        assert_eq!(merged.subtree_hash(0), ours.subtree_hash(0));
+    }
+
+    // ── Deleted subtrees ──
+
+    /// root ── g ─┬─ sphere ── radius
+    ///            └─ box
+    fn group() -> (AstTree, [NodeId; 4]) {
+        let mut base = AstTree::new();
+        let g = base.add_node(AstNodeKind::Group, "g", 0);
+        let sphere = base.add_node(AstNodeKind::Primitive, "sphere", g);
+        let radius = base.add_node_with_value(
+            AstNodeKind::Parameter,
+            "radius",
+            NodeValue::Float(1.0),
+            sphere,
+        );
+        let cube = base.add_node(AstNodeKind::Primitive, "box", g);
+        (base, [g, sphere, radius, cube])
+    }
+
+    #[test]
+    fn test_merge_with_base_conflicts_on_child_moved_out_of_deleted_group() {
+        let (base, [g, sphere, ..]) = group();
+        let mut ours = base.clone();
+        assert!(ours.remove_subtree(g));
+        let mut theirs = base.clone();
+        assert!(theirs.move_node(sphere, 0, 0));
+
+        let patch_a = crate::diff::diff_trees(&base, &ours);
+        let patch_b = crate::diff::diff_trees(&base, &theirs);
+        let result = merge_patches_with_base(&base, &patch_a, &patch_b);
+        assert_eq!(result.conflicts.len(), 1);
+        let conflict = &result.conflicts[0];
+        assert_eq!(conflict.node_id, g);
+        assert_eq!(conflict.ops_a, patch_a);
+        assert_eq!(conflict.ops_b, patch_b);
+        assert!(result.merged_ops.is_empty());
+    }
+
+    #[test]
+    fn test_merge_with_base_conflicts_on_edit_deep_in_deleted_group() {
+        let (base, [g, _, radius, _]) = group();
+        let patch_a = vec![DiffOp::Delete { node_id: g }];
+        let patch_b = vec![DiffOp::Update {
+            node_id: radius,
+            old_value: NodeValue::Float(1.0),
+            new_value: NodeValue::Float(2.0),
+        }];
+        let result = merge_patches_with_base(&base, &patch_a, &patch_b);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].node_id, g);
+        // Same outcome with the branches swapped
+        let swapped = merge_patches_with_base(&base, &patch_b, &patch_a);
+        assert_eq!(swapped.conflicts.len(), 1);
+        assert_eq!(swapped.conflicts[0].ops_a, patch_b);
+    }
+
+    #[test]
+    fn test_merge_with_base_folds_delete_inside_deleted_group() {
+        let (base, [g, _, radius, cube]) = group();
+        // Deleting part of a group the other branch deletes whole is no conflict
+        let patch_a = vec![DiffOp::Delete { node_id: g }];
+        let patch_b = vec![DiffOp::Delete { node_id: cube }];
+        let result = merge_patches_with_base(&base, &patch_a, &patch_b);
+        assert!(result.is_clean());
+        assert_eq!(result.merged_ops, patch_a);
+
+        // ... unless the group is in conflict anyway, then it shows there
+        let patch_b = vec![
+            DiffOp::Delete { node_id: cube },
+            DiffOp::Update {
+                node_id: radius,
+                old_value: NodeValue::Float(1.0),
+                new_value: NodeValue::Float(2.0),
+            },
+        ];
+        let result = merge_patches_with_base(&base, &patch_a, &patch_b);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].ops_b.len(), 2);
+        assert!(result.merged_ops.is_empty());
+    }
+
+    // ── Child positions ──
+
+    /// root ── subtract ─┬─ x
+    ///                   ├─ y
+    ///                   └─ z
+    fn operands() -> (AstTree, [NodeId; 4]) {
+        let mut base = AstTree::new();
+        let sub = base.add_node(AstNodeKind::CsgOp, "subtract", 0);
+        let x = base.add_node(AstNodeKind::Primitive, "x", sub);
+        let y = base.add_node(AstNodeKind::Primitive, "y", sub);
+        let z = base.add_node(AstNodeKind::Primitive, "z", sub);
+        (base, [sub, x, y, z])
+    }
+
+    /// Merge `ours` and `theirs` against `base`, apply the clean result
+    /// strictly and return the labels under `parent`.
+    fn merged_labels(
+        base: &AstTree,
+        ours: &AstTree,
+        theirs: &AstTree,
+        parent: NodeId,
+    ) -> Vec<String> {
+        let patch_a = crate::diff::diff_trees(base, ours);
+        let patch_b = crate::diff::diff_trees(base, theirs);
+        let result = merge_patches_with_base(base, &patch_a, &patch_b);
+        assert!(result.is_clean());
+        let mut merged = base.clone();
+        crate::diff::try_apply_patch(&mut merged, &result.merged_ops).unwrap();
+        assert_eq!(merged.check_invariants(), []);
+        merged
+            .get_node(parent)
+            .unwrap()
+            .children
+            .iter()
+            .map(|&c| merged.get_node(c).unwrap().label.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_rebases_move_past_delete_of_earlier_sibling() {
+        let (base, [sub, x, _, z]) = operands();
+        let mut ours = base.clone();
+        assert!(ours.remove_subtree(x));
+        let mut theirs = base.clone();
+        assert!(theirs.move_node(z, sub, 1));
+
+        // B's [x, z, y] without A's x
+        assert_eq!(merged_labels(&base, &ours, &theirs, sub), ["z", "y"]);
+        assert_eq!(merged_labels(&base, &theirs, &ours, sub), ["z", "y"]);
+    }
+
+    #[test]
+    fn test_merge_rebases_insert_after_child_moved_out() {
+        let (base, [sub, x, y, _]) = operands();
+        let mut ours = base.clone();
+        assert!(ours.move_node(x, 0, 0));
+        assert!(ours.move_node(y, 0, 1));
+        let mut theirs = base.clone();
+        assert!(theirs.insert_with_id_at(
+            base.next_id(),
+            AstNodeKind::Primitive,
+            "w",
+            NodeValue::None,
+            sub,
+            2,
+        ));
+
+        // B put w after y, which A moved away; x before it went too
+        assert_eq!(merged_labels(&base, &ours, &theirs, sub), ["w", "z"]);
+        assert_eq!(
+            merged_labels(&base, &ours, &theirs, 0),
+            ["x", "y", "subtract"]
+        );
+    }
+
+    #[test]
+    fn test_merge_rebases_insert_after_other_branch_reorder() {
+        let (base, [sub, x, ..]) = operands();
+        let mut ours = base.clone();
+        assert!(ours.move_node(x, sub, 2));
+        let mut theirs = base.clone();
+        assert!(theirs.insert_with_id_at(
+            base.next_id(),
+            AstNodeKind::Primitive,
+            "w",
+            NodeValue::None,
+            sub,
+            2,
+        ));
+
+        // A: [y, z, x]; B: w right after y
+        assert_eq!(
+            merged_labels(&base, &ours, &theirs, sub),
+            ["y", "w", "z", "x"]
+        );
+        assert_eq!(
+            merged_labels(&base, &theirs, &ours, sub),
+            ["y", "w", "z", "x"]
+        );
+    }
 }
@@ -0,0 +1,1292 @@
+//! Patch algebra
+//!
+//! Operations on `DiffOp` sequences as values: inversion for undo and
+//! revert, composition for squashing streams of small patches, and
+//! normalization to a canonical form.
+//!
+//! Author: Moroya Sakamoto
+
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
+#[cfg(not(feature = "std"))]
+use alloc::{string::String, vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::ast::{AstTree, NodeId, NodeValue};
+use crate::diff::{apply_patch, attr_inserts, diff_by_id, same_value, DiffOp};
+
+// ── Inversion ──────────────────────────────────────────────────────────
+
+/// Compute the patch that undoes `ops`
+///
+/// `tree` is the pre-image — the tree `ops` will be applied to. Applying
+/// `ops` and then the result restores it exactly: same node IDs, same
+/// child order, same subtree hash.
+///
+/// Each op is inverted against the tree as the ops before it leave it, so
+/// Update, Relabel and attribute edits restore the node's actual value
+/// rather than trusting the op's `old_value`/`old_label`, a Delete becomes
+/// nested Inserts (and `SetAttr`s) that recreate the subtree under its
+/// original IDs, and a Move returns the node
+/// to its old parent and position. Ops that [`apply_patch`] would skip
+/// (missing nodes, ID collisions, cyclic moves, deleting the root) have no
+/// inverse.
+#[must_use]
+pub fn invert_patch(tree: &AstTree, ops: &[DiffOp]) -> Vec<DiffOp> {
+    let mut state = tree.clone();
+    let mut undo: Vec<Vec<DiffOp>> = Vec::with_capacity(ops.len());
+    for op in ops {
+        undo.push(invert_op(&state, op));
+        apply_patch(&mut state, core::slice::from_ref(op));
+    }
+    undo.into_iter().rev().flatten().collect()
+}
+
+/// Ops that undo `op`, given the tree it is about to be applied to.
+pub(crate) fn invert_op(tree: &AstTree, op: &DiffOp) -> Vec<DiffOp> {
+    match op {
+        DiffOp::Insert {
+            node_id, parent_id, ..
+        } => {
+            if tree.get_node(*node_id).is_some() || tree.get_node(*parent_id).is_none() {
+                return Vec::new();
+            }
+            vec![DiffOp::Delete { node_id: *node_id }]
+        }
+        DiffOp::Delete { node_id } => {
+            if *node_id == tree.root_id() {
+                return Vec::new();
+            }
+            subtree_inserts(tree, *node_id)
+        }
+        DiffOp::Update {
+            node_id, new_value, ..
+        } => tree
+            .get_node(*node_id)
+            .map(|node| {
+                vec![DiffOp::Update {
+                    node_id: *node_id,
+                    old_value: new_value.clone(),
+                    new_value: node.value.clone(),
+                }]
+            })
+            .unwrap_or_default(),
+        DiffOp::Relabel {
+            node_id, new_label, ..
+        } => tree
+            .get_node(*node_id)
+            .map(|node| {
+                vec![DiffOp::Relabel {
+                    node_id: *node_id,
+                    old_label: new_label.clone(),
+                    new_label: node.label.clone(),
+                }]
+            })
+            .unwrap_or_default(),
+        DiffOp::Retype {
+            node_id, new_kind, ..
+        } => match tree.get_node(*node_id) {
+            Some(node) if *node_id != tree.root_id() => vec![DiffOp::Retype {
+                node_id: *node_id,
+                old_kind: *new_kind,
+                new_kind: node.kind,
+            }],
+            _ => Vec::new(),
+        },
+        DiffOp::SetAttr {
+            node_id,
+            key,
+            new_value,
+            ..
+        } => tree
+            .get_node(*node_id)
+            .map(|node| {
+                let undo = node.attrs.get(key).map_or_else(
+                    || DiffOp::RemoveAttr {
+                        node_id: *node_id,
+                        key: key.clone(),
+                        old_value: new_value.clone(),
+                    },
+                    |prior| DiffOp::SetAttr {
+                        node_id: *node_id,
+                        key: key.clone(),
+                        old_value: Some(new_value.clone()),
+                        new_value: prior.clone(),
+                    },
+                );
+                vec![undo]
+            })
+            .unwrap_or_default(),
+        DiffOp::RemoveAttr { node_id, key, .. } => tree
+            .get_node(*node_id)
+            .and_then(|node| node.attrs.get(key))
+            .map(|prior| {
+                vec![DiffOp::SetAttr {
+                    node_id: *node_id,
+                    key: key.clone(),
+                    old_value: None,
+                    new_value: prior.clone(),
+                }]
+            })
+            .unwrap_or_default(),
+        DiffOp::Move {
+            node_id,
+            new_parent_id,
+            ..
+        } => {
+            let movable = *node_id != tree.root_id()
+                && tree.get_node(*new_parent_id).is_some()
+                && !tree.is_ancestor_or_self(*node_id, *new_parent_id);
+            match (movable, position(tree, *node_id)) {
+                (true, Some((old_parent, old_index))) => vec![DiffOp::Move {
+                    node_id: *node_id,
+                    new_parent_id: old_parent,
+                    new_index: old_index,
+                }],
+                _ => Vec::new(),
+            }
+        }
+    }
+}
+
+/// Parent of `id` and its index among the parent's children.
+fn position(tree: &AstTree, id: NodeId) -> Option<(NodeId, usize)> {
+    let parent = tree.parent_of(id)?;
+    let index = tree
+        .get_node(parent)?
+        .children
+        .iter()
+        .position(|&c| c == id)?;
+    Some((parent, index))
+}
+
+/// Nested Inserts that recreate the subtree at `id` — same IDs, same
+/// place — in pre-order, so every parent exists before its children.
+/// Each Insert is followed by `SetAttr`s restoring the node's attributes.
+pub(crate) fn subtree_inserts(tree: &AstTree, id: NodeId) -> Vec<DiffOp> {
+    let Some((parent_id, index)) = position(tree, id) else {
+        return Vec::new();
+    };
+    let mut ops = Vec::new();
+    let mut stack = vec![(id, parent_id, index)];
+    while let Some((node_id, parent_id, index)) = stack.pop() {
+        let Some(node) = tree.get_node(node_id) else {
+            continue;
+        };
+        ops.push(DiffOp::Insert {
+            node_id,
+            parent_id,
+            index,
+            kind: node.kind,
+            label: node.label.clone(),
+            value: node.value.clone(),
+        });
+        ops.extend(attr_inserts(node_id, node));
+        stack.extend(
+            node.children
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, &c)| (c, node_id, i)),
+        );
+    }
+    ops
+}
+
+// ── Composition ────────────────────────────────────────────────────────
+
+/// Combine two consecutive patches into one equivalent, smaller patch
+///
+/// Applying the result has the same effect as applying `a` then `b`.
+/// Compose a patch with `&[]` to squash it on its own.
+///
+/// - Update followed by Update of the same node becomes one Update; if
+///   the value ends where it started, both vanish. Relabel, Retype and
+///   edits of the same attribute likewise.
+/// - Edits of a node inserted earlier are folded into its Insert;
+///   attribute edits stay separate `SetAttr`s.
+/// - Edits of a node that is later deleted are dropped.
+/// - A node inserted and later deleted vanishes together with everything
+///   done to its subtree in between, unless the meantime moved an outside
+///   node into that subtree, moved one of its nodes out, or placed another
+///   node beside it (its presence then shifts that op's index).
+#[must_use]
+pub fn compose_patches(a: &[DiffOp], b: &[DiffOp]) -> Vec<DiffOp> {
+    let mut ops: Vec<Option<DiffOp>> = a.iter().chain(b).cloned().map(Some).collect();
+    cancel_transient_inserts(&mut ops);
+    fold_edits(&mut ops);
+    ops.into_iter().flatten().collect()
+}
+
+/// Drop Insert … Delete pairs whose subtree leaves no trace.
+///
+/// Ops are indexed once by the node they act on and the parent they place
+/// under, so each Insert only visits the ops on its own subtree and its
+/// parent rather than every op after it.
+fn cancel_transient_inserts(ops: &mut [Option<DiffOp>]) {
+    let mut acting: HashMap<NodeId, Vec<usize>> = HashMap::new();
+    let mut placing: HashMap<NodeId, Vec<usize>> = HashMap::new();
+    for (k, op) in ops.iter().enumerate() {
+        let Some(op) = op else {
+            continue;
+        };
+        acting.entry(subject(op)).or_default().push(k);
+        if let DiffOp::Insert { parent_id, .. }
+        | DiffOp::Move {
+            new_parent_id: parent_id,
+            ..
+        } = op
+        {
+            placing.entry(*parent_id).or_default().push(k);
+        }
+    }
+    for i in 0..ops.len() {
+        if let Some(members) = transient_members(ops, i, &acting, &placing) {
+            for m in members {
+                ops[m] = None;
+            }
+        }
+    }
+}
+
+/// Positions of the ops to drop if the Insert at `i` is later deleted with
+/// nothing outside its subtree depending on it having existed.
+///
+/// The subtree is the Insert's node plus every node inserted under one of
+/// them before the Delete. Placing another node under the Insert's parent,
+/// moving a node into or out of the subtree, or inserting one of its IDs
+/// again keeps the Insert.
+fn transient_members(
+    ops: &[Option<DiffOp>],
+    i: usize,
+    acting: &HashMap<NodeId, Vec<usize>>,
+    placing: &HashMap<NodeId, Vec<usize>>,
+) -> Option<Vec<usize>> {
+    let Some(DiffOp::Insert {
+        node_id: root,
+        parent_id: parent,
+        ..
+    }) = ops[i]
+    else {
+        return None;
+    };
+    let (end, _) = live_between(ops, acting, root, i, ops.len())
+        .find(|(_, op)| matches!(op, DiffOp::Delete { .. }))?;
+
+    // Node → position of the Insert that created it
+    let mut created: HashMap<NodeId, usize> = HashMap::new();
+    created.insert(root, i);
+    let mut stack = vec![(root, i)];
+    while let Some((id, at)) = stack.pop() {
+        for (k, op) in live_between(ops, placing, id, at, end) {
+            if let DiffOp::Insert { node_id, .. } = op {
+                if created.insert(*node_id, k).is_some() {
+                    return None;
+                }
+                stack.push((*node_id, k));
+            }
+        }
+    }
+    let created_by = |id: NodeId, k: usize| created.get(&id).is_some_and(|&at| at < k);
+
+    for (k, op) in live_between(ops, placing, parent, i, end) {
+        let inside = match op {
+            DiffOp::Move { node_id, .. } => created_by(*node_id, k) && created_by(parent, k),
+            _ => false,
+        };
+        if !inside {
+            return None;
+        }
+    }
+    let mut members: Vec<usize> = created.values().copied().collect();
+    members.push(end);
+    for (&id, &at) in &created {
+        for (k, op) in live_between(ops, acting, id, at, end) {
+            match op {
+                DiffOp::Insert { .. } => return None,
+                DiffOp::Move { new_parent_id, .. } if !created_by(*new_parent_id, k) => {
+                    return None;
+                }
+                _ => members.push(k),
+            }
+        }
+        for (k, op) in live_between(ops, placing, id, at, end) {
+            if let DiffOp::Move { node_id, .. } = op {
+                if !created_by(*node_id, k) {
+                    return None;
+                }
+            }
+        }
+    }
+    Some(members)
+}
+
+/// Live ops listed under `id` in `index`, strictly between positions
+/// `from` and `to`.
+fn live_between<'a>(
+    ops: &'a [Option<DiffOp>],
+    index: &'a HashMap<NodeId, Vec<usize>>,
+    id: NodeId,
+    from: usize,
+    to: usize,
+) -> impl Iterator<Item = (usize, &'a DiffOp)> + 'a {
+    let list = index.get(&id).map_or(&[][..], Vec::as_slice);
+    let start = list.partition_point(|&k| k <= from);
+    list[start..]
+        .iter()
+        .copied()
+        .take_while(move |&k| k < to)
+        .filter_map(|k| Some((k, ops[k].as_ref()?)))
+}
+
+/// The node an op acts on.
+const fn subject(op: &DiffOp) -> NodeId {
+    match op {
+        DiffOp::Insert { node_id, .. }
+        | DiffOp::Delete { node_id }
+        | DiffOp::Update { node_id, .. }
+        | DiffOp::Relabel { node_id, .. }
+        | DiffOp::Move { node_id, .. }
+        | DiffOp::Retype { node_id, .. }
+        | DiffOp::SetAttr { node_id, .. }
+        | DiffOp::RemoveAttr { node_id, .. } => *node_id,
+    }
+}
+
+/// The part of a node an edit op sets
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Field {
+    Value,
+    Label,
+    Kind,
+    Attr(String),
+}
+
+/// Node and field an edit op sets, or `None` for structural ops.
+fn edited_field(op: &DiffOp) -> Option<(NodeId, Field)> {
+    match op {
+        DiffOp::Update { node_id, .. } => Some((*node_id, Field::Value)),
+        DiffOp::Relabel { node_id, .. } => Some((*node_id, Field::Label)),
+        DiffOp::Retype { node_id, .. } => Some((*node_id, Field::Kind)),
+        DiffOp::SetAttr { node_id, key, .. } | DiffOp::RemoveAttr { node_id, key, .. } => {
+            Some((*node_id, Field::Attr(key.clone())))
+        }
+        DiffOp::Insert { .. } | DiffOp::Delete { .. } | DiffOp::Move { .. } => None,
+    }
+}
+
+/// Merge repeated edits of a node's fields and fold them into the node's
+/// Insert or its later Delete.
+fn fold_edits(ops: &mut [Option<DiffOp>]) {
+    let mut inserted: HashMap<NodeId, usize> = HashMap::new();
+    let mut last_edit: HashMap<NodeId, HashMap<Field, usize>> = HashMap::new();
+
+    for k in 0..ops.len() {
+        let Some(op) = ops[k].take() else {
+            continue;
+        };
+        let Some((node_id, field)) = edited_field(&op) else {
+            match op {
+                DiffOp::Insert { node_id, .. } => {
+                    inserted.insert(node_id, k);
+                    last_edit.remove(&node_id);
+                }
+                DiffOp::Delete { node_id } => {
+                    inserted.remove(&node_id);
+                    for (_, pending) in last_edit.remove(&node_id).into_iter().flatten() {
+                        ops[pending] = None;
+                    }
+                }
+                _ => {}
+            }
+            ops[k] = Some(op);
+            continue;
+        };
+        // Inserts carry no attributes, so attribute edits are chained instead
+        let insert = inserted
+            .get(&node_id)
+            .filter(|_| !matches!(field, Field::Attr(_)));
+        let edits = last_edit.entry(node_id).or_default();
+        if let Some(&i) = insert {
+            if let Some(insert) = &mut ops[i] {
+                fold_into_insert(insert, op);
+            }
+        } else if let Some(&j) = edits.get(&field) {
+            let undone = ops[j].as_mut().is_some_and(|first| chain_edit(first, op));
+            if undone {
+                ops[j] = None;
+                edits.remove(&field);
+            }
+        } else {
+            edits.insert(field, k);
+            ops[k] = Some(op);
+        }
+    }
+}
+
+/// Make `insert` create the node as `edit` leaves it.
+fn fold_into_insert(insert: &mut DiffOp, edit: DiffOp) {
+    let DiffOp::Insert {
+        kind, label, value, ..
+    } = insert
+    else {
+        return;
+    };
+    match edit {
+        DiffOp::Update { new_value, .. } => *value = new_value,
+        DiffOp::Relabel { new_label, .. } => *label = new_label,
+        DiffOp::Retype { new_kind, .. } => *kind = new_kind,
+        DiffOp::Insert { .. }
+        | DiffOp::Delete { .. }
+        | DiffOp::Move { .. }
+        | DiffOp::SetAttr { .. }
+        | DiffOp::RemoveAttr { .. } => {}
+    }
+}
+
+/// Extend the edit `first` to end where the later edit `then` of the same
+/// field ends. Returns true if the pair now changes nothing.
+fn chain_edit(first: &mut DiffOp, then: DiffOp) -> bool {
+    match (first, then) {
+        (
+            DiffOp::Update {
+                old_value,
+                new_value,
+                ..
+            },
+            DiffOp::Update { new_value: to, .. },
+        ) => {
+            *new_value = to;
+            same_value(old_value, new_value)
+        }
+        (
+            DiffOp::Relabel {
+                old_label,
+                new_label,
+                ..
+            },
+            DiffOp::Relabel { new_label: to, .. },
+        ) => {
+            *new_label = to;
+            old_label == new_label
+        }
+        (
+            DiffOp::Retype {
+                old_kind, new_kind, ..
+            },
+            DiffOp::Retype { new_kind: to, .. },
+        ) => {
+            *new_kind = to;
+            old_kind == new_kind
+        }
+        (first, then) => {
+            let (Some((from, _)), Some((_, to))) = (attr_change(first), attr_change(&then)) else {
+                return false;
+            };
+            let Some(op) = attr_edit(first, from, to) else {
+                return true;
+            };
+            *first = op;
+            false
+        }
+    }
+}
+
+/// An attribute edit as the values before and after it (`None` = unset).
+fn attr_change(op: &DiffOp) -> Option<(Option<NodeValue>, Option<NodeValue>)> {
+    match op {
+        DiffOp::SetAttr {
+            old_value,
+            new_value,
+            ..
+        } => Some((old_value.clone(), Some(new_value.clone()))),
+        DiffOp::RemoveAttr { old_value, .. } => Some((Some(old_value.clone()), None)),
+        _ => None,
+    }
+}
+
+/// The edit of `like`'s attribute that takes it from `from` to `to`, or
+/// `None` if that changes nothing.
+fn attr_edit(like: &DiffOp, from: Option<NodeValue>, to: Option<NodeValue>) -> Option<DiffOp> {
+    let (DiffOp::SetAttr { node_id, key, .. } | DiffOp::RemoveAttr { node_id, key, .. }) = like
+    else {
+        return None;
+    };
+    let (node_id, key) = (*node_id, key.clone());
+    match (from, to) {
+        (Some(old_value), None) => Some(DiffOp::RemoveAttr {
+            node_id,
+            key,
+            old_value,
+        }),
+        (old_value, Some(new_value)) => {
+            if old_value
+                .as_ref()
+                .is_some_and(|v| same_value(v, &new_value))
+            {
+                None
+            } else {
+                Some(DiffOp::SetAttr {
+                    node_id,
+                    key,
+                    old_value,
+                    new_value,
+                })
+            }
+        }
+        (None, None) => None,
+    }
+}
+
+// ── Normalization ──────────────────────────────────────────────────────
+
+/// Rewrite `ops` into the canonical patch for its effect on `tree`
+///
+/// Patches with the same effect normalize to the same op sequence,
+/// whatever their order or redundancy: Relabels and Updates first, then
+/// Moves and Inserts in pre-order of the resulting tree, then Deletes —
+/// the layout `diff_trees` emits. Inserted nodes keep their IDs. Ops that
+/// `apply_patch` would skip on `tree` are dropped.
+#[must_use]
+pub fn normalize_patch(tree: &AstTree, ops: &[DiffOp]) -> Vec<DiffOp> {
+    let mut after = tree.clone();
+    apply_patch(&mut after, ops);
+    diff_by_id(tree, &after)
+}
+
+/// True if `a` and `b` have the same effect on `tree`, node IDs included.
+#[must_use]
+pub fn patches_equivalent(tree: &AstTree, a: &[DiffOp], b: &[DiffOp]) -> bool {
+    a == b || normalize_patch(tree, a) == normalize_patch(tree, b)
+}
+
+// ── Tests ──────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{AstNodeKind, NodeValue};
+    use crate::content_id::ContentId;
+    use crate::diff::{diff_trees, try_apply_patch};
+    #[cfg(not(feature = "std"))]
+    use alloc::string::String;
+
+    fn scene() -> AstTree {
+        let mut tree = AstTree::new();
+        let u = tree.add_node(AstNodeKind::CsgOp, "union", 0);
+        let s = tree.add_node(AstNodeKind::Primitive, "sphere", u);
+        tree.add_node_with_value(AstNodeKind::Parameter, "r", NodeValue::Float(1.0), s);
+        let b = tree.add_node(AstNodeKind::Primitive, "box", u);
+        tree.add_node_with_value(AstNodeKind::Parameter, "w", NodeValue::Float(2.0), b);
+        tree.add_node(AstNodeKind::Group, "lights", 0);
+        tree
+    }
+
+    /// Apply `ops`, then their inverse, and check the tree is unchanged.
+    fn assert_undoes(tree: &AstTree, ops: &[DiffOp]) {
+        let inverse = invert_patch(tree, ops);
+        let mut t = tree.clone();
+        apply_patch(&mut t, ops);
+        assert_eq!(try_apply_patch(&mut t, &inverse), Ok(()));
+        assert_eq!(t.check_invariants(), []);
+        assert_eq!(t.subtree_hash(0), tree.subtree_hash(0));
+        assert_eq!(ContentId::of_tree(&t), ContentId::of_tree(tree));
+    }
+
+    #[test]
+    fn invert_empty_patch() {
+        assert!(invert_patch(&scene(), &[]).is_empty());
+    }
+
+    #[test]
+    fn invert_update_and_relabel() {
+        let tree = scene();
+        let ops = vec![
+            DiffOp::Update {
+                node_id: 3,
+                old_value: NodeValue::Float(1.0),
+                new_value: NodeValue::Float(4.0),
+            },
+            DiffOp::Relabel {
+                node_id: 2,
+                old_label: String::from("sphere"),
+                new_label: String::from("ball"),
+            },
+        ];
+        let inverse = invert_patch(&tree, &ops);
+        assert_eq!(
+            inverse,
+            vec![
+                DiffOp::Relabel {
+                    node_id: 2,
+                    old_label: String::from("ball"),
+                    new_label: String::from("sphere"),
+                },
+                DiffOp::Update {
+                    node_id: 3,
+                    old_value: NodeValue::Float(4.0),
+                    new_value: NodeValue::Float(1.0),
+                },
+            ]
+        );
+        assert_undoes(&tree, &ops);
+    }
+
+    #[test]
+    fn invert_update_uses_actual_old_value() {
+        let tree = scene();
+        let ops = [DiffOp::Update {
+            node_id: 3,
+            old_value: NodeValue::Float(99.0), // stale
+            new_value: NodeValue::Float(4.0),
+        }];
+        let inverse = invert_patch(&tree, &ops);
+        assert!(
+            matches!(&inverse[0], DiffOp::Update { new_value, .. } if *new_value == NodeValue::Float(1.0))
+        );
+        assert_undoes(&tree, &ops);
+    }
+
+    #[test]
+    fn invert_delete_recreates_subtree_in_place() {
+        let tree = scene();
+        let ops = [DiffOp::Delete { node_id: 2 }];
+        let inverse = invert_patch(&tree, &ops);
+        assert_eq!(inverse.len(), 2);
+        assert!(matches!(
+            &inverse[0],
+            DiffOp::Insert {
+                node_id: 2,
+                parent_id: 1,
+                index: 0,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &inverse[1],
+            DiffOp::Insert {
+                node_id: 3,
+                parent_id: 2,
+                ..
+            }
+        ));
+        assert_undoes(&tree, &ops);
+    }
+
+    #[test]
+    fn invert_insert_is_delete() {
+        let tree = scene();
+        let ops = [DiffOp::Insert {
+            node_id: 10,
+            parent_id: 1,
+            index: 1,
+            kind: AstNodeKind::Primitive,
+            label: String::from("cone"),
+            value: NodeValue::None,
+        }];
+        assert_eq!(
+            invert_patch(&tree, &ops),
+            vec![DiffOp::Delete { node_id: 10 }]
+        );
+        assert_undoes(&tree, &ops);
+    }
+
+    #[test]
+    fn invert_retype_restores_actual_kind() {
+        let tree = scene();
+        let ops = [DiffOp::Retype {
+            node_id: 2,
+            old_kind: AstNodeKind::Group, // stale
+            new_kind: AstNodeKind::CsgOp,
+        }];
+        assert_eq!(
+            invert_patch(&tree, &ops),
+            vec![DiffOp::Retype {
+                node_id: 2,
+                old_kind: AstNodeKind::CsgOp,
+                new_kind: AstNodeKind::Primitive,
+            }]
+        );
+        assert_undoes(
+            &tree,
+            &[DiffOp::Retype {
+                node_id: 2,
+                old_kind: AstNodeKind::Primitive,
+                new_kind: AstNodeKind::CsgOp,
+            }],
+        );
+    }
+
+    #[test]
+    fn invert_move_restores_parent_and_index() {
+        let tree = scene();
+        let ops = [DiffOp::Move {
+            node_id: 4,
+            new_parent_id: 6,
+            new_index: 0,
+        }];
+        assert_eq!(
+            invert_patch(&tree, &ops),
+            vec![DiffOp::Move {
+                node_id: 4,
+                new_parent_id: 1,
+                new_index: 1,
+            }]
+        );
+        assert_undoes(&tree, &ops);
+    }
+
+    #[test]
+    fn invert_dependent_sequence() {
+        // Insert a node, move an existing subtree under it, edit inside it,
+        // then delete the original parent
+        let tree = scene();
+        let ops = vec![
+            DiffOp::Insert {
+                node_id: 10,
+                parent_id: 6,
+                index: 0,
+                kind: AstNodeKind::Transform,
+                label: String::from("translate"),
+                value: NodeValue::None,
+            },
+            DiffOp::Move {
+                node_id: 2,
+                new_parent_id: 10,
+                new_index: 0,
+            },
+            DiffOp::Update {
+                node_id: 3,
+                old_value: NodeValue::Float(1.0),
+                new_value: NodeValue::Float(0.5),
+            },
+            DiffOp::Delete { node_id: 1 },
+        ];
+        assert_undoes(&tree, &ops);
+    }
+
+    #[test]
+    fn invert_skips_ops_apply_would_skip() {
+        let tree = scene();
+        let ops = [
+            DiffOp::Delete { node_id: 99 },
+            DiffOp::Delete { node_id: 0 },
+            DiffOp::Move {
+                node_id: 1,
+                new_parent_id: 3,
+                new_index: 0,
+            },
+            DiffOp::Insert {
+                node_id: 2,
+                parent_id: 0,
+                index: 0,
+                kind: AstNodeKind::Primitive,
+                label: String::from("dup"),
+                value: NodeValue::None,
+            },
+        ];
+        assert!(invert_patch(&tree, &ops[..1]).is_empty());
+        assert!(invert_patch(&tree, &ops[1..2]).is_empty());
+        assert!(invert_patch(&tree, &ops[2..]).is_empty());
+    }
+
+    #[test]
+    fn invert_diff_output_restores_tree() {
+        let old = scene();
+        let mut new = AstTree::new();
+        let g = new.add_node(AstNodeKind::Group, "lights", 0);
+        let u = new.add_node(AstNodeKind::CsgOp, "subtract", g);
+        let b = new.add_node(AstNodeKind::Primitive, "box", u);
+        new.add_node_with_value(AstNodeKind::Parameter, "w", NodeValue::Float(3.0), b);
+        new.add_node(AstNodeKind::Primitive, "cone", u);
+
+        let ops = diff_trees(&old, &new);
+        assert_undoes(&old, &ops);
+
+        // The inverse of the inverse redoes the change
+        let mut t = old.clone();
+        apply_patch(&mut t, &ops);
+        let undo = invert_patch(&old, &ops);
+        let redo = invert_patch(&t, &undo);
+        apply_patch(&mut t, &undo);
+        assert_eq!(t.subtree_hash(0), old.subtree_hash(0));
+        apply_patch(&mut t, &redo);
+        assert_eq!(t.subtree_hash(0), new.subtree_hash(0));
+    }
+
+    // ── Composition ──
+
+    fn update(node_id: NodeId, old: f64, new: f64) -> DiffOp {
+        DiffOp::Update {
+            node_id,
+            old_value: NodeValue::Float(old),
+            new_value: NodeValue::Float(new),
+        }
+    }
+
+    fn relabel(node_id: NodeId, old: &str, new: &str) -> DiffOp {
+        DiffOp::Relabel {
+            node_id,
+            old_label: String::from(old),
+            new_label: String::from(new),
+        }
+    }
+
+    fn insert(node_id: NodeId, parent_id: NodeId, index: usize, label: &str) -> DiffOp {
+        DiffOp::Insert {
+            node_id,
+            parent_id,
+            index,
+            kind: AstNodeKind::Primitive,
+            label: String::from(label),
+            value: NodeValue::None,
+        }
+    }
+
+    /// Check that `compose_patches(a, b)` applies cleanly and has the same
+    /// effect as applying `a` then `b`.
+    fn assert_composes(tree: &AstTree, a: &[DiffOp], b: &[DiffOp]) -> Vec<DiffOp> {
+        let mut expected = tree.clone();
+        apply_patch(&mut expected, a);
+        apply_patch(&mut expected, b);
+        let composed = compose_patches(a, b);
+        let mut t = tree.clone();
+        assert_eq!(try_apply_patch(&mut t, &composed), Ok(()));
+        assert_eq!(t.subtree_hash(0), expected.subtree_hash(0));
+        assert_eq!(ContentId::of_tree(&t), ContentId::of_tree(&expected));
+        composed
+    }
+
+    #[test]
+    fn compose_empty_patches() {
+        assert!(compose_patches(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn compose_update_update_is_one_update() {
+        let composed = assert_composes(&scene(), &[update(3, 1.0, 2.0)], &[update(3, 2.0, 5.0)]);
+        assert_eq!(composed, vec![update(3, 1.0, 5.0)]);
+    }
+
+    #[test]
+    fn compose_update_back_to_original_vanishes() {
+        let composed = assert_composes(&scene(), &[update(3, 1.0, 2.0)], &[update(3, 2.0, 1.0)]);
+        assert!(composed.is_empty());
+    }
+
+    #[test]
+    fn compose_slider_drag_stream() {
+        let tree = scene();
+        let mut squashed = Vec::new();
+        let mut t = tree.clone();
+        for step in 1..=100 {
+            let old = if step == 1 {
+                1.0
+            } else {
+                f64::from(step - 1) / 10.0
+            };
+            let tick = [update(3, old, f64::from(step) / 10.0)];
+            apply_patch(&mut t, &tick);
+            squashed = compose_patches(&squashed, &tick);
+        }
+        assert_eq!(squashed, vec![update(3, 1.0, 10.0)]);
+        let mut replay = tree;
+        apply_patch(&mut replay, &squashed);
+        assert_eq!(replay.subtree_hash(0), t.subtree_hash(0));
+    }
+
+    #[test]
+    fn compose_retypes() {
+        let retype = |node_id, old_kind, new_kind| DiffOp::Retype {
+            node_id,
+            old_kind,
+            new_kind,
+        };
+        let tree = scene();
+        let composed = assert_composes(
+            &tree,
+            &[retype(2, AstNodeKind::Primitive, AstNodeKind::Group)],
+            &[retype(2, AstNodeKind::Group, AstNodeKind::CsgOp)],
+        );
+        assert_eq!(
+            composed,
+            vec![retype(2, AstNodeKind::Primitive, AstNodeKind::CsgOp)]
+        );
+
+        let back = compose_patches(
+            &[retype(2, AstNodeKind::Primitive, AstNodeKind::Group)],
+            &[retype(2, AstNodeKind::Group, AstNodeKind::Primitive)],
+        );
+        assert!(back.is_empty());
+
+        // Folded into an earlier Insert
+        let composed = assert_composes(
+            &tree,
+            &[insert(7, 1, 0, "cone")],
+            &[retype(7, AstNodeKind::Primitive, AstNodeKind::Group)],
+        );
+        assert!(matches!(
+            composed[..],
+            [DiffOp::Insert {
+                kind: AstNodeKind::Group,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn compose_relabel_relabel_is_one_relabel() {
+        let tree = scene();
+        let composed = assert_composes(
+            &tree,
+            &[relabel(2, "sphere", "ball")],
+            &[relabel(2, "ball", "orb")],
+        );
+        assert_eq!(composed, vec![relabel(2, "sphere", "orb")]);
+
+        let back = compose_patches(
+            &[relabel(2, "sphere", "ball")],
+            &[relabel(2, "ball", "sphere")],
+        );
+        assert!(back.is_empty());
+    }
+
+    #[test]
+    fn compose_insert_then_delete_vanishes() {
+        let tree = scene();
+        let composed = assert_composes(
+            &tree,
+            &[insert(7, 1, 2, "cone")],
+            &[DiffOp::Delete { node_id: 7 }],
+        );
+        assert!(composed.is_empty());
+    }
+
+    #[test]
+    fn compose_insert_then_delete_drops_subtree_edits() {
+        let tree = scene();
+        let a = [
+            insert(7, 1, 0, "cone"),
+            DiffOp::Insert {
+                node_id: 8,
+                parent_id: 7,
+                index: 0,
+                kind: AstNodeKind::Parameter,
+                label: String::from("h"),
+                value: NodeValue::Float(1.0),
+            },
+            update(3, 1.0, 2.0),
+        ];
+        let b = [
+            update(8, 1.0, 3.0),
+            relabel(7, "cone", "cylinder"),
+            DiffOp::Delete { node_id: 7 },
+        ];
+        let composed = assert_composes(&tree, &a, &b);
+        assert_eq!(composed, vec![update(3, 1.0, 2.0)]);
+    }
+
+    #[test]
+    fn compose_insert_then_update_folds_into_insert() {
+        let tree = scene();
+        let a = [DiffOp::Insert {
+            node_id: 7,
+            parent_id: 2,
+            index: 1,
+            kind: AstNodeKind::Parameter,
+            label: String::from("h"),
+            value: NodeValue::Float(1.0),
+        }];
+        let b = [update(7, 1.0, 4.0), relabel(7, "h", "height")];
+        let composed = assert_composes(&tree, &a, &b);
+        assert_eq!(
+            composed,
+            vec![DiffOp::Insert {
+                node_id: 7,
+                parent_id: 2,
+                index: 1,
+                kind: AstNodeKind::Parameter,
+                label: String::from("height"),
+                value: NodeValue::Float(4.0),
+            }]
+        );
+    }
+
+    #[test]
+    fn compose_edits_then_delete_drop_edits() {
+        let tree = scene();
+        let a = [update(5, 2.0, 3.0), relabel(4, "box", "cube")];
+        let b = [DiffOp::Delete { node_id: 5 }, DiffOp::Delete { node_id: 4 }];
+        let composed = assert_composes(&tree, &a, &b);
+        assert_eq!(composed, b.to_vec());
+    }
+
+    #[test]
+    fn compose_keeps_insert_when_sibling_placed_after_it() {
+        // The cone shifts the index the cylinder is inserted at, so
+        // dropping the pair would change where the cylinder lands.
+        let tree = scene();
+        let a = [insert(7, 1, 0, "cone"), insert(8, 1, 2, "cylinder")];
+        let b = [DiffOp::Delete { node_id: 7 }];
+        let composed = assert_composes(&tree, &a, &b);
+        assert_eq!(composed.len(), 3);
+    }
+
+    #[test]
+    fn compose_keeps_insert_when_existing_node_moved_into_it() {
+        let tree = scene();
+        let a = [
+            insert(7, 6, 0, "spot"),
+            DiffOp::Move {
+                node_id: 4,
+                new_parent_id: 7,
+                new_index: 0,
+            },
+        ];
+        let b = [DiffOp::Delete { node_id: 7 }];
+        let composed = assert_composes(&tree, &a, &b);
+        assert_eq!(composed.len(), 3);
+        let mut t = tree;
+        apply_patch(&mut t, &composed);
+        assert!(t.get_node(4).is_none());
+    }
+
+    #[test]
+    fn compose_deep_inserted_chain_then_delete_vanishes() {
+        let mut chain = AstTree::new();
+        let mut parent = 0;
+        for i in 0..20_000 {
+            parent = chain.add_node_with_value(AstNodeKind::Group, "k", NodeValue::Int(i), parent);
+        }
+        let a = diff_trees(&AstTree::new(), &chain);
+        assert_eq!(compose_patches(&a, &[]), a);
+        assert!(compose_patches(&a, &[DiffOp::Delete { node_id: 1 }]).is_empty());
+    }
+
+    #[test]
+    fn compose_diff_sequence_matches_direct_diff() {
+        let v0 = scene();
+        let mut v1 = v0.clone();
+        v1.get_node_mut(3).unwrap().value = NodeValue::Float(1.5);
+        let c = v1.add_node(AstNodeKind::Primitive, "cone", 6);
+        let mut v2 = v1.clone();
+        v2.get_node_mut(3).unwrap().value = NodeValue::Float(2.5);
+        v2.remove_subtree(c);
+
+        let composed = assert_composes(&v0, &diff_trees(&v0, &v1), &diff_trees(&v1, &v2));
+        assert_eq!(composed, diff_trees(&v0, &v2));
+    }
+
+    // ── Normalization ──
+
+    #[test]
+    fn normalize_empty_patch() {
+        assert!(normalize_patch(&scene(), &[]).is_empty());
+    }
+
+    #[test]
+    fn normalize_orders_edits_before_structure() {
+        let tree = scene();
+        let ops = [
+            DiffOp::Delete { node_id: 6 },
+            insert(7, 1, 2, "cone"),
+            update(3, 1.0, 2.0),
+            relabel(2, "sphere", "ball"),
+        ];
+        let normal = normalize_patch(&tree, &ops);
+        assert_eq!(
+            normal,
+            vec![
+                relabel(2, "sphere", "ball"),
+                update(3, 1.0, 2.0),
+                insert(7, 1, 2, "cone"),
+                DiffOp::Delete { node_id: 6 },
+            ]
+        );
+        assert_eq!(normalize_patch(&tree, &normal), normal);
+    }
+
+    #[test]
+    fn normalize_same_effect_different_order() {
+        let tree = scene();
+        let a = [
+            insert(7, 6, 0, "spot"),
+            DiffOp::Move {
+                node_id: 4,
+                new_parent_id: 6,
+                new_index: 0,
+            },
+            update(5, 2.0, 3.0),
+        ];
+        let b = [
+            update(5, 2.0, 2.5),
+            DiffOp::Move {
+                node_id: 4,
+                new_parent_id: 6,
+                new_index: 0,
+            },
+            update(5, 2.5, 3.0),
+            insert(7, 6, 1, "spot"),
+        ];
+        assert_ne!(a.as_slice(), b.as_slice());
+        assert!(patches_equivalent(&tree, &a, &b));
+        assert_eq!(normalize_patch(&tree, &a), normalize_patch(&tree, &b));
+    }
+
+    #[test]
+    fn normalize_distinguishes_different_effects() {
+        let tree = scene();
+        let a = [insert(7, 6, 0, "spot")];
+        let b = [insert(8, 6, 0, "spot")];
+        assert!(!patches_equivalent(&tree, &a, &b));
+        assert!(!patches_equivalent(
+            &tree,
+            &[update(3, 1.0, 2.0)],
+            &[update(3, 1.0, 3.0)]
+        ));
+    }
+
+    #[test]
+    fn normalize_keeps_inserted_ids() {
+        let tree = scene();
+        let ops = [insert(40, 1, 0, "cone"), insert(41, 40, 0, "tip")];
+        let normal = normalize_patch(&tree, &ops);
+        assert_eq!(normal, ops.to_vec());
+    }
+
+    #[test]
+    fn normalize_drops_redundant_and_skipped_ops() {
+        let tree = scene();
+        let ops = [
+            update(3, 1.0, 2.0),
+            update(3, 2.0, 1.0),
+            DiffOp::Delete { node_id: 99 },
+            insert(7, 1, 0, "cone"),
+            DiffOp::Delete { node_id: 7 },
+        ];
+        assert!(normalize_patch(&tree, &ops).is_empty());
+    }
+
+    #[test]
+    fn normalize_leaves_diff_output_unchanged() {
+        let old = scene();
+        let mut new = AstTree::new();
+        let g = new.add_node(AstNodeKind::Group, "lights", 0);
+        let u = new.add_node(AstNodeKind::CsgOp, "subtract", g);
+        let b = new.add_node(AstNodeKind::Primitive, "box", u);
+        new.add_node_with_value(AstNodeKind::Parameter, "w", NodeValue::Float(3.0), b);
+        new.add_node(AstNodeKind::Primitive, "cone", u);
+
+        let ops = diff_trees(&old, &new);
+        assert_eq!(normalize_patch(&old, &ops), ops);
+    }
+
+    // ── Attributes ──
+
+    fn set_attr(node_id: NodeId, key: &str, old: Option<f64>, new: f64) -> DiffOp {
+        DiffOp::SetAttr {
+            node_id,
+            key: String::from(key),
+            old_value: old.map(NodeValue::Float),
+            new_value: NodeValue::Float(new),
+        }
+    }
+
+    fn remove_attr(node_id: NodeId, key: &str, old: f64) -> DiffOp {
+        DiffOp::RemoveAttr {
+            node_id,
+            key: String::from(key),
+            old_value: NodeValue::Float(old),
+        }
+    }
+
+    /// `scene()` with attributes `w` = 2.0 on the box and `r` = 1.0 on the
+    /// sphere.
+    fn attr_scene() -> AstTree {
+        let mut tree = scene();
+        let attrs = &mut tree.get_node_mut(4).unwrap().attrs;
+        attrs.insert(String::from("w"), NodeValue::Float(2.0));
+        let attrs = &mut tree.get_node_mut(2).unwrap().attrs;
+        attrs.insert(String::from("r"), NodeValue::Float(1.0));
+        tree
+    }
+
+    #[test]
+    fn invert_attr_edits() {
+        let tree = attr_scene();
+        let ops = [
+            set_attr(4, "w", Some(2.0), 3.0),
+            set_attr(4, "h", None, 1.0),
+            remove_attr(2, "r", 1.0),
+        ];
+        assert_eq!(
+            invert_patch(&tree, &ops),
+            vec![
+                set_attr(2, "r", None, 1.0),
+                remove_attr(4, "h", 1.0),
+                set_attr(4, "w", Some(3.0), 2.0),
+            ]
+        );
+        assert_undoes(&tree, &ops);
+    }
+
+    #[test]
+    fn invert_delete_restores_attrs() {
+        let tree = attr_scene();
+        let ops = [DiffOp::Delete { node_id: 1 }];
+        let inverse = invert_patch(&tree, &ops);
+        assert!(inverse.contains(&set_attr(2, "r", None, 1.0)));
+        assert!(inverse.contains(&set_attr(4, "w", None, 2.0)));
+        assert_undoes(&tree, &ops);
+    }
+
+    #[test]
+    fn compose_attr_edits_chain_per_key() {
+        let tree = attr_scene();
+        let composed = assert_composes(
+            &tree,
+            &[
+                set_attr(4, "w", Some(2.0), 3.0),
+                set_attr(4, "h", None, 1.0),
+            ],
+            &[set_attr(4, "w", Some(3.0), 4.0), remove_attr(4, "h", 1.0)],
+        );
+        assert_eq!(composed, vec![set_attr(4, "w", Some(2.0), 4.0)]);
+
+        let composed = assert_composes(
+            &tree,
+            &[remove_attr(2, "r", 1.0)],
+            &[set_attr(2, "r", None, 5.0)],
+        );
+        assert_eq!(composed, vec![set_attr(2, "r", Some(1.0), 5.0)]);
+
+        let composed = assert_composes(
+            &tree,
+            &[set_attr(2, "r", Some(1.0), 5.0)],
+            &[remove_attr(2, "r", 5.0)],
+        );
+        assert_eq!(composed, vec![remove_attr(2, "r", 1.0)]);
+    }
+
+    #[test]
+    fn compose_drops_attr_edits_of_transient_and_deleted_nodes() {
+        let tree = attr_scene();
+        let composed = assert_composes(
+            &tree,
+            &[insert(7, 6, 0, "spot"), set_attr(7, "i", None, 1.0)],
+            &[
+                set_attr(7, "i", Some(1.0), 2.0),
+                DiffOp::Delete { node_id: 7 },
+            ],
+        );
+        assert!(composed.is_empty());
+
+        let composed = assert_composes(
+            &tree,
+            &[set_attr(4, "w", Some(2.0), 3.0)],
+            &[DiffOp::Delete { node_id: 4 }],
+        );
+        assert_eq!(composed, vec![DiffOp::Delete { node_id: 4 }]);
+    }
+}
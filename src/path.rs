@@ -0,0 +1,311 @@
+//! Node paths
+//!
+//! Human-readable addresses like `/root/union/sphere[1]/radius`: one
+//! segment per node from the root down, each the node's label plus its
+//! ordinal among the siblings sharing that label. Unlike `NodeId`s, paths
+//! mean the same thing on every branch that has the same structure.
+//!
+//! Ordinals count from 0 and are omitted when 0, so `sphere` is the first
+//! child labelled "sphere" and `sphere[1]` the second. A `/`, `[`, `]` or
+//! `\` inside a label is escaped with a backslash.
+//!
+//! Author: Moroya Sakamoto
+
+#[cfg(not(feature = "std"))]
+use alloc::{string::String, vec::Vec};
+
+use core::fmt;
+use core::str::FromStr;
+
+use crate::ast::{AstTree, NodeId};
+
+/// One step of a [`NodePath`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PathSegment {
+    /// Label of the node
+    pub label: String,
+    /// Position among the siblings with the same label, from 0
+    pub ordinal: usize,
+}
+
+/// Path from the root to a node — see the [module docs](self)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NodePath {
+    segments: Vec<PathSegment>,
+}
+
+impl NodePath {
+    /// Parse a path such as `/root/union/sphere[1]/radius`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`PathError`] at the offending byte if the path does not
+    /// start with `/`, an ordinal is not a closed decimal number, or the
+    /// path ends in a lone `\`.
+    pub fn parse(s: &str) -> Result<Self, PathError> {
+        let err = |offset, kind| Err(PathError { offset, kind });
+        let Some(rest) = s.strip_prefix('/') else {
+            return err(0, PathErrorKind::NotAbsolute);
+        };
+        let mut segments = Vec::new();
+        let mut label = String::new();
+        let mut chars = rest.char_indices().map(|(i, c)| (i + 1, c)).peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => label.push(escaped),
+                    None => return err(i, PathErrorKind::TrailingEscape),
+                },
+                '/' => segments.push(PathSegment {
+                    label: core::mem::take(&mut label),
+                    ordinal: 0,
+                }),
+                '[' => {
+                    let mut digits = String::new();
+                    while let Some(&(_, d)) = chars.peek().filter(|(_, d)| d.is_ascii_digit()) {
+                        digits.push(d);
+                        chars.next();
+                    }
+                    let closed = matches!(chars.next(), Some((_, ']')));
+                    let at_end = matches!(chars.peek(), None | Some((_, '/')));
+                    let Some(ordinal) = digits.parse().ok().filter(|_| closed && at_end) else {
+                        return err(i, PathErrorKind::BadOrdinal);
+                    };
+                    segments.push(PathSegment {
+                        label: core::mem::take(&mut label),
+                        ordinal,
+                    });
+                    // Skip the separator, or stop: the segment is complete
+                    if chars.next().is_none() {
+                        return Ok(Self { segments });
+                    }
+                }
+                ']' => return err(i, PathErrorKind::BadOrdinal),
+                _ => label.push(c),
+            }
+        }
+        segments.push(PathSegment { label, ordinal: 0 });
+        Ok(Self { segments })
+    }
+
+    /// The segments, root first.
+    #[must_use]
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+
+    /// Path of `id` in `tree`, or `None` if it does not exist or is
+    /// detached from the root — see [`AstTree::path_of`].
+    pub(crate) fn of(tree: &AstTree, id: NodeId) -> Option<Self> {
+        let mut segments = Vec::new();
+        let mut current = tree.get_node(id)?;
+        while let Some(parent_id) = tree.parent_of(current.id) {
+            let parent = tree.get_node(parent_id)?;
+            let ordinal = parent
+                .children
+                .iter()
+                .take_while(|&&c| c != current.id)
+                .filter(|&&c| tree.get_node(c).is_some_and(|n| n.label == current.label))
+                .count();
+            segments.push(PathSegment {
+                label: current.label.clone(),
+                ordinal,
+            });
+            current = parent;
+        }
+        if current.id != tree.root_id() {
+            return None;
+        }
+        segments.push(PathSegment {
+            label: current.label.clone(),
+            ordinal: 0,
+        });
+        segments.reverse();
+        Some(Self { segments })
+    }
+
+    /// The node this path leads to in `tree`, if any — see
+    /// [`AstTree::node_at`].
+    pub(crate) fn resolve(&self, tree: &AstTree) -> Option<NodeId> {
+        let (first, rest) = self.segments.split_first()?;
+        let root = tree.get_node(tree.root_id())?;
+        if root.label != first.label || first.ordinal != 0 {
+            return None;
+        }
+        let mut current = root;
+        for segment in rest {
+            current = current
+                .children
+                .iter()
+                .filter_map(|&c| tree.get_node(c))
+                .filter(|c| c.label == segment.label)
+                .nth(segment.ordinal)?;
+        }
+        Some(current.id)
+    }
+}
+
+impl FromStr for NodePath {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for NodePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in &self.segments {
+            f.write_str("/")?;
+            for c in segment.label.chars() {
+                if matches!(c, '/' | '[' | ']' | '\\') {
+                    f.write_str("\\")?;
+                }
+                write!(f, "{c}")?;
+            }
+            if segment.ordinal > 0 {
+                write!(f, "[{}]", segment.ordinal)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A path string that [`NodePath::parse`] rejects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathError {
+    /// Byte offset of the problem in the input
+    pub offset: usize,
+    /// What is wrong there
+    pub kind: PathErrorKind,
+}
+
+/// Why a path string was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathErrorKind {
+    /// The path does not start with `/`
+    NotAbsolute,
+    /// A `[` not followed by digits, `]` and the end of the segment, or a
+    /// stray `]`
+    BadOrdinal,
+    /// The path ends in a `\` with nothing to escape
+    TrailingEscape,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "byte {}: ", self.offset)?;
+        match self.kind {
+            PathErrorKind::NotAbsolute => write!(f, "path must start with '/'"),
+            PathErrorKind::BadOrdinal => write!(f, "expected [<number>] at the end of a segment"),
+            PathErrorKind::TrailingEscape => write!(f, "nothing to escape after '\\'"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PathError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{AstNodeKind, NodeValue};
+    #[cfg(not(feature = "std"))]
+    use alloc::{format, string::ToString};
+
+    /// root ── union ─┬─ sphere ── radius
+    ///                ├─ box
+    ///                └─ sphere ── radius
+    fn scene() -> (AstTree, NodeId, NodeId) {
+        let mut tree = AstTree::new();
+        let u = tree.add_node(AstNodeKind::CsgOp, "union", 0);
+        for label in ["sphere", "box", "sphere"] {
+            let p = tree.add_node(AstNodeKind::Primitive, label, u);
+            if label == "sphere" {
+                tree.add_node_with_value(
+                    AstNodeKind::Parameter,
+                    "radius",
+                    NodeValue::Float(1.0),
+                    p,
+                );
+            }
+        }
+        let second = tree.get_node(u).unwrap().children[2];
+        let radius = tree.get_node(second).unwrap().children[0];
+        (tree, second, radius)
+    }
+
+    #[test]
+    fn test_path_of_and_node_at_roundtrip() {
+        let (tree, second, radius) = scene();
+        let path = tree.path_of(radius).unwrap();
+        assert_eq!(path.to_string(), "/root/union/sphere[1]/radius");
+        assert_eq!(tree.node_at(&path), Some(radius));
+        assert_eq!(
+            tree.path_of(second).unwrap().to_string(),
+            "/root/union/sphere[1]"
+        );
+        assert_eq!(tree.path_of(0).unwrap().to_string(), "/root");
+        for node in tree.nodes() {
+            let path = tree.path_of(node.id).unwrap();
+            assert_eq!(tree.node_at(&path), Some(node.id));
+        }
+    }
+
+    #[test]
+    fn test_paths_survive_renumbering() {
+        let (tree, _, radius) = scene();
+        // Same structure built in another order: different IDs
+        let mut other = AstTree::new();
+        let lights = other.add_node(AstNodeKind::Group, "lights", 0);
+        other.add_node(AstNodeKind::Group, "key", lights);
+        let u = other.add_node(AstNodeKind::CsgOp, "union", 0);
+        let s2 = other.add_node(AstNodeKind::Primitive, "sphere", u);
+        other.add_node(AstNodeKind::Primitive, "box", u);
+        other.move_node(s2, u, 2);
+        let s1 = other.add_node(AstNodeKind::Primitive, "sphere", u);
+        other.move_node(s1, u, 0);
+        let r = other.add_node(AstNodeKind::Parameter, "radius", s2);
+        let path = tree.path_of(radius).unwrap();
+        assert_eq!(other.node_at(&path), Some(r));
+        assert_ne!(r, radius);
+    }
+
+    #[test]
+    fn test_missing_paths_resolve_to_none() {
+        let (tree, ..) = scene();
+        for path in ["/root/union/sphere[2]", "/scene", "/root[1]", "/root/box"] {
+            assert_eq!(tree.node_at(&path.parse().unwrap()), None, "{path}");
+        }
+        assert!(tree.path_of(99).is_none());
+    }
+
+    #[test]
+    fn test_labels_with_separators_are_escaped() {
+        let mut tree = AstTree::new();
+        let odd = tree.add_node(AstNodeKind::Group, "a/b[0]\\c", 0);
+        let path = tree.path_of(odd).unwrap();
+        assert_eq!(path.to_string(), "/root/a\\/b\\[0\\]\\\\c");
+        assert_eq!(NodePath::parse(&path.to_string()), Ok(path.clone()));
+        assert_eq!(tree.node_at(&path), Some(odd));
+    }
+
+    #[test]
+    fn test_parse_segments_and_errors() {
+        let path: NodePath = "/root/union/sphere[1]/radius".parse().unwrap();
+        let ordinals: Vec<usize> = path.segments().iter().map(|s| s.ordinal).collect();
+        assert_eq!(ordinals, [0, 0, 1, 0]);
+        assert_eq!(path.segments()[2].label, "sphere");
+        assert_eq!(NodePath::parse("/a[0]").unwrap().to_string(), "/a");
+
+        let kind = |s: &str| NodePath::parse(s).map_err(|e| (e.offset, e.kind));
+        assert_eq!(kind("root/a"), Err((0, PathErrorKind::NotAbsolute)));
+        assert_eq!(kind("/a[x]"), Err((2, PathErrorKind::BadOrdinal)));
+        assert_eq!(kind("/a[1"), Err((2, PathErrorKind::BadOrdinal)));
+        assert_eq!(kind("/a[1]b"), Err((2, PathErrorKind::BadOrdinal)));
+        assert_eq!(kind("/a]"), Err((2, PathErrorKind::BadOrdinal)));
+        assert_eq!(kind("/a\\"), Err((2, PathErrorKind::TrailingEscape)));
+        let err = NodePath::parse("x").unwrap_err();
+        assert_eq!(format!("{err}"), "byte 0: path must start with '/'");
+    }
+}
@@ -93,6 +93,7 @@ mod tests {
     fn make_conflict() -> Conflict {
         Conflict {
             node_id: 1,
+            path: None,
             description: String::from("test conflict"),
             ops_a: vec![DiffOp::Update {
                 node_id: 1,
@@ -0,0 +1,431 @@
+//! Tree schemas
+//!
+//! Rules for what each kind of node may hold — child kinds, child count,
+//! required parameters and value types — and validation of trees against
+//! them, so malformed trees (a `Parameter` with children, a `CsgOp`
+//! without operands) are caught before they are committed.
+//!
+//! Author: Moroya Sakamoto
+
+#[cfg(not(feature = "std"))]
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+
+use core::fmt;
+
+use crate::ast::{AstNode, AstNodeKind, AstTree, NodeId, NodeValue};
+
+/// Variant of a [`NodeValue`], without its payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ValueType {
+    None,
+    Int,
+    Float,
+    Text,
+    Ident,
+    Bytes,
+    Bool,
+    Vec2,
+    Vec3,
+    Vec4,
+    Quat,
+    Mat4,
+    Color,
+    List,
+    Ref,
+}
+
+impl ValueType {
+    /// Type of `value`
+    #[must_use]
+    pub const fn of(value: &NodeValue) -> Self {
+        match value {
+            NodeValue::None => Self::None,
+            NodeValue::Int(_) => Self::Int,
+            NodeValue::Float(_) => Self::Float,
+            NodeValue::Text(_) => Self::Text,
+            NodeValue::Ident(_) => Self::Ident,
+            NodeValue::Bytes(_) => Self::Bytes,
+            NodeValue::Bool(_) => Self::Bool,
+            NodeValue::Vec2(_) => Self::Vec2,
+            NodeValue::Vec3(_) => Self::Vec3,
+            NodeValue::Vec4(_) => Self::Vec4,
+            NodeValue::Quat(_) => Self::Quat,
+            NodeValue::Mat4(_) => Self::Mat4,
+            NodeValue::Color(_) => Self::Color,
+            NodeValue::List(_) => Self::List,
+            NodeValue::Ref(_) => Self::Ref,
+        }
+    }
+}
+
+/// What a node matching a [`Schema`] entry may hold
+///
+/// The default rule allows anything; each `with_*` call narrows it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct NodeRule {
+    /// Kinds children may have; `None` allows any
+    pub child_kinds: Option<Vec<AstNodeKind>>,
+    /// Fewest children allowed
+    pub min_children: usize,
+    /// Most children allowed; `None` for no limit
+    pub max_children: Option<usize>,
+    /// Parameters that must be present, each as a `Parameter` child with
+    /// that label or an attribute with that key
+    pub required_params: Vec<String>,
+    /// Types the node's value may have; `None` allows any
+    pub value_types: Option<Vec<ValueType>>,
+}
+
+impl NodeRule {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A node that may not have children
+    #[must_use]
+    pub fn leaf() -> Self {
+        Self::new().with_child_count(0, Some(0))
+    }
+
+    /// Allow only children of `kinds`
+    #[must_use]
+    pub fn with_child_kinds(mut self, kinds: &[AstNodeKind]) -> Self {
+        self.child_kinds = Some(kinds.to_vec());
+        self
+    }
+
+    /// Require between `min` and `max` children (no upper limit if `None`)
+    #[must_use]
+    pub const fn with_child_count(mut self, min: usize, max: Option<usize>) -> Self {
+        self.min_children = min;
+        self.max_children = max;
+        self
+    }
+
+    /// Require the parameter `name`
+    #[must_use]
+    pub fn with_param(mut self, name: &str) -> Self {
+        self.required_params.push(String::from(name));
+        self
+    }
+
+    /// Allow only values of `types`
+    #[must_use]
+    pub fn with_value_types(mut self, types: &[ValueType]) -> Self {
+        self.value_types = Some(types.to_vec());
+        self
+    }
+
+    /// Append the ways `node` breaks this rule to `out`.
+    fn check(&self, tree: &AstTree, node: &AstNode, out: &mut Vec<Violation>) {
+        let mut report = |kind| {
+            out.push(Violation {
+                node_id: node.id,
+                kind,
+            });
+        };
+        let children: Vec<&AstNode> = node
+            .children
+            .iter()
+            .filter_map(|&c| tree.get_node(c))
+            .collect();
+
+        if let Some(kinds) = &self.child_kinds {
+            for child in &children {
+                if !kinds.contains(&child.kind) {
+                    report(ViolationKind::ChildKind {
+                        child_id: child.id,
+                        kind: child.kind,
+                    });
+                }
+            }
+        }
+        let count = children.len();
+        if count < self.min_children {
+            report(ViolationKind::TooFewChildren {
+                count,
+                min: self.min_children,
+            });
+        }
+        if let Some(max) = self.max_children.filter(|&max| count > max) {
+            report(ViolationKind::TooManyChildren { count, max });
+        }
+        for name in &self.required_params {
+            let present = node.attrs.contains_key(name)
+                || children
+                    .iter()
+                    .any(|c| c.kind == AstNodeKind::Parameter && c.label == *name);
+            if !present {
+                report(ViolationKind::MissingParam(name.clone()));
+            }
+        }
+        let value_type = ValueType::of(&node.value);
+        if self
+            .value_types
+            .as_ref()
+            .is_some_and(|types| !types.contains(&value_type))
+        {
+            report(ViolationKind::ValueType(value_type));
+        }
+    }
+}
+
+/// Rules per node kind, optionally refined per label
+///
+/// A node is checked against the rule for its `(kind, label)` if there is
+/// one, else the rule for its kind; nodes with neither are unconstrained.
+///
+/// ```
+/// use alice_vcs::{AstNodeKind, AstTree, NodeRule, Schema, ValueType};
+///
+/// let schema = Schema::new()
+///     .with_kind(AstNodeKind::Parameter, NodeRule::leaf())
+///     .with_kind(AstNodeKind::CsgOp, NodeRule::new().with_child_count(2, None))
+///     .with_label(
+///         AstNodeKind::Primitive,
+///         "sphere",
+///         NodeRule::new().with_param("radius"),
+///     );
+///
+/// let mut tree = AstTree::new();
+/// tree.add_node(AstNodeKind::CsgOp, "union", 0);
+/// assert_eq!(tree.validate(&schema).len(), 1);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Schema {
+    kinds: BTreeMap<AstNodeKind, NodeRule>,
+    labels: BTreeMap<AstNodeKind, BTreeMap<String, NodeRule>>,
+}
+
+impl Schema {
+    /// Schema without rules — every tree conforms
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the rule for nodes of `kind`
+    #[must_use]
+    pub fn with_kind(mut self, kind: AstNodeKind, rule: NodeRule) -> Self {
+        self.kinds.insert(kind, rule);
+        self
+    }
+
+    /// Set the rule for nodes of `kind` labelled `label`, which takes
+    /// precedence over the rule for `kind`
+    #[must_use]
+    pub fn with_label(mut self, kind: AstNodeKind, label: &str, rule: NodeRule) -> Self {
+        self.labels
+            .entry(kind)
+            .or_default()
+            .insert(String::from(label), rule);
+        self
+    }
+
+    /// The rule `node` is checked against, if any
+    #[must_use]
+    pub fn rule_for(&self, node: &AstNode) -> Option<&NodeRule> {
+        self.labels
+            .get(&node.kind)
+            .and_then(|rules| rules.get(node.label.as_str()))
+            .or_else(|| self.kinds.get(&node.kind))
+    }
+
+    /// Every violation in `tree`, in pre-order.
+    pub(crate) fn check(&self, tree: &AstTree) -> Vec<Violation> {
+        let mut out = Vec::new();
+        for node in tree.pre_order(tree.root_id()) {
+            if let Some(rule) = self.rule_for(node) {
+                rule.check(tree, node, &mut out);
+            }
+        }
+        out
+    }
+}
+
+/// A node that breaks its [`Schema`] rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// The offending node
+    pub node_id: NodeId,
+    /// What is wrong with it
+    pub kind: ViolationKind,
+}
+
+/// How a node breaks its [`Schema`] rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// A child's kind is not among the allowed child kinds
+    ChildKind { child_id: NodeId, kind: AstNodeKind },
+    /// Fewer children than the rule's minimum
+    TooFewChildren { count: usize, min: usize },
+    /// More children than the rule's maximum
+    TooManyChildren { count: usize, max: usize },
+    /// A required parameter is neither a `Parameter` child nor an
+    /// attribute
+    MissingParam(String),
+    /// The node's value has a type the rule does not allow
+    ValueType(ValueType),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "node {}: ", self.node_id)?;
+        match &self.kind {
+            ViolationKind::ChildKind { child_id, kind } => {
+                write!(f, "child {child_id} of kind {kind:?} is not allowed")
+            }
+            ViolationKind::TooFewChildren { count, min } => {
+                write!(f, "{count} children, at least {min} required")
+            }
+            ViolationKind::TooManyChildren { count, max } => {
+                write!(f, "{count} children, at most {max} allowed")
+            }
+            ViolationKind::MissingParam(name) => write!(f, "missing parameter {name:?}"),
+            ViolationKind::ValueType(t) => write!(f, "value of type {t:?} is not allowed"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Violation {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::{format, vec};
+
+    fn csg_schema() -> Schema {
+        Schema::new()
+            .with_kind(AstNodeKind::Parameter, NodeRule::leaf())
+            .with_kind(
+                AstNodeKind::CsgOp,
+                NodeRule::new()
+                    .with_child_kinds(&[AstNodeKind::CsgOp, AstNodeKind::Primitive])
+                    .with_child_count(2, Some(2)),
+            )
+            .with_label(
+                AstNodeKind::Primitive,
+                "sphere",
+                NodeRule::new()
+                    .with_param("radius")
+                    .with_value_types(&[ValueType::None]),
+            )
+    }
+
+    /// `union(sphere(radius), box)`: valid under [`csg_schema`].
+    fn csg_tree() -> (AstTree, NodeId, NodeId, NodeId) {
+        let mut tree = AstTree::new();
+        let union = tree.add_node(AstNodeKind::CsgOp, "union", 0);
+        let sphere = tree.add_node(AstNodeKind::Primitive, "sphere", union);
+        let radius = tree.add_node_with_value(
+            AstNodeKind::Parameter,
+            "radius",
+            NodeValue::Float(1.0),
+            sphere,
+        );
+        tree.add_node(AstNodeKind::Primitive, "box", union);
+        (tree, union, sphere, radius)
+    }
+
+    #[test]
+    fn empty_schema_accepts_anything() {
+        let (tree, ..) = csg_tree();
+        assert!(tree.validate(&Schema::new()).is_empty());
+        assert!(tree.validate(&csg_schema()).is_empty());
+    }
+
+    #[test]
+    fn leaf_rule_rejects_children() {
+        let (mut tree, _, _, radius) = csg_tree();
+        tree.add_node(AstNodeKind::Group, "stray", radius);
+        assert_eq!(
+            tree.validate(&csg_schema()),
+            vec![Violation {
+                node_id: radius,
+                kind: ViolationKind::TooManyChildren { count: 1, max: 0 },
+            }]
+        );
+    }
+
+    #[test]
+    fn child_kinds_and_count_are_checked() {
+        let (mut tree, union, ..) = csg_tree();
+        let group = tree.add_node(AstNodeKind::Group, "g", union);
+        let violations = tree.validate(&csg_schema());
+        assert_eq!(
+            violations.iter().map(|v| &v.kind).collect::<Vec<_>>(),
+            [
+                &ViolationKind::ChildKind {
+                    child_id: group,
+                    kind: AstNodeKind::Group,
+                },
+                &ViolationKind::TooManyChildren { count: 3, max: 2 },
+            ]
+        );
+
+        let mut empty = AstTree::new();
+        let op = empty.add_node(AstNodeKind::CsgOp, "subtract", 0);
+        assert_eq!(
+            empty.validate(&csg_schema()),
+            vec![Violation {
+                node_id: op,
+                kind: ViolationKind::TooFewChildren { count: 0, min: 2 },
+            }]
+        );
+    }
+
+    #[test]
+    fn required_param_may_be_child_or_attribute() {
+        let (mut tree, _, sphere, radius) = csg_tree();
+        assert!(tree.remove_subtree(radius));
+        assert_eq!(
+            tree.validate(&csg_schema()),
+            vec![Violation {
+                node_id: sphere,
+                kind: ViolationKind::MissingParam(String::from("radius")),
+            }]
+        );
+        tree.get_node_mut(sphere)
+            .unwrap()
+            .attrs
+            .insert(String::from("radius"), NodeValue::Float(1.0));
+        assert!(tree.validate(&csg_schema()).is_empty());
+    }
+
+    #[test]
+    fn label_rule_takes_precedence_over_kind_rule() {
+        let schema = csg_schema().with_kind(AstNodeKind::Primitive, NodeRule::leaf());
+        let (mut tree, _, sphere, _) = csg_tree();
+        // The sphere's own rule allows its radius child
+        assert!(tree.validate(&schema).is_empty());
+        tree.get_node_mut(sphere).unwrap().value = NodeValue::Int(3);
+        assert_eq!(
+            tree.validate(&schema),
+            vec![Violation {
+                node_id: sphere,
+                kind: ViolationKind::ValueType(ValueType::Int),
+            }]
+        );
+    }
+
+    #[test]
+    fn value_type_of_covers_every_variant() {
+        assert_eq!(ValueType::of(&NodeValue::Ref(3)), ValueType::Ref);
+        assert_eq!(ValueType::of(&NodeValue::List(vec![])), ValueType::List);
+        assert_eq!(ValueType::of(&NodeValue::Quat([0.0; 4])), ValueType::Quat);
+    }
+
+    #[test]
+    fn violation_display() {
+        let v = Violation {
+            node_id: 4,
+            kind: ViolationKind::MissingParam(String::from("radius")),
+        };
+        assert_eq!(format!("{v}"), "node 4: missing parameter \"radius\"");
+    }
+}
@@ -1,7 +1,9 @@
 //! Content-addressed snapshot store
 //!
 //! Merkle DAG storage for AST snapshots. Each snapshot is identified
-//! by its content hash (FNV-1a). Deduplication is automatic.
+//! by its 256-bit [`ContentId`] (SHA-256 over the tree and its parents).
+//! Deduplication is automatic. The 64-bit FNV-1a tree hash is kept per
+//! snapshot as a fast in-memory pre-check.
 //!
 //! Author: Moroya Sakamoto
 
@@ -11,8 +13,9 @@ use alloc::{collections::BTreeMap as HashMap, vec::Vec};
 use std::collections::HashMap;
 
 use crate::ast::AstTree;
+use crate::content_id::ContentId;
 
-/// Content hash (FNV-1a 64-bit)
+/// Fast content hash (FNV-1a 64-bit)
 pub type Hash = u64;
 
 /// Snapshot entry in the store
@@ -20,13 +23,27 @@ pub type Hash = u64;
 struct Snapshot {
     /// The AST tree
     tree: AstTree,
-    /// Parent snapshot hashes
-    parents: Vec<Hash>,
+    /// Parent snapshot IDs
+    parents: Vec<ContentId>,
+    /// FNV-1a hash of the tree root
+    tree_hash: Hash,
+}
+
+impl Snapshot {
+    /// Hashes `tree` before copying it, so the copy carries the caches.
+    fn of(tree: &AstTree, parents: Vec<ContentId>) -> Self {
+        let tree_hash = tree.subtree_hash(tree.root_id());
+        Self {
+            tree: tree.clone(),
+            parents,
+            tree_hash,
+        }
+    }
 }
 
 /// Content-addressed snapshot store (O(1) lookup via `HashMap`)
 pub struct SnapshotStore {
-    snapshots: HashMap<Hash, Snapshot>,
+    snapshots: HashMap<ContentId, Snapshot>,
 }
 
 impl Default for SnapshotStore {
@@ -44,42 +61,64 @@ impl SnapshotStore {
         }
     }
 
-    /// Store a snapshot, returns its content hash
-    pub fn store(&mut self, tree: &AstTree, parents: Vec<Hash>) -> Hash {
-        let hash = tree.subtree_hash(tree.root_id());
-        // Include parents in hash for unique commit identity
-        let mut commit_hash = hash;
-        for &p in &parents {
-            commit_hash ^= p;
-            commit_hash = commit_hash.wrapping_mul(0x0000_0100_0000_01b3);
-        }
+    /// Store a snapshot, returns its content ID
+    ///
+    /// Storing identical content with identical parents is a no-op that
+    /// returns the existing ID.
+    pub fn store(&mut self, tree: &AstTree, parents: Vec<ContentId>) -> ContentId {
+        tree.debug_assert_intact();
+        let id = ContentId::of_snapshot(tree, &parents);
+        self.snapshots
+            .entry(id)
+            .or_insert_with(|| Snapshot::of(tree, parents));
+        id
+    }
 
-        self.snapshots.insert(
-            commit_hash,
-            Snapshot {
-                tree: tree.clone(),
-                parents,
-            },
-        );
-        commit_hash
+    /// Store a tree without parents under its tree ID
+    /// ([`ContentId::of_tree`]), the ID a repository's commits refer to,
+    /// and return it. Storing a tree already stored is a no-op.
+    pub fn store_tree(&mut self, tree: &AstTree) -> ContentId {
+        tree.debug_assert_intact();
+        let id = ContentId::of_tree(tree);
+        self.snapshots
+            .entry(id)
+            .or_insert_with(|| Snapshot::of(tree, Vec::new()));
+        id
     }
 
-    /// Retrieve a snapshot by hash
+    /// Retrieve a snapshot by ID
     #[must_use]
-    pub fn get(&self, hash: Hash) -> Option<&AstTree> {
-        self.snapshots.get(&hash).map(|s| &s.tree)
+    pub fn get(&self, id: ContentId) -> Option<&AstTree> {
+        self.snapshots.get(&id).map(|s| &s.tree)
     }
 
-    /// Get parent hashes
+    /// Get parent IDs
     #[must_use]
-    pub fn parents(&self, hash: Hash) -> Option<&[Hash]> {
-        self.snapshots.get(&hash).map(|s| s.parents.as_slice())
+    pub fn parents(&self, id: ContentId) -> Option<&[ContentId]> {
+        self.snapshots.get(&id).map(|s| s.parents.as_slice())
     }
 
-    /// Check if hash exists
+    /// FNV-1a hash of a snapshot's tree.
+    ///
+    /// Differing values prove the trees differ without touching them;
+    /// equal values must be confirmed with the content ID.
     #[must_use]
-    pub fn contains(&self, hash: Hash) -> bool {
-        self.snapshots.contains_key(&hash)
+    pub fn tree_hash(&self, id: ContentId) -> Option<Hash> {
+        self.snapshots.get(&id).map(|s| s.tree_hash)
+    }
+
+    /// Check if ID exists
+    #[must_use]
+    pub fn contains(&self, id: ContentId) -> bool {
+        self.snapshots.contains_key(&id)
+    }
+
+    /// Resolve an abbreviated hex ID.
+    ///
+    /// Returns `None` if no snapshot or more than one snapshot matches.
+    #[must_use]
+    pub fn resolve_prefix(&self, prefix: &str) -> Option<ContentId> {
+        unique_prefix_match(self.snapshots.keys().copied(), prefix)
     }
 
     /// Total stored snapshots
@@ -94,22 +133,37 @@ impl SnapshotStore {
         self.snapshots.is_empty()
     }
 
-    /// List all stored snapshot hashes.
+    /// List all stored snapshot IDs.
     #[must_use]
-    pub fn all_hashes(&self) -> Vec<Hash> {
+    pub fn all_hashes(&self) -> Vec<ContentId> {
         self.snapshots.keys().copied().collect()
     }
 
-    /// Remove a snapshot by hash. Returns `true` if it existed.
-    pub fn remove(&mut self, hash: Hash) -> bool {
-        self.snapshots.remove(&hash).is_some()
+    /// Remove a snapshot by ID. Returns `true` if it existed.
+    pub fn remove(&mut self, id: ContentId) -> bool {
+        self.snapshots.remove(&id).is_some()
+    }
+}
+
+/// The single ID in `ids` whose hex form starts with `prefix`, if unique.
+pub(crate) fn unique_prefix_match(
+    ids: impl Iterator<Item = ContentId>,
+    prefix: &str,
+) -> Option<ContentId> {
+    let mut found = None;
+    for id in ids.filter(|id| id.has_hex_prefix(prefix)) {
+        if found.is_some() {
+            return None;
+        }
+        found = Some(id);
     }
+    found
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ast::{AstNodeKind, AstTree};
+    use crate::ast::{AstNodeKind, AstTree, NodeValue};
     #[cfg(not(feature = "std"))]
     use alloc::format;
     #[cfg(not(feature = "std"))]
@@ -164,7 +218,7 @@ mod tests {
     #[test]
     fn test_store_get_nonexistent_returns_none() {
         let store = SnapshotStore::new();
-        assert!(store.get(0xDEAD_BEEF).is_none());
+        assert!(store.get(ContentId([0xDE; 32])).is_none());
     }
 
     #[test]
@@ -188,7 +242,7 @@ mod tests {
     #[test]
     fn test_store_remove_returns_false_when_absent() {
         let mut store = SnapshotStore::new();
-        assert!(!store.remove(0xABCD));
+        assert!(!store.remove(ContentId([0xAB; 32])));
     }
 
     #[test]
@@ -209,7 +263,7 @@ mod tests {
         let mut store = SnapshotStore::new();
         let tree = AstTree::new();
         let hash = store.store(&tree, vec![]);
-        assert_eq!(store.parents(hash).unwrap(), &[] as &[u64]);
+        assert_eq!(store.parents(hash).unwrap(), &[] as &[ContentId]);
     }
 
     #[test]
@@ -222,4 +276,96 @@ mod tests {
         }
         assert_eq!(store.len(), 5);
     }
+
+    #[test]
+    fn test_store_distinguishes_value_only_change() {
+        let mut store = SnapshotStore::new();
+        let mut t1 = AstTree::new();
+        t1.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(1.0), 0);
+        let mut t2 = AstTree::new();
+        t2.add_node_with_value(AstNodeKind::Primitive, "sphere", NodeValue::Float(2.0), 0);
+        let h1 = store.store(&t1, vec![]);
+        let h2 = store.store(&t2, vec![]);
+        assert_ne!(h1, h2);
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn test_store_same_content_same_parents_dedupes() {
+        let mut store = SnapshotStore::new();
+        let mut tree = AstTree::new();
+        tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let h1 = store.store(&tree, vec![]);
+        let h2 = store.store(&tree, vec![]);
+        assert_eq!(h1, h2);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_store_same_tree_different_parents_distinct() {
+        let mut store = SnapshotStore::new();
+        let tree = AstTree::new();
+        let h1 = store.store(&tree, vec![]);
+        let h2 = store.store(&tree, vec![h1]);
+        assert_ne!(h1, h2);
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn test_tree_hash_precheck() {
+        let mut store = SnapshotStore::new();
+        let mut tree = AstTree::new();
+        let h0 = store.store(&tree, vec![]);
+        tree.add_node(AstNodeKind::Primitive, "sphere", 0);
+        let h1 = store.store(&tree, vec![h0]);
+        assert_eq!(store.tree_hash(h1), Some(tree.subtree_hash(0)));
+        assert_ne!(store.tree_hash(h0), store.tree_hash(h1));
+        assert!(store.tree_hash(ContentId::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_resolve_prefix() {
+        let mut store = SnapshotStore::new();
+        let tree = AstTree::new();
+        let h = store.store(&tree, vec![]);
+        assert_eq!(store.resolve_prefix(&h.short()), Some(h));
+        assert_eq!(store.resolve_prefix(&h.to_hex()), Some(h));
+        assert_eq!(store.resolve_prefix(""), None);
+    }
+
+    #[test]
+    fn test_resolve_prefix_ambiguous_is_none() {
+        let mut store = SnapshotStore::new();
+        let mut tree = AstTree::new();
+        let mut ids = Vec::new();
+        for i in 0u32..40 {
+            tree.add_node(AstNodeKind::Primitive, &format!("n{i}"), 0);
+            ids.push(store.store(&tree, vec![]));
+        }
+        // 40 snapshots over 16 leading hex digits: some digit is shared
+        let shared = [
+            "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "a", "b", "c", "d", "e", "f",
+        ]
+        .into_iter()
+        .find(|d| ids.iter().filter(|id| id.has_hex_prefix(d)).count() > 1)
+        .unwrap();
+        assert_eq!(store.resolve_prefix(shared), None);
+        for id in &ids {
+            assert_eq!(store.resolve_prefix(&id.to_hex()), Some(*id));
+        }
+    }
+
+    #[test]
+    fn test_store_tree() {
+        let mut store = SnapshotStore::new();
+        let tree = AstTree::new();
+        let snapshot = store.store(&tree, vec![]);
+        let id = store.store_tree(&tree);
+        assert_eq!(id, ContentId::of_tree(&tree));
+        assert_ne!(id, snapshot);
+        assert!(store.contains(id));
+        assert_eq!(store.parents(id).unwrap(), &[]);
+        assert_eq!(store.get(id).unwrap().node_count(), 1);
+        assert_eq!(store.store_tree(&tree), id);
+    }
 }
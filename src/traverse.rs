@@ -0,0 +1,374 @@
+//! Tree traversal
+//!
+//! Iterators over an [`AstTree`] — pre-order, post-order, breadth-first,
+//! ancestors, descendants with depth, siblings — and a [`Visitor`] with
+//! enter/leave callbacks. All of them keep their own stack or queue
+//! instead of recursing, so a 100k-deep keyframe chain walks as safely as
+//! a flat list. Children are visited in order; child IDs that name no
+//! node are skipped.
+//!
+//! Author: Moroya Sakamoto
+
+#[cfg(not(feature = "std"))]
+use alloc::{collections::VecDeque, vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+
+use crate::ast::{AstNode, AstTree, NodeId};
+
+/// Callbacks for [`AstTree::walk`], a depth-first walk of a subtree
+///
+/// `depth` counts levels below the node the walk started from, which is
+/// at depth 0.
+pub trait Visitor {
+    /// Called on reaching `node`, before its children. Return `false` to
+    /// skip its descendants.
+    fn enter(&mut self, node: &AstNode, depth: usize) -> bool {
+        let _ = (node, depth);
+        true
+    }
+
+    /// Called once `node`'s descendants are done — straight after
+    /// [`Self::enter`] if it skipped them.
+    fn leave(&mut self, node: &AstNode, depth: usize) {
+        let _ = (node, depth);
+    }
+}
+
+pub(crate) fn walk(tree: &AstTree, id: NodeId, visitor: &mut impl Visitor) {
+    let Some(start) = tree.get_node(id) else {
+        return;
+    };
+    // (entered node, index of its next child)
+    let mut stack: Vec<(&AstNode, usize)> = Vec::new();
+    if visitor.enter(start, 0) {
+        stack.push((start, 0));
+    } else {
+        visitor.leave(start, 0);
+    }
+    loop {
+        let depth = stack.len();
+        let Some((node, next)) = stack.last_mut() else {
+            break;
+        };
+        let node = *node;
+        if let Some(&child_id) = node.children.get(*next) {
+            *next += 1;
+            if let Some(child) = tree.get_node(child_id) {
+                if visitor.enter(child, depth) {
+                    stack.push((child, 0));
+                } else {
+                    visitor.leave(child, depth);
+                }
+            }
+        } else {
+            stack.pop();
+            visitor.leave(node, depth - 1);
+        }
+    }
+}
+
+/// Pre-order iterator, parents before children — see [`AstTree::pre_order`]
+#[derive(Debug, Clone)]
+pub struct PreOrder<'a> {
+    tree: &'a AstTree,
+    stack: Vec<NodeId>,
+}
+
+impl<'a> PreOrder<'a> {
+    pub(crate) fn new(tree: &'a AstTree, id: NodeId) -> Self {
+        Self {
+            tree,
+            stack: vec![id],
+        }
+    }
+}
+
+impl<'a> Iterator for PreOrder<'a> {
+    type Item = &'a AstNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let id = self.stack.pop()?;
+            if let Some(node) = self.tree.get_node(id) {
+                self.stack.extend(node.children.iter().rev());
+                return Some(node);
+            }
+        }
+    }
+}
+
+/// Post-order iterator, children before parents — see
+/// [`AstTree::post_order`]
+#[derive(Debug, Clone)]
+pub struct PostOrder<'a> {
+    tree: &'a AstTree,
+    /// (node, index of its next child)
+    stack: Vec<(&'a AstNode, usize)>,
+}
+
+impl<'a> PostOrder<'a> {
+    pub(crate) fn new(tree: &'a AstTree, id: NodeId) -> Self {
+        Self {
+            tree,
+            stack: tree.get_node(id).map(|n| (n, 0)).into_iter().collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for PostOrder<'a> {
+    type Item = &'a AstNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, next) = self.stack.last_mut()?;
+            let node = *node;
+            if let Some(&child_id) = node.children.get(*next) {
+                *next += 1;
+                if let Some(child) = self.tree.get_node(child_id) {
+                    self.stack.push((child, 0));
+                }
+            } else {
+                self.stack.pop();
+                return Some(node);
+            }
+        }
+    }
+}
+
+/// Breadth-first iterator, level by level — see
+/// [`AstTree::breadth_first`]
+#[derive(Debug, Clone)]
+pub struct BreadthFirst<'a> {
+    tree: &'a AstTree,
+    queue: VecDeque<NodeId>,
+}
+
+impl<'a> BreadthFirst<'a> {
+    pub(crate) fn new(tree: &'a AstTree, id: NodeId) -> Self {
+        Self {
+            tree,
+            queue: VecDeque::from([id]),
+        }
+    }
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = &'a AstNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let id = self.queue.pop_front()?;
+            if let Some(node) = self.tree.get_node(id) {
+                self.queue.extend(&node.children);
+                return Some(node);
+            }
+        }
+    }
+}
+
+/// Iterator up the parent chain — see [`AstTree::ancestors`]
+#[derive(Debug, Clone)]
+pub struct Ancestors<'a> {
+    tree: &'a AstTree,
+    current: Option<NodeId>,
+}
+
+impl<'a> Ancestors<'a> {
+    pub(crate) const fn new(tree: &'a AstTree, id: NodeId) -> Self {
+        Self {
+            tree,
+            current: Some(id),
+        }
+    }
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = &'a AstNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let parent = self.current.and_then(|id| self.tree.parent_of(id));
+        self.current = parent;
+        self.tree.get_node(parent?)
+    }
+}
+
+/// Pre-order iterator over strict descendants, paired with their depth —
+/// see [`AstTree::descendants`]
+#[derive(Debug, Clone)]
+pub struct Descendants<'a> {
+    tree: &'a AstTree,
+    stack: Vec<(usize, NodeId)>,
+}
+
+impl<'a> Descendants<'a> {
+    pub(crate) fn new(tree: &'a AstTree, id: NodeId) -> Self {
+        let stack = tree
+            .get_node(id)
+            .map(|n| n.children.iter().rev().map(|&c| (1, c)).collect())
+            .unwrap_or_default();
+        Self { tree, stack }
+    }
+}
+
+impl<'a> Iterator for Descendants<'a> {
+    type Item = (usize, &'a AstNode);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (depth, id) = self.stack.pop()?;
+            if let Some(node) = self.tree.get_node(id) {
+                self.stack
+                    .extend(node.children.iter().rev().map(|&c| (depth + 1, c)));
+                return Some((depth, node));
+            }
+        }
+    }
+}
+
+/// Iterator over the other children of a node's parent — see
+/// [`AstTree::siblings`]
+#[derive(Debug, Clone)]
+pub struct Siblings<'a> {
+    tree: &'a AstTree,
+    id: NodeId,
+    children: core::slice::Iter<'a, NodeId>,
+}
+
+impl<'a> Siblings<'a> {
+    pub(crate) fn new(tree: &'a AstTree, id: NodeId) -> Self {
+        let children = tree
+            .parent_of(id)
+            .and_then(|p| tree.get_node(p))
+            .map_or(&[][..], |p| p.children.as_slice());
+        Self {
+            tree,
+            id,
+            children: children.iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for Siblings<'a> {
+    type Item = &'a AstNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let &id = self.children.next()?;
+            if id != self.id {
+                if let Some(node) = self.tree.get_node(id) {
+                    return Some(node);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::AstNodeKind;
+    #[cfg(not(feature = "std"))]
+    use alloc::{format, string::String};
+
+    /// root ─┬─ a ─┬─ b ── c
+    ///       │     └─ d
+    ///       └─ e
+    fn scene() -> (AstTree, [NodeId; 5]) {
+        let mut tree = AstTree::new();
+        let a = tree.add_node(AstNodeKind::Group, "a", 0);
+        let b = tree.add_node(AstNodeKind::Group, "b", a);
+        let c = tree.add_node(AstNodeKind::Primitive, "c", b);
+        let d = tree.add_node(AstNodeKind::Primitive, "d", a);
+        let e = tree.add_node(AstNodeKind::Primitive, "e", 0);
+        (tree, [a, b, c, d, e])
+    }
+
+    fn ids<'a>(nodes: impl Iterator<Item = &'a AstNode>) -> Vec<NodeId> {
+        nodes.map(|n| n.id).collect()
+    }
+
+    #[test]
+    fn test_pre_post_and_breadth_first_order() {
+        let (tree, [a, b, c, d, e]) = scene();
+        assert_eq!(ids(tree.pre_order(0)), [0, a, b, c, d, e]);
+        assert_eq!(ids(tree.post_order(0)), [c, b, d, a, e, 0]);
+        assert_eq!(ids(tree.breadth_first(0)), [0, a, e, b, d, c]);
+        assert_eq!(ids(tree.pre_order(b)), [b, c]);
+        assert_eq!(tree.pre_order(99).count(), 0);
+        assert_eq!(tree.post_order(99).count(), 0);
+    }
+
+    #[test]
+    fn test_ancestors_descendants_and_siblings() {
+        let (tree, [a, b, c, d, e]) = scene();
+        assert_eq!(ids(tree.ancestors(c)), [b, a, 0]);
+        assert_eq!(tree.ancestors(0).count(), 0);
+        let desc: Vec<(usize, NodeId)> = tree.descendants(a).map(|(k, n)| (k, n.id)).collect();
+        assert_eq!(desc, [(1, b), (2, c), (1, d)]);
+        assert_eq!(ids(tree.siblings(a)), [e]);
+        assert_eq!(ids(tree.siblings(d)), [b]);
+        assert_eq!(tree.siblings(0).count(), 0);
+    }
+
+    #[test]
+    fn test_iterators_skip_missing_children() {
+        let (mut tree, [a, b, c, d, _]) = scene();
+        tree.get_node_mut(a).unwrap().children.insert(1, 99);
+        assert_eq!(ids(tree.pre_order(a)), [a, b, c, d]);
+        assert_eq!(tree.post_order(a).count(), 4);
+        assert_eq!(tree.breadth_first(a).count(), 4);
+        assert_eq!(tree.descendants(a).count(), 3);
+        assert_eq!(ids(tree.siblings(b)), [d]);
+    }
+
+    struct Trace {
+        events: Vec<String>,
+        skip: NodeId,
+    }
+
+    impl Visitor for Trace {
+        fn enter(&mut self, node: &AstNode, depth: usize) -> bool {
+            self.events.push(format!("+{}@{depth}", node.label));
+            node.id != self.skip
+        }
+
+        fn leave(&mut self, node: &AstNode, depth: usize) {
+            self.events.push(format!("-{}@{depth}", node.label));
+        }
+    }
+
+    #[test]
+    fn test_walk_enters_and_leaves_in_nesting_order() {
+        let (tree, [a, b, ..]) = scene();
+        let mut trace = Trace {
+            events: Vec::new(),
+            skip: b,
+        };
+        tree.walk(a, &mut trace);
+        assert_eq!(
+            trace.events,
+            ["+a@0", "+b@1", "-b@1", "+d@1", "-d@1", "-a@0"]
+        );
+    }
+
+    #[test]
+    fn test_deep_chain_does_not_overflow() {
+        let mut tree = AstTree::new();
+        let mut parent = 0;
+        for _ in 0..100_000 {
+            parent = tree.add_node(AstNodeKind::Group, "key", parent);
+        }
+        assert_eq!(tree.pre_order(0).count(), 100_001);
+        assert_eq!(tree.post_order(0).next().map(|n| n.id), Some(parent));
+        assert_eq!(tree.breadth_first(0).count(), 100_001);
+        assert_eq!(tree.ancestors(parent).count(), 100_000);
+        assert_eq!(tree.descendants(0).last().map(|(k, _)| k), Some(100_000));
+        let mut trace = Trace {
+            events: Vec::new(),
+            skip: NodeId::MAX,
+        };
+        tree.walk(0, &mut trace);
+        assert_eq!(trace.events.len(), 200_002);
+    }
+}